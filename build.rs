@@ -1,5 +1,3 @@
-use std::env;
-use std::path::PathBuf;
 use std::process::Command;
 
 fn main() {
@@ -8,8 +6,76 @@ fn main() {
         .compile_protos(&["src/unixfs.proto", "src/merkledag.proto"], &["src"])
         .expect("unable to generate unixfs protobufs");
 
+    #[cfg(feature = "nginx")]
+    nginx_bindings();
+
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .expect("unable to print git commit hash");
+    let git_hash =
+        String::from_utf8(output.stdout).expect("unable to parse git stdout as utf8 string");
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+}
+
+// oldest nginx the module builds against, as its nginx_version
+#[cfg(feature = "nginx")]
+const NGINX_MIN_VERSION: u64 = 1_018_000;
+
+// `nginx_version` of the nginx sources, such as 1025003 for 1.25.3
+#[cfg(feature = "nginx")]
+fn nginx_version(nginx_h: &std::path::Path) -> u64 {
+    let source = std::fs::read_to_string(nginx_h)
+        .unwrap_or_else(|e| panic!("unable to read {}: {}", nginx_h.display(), e));
+    source
+        .lines()
+        .find_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some("nginx_version"), Some(version)) => version.parse().ok(),
+                _ => None,
+            }
+        })
+        .unwrap_or_else(|| panic!("no nginx_version in {}", nginx_h.display()))
+}
+
+#[cfg(feature = "nginx")]
+fn nginx_bindings() {
+    use std::env;
+    use std::path::PathBuf;
+
     // Path to the nginx repo in the local file system
     let nginx_dir = env::var("NGINX_DIR").unwrap_or(String::from("../nginx"));
+    // the module signature and the layout of the structs come from the configure step
+    let auto_config = PathBuf::from(&nginx_dir).join("objs/ngx_auto_config.h");
+    if !auto_config.exists() {
+        panic!(
+            "{} not found, run ./configure in {} first, with --with-compat to load the \
+             module into a stock nginx of the same version",
+            auto_config.display(),
+            nginx_dir
+        );
+    }
+    println!("cargo:rerun-if-changed={}", auto_config.display());
+
+    // the bindings follow the layout of the structs of the version, the cfgs tell the
+    // module about the fields and APIs that come and go
+    let nginx_h = PathBuf::from(&nginx_dir).join("src/core/nginx.h");
+    println!("cargo:rerun-if-changed={}", nginx_h.display());
+    let version = nginx_version(&nginx_h);
+    if version < NGINX_MIN_VERSION {
+        panic!(
+            "nginx {}.{}.{} is not supported, the module needs 1.18.0 or later",
+            version / 1_000_000,
+            version / 1_000 % 1_000,
+            version % 1_000
+        );
+    }
+    println!("cargo:rustc-check-cfg=cfg(ngx_header_next)");
+    // headers of the same name are linked together since 1.23.0
+    if version >= 1_023_000 {
+        println!("cargo:rustc-cfg=ngx_header_next");
+    }
 
     let clang_args = [
         format!("-I{}/objs", nginx_dir),
@@ -37,12 +103,4 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("unable to write nginx bindings.");
-
-    let output = Command::new("git")
-        .args(&["rev-parse", "HEAD"])
-        .output()
-        .expect("unable to print git commit hash");
-    let git_hash =
-        String::from_utf8(output.stdout).expect("unable to parse git stdout as utf8 string");
-    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
 }
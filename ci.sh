@@ -1,13 +1,43 @@
 #!/bin/bash
 set -x #echo on
 
+# the protocols every range is requested over, with the curl flags and port for each
+PROTOCOLS=("http1.0:--http1.0:8080" "http1.1:--http1.1:8080" "h2c:--http2-prior-knowledge:8081")
+
 test_range_request () {
   range="$1"
   name="$2"
-  code="$(curl -sw "%{http_code}\n" -o partial.car -H "Accept: application/vnd.ipld.car" "http://127.0.0.1:8080/${name}.car?entity-bytes=${range}")"
-  test "$code" -eq 200 || (cat /var/log/nginx/error.log && exit 1)
-  ls -l partial.car
-  /usr/local/bin/car ls -v partial.car
+  for protocol in "${PROTOCOLS[@]}"; do
+    IFS=: read -r proto flag port <<< "$protocol"
+    code="$(curl -s "$flag" -D "headers.$proto" -w "%{http_code}\n" -o "partial.$proto.car" -H "Accept: application/vnd.ipld.car" "http://127.0.0.1:${port}/${name}.car?entity-bytes=${range}")"
+    test "$code" -eq 200 || { cat /var/log/nginx/error.log; exit 1; }
+    grep -qi "^x-car-range: filtered" "headers.$proto" || { cat "headers.$proto"; exit 1; }
+    # the length of a filtered CAR that doesn't end in the first buffers isn't known when
+    # the header goes out
+    ! grep -qi "^content-length:" "headers.$proto" || { cat "headers.$proto"; exit 1; }
+    ls -l "partial.$proto.car"
+  done
+  # the framing differs, the CAR mustn't
+  cmp partial.http1.0.car partial.http1.1.car || exit 1
+  cmp partial.h2c.car partial.http1.1.car || exit 1
+  /usr/local/bin/car ls -v partial.http1.1.car
+}
+
+# a filtered CAR that ends in the first buffer read from the file has a length
+test_known_length () {
+  args="$1"
+  name="$2"
+  for protocol in "${PROTOCOLS[@]}"; do
+    IFS=: read -r proto flag port <<< "$protocol"
+    code="$(curl -s "$flag" -D "headers.$proto" -w "%{http_code}\n" -o "partial.$proto.car" -H "Accept: application/vnd.ipld.car" "http://127.0.0.1:${port}/${name}.car?${args}")"
+    test "$code" -eq 200 || { cat /var/log/nginx/error.log; exit 1; }
+    grep -qi "^x-car-range: filtered" "headers.$proto" || { cat "headers.$proto"; exit 1; }
+    len="$(grep -i "^content-length:" "headers.$proto" | tr -d '\r' | awk '{print $2}')"
+    test -n "$len" && test "$len" -eq "$(stat -c %s "partial.$proto.car")" || { cat "headers.$proto"; exit 1; }
+  done
+  cmp partial.http1.0.car partial.http1.1.car || exit 1
+  cmp partial.h2c.car partial.http1.1.car || exit 1
+  /usr/local/bin/car ls -v partial.http1.1.car
 }
 
 /etc/init.d/nginx stop
@@ -26,4 +56,7 @@ test_range_request "1048576:2097152" "midfixture"
 
 test_range_request "555555:999999" "bigfixture"
 
+# the header and the root block
+test_known_length "dag-scope=block" "midfixture"
+
 cat /var/log/nginx/error.log
@@ -0,0 +1,17 @@
+#!/bin/sh
+# Fetch the CAR fixtures of the gateway conformance suite into conformance/fixtures, run
+# from the repository root. GATEWAY_CONFORMANCE_REF picks the tag to fetch, by default the
+# one the CI runs.
+set -e
+
+ref="${GATEWAY_CONFORMANCE_REF:-v0.5.0}"
+tmp="$(mktemp -d)"
+trap 'rm -rf "$tmp"' EXIT
+
+git clone --quiet --depth 1 --branch "$ref" https://github.com/ipfs/gateway-conformance "$tmp"
+
+dest="$(pwd)/conformance/fixtures"
+rm -rf "$dest"
+mkdir -p "$dest"
+cd "$tmp/fixtures"
+find . -name '*.car' -exec cp --parents {} "$dest" \;
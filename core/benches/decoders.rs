@@ -0,0 +1,89 @@
+//! The varint and CID decoders of the parser, on inputs in one buffer and split across
+//! buffers the way upstream responses straddle them.
+//!
+//! ```text
+//! cargo bench -p car-range-core --features bench
+//! ```
+
+use car_range_core::framed::Framed;
+use car_range_core::varint::VarInt;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+
+// varints of 1, 2, 3, 5 and 10 bytes
+const VALUES: &[u64] = &[0x7f, 0x3fff, 1 << 20, 1 << 32, u64::MAX];
+
+// the digest of the CIDs, followed by the start of the block
+const DIGEST: &str = "afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b5";
+
+// the buffers `input` is in: whole, split in the middle and one byte per buffer
+fn splits(input: &[u8]) -> Vec<(&'static str, Vec<&[u8]>)> {
+    let (head, tail) = input.split_at(input.len() / 2);
+    vec![
+        ("whole", vec![input]),
+        ("half", vec![head, tail]),
+        ("bytes", input.chunks(1).collect()),
+    ]
+}
+
+fn bench_decode_var(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_var");
+    for &value in VALUES {
+        let input = value.encode_var_vec();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(input.len()),
+            &input,
+            |b, input| b.iter(|| u64::decode_var(black_box(input))),
+        );
+    }
+    group.finish();
+}
+
+fn bench_decode_len(c: &mut Criterion) {
+    let mut framed = Framed::new(..);
+    for (name, section) in [("decode_len/section", true), ("decode_len/protobuf", false)] {
+        let mut group = c.benchmark_group(name);
+        for &value in VALUES {
+            // section lengths are at most 4MiB
+            if section && value > 4 << 20 {
+                continue;
+            }
+            let input = value.encode_var_vec();
+            for (split, parts) in splits(&input) {
+                let id = BenchmarkId::new(split, input.len());
+                group.bench_with_input(id, &parts, |b, parts| {
+                    b.iter(|| framed.bench_decode_len(black_box(parts), section).unwrap())
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+fn bench_decode_cid(c: &mut Criterion) {
+    let mut framed = Framed::new(..);
+    let mut group = c.benchmark_group("decode_cid");
+    let cids = [
+        ("v0", format!("1220{}", DIGEST)),
+        ("raw", format!("01551220{}", DIGEST)),
+        ("dag-pb", format!("01701220{}", DIGEST)),
+    ];
+    for (kind, cid) in cids {
+        let input = hex::decode(format!("{}0a2c0802", cid)).unwrap();
+        for (split, parts) in splits(&input) {
+            let id = BenchmarkId::new(kind, split);
+            group.bench_with_input(id, &parts, |b, parts| {
+                b.iter(|| framed.bench_decode_cid(black_box(parts)).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_decode_var,
+    bench_decode_len,
+    bench_decode_cid
+);
+criterion_main!(benches);
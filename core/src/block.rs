@@ -0,0 +1,259 @@
+//! Picks the block of one CID out of a CAR, for gateways answering `format=raw` requests
+//! from upstreams that only send CARs. The block isn't hashed here, the caller checks it
+//! against its CID with the hash functions it has.
+//!
+//! ```
+//! use car_range_core::block::BlockExtractor;
+//! use cid::Cid;
+//!
+//! fn extract(cid: Cid, upstream: &[&[u8]]) -> Option<Vec<u8>> {
+//!     let mut blocks = BlockExtractor::new(cid);
+//!     for buf in upstream {
+//!         blocks.push(buf).ok()?;
+//!     }
+//!     blocks.block().map(|block| block.to_vec())
+//! }
+//! ```
+
+use crate::framed::{MAX_CID_LEN, MAX_SECTION_LEN};
+use crate::varint::{decode_uvarint, MAX_UVARINT_LEN};
+use cid::Cid;
+use core2::io::{self, Cursor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    HeaderLen,
+    Header,
+    SectionLen,
+    Cid,
+    // the data of a block of another CID
+    Skip,
+    Block,
+    Done,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a CAR pushed in buffers of any size until the block of a CID is complete.
+pub struct BlockExtractor {
+    cid: Cid,
+    state: State,
+    // the start of a length or CID split across buffers
+    held: Vec<u8>,
+    // bytes left in the header or section being read
+    left: usize,
+    block: Vec<u8>,
+    // bytes of the CAR read, the ones after the block are not
+    offset: usize,
+}
+
+impl BlockExtractor {
+    pub fn new(cid: Cid) -> Self {
+        Self {
+            cid,
+            state: State::HeaderLen,
+            held: Vec::new(),
+            left: 0,
+            block: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Read the next bytes of the CAR, the error is the one of the first push that failed.
+    pub fn push(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() && self.state != State::Done {
+            let read = match self.state {
+                State::HeaderLen | State::SectionLen => self.read_len(buf)?,
+                State::Cid => self.read_cid(buf)?,
+                State::Header | State::Skip | State::Block => {
+                    let read = std::cmp::min(self.left, buf.len());
+                    if self.state == State::Block {
+                        self.block.extend_from_slice(&buf[..read]);
+                    }
+                    self.left -= read;
+                    if self.left == 0 {
+                        self.next_section();
+                    }
+                    read
+                }
+                State::Done => 0,
+            };
+            self.offset += read;
+            buf = &buf[read..];
+        }
+        Ok(())
+    }
+
+    // the section after the header or data just read, unless it was the block
+    fn next_section(&mut self) {
+        self.state = match self.state {
+            State::Block => State::Done,
+            _ => State::SectionLen,
+        };
+    }
+
+    fn read_len(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let filled = self.held.len();
+        let take = std::cmp::min(buf.len(), MAX_UVARINT_LEN - filled);
+        self.held.extend_from_slice(&buf[..take]);
+        let (len, read) = match decode_uvarint(&self.held) {
+            Ok(Some((len, read))) => (len, read),
+            Ok(None) => return Ok(take),
+            Err(e) => return Err(invalid(e.as_str())),
+        };
+        self.held.clear();
+        if len > MAX_SECTION_LEN as u64 {
+            return Err(invalid("CAR section too long"));
+        }
+        self.left = len as usize;
+        self.state = match self.state {
+            State::HeaderLen if len == 0 => return Err(invalid("empty CAR header")),
+            State::HeaderLen => State::Header,
+            // a section holds at least a CID
+            _ if len == 0 => return Err(invalid("empty CAR section")),
+            _ => State::Cid,
+        };
+        Ok(read - filled)
+    }
+
+    fn read_cid(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let filled = self.held.len();
+        let max = std::cmp::min(self.left, MAX_CID_LEN);
+        let take = std::cmp::min(buf.len(), max - filled);
+        self.held.extend_from_slice(&buf[..take]);
+        let mut reader = Cursor::new(&self.held[..]);
+        let cid = match Cid::read_bytes(&mut reader) {
+            Ok(cid) => cid,
+            // we have the whole section or more than any CID takes
+            Err(_) if self.held.len() >= max => return Err(invalid("invalid CID")),
+            Err(_) => return Ok(take),
+        };
+        let len = reader.position() as usize;
+        self.held.clear();
+        self.left -= len;
+        self.state = if cid == self.cid {
+            State::Block
+        } else {
+            State::Skip
+        };
+        if self.left == 0 {
+            self.next_section();
+        }
+        Ok(len - filled)
+    }
+
+    /// The data of the block, once all of it was read.
+    pub fn block(&self) -> Option<&[u8]> {
+        (self.state == State::Done).then_some(&self.block[..])
+    }
+
+    /// Bytes read from the CAR, up to the end of the block.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The CAR read so far ends in the middle of its header or of a section.
+    pub fn is_partial(&self) -> bool {
+        match self.state {
+            State::SectionLen => !self.held.is_empty(),
+            State::Done => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::varint::VarInt;
+
+    // a CAR of the dag-cbor block {"dog": true} and its CID, which is the root
+    const CAR: &str = "3aa265726f6f747381d82a58250001711220151fe9e73c6267a7060c6f6c4cca943c236f4b196723489608edb42a8b8fa80b6776657273696f6e012c01711220151fe9e73c6267a7060c6f6c4cca943c236f4b196723489608edb42a8b8fa80ba165646f646779f5";
+    const CID: &str = "bafyreiavd7u6opdcm6tqmddpnrgmvfb4enxuwglhenejmchnwqvixd5ibm";
+
+    fn raw_section(data: &[u8]) -> (Cid, Vec<u8>) {
+        // a raw block with an identity hash of its data
+        let mut cid = vec![0x01, 0x55, 0x00, data.len() as u8];
+        cid.extend_from_slice(data);
+        let mut section = (cid.len() + data.len()).encode_var_vec();
+        section.extend_from_slice(&cid);
+        section.extend_from_slice(data);
+        (Cid::try_from(&cid[..]).unwrap(), section)
+    }
+
+    #[test]
+    fn test_extract_block() {
+        let car = hex::decode(CAR).unwrap();
+        let cid = Cid::try_from(CID).unwrap();
+        for split in 1..car.len() {
+            let mut blocks = BlockExtractor::new(cid);
+            for buf in car.chunks(split) {
+                blocks.push(buf).unwrap();
+            }
+            assert_eq!(
+                blocks.block(),
+                Some(&hex::decode("a165646f646779f5").unwrap()[..])
+            );
+            assert_eq!(blocks.offset(), car.len());
+            assert!(!blocks.is_partial());
+        }
+    }
+
+    // the blocks of other CIDs are skipped, and so are the bytes after the block
+    #[test]
+    fn test_extract_block_skip() {
+        let mut car = hex::decode(CAR).unwrap();
+        let (first, section) = raw_section(b"first");
+        car.extend_from_slice(&section);
+        let (second, section) = raw_section(b"second");
+        let end = car.len() + section.len();
+        car.extend_from_slice(&section);
+        car.extend_from_slice(&raw_section(b"third").1);
+
+        for split in [1, 3, 7, car.len()] {
+            let mut blocks = BlockExtractor::new(second);
+            for buf in car.chunks(split) {
+                blocks.push(buf).unwrap();
+            }
+            assert_eq!(blocks.block(), Some(&b"second"[..]));
+            assert_eq!(blocks.offset(), end);
+        }
+
+        let mut blocks = BlockExtractor::new(first);
+        blocks.push(&car[..car.len() - 3]).unwrap();
+        assert_eq!(blocks.block(), Some(&b"first"[..]));
+    }
+
+    #[test]
+    fn test_extract_block_missing() {
+        let car = hex::decode(CAR).unwrap();
+        let (cid, _) = raw_section(b"missing");
+        let mut blocks = BlockExtractor::new(cid);
+        blocks.push(&car).unwrap();
+        assert_eq!(blocks.block(), None);
+        assert!(!blocks.is_partial());
+
+        // the CAR ends in the block
+        let mut blocks = BlockExtractor::new(Cid::try_from(CID).unwrap());
+        blocks.push(&car[..car.len() - 1]).unwrap();
+        assert_eq!(blocks.block(), None);
+        assert!(blocks.is_partial());
+    }
+
+    #[test]
+    fn test_extract_block_invalid() {
+        let cid = Cid::try_from(CID).unwrap();
+        // a section of a single byte that isn't a CID
+        let mut car = hex::decode(CAR).unwrap()[..59].to_vec();
+        car.extend_from_slice(&[0x01, 0xff]);
+        assert!(BlockExtractor::new(cid).push(&car).is_err());
+
+        let mut car = hex::decode(CAR).unwrap()[..59].to_vec();
+        car.extend_from_slice(&(MAX_SECTION_LEN + 1).encode_var_vec());
+        assert!(BlockExtractor::new(cid).push(&car).is_err());
+
+        assert!(BlockExtractor::new(cid).push(&[0x00]).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! The CARv2 of a filtered CAR, for clients asking for `version=2`: the pragma and the
+//! header, the CARv1 as the data payload and a `MultihashIndexSorted` index of its blocks,
+//! the default index of go-car. The header holds the length of the payload, so the CARv1
+//! has to be complete before any of its CARv2 is sent.
+//!
+//! ```
+//! use car_range_core::carv2;
+//!
+//! fn wrap(car: &[u8]) -> Option<Vec<u8>> {
+//!     carv2::wrap(car).ok()
+//! }
+//! ```
+
+use crate::framed::MAX_CID_LEN;
+use crate::varint::VarInt;
+use cid::Cid;
+use core2::io::{self, Cursor};
+use std::collections::BTreeMap;
+
+/// The CARv1 header of version 2 a CARv2 starts with.
+pub const PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+
+/// Length of the characteristics, data offset, data size and index offset of a CARv2.
+pub const HEADER_LEN: usize = 40;
+
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+const IDENTITY: u64 = 0x00;
+
+// the digests of a multihash code and digest length, and the offsets of their sections
+type Bucket<'a> = Vec<(&'a [u8], u64)>;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The CARv2 of the CARv1 `car`, with the payload right after the header and the index
+/// right after the payload.
+pub fn wrap(car: &[u8]) -> io::Result<Vec<u8>> {
+    let index = index(car)?;
+    let data_offset = (PRAGMA.len() + HEADER_LEN) as u64;
+    let data_size = car.len() as u64;
+
+    let mut out = Vec::with_capacity(PRAGMA.len() + HEADER_LEN + car.len() + index.len());
+    out.extend_from_slice(&PRAGMA);
+    // no characteristics, identity CIDs aren't indexed
+    out.extend_from_slice(&[0; 16]);
+    out.extend_from_slice(&data_offset.to_le_bytes());
+    out.extend_from_slice(&data_size.to_le_bytes());
+    out.extend_from_slice(&(data_offset + data_size).to_le_bytes());
+    out.extend_from_slice(car);
+    out.extend_from_slice(&index);
+    Ok(out)
+}
+
+// the index of the blocks of `car`: the offsets of their sections in the payload by
+// multihash code, then digest length, then digest
+fn index(car: &[u8]) -> io::Result<Vec<u8>> {
+    let section = |rest: &[u8]| -> io::Result<(usize, usize)> {
+        let (len, read) = usize::decode_var(rest).ok_or_else(|| invalid("invalid CAR section"))?;
+        match read.checked_add(len) {
+            Some(end) if end <= rest.len() => Ok((read, end)),
+            _ => Err(invalid("CAR section past the end of the CAR")),
+        }
+    };
+
+    let mut buckets: BTreeMap<u64, BTreeMap<usize, Bucket>> = BTreeMap::new();
+    // the header isn't indexed
+    let (_, mut pos) = section(car)?;
+    while pos < car.len() {
+        let (read, end) = section(&car[pos..])?;
+        let block = &car[pos + read..pos + end];
+        let mut reader = Cursor::new(&block[..block.len().min(MAX_CID_LEN)]);
+        let cid = Cid::read_bytes(&mut reader).map_err(|_| invalid("invalid CID"))?;
+        let hash = cid.hash();
+        if hash.code() != IDENTITY {
+            // the digest is at the end of the multihash of the CID
+            let cid_len = reader.position() as usize;
+            let digest = &block[cid_len - hash.digest().len()..cid_len];
+            buckets
+                .entry(hash.code())
+                .or_default()
+                .entry(digest.len())
+                .or_default()
+                .push((digest, pos as u64));
+        }
+        pos += end;
+    }
+
+    let mut out = MULTIHASH_INDEX_SORTED.encode_var_vec();
+    out.extend_from_slice(&(buckets.len() as i32).to_le_bytes());
+    for (code, widths) in buckets {
+        out.extend_from_slice(&code.to_le_bytes());
+        out.extend_from_slice(&(widths.len() as i32).to_le_bytes());
+        for (len, mut entries) in widths {
+            entries.sort();
+            let width = len + 8;
+            out.extend_from_slice(&(width as u32).to_le_bytes());
+            out.extend_from_slice(&((entries.len() * width) as i64).to_le_bytes());
+            for (digest, offset) in entries {
+                out.extend_from_slice(digest);
+                out.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framed::Framed;
+
+    // a header without roots, a raw block of an identity CID and one of a sha2-256 CID
+    const CAR: &str = "0aa16776657273696f6e010c0155000461626364616263642801551220\
+                       88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589\
+                       61626364";
+
+    #[test]
+    fn test_wrap() {
+        let car = hex::decode(CAR).unwrap();
+        let v2 = wrap(&car).unwrap();
+        assert_eq!(v2[..PRAGMA.len()], PRAGMA);
+        let field = |i: usize| {
+            let at = PRAGMA.len() + i;
+            u64::from_le_bytes(v2[at..at + 8].try_into().unwrap())
+        };
+        assert_eq!(field(16), 51);
+        assert_eq!(field(24), car.len() as u64);
+        assert_eq!(field(32), 51 + car.len() as u64);
+        assert_eq!(v2[51..51 + car.len()], car[..]);
+
+        // the sha2-256 block at offset 24, the identity one isn't indexed
+        let mut index = hex::decode("8108").unwrap();
+        index.extend_from_slice(&1i32.to_le_bytes());
+        index.extend_from_slice(&0x12u64.to_le_bytes());
+        index.extend_from_slice(&1i32.to_le_bytes());
+        index.extend_from_slice(&40u32.to_le_bytes());
+        index.extend_from_slice(&40i64.to_le_bytes());
+        index.extend_from_slice(&car[car.len() - 36..car.len() - 4]);
+        index.extend_from_slice(&24u64.to_le_bytes());
+        assert_eq!(v2[51 + car.len()..], index[..]);
+
+        // the parser reads the payload back
+        let mut framed = Framed::new(..);
+        let parts = framed.next(&v2).unwrap();
+        let out: Vec<u8> = parts
+            .into_iter()
+            .flat_map(|(start, end)| v2[start..end].to_vec())
+            .collect();
+        assert_eq!(out, car);
+    }
+
+    #[test]
+    fn test_wrap_invalid() {
+        let car = hex::decode(CAR).unwrap();
+        assert!(wrap(&car[..car.len() - 1]).is_err());
+        assert!(wrap(&[]).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! A streaming decoder selecting the CAR sections needed for an `entity-bytes` range, the
+//! same selection the nginx module makes on the upstream buffers, for other proxies to
+//! reuse without nginx.
+//!
+//! The CAR is pushed in buffers of any size, then the parts of each buffer to forward are
+//! pulled in order. The start of a section may be held back until it is known to be in
+//! the range, it comes out with the buffer it was found to be in.
+//!
+//! ```
+//! use car_range_core::decoder::{CarRangeDecoder, Part};
+//!
+//! fn filter(upstream: &[&[u8]]) -> Vec<u8> {
+//!     let mut decoder = CarRangeDecoder::new(0..=1023);
+//!     let mut out = Vec::new();
+//!     for buf in upstream {
+//!         if decoder.push(buf).is_err() {
+//!             break;
+//!         }
+//!         while let Some(part) = decoder.pull() {
+//!             match part {
+//!                 Part::Held(bytes) => out.extend_from_slice(&bytes),
+//!                 Part::Range(range) => out.extend_from_slice(&buf[range]),
+//!             }
+//!         }
+//!         if decoder.past_end() {
+//!             break;
+//!         }
+//!     }
+//!     out
+//! }
+//! ```
+
+use crate::framed::Framed;
+pub use crate::framed::Malformed;
+pub use crate::range::{
+    parse_entity_bytes, parse_from_end, parse_range, parse_range_from_end, FromEnd,
+};
+use std::collections::VecDeque;
+use std::ops::{Range, RangeBounds};
+
+/// Bytes to forward, in the order they are pulled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// The start of a section from earlier buffers, found to be in the range.
+    Held(Vec<u8>),
+    /// Bytes of the last buffer pushed.
+    Range(Range<usize>),
+}
+
+/// Selects the CAR header and the blocks of a unixfs file needed to verify the bytes of
+/// `range`, like a `dag-scope=entity` request.
+pub struct CarRangeDecoder<R: RangeBounds<u64> + Clone> {
+    framed: Framed<R>,
+    parts: VecDeque<Part>,
+    error: Option<Malformed>,
+}
+
+impl<R: RangeBounds<u64> + Clone> CarRangeDecoder<R> {
+    pub fn new(range: R) -> Self {
+        Self {
+            framed: Framed::new(range),
+            parts: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Fail if the CAR header is longer than `len`.
+    pub fn set_max_header_len(&mut self, len: usize) {
+        self.framed.set_max_header_len(len);
+    }
+
+    /// Fail if the first block isn't the root of the CAR header.
+    pub fn set_verify_root(&mut self, verify: bool) {
+        self.framed.set_verify_root(verify);
+    }
+
+    /// Only select the CAR header and the first block, like a `dag-scope=block` request.
+    pub fn set_block_scope(&mut self, block: bool) {
+        self.framed.set_block_scope(block);
+    }
+
+    /// Count the bounds of `from_end` back from the end of the file, for the negative
+    /// bounds of `entity-bytes`, see [`parse_range_from_end`].
+    pub fn set_from_end(&mut self, from_end: FromEnd) {
+        self.framed.set_from_end(from_end);
+    }
+
+    /// Parse the next bytes of the CAR. The parts left from the previous buffer are
+    /// dropped, once the CAR fails to parse every call returns the same error.
+    pub fn push(&mut self, buf: &[u8]) -> Result<(), &Malformed> {
+        self.parts.clear();
+        if self.error.is_none() {
+            match self.framed.next(buf) {
+                Ok(parts) => {
+                    let held = self.framed.take_released();
+                    if !held.is_empty() {
+                        self.parts.push_back(Part::Held(held));
+                    }
+                    self.parts.extend(
+                        parts
+                            .into_iter()
+                            .map(|(start, end)| Part::Range(start..end)),
+                    );
+                }
+                Err(error) => self.error = Some(self.framed.malformed(error)),
+            }
+        }
+        match &self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// The next part to forward of the last buffer pushed.
+    pub fn pull(&mut self) -> Option<Part> {
+        self.parts.pop_front()
+    }
+
+    /// The data of the range was all read, the rest of the CAR can be dropped.
+    pub fn past_end(&self) -> bool {
+        self.framed.past_end()
+    }
+
+    /// The bytes pushed end in the middle of a CAR section.
+    pub fn is_partial(&self) -> bool {
+        self.framed.is_partial()
+    }
+
+    pub fn error(&self) -> Option<&Malformed> {
+        self.error.as_ref()
+    }
+
+    /// Whether the decoder failed because the first block isn't the CAR root.
+    pub fn root_mismatch(&self) -> bool {
+        self.framed.root_mismatch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Builder;
+
+    fn decode(car: &[u8], range: Range<u64>, chunk: usize) -> Vec<u8> {
+        let mut decoder = CarRangeDecoder::new(range);
+        decoder.set_verify_root(true);
+        let mut out = Vec::new();
+        for buf in car.chunks(chunk) {
+            decoder.push(buf).unwrap();
+            while let Some(part) = decoder.pull() {
+                match part {
+                    Part::Held(bytes) => out.extend_from_slice(&bytes),
+                    Part::Range(range) => out.extend_from_slice(&buf[range]),
+                }
+            }
+            if decoder.past_end() {
+                break;
+            }
+        }
+        out
+    }
+
+    // the header, the nodes leading to the range and the leaves overlapping it, however
+    // the CAR is split
+    #[test]
+    fn test_decoder_splits() {
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let leaves = |range: &Range<u64>| {
+            let overlaps = |data: &Range<u64>| data.start < range.end && range.start < data.end;
+            let last = file
+                .sections
+                .iter()
+                .rposition(|section| section.data.as_ref().is_some_and(overlaps));
+            let mut want = file.car[file.header.clone()].to_vec();
+            for section in &file.sections[..last.map_or(0, |last| last + 1)] {
+                if section.data.as_ref().is_none_or(overlaps) {
+                    want.extend_from_slice(&file.car[section.bytes.clone()]);
+                }
+            }
+            want
+        };
+        for range in [0..1, 2500..4000, 9000..20000] {
+            let want = leaves(&range);
+            for chunk in [1, 7, 100, file.car.len()] {
+                assert_eq!(decode(&file.car, range.clone(), chunk), want);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder_error() {
+        let mut decoder = CarRangeDecoder::new(..);
+        decoder.set_max_header_len(10);
+        let file = Builder::new().build(b"hello");
+        assert!(decoder.push(&file.car).is_err());
+        assert!(decoder.pull().is_none());
+        // the parser doesn't resume
+        assert_eq!(decoder.push(&[]).unwrap_err().offset, 0);
+        assert!(decoder.error().is_some());
+    }
+}
@@ -0,0 +1,1078 @@
+//! The parser of the upstream CAR: it follows the CAR sections and the dag-pb and unixfs
+//! fields of the blocks across buffers, and picks the sections of the blocks leading to
+//! the range of the file.
+
+use crate::carv2;
+use crate::range::FromEnd;
+use crate::varint::{decode_u64, decode_uvarint};
+use cid::Cid;
+use core2::io::{self, Cursor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Bound, Deref, Range, RangeBounds};
+
+fn ranges_overlap<T: RangeBounds<u64>>(range1: T, range2: Range<usize>) -> bool {
+    let (start1, end1) = (
+        match range1.start_bound() {
+            Bound::Included(x) => *x,
+            Bound::Excluded(x) => x.saturating_add(1),
+            Bound::Unbounded => u64::MIN,
+        },
+        match range1.end_bound() {
+            Bound::Included(x) => x.saturating_add(1),
+            Bound::Excluded(x) => *x,
+            Bound::Unbounded => u64::MAX,
+        },
+    );
+    let (start2, end2) = (range2.start as u64, range2.end as u64);
+
+    start1 < end2 && start2 < end1
+}
+
+// forward `start..end` of the buffer, merged with the previous part when they touch
+fn push_part(parts: &mut Vec<(usize, usize)>, start: usize, end: usize) {
+    if start == end {
+        return;
+    }
+    match parts.last_mut() {
+        Some(last) if last.1 == start => last.1 = end,
+        _ => parts.push((start, end)),
+    }
+}
+
+// CAR V1 header, should contain a single root and be CBOR encoded
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct CarHeader {
+    pub roots: Vec<Cid>,
+    pub version: u64,
+}
+
+// Unixfs data type enum
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, num_enum::IntoPrimitive, num_enum::TryFromPrimitive,
+)]
+#[repr(i32)]
+pub enum DataType {
+    Raw = 0,
+    Directory = 1,
+    File = 2,
+    Metadata = 3,
+    Symlink = 4,
+    HamtShard = 5,
+}
+
+// bitswap doesn't transfer blocks larger than 2MiB, leave room for the CID
+pub(crate) const MAX_SECTION_LEN: usize = 4 << 20;
+
+/// Default limit of the CAR header length, enough for a hundred roots.
+pub const MAX_HEADER_LEN: usize = 4096;
+
+/// No CID we can decode is longer than that, a sha2-512 CIDv1 takes 68 bytes.
+pub const MAX_CID_LEN: usize = 128;
+
+// a section is forwarded anyway once that many of its bytes are held, more than the prefix
+// of any block before its place in the file is known
+const MAX_HELD_LEN: usize = 4096;
+
+/// Where the parser gave up on the upstream CAR.
+#[derive(Debug)]
+pub struct Malformed {
+    pub error: io::Error,
+    // offset in the upstream response of the CAR section being parsed
+    pub offset: usize,
+    // the CID of the section, or a hex prefix of the bytes that failed to parse as one
+    pub cid: Option<String>,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.error, self.offset)?;
+        if let Some(cid) = &self.cid {
+            write!(f, ", block {}", cid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the parser keeps the bytes of a varint or CID split across buffers, never more
+/// than [`MAX_CID_LEN`] of them.
+pub trait Scratch: Deref<Target = [u8]> {
+    fn push(&mut self, b: u8);
+
+    fn clear(&mut self);
+}
+
+impl Scratch for Vec<u8> {
+    fn push(&mut self, b: u8) {
+        Vec::push(self, b);
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+}
+
+// the frame being read can't be in that state, the input is corrupt in a way the checks
+// of each frame missed
+fn unexpected_state() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unexpected parser state")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WireType {
+    Varint = 0,
+    SixtyFourBit = 1,
+    LengthDelimited = 2,
+    StartGroup = 3,
+    EndGroup = 4,
+    ThirtyTwoBit = 5,
+}
+
+impl TryFrom<u64> for WireType {
+    type Error = anyhow::Error;
+
+    #[inline]
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(WireType::Varint),
+            1 => Ok(WireType::SixtyFourBit),
+            2 => Ok(WireType::LengthDelimited),
+            3 => Ok(WireType::StartGroup),
+            4 => Ok(WireType::EndGroup),
+            5 => Ok(WireType::ThirtyTwoBit),
+            _ => Err(anyhow::format_err!("invalid wire type value: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameType {
+    CarHeader,
+    // the header of a CARv2 after its pragma
+    V2Header,
+    // the bytes of a CARv2 up to its CARv1 payload
+    V2Padding,
+    Block,
+    Cid,
+    RawLeaf,
+    // the data of a block read whole, in the block scope
+    WholeBlock,
+    MerkleDag,
+    PBLinks,
+    PBData,
+    UnixFs,
+    DataType,
+    FileSize,
+    BlockSizes,
+    UnixFsData,
+    // the value of an unknown varint field
+    SkipVarint,
+    // the length of an unknown length delimited field
+    SkipLen,
+    // the bytes of an unknown field
+    Skip,
+    // the input was rejected, the parser doesn't read any further
+    Error,
+}
+
+/// Reads the sections of a CAR as they come in buffers of any size and selects the parts
+/// of each buffer to forward for the range of a unixfs file.
+pub struct Framed<R: RangeBounds<u64> + Clone, S: Scratch = Vec<u8>> {
+    // the size of the current frame
+    len: usize,
+    // the size of the CAR block containing the current frame
+    blk_len: usize,
+    // the position of the current frame in the CAR block
+    blk_pos: usize,
+    // the buffer containing enough bytes to decode the varing or CID
+    buf: S,
+    // the range of the CAR file we are reading from.
+    range: R,
+    // the bounds of the range counted from the end of the file instead, once its size is
+    // known
+    from_end: FromEnd,
+    // the size of the file, from the first unixfs node of a file or raw block
+    file_size: Option<u64>,
+    // only the first block is forwarded, whatever its codec
+    block_scope: bool,
+    // the first block was read
+    block_read: bool,
+    // the current position in the unixfs file data
+    unixfs_read: usize,
+    // the size of the unixfs frame
+    unixfs_len: usize,
+    // if the current dag node has links in which case it will be included
+    has_links: bool,
+    // the current node is a directory, the data of HAMT shards isn't part of the file
+    dir_node: bool,
+    // the current frame type
+    state: FrameType,
+    // the message of the field being skipped
+    parent: FrameType,
+    // the state the parser was in when it rejected the input
+    failed_in: FrameType,
+    // bytes of the upstream response passed to the parser
+    consumed: usize,
+    // offset of the current CAR section in the upstream response
+    section: usize,
+    // CID of the current block
+    cid: Option<Cid>,
+    // longest CAR header accepted
+    max_header: usize,
+    // check the first block against the root of the CAR header
+    verify_root: bool,
+    // the CAR header, until it is decoded to find the root
+    header: Vec<u8>,
+    // the root the first block must match, taken once the first CID is read
+    root: Option<Cid>,
+    root_mismatch: bool,
+    // a CAR section was started and not finished
+    in_section: bool,
+    // whether the current section is forwarded, once it is known
+    include: Option<bool>,
+    // bytes of the current section from earlier buffers, until it is known to be forwarded
+    held: Vec<u8>,
+    // held bytes of a section found to be in the range, to forward ahead of the buffer
+    released: Vec<u8>,
+    // offset in the upstream response of the end of the CARv1 payload of a CARv2
+    payload_end: Option<usize>,
+    // the payload was read, the index of the CARv2 after it isn't forwarded
+    payload_read: bool,
+}
+
+impl<R: RangeBounds<u64> + Clone> Framed<R> {
+    pub fn new(range: R) -> Self {
+        Self::with_scratch(range, Vec::new())
+    }
+}
+
+impl<R: RangeBounds<u64> + Clone, S: Scratch> Framed<R, S> {
+    /// A parser stashing the bytes of varints and CIDs split across buffers in `buf`.
+    pub fn with_scratch(range: R, buf: S) -> Self {
+        Self {
+            len: 0,
+            blk_len: 0,
+            blk_pos: 0,
+            unixfs_read: 0,
+            unixfs_len: 0,
+            has_links: false,
+            dir_node: false,
+            range,
+            from_end: FromEnd::default(),
+            file_size: None,
+            block_scope: false,
+            block_read: false,
+            buf,
+            state: FrameType::CarHeader,
+            parent: FrameType::MerkleDag,
+            failed_in: FrameType::CarHeader,
+            consumed: 0,
+            section: 0,
+            cid: None,
+            max_header: MAX_HEADER_LEN,
+            verify_root: false,
+            header: Vec::new(),
+            root: None,
+            root_mismatch: false,
+            in_section: false,
+            // the header is always forwarded
+            include: Some(true),
+            held: Vec::new(),
+            released: Vec::new(),
+            payload_end: None,
+            payload_read: false,
+        }
+    }
+
+    // reads all the frames in the buffer returning the number of bytes to remove from the start
+    // and end, once it fails the parser stays in the error state.
+    pub fn next(&mut self, buf: &[u8]) -> io::Result<Vec<(usize, usize)>> {
+        if self.state == FrameType::Error {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parser stopped on an earlier error",
+            ));
+        }
+        let parts = self.next_frames(buf);
+        if parts.is_err() {
+            self.failed_in = self.state;
+            self.state = FrameType::Error;
+        }
+        parts
+    }
+
+    fn next_frames(&mut self, buf: &[u8]) -> io::Result<Vec<(usize, usize)>> {
+        let base = self.consumed;
+        self.consumed += buf.len();
+        let mut ranges = Vec::with_capacity(2);
+        // start of the bytes of the current section in this buffer
+        let mut mark = 0;
+        let mut current = buf;
+        loop {
+            let offset = buf.len() - current.len();
+            if self.in_section
+                && self.state == FrameType::Block
+                && self.len == 0
+                && self.buf.is_empty()
+            {
+                // sections nothing was decided for are nodes without unixfs data
+                self.decide(true);
+                if self.include == Some(true) {
+                    push_part(&mut ranges, mark, offset);
+                }
+                // the header has no CID
+                self.block_read |= self.cid.is_some();
+                self.in_section = false;
+                self.include = None;
+                self.payload_read |= self.payload_end.is_some_and(|end| base + offset >= end);
+            }
+            if current.is_empty() {
+                break;
+            }
+            if cfg!(any(debug_assertions, feature = "hardened")) {
+                self.check_invariants()?;
+            }
+            if self.past_end() {
+                return Ok(ranges);
+            }
+            if !self.in_section {
+                self.in_section = true;
+                mark = offset;
+            }
+            if self.state == FrameType::Cid {
+                match self.decode_cid(current)? {
+                    Some((cid, read)) => {
+                        if self.root.take().is_some_and(|root| root != cid) {
+                            self.cid = Some(cid);
+                            self.root_mismatch = true;
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "first block doesn't match the CAR root",
+                            ));
+                        }
+                        self.state = FrameType::Block;
+                        current = &current[read..];
+
+                        if self.block_scope {
+                            self.state = FrameType::WholeBlock;
+                            self.len = self.blk_remaining()?;
+                            if self.len == 0 {
+                                self.state = FrameType::Block;
+                                self.blk_pos = 0;
+                            }
+                            self.decide(true);
+                            self.cid = Some(cid);
+                            continue;
+                        }
+
+                        match cid.codec() {
+                            0x55 => {
+                                self.state = FrameType::RawLeaf;
+                                self.len = self.blk_remaining()?;
+                                self.unixfs_len = self.len;
+                                // a file of a single raw block
+                                self.file_size.get_or_insert(self.len as u64);
+                                // the block of an empty file has nothing after its CID
+                                if self.len == 0 {
+                                    self.state = FrameType::Block;
+                                    self.blk_pos = 0;
+                                }
+                                self.decide(self.include_block());
+                            }
+                            0x70 => {
+                                self.state = FrameType::MerkleDag;
+                            }
+                            _ => {
+                                self.cid = Some(cid);
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "unsupported block codec",
+                                ));
+                            }
+                        };
+                        self.cid = Some(cid);
+                        continue;
+                    }
+                    None => {
+                        current = &[];
+                        continue;
+                    }
+                };
+            }
+            // beginning of the frame
+            if self.len == 0 {
+                if self.state == FrameType::Block && self.buf.is_empty() {
+                    self.section = base + offset;
+                    self.cid = None;
+                }
+                match self.decode_len(current)? {
+                    Some((size, read)) => {
+                        current = &current[read..];
+                        self.len = size;
+
+                        match self.state {
+                            FrameType::Block => {
+                                self.state = FrameType::Cid;
+                                self.blk_len = size;
+                                self.len = 0;
+                                self.has_links = false;
+                                self.dir_node = false;
+                            }
+                            FrameType::MerkleDag => {
+                                self.blk_pos += read;
+
+                                let key = size as u64;
+                                let wire_type = WireType::try_from(key & 0x7).ok();
+                                let tag = key >> 3;
+
+                                match (tag, wire_type) {
+                                    (2, Some(WireType::LengthDelimited)) => {
+                                        self.state = FrameType::PBLinks;
+                                        self.len = 0;
+                                    }
+                                    (1, Some(WireType::LengthDelimited)) => {
+                                        self.state = FrameType::PBData;
+                                        self.len = 0;
+                                    }
+                                    _ => self.skip_field(wire_type)?,
+                                };
+                            }
+                            FrameType::UnixFs => {
+                                self.blk_pos += read;
+
+                                let key = size as u64;
+                                let wire_type = WireType::try_from(key & 0x7).ok();
+                                let tag = key >> 3;
+
+                                match (tag, wire_type) {
+                                    (1, Some(WireType::Varint)) => {
+                                        self.state = FrameType::DataType;
+                                        self.len = 0;
+                                    }
+                                    (2, Some(WireType::LengthDelimited)) if !self.dir_node => {
+                                        self.state = FrameType::UnixFsData;
+                                        self.len = 0;
+                                    }
+                                    (3, Some(WireType::Varint)) => {
+                                        self.state = FrameType::FileSize;
+                                        self.len = 0;
+                                    }
+                                    (4, Some(WireType::Varint)) => {
+                                        self.state = FrameType::BlockSizes;
+                                        self.len = 0;
+                                    }
+                                    // packed blocksizes, the HAMT fields, mode, mtime and
+                                    // fields added after this parser
+                                    _ => self.skip_field(wire_type)?,
+                                };
+                            }
+                            FrameType::PBLinks => {
+                                self.blk_pos += read;
+                                self.check_field_len(size)?;
+                                self.has_links = true;
+                                // nodes with links lead to the leaves of the range
+                                self.decide(true);
+                            }
+                            FrameType::SkipVarint => {
+                                self.blk_pos += read;
+                                self.len = 0;
+                                self.end_field()?;
+                            }
+                            FrameType::SkipLen => {
+                                self.blk_pos += read;
+                                self.check_field_len(size)?;
+                                self.state = FrameType::Skip;
+                                if size == 0 {
+                                    self.end_field()?;
+                                }
+                            }
+                            FrameType::UnixFsData => {
+                                self.blk_pos += read;
+                                self.check_field_len(size)?;
+                                self.len = self.blk_remaining()?;
+                                self.unixfs_len = size;
+                                self.decide(self.include_block());
+                            }
+                            FrameType::PBData
+                            | FrameType::DataType
+                            | FrameType::FileSize
+                            | FrameType::BlockSizes => {
+                                self.blk_pos += read;
+                                self.len = 0;
+                                // the data of the dag-pb node, read as a unixfs message
+                                if self.state == FrameType::PBData {
+                                    self.check_field_len(size)?;
+                                }
+                                if self.state == FrameType::DataType {
+                                    self.dir_node = matches!(
+                                        DataType::try_from(size as i32),
+                                        Ok(DataType::Directory | DataType::HamtShard)
+                                    );
+                                }
+                                // the root of the file comes before its leaves
+                                if self.state == FrameType::FileSize && !self.dir_node {
+                                    self.file_size.get_or_insert(size as u64);
+                                }
+
+                                // the data type isn't needed to find the range, so nodes of
+                                // types added after this parser are forwarded like files
+                                if self.blk_remaining()? == 0 {
+                                    self.state = FrameType::Block;
+                                    self.blk_pos = 0;
+                                } else {
+                                    self.state = FrameType::UnixFs;
+                                }
+                            }
+                            // the header is read as a single frame, one as long as the
+                            // CARv2 pragma is held until it is known to be another
+                            FrameType::CarHeader => {
+                                if size == carv2::PRAGMA.len() - 1 && self.payload_end.is_none() {
+                                    self.include = None;
+                                }
+                            }
+                            _ => return Err(unexpected_state()),
+                        };
+                    }
+                    None => {
+                        // the varint continues in the next buffer
+                        let partial = current.len();
+                        current = &[];
+                        if matches!(
+                            self.state,
+                            FrameType::MerkleDag
+                                | FrameType::UnixFs
+                                | FrameType::PBData
+                                | FrameType::DataType
+                                | FrameType::FileSize
+                                | FrameType::BlockSizes
+                                | FrameType::PBLinks
+                                | FrameType::UnixFsData
+                                | FrameType::SkipVarint
+                                | FrameType::SkipLen
+                        ) {
+                            self.blk_pos += partial;
+                        }
+                    }
+                };
+
+            // end of the frame
+            } else if current.len() >= self.len {
+                match self.state {
+                    FrameType::CarHeader => {
+                        if self.verify_root || self.include.is_none() {
+                            self.header.extend_from_slice(&current[..self.len]);
+                        }
+                        if self.include.is_none() && self.header == carv2::PRAGMA[1..] {
+                            // the CARv2 pragma, its header and padding aren't forwarded
+                            self.header.clear();
+                            self.decide(false);
+                            self.state = FrameType::V2Header;
+                            current = &current[self.len..];
+                            self.len = carv2::HEADER_LEN;
+                            continue;
+                        }
+                        self.decide(true);
+                        if self.verify_root {
+                            self.root = self.decode_root()?;
+                        }
+                        self.header.clear();
+                        self.state = FrameType::Block;
+                    }
+                    FrameType::V2Header => {
+                        self.header.extend_from_slice(&current[..self.len]);
+                        let padding = self.decode_v2_header(base + offset + self.len)?;
+                        if padding > 0 {
+                            self.state = FrameType::V2Padding;
+                            current = &current[self.len..];
+                            self.len = padding;
+                            continue;
+                        }
+                        self.start_payload();
+                    }
+                    FrameType::V2Padding => self.start_payload(),
+                    FrameType::PBLinks => {
+                        self.state = FrameType::MerkleDag;
+                        self.blk_pos += self.len;
+                    }
+                    FrameType::Skip => {
+                        self.blk_pos += self.len;
+                        self.end_field()?;
+                    }
+                    FrameType::UnixFsData | FrameType::RawLeaf => {
+                        self.blk_pos = 0;
+                        self.state = FrameType::Block;
+                        self.unixfs_read += self.unixfs_len;
+                        self.unixfs_len = 0;
+                    }
+                    FrameType::WholeBlock => {
+                        self.blk_pos = 0;
+                        self.state = FrameType::Block;
+                    }
+                    _ => return Err(unexpected_state()),
+                };
+                current = &current[self.len..];
+                self.len = 0;
+            // partial frame
+            } else {
+                match self.state {
+                    FrameType::CarHeader if self.verify_root || self.include.is_none() => {
+                        self.header.extend_from_slice(current);
+                    }
+                    FrameType::V2Header => self.header.extend_from_slice(current),
+                    FrameType::PBLinks | FrameType::UnixFsData | FrameType::Skip => {
+                        self.blk_pos += current.len();
+                    }
+                    FrameType::CarHeader
+                    | FrameType::V2Padding
+                    | FrameType::RawLeaf
+                    | FrameType::WholeBlock => {}
+                    _ => return Err(unexpected_state()),
+                };
+                self.len -= current.len();
+                current = &[];
+            }
+        }
+        // the section continues in the next buffer
+        if self.in_section {
+            if self.include.is_none() && self.held.len() + buf.len() - mark > MAX_HELD_LEN {
+                self.decide(true);
+            }
+            match self.include {
+                Some(true) => push_part(&mut ranges, mark, buf.len()),
+                Some(false) => {}
+                None => self.held.extend_from_slice(&buf[mark..]),
+            }
+        }
+        Ok(ranges)
+    }
+
+    // forward or skip the current section, the first decision sticks. The bytes held for it
+    // are released to be forwarded ahead of the current buffer.
+    fn decide(&mut self, include: bool) {
+        if self.include.is_some() {
+            return;
+        }
+        self.include = Some(include);
+        if include {
+            self.released.append(&mut self.held);
+        } else {
+            self.held.clear();
+        }
+    }
+
+    // the end of the CARv2 header at `end` in the upstream response was read, returns the
+    // bytes up to the CARv1 payload
+    fn decode_v2_header(&mut self, end: usize) -> io::Result<usize> {
+        let header = std::mem::take(&mut self.header);
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let (data_offset, data_size) = (field(16), field(24));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid CARv2 header");
+        let data_offset = usize::try_from(data_offset).map_err(|_| invalid())?;
+        let padding = data_offset.checked_sub(end).ok_or_else(invalid)?;
+        let payload_end = usize::try_from(data_size)
+            .ok()
+            .and_then(|size| data_offset.checked_add(size))
+            .ok_or_else(invalid)?;
+        self.payload_end = Some(payload_end);
+        Ok(padding)
+    }
+
+    // the CARv1 payload of a CARv2 starts, with its header
+    fn start_payload(&mut self) {
+        self.state = FrameType::CarHeader;
+        self.in_section = false;
+        self.include = Some(true);
+    }
+
+    pub fn set_max_header_len(&mut self, len: usize) {
+        self.max_header = len;
+    }
+
+    pub fn set_verify_root(&mut self, verify: bool) {
+        self.verify_root = verify;
+    }
+
+    pub fn root_mismatch(&self) -> bool {
+        self.root_mismatch
+    }
+
+    /// Count the bounds of `from_end` back from the end of the file, in place of the ones of
+    /// `range`. Until the root of the file is read they are unbounded.
+    pub fn set_from_end(&mut self, from_end: FromEnd) {
+        self.from_end = from_end;
+    }
+
+    /// Only forward the CAR header and the first block, for `dag-scope=block` requests. The
+    /// block isn't parsed, so it may be of any codec, and the range is ignored.
+    pub fn set_block_scope(&mut self, block: bool) {
+        self.block_scope = block;
+    }
+
+    /// The size of the file, read from its root, the first unixfs file node or raw block.
+    pub fn file_size(&self) -> Option<u64> {
+        self.file_size
+    }
+
+    /// The bounds of the range, known once the size of the file is read when they count
+    /// from its end. A file shorter than the bytes counted keeps its first byte, so that the
+    /// root of a single block file is still forwarded.
+    pub fn bounds(&self) -> (Bound<u64>, Bound<u64>) {
+        let range = (
+            self.range.start_bound().cloned(),
+            self.range.end_bound().cloned(),
+        );
+        self.from_end.resolve(range, self.file_size)
+    }
+
+    /// Bytes of earlier buffers to forward ahead of the parts of the last one passed to
+    /// [`Self::next`], the start of a section that wasn't known to be in the range yet.
+    pub fn take_released(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.released)
+    }
+
+    // the unixfs data of the current leaf overlaps the range, the leaf of an empty file
+    // covers the offset it is at
+    fn include_block(&self) -> bool {
+        let start = self.unixfs_read;
+        ranges_overlap(
+            self.bounds(),
+            start..start + std::cmp::max(self.unixfs_len, 1),
+        )
+    }
+
+    /// The data of the range was all read, or the block of the block scope, or the payload
+    /// of a CARv2, the parser doesn't read any further.
+    pub fn past_end(&self) -> bool {
+        if self.payload_read {
+            return true;
+        }
+        if self.block_scope {
+            return self.block_read;
+        }
+        let read = self.unixfs_read as u64;
+        match self.bounds().1 {
+            Bound::Included(to) => to < read,
+            Bound::Excluded(to) => to <= read,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn decode_len(&mut self, buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        // section lengths are multiformats varints, the rest are protobuf ones
+        let section = matches!(self.state, FrameType::CarHeader | FrameType::Block);
+        let mut i = 0;
+        loop {
+            self.buf.push(buf[i]);
+            let decoded = if section {
+                decode_uvarint(&self.buf[..])
+            } else {
+                decode_u64(&self.buf[..])
+            };
+            match decoded.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.as_str()))? {
+                Some((size, _)) => {
+                    if self.state == FrameType::CarHeader && size > self.max_header as u64 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "CAR header too large",
+                        ));
+                    }
+                    if section && size > MAX_SECTION_LEN as u64 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "CAR section longer than 4MiB",
+                        ));
+                    }
+                    self.buf.clear();
+                    return Ok(Some((size as usize, i + 1)));
+                }
+                None => {
+                    if buf.len() - (i + 1) > 0 {
+                        i += 1;
+                        continue;
+                    } else {
+                        return Ok(None);
+                    }
+                }
+            };
+        }
+    }
+
+    fn decode_cid(&mut self, buf: &[u8]) -> io::Result<Option<(Cid, usize)>> {
+        let mut i = 0;
+
+        let filled = self.buf.len();
+
+        loop {
+            let end = std::cmp::min(i + 36, buf.len());
+            for &b in &buf[i..end] {
+                self.buf.push(b);
+            }
+            // start from the next index
+            i = end;
+            let mut reader = Cursor::new(&self.buf[..]);
+            match Cid::read_bytes(&mut reader) {
+                Ok(cid) => {
+                    let read = reader.position() as usize;
+                    self.buf.clear();
+                    self.blk_pos += read;
+                    return Ok(Some((cid, read - filled)));
+                }
+                Err(_) => {
+                    // we have the whole section or more than any CID takes
+                    if self.buf.len() >= std::cmp::min(self.blk_len, MAX_CID_LEN) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid CID"));
+                    }
+                    // i is the next byte to stash, the caller drops the rest of the buffer
+                    if buf.len() > i {
+                        continue;
+                    } else {
+                        return Ok(None);
+                    }
+                }
+            };
+        }
+    }
+
+    // the first root of the CAR header
+    fn decode_root(&mut self) -> io::Result<Option<Cid>> {
+        let header = std::mem::take(&mut self.header);
+        let header: CarHeader = serde_ipld_dagcbor::from_slice(&header)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid CAR header"))?;
+        Ok(header.roots.first().copied())
+    }
+
+    // skip a field of the dag-pb or unixfs message being read
+    fn skip_field(&mut self, wire_type: Option<WireType>) -> io::Result<()> {
+        self.parent = self.state;
+        self.len = 0;
+        match wire_type {
+            Some(WireType::Varint) => self.state = FrameType::SkipVarint,
+            Some(WireType::LengthDelimited) => self.state = FrameType::SkipLen,
+            Some(WireType::SixtyFourBit) => {
+                self.state = FrameType::Skip;
+                self.len = 8;
+            }
+            Some(WireType::ThirtyTwoBit) => {
+                self.state = FrameType::Skip;
+                self.len = 4;
+            }
+            // groups are deprecated, dag-pb and unixfs don't use them
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unsupported protobuf wire type",
+                ))
+            }
+        }
+        if self.len > self.blk_remaining()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block shorter than its fields",
+            ));
+        }
+        Ok(())
+    }
+
+    // the skipped field ended, returns true if it was the last one of the block
+    fn end_field(&mut self) -> io::Result<bool> {
+        if self.blk_remaining()? == 0 {
+            self.state = FrameType::Block;
+            self.blk_pos = 0;
+            return Ok(true);
+        }
+        self.state = self.parent;
+        Ok(false)
+    }
+
+    // corrupt input that got past the checks of each frame leaves the counters inconsistent,
+    // better to stop than to forward garbage
+    fn check_invariants(&self) -> io::Result<()> {
+        let skipping = matches!(
+            self.state,
+            FrameType::SkipVarint | FrameType::SkipLen | FrameType::Skip
+        );
+        let ok = self.blk_pos <= self.blk_len
+            && self.unixfs_len <= self.blk_len
+            && self.buf.len() <= MAX_CID_LEN
+            && (self.state != FrameType::CarHeader || self.blk_len == 0)
+            && (!skipping || matches!(self.parent, FrameType::MerkleDag | FrameType::UnixFs));
+        if !ok {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parser invariant violated",
+            ));
+        }
+        Ok(())
+    }
+
+    // a length delimited field of the block can't extend past the end of its CAR section
+    fn check_field_len(&self, len: usize) -> io::Result<()> {
+        if len > self.blk_remaining()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "protobuf field longer than its CAR section",
+            ));
+        }
+        Ok(())
+    }
+
+    // bytes of the current block left to read, the fields read so far can't go past its end
+    fn blk_remaining(&self) -> io::Result<usize> {
+        self.blk_len.checked_sub(self.blk_pos).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "block shorter than its fields")
+        })
+    }
+
+    /// Offset of the current CAR section in the bytes passed to the parser.
+    pub fn section(&self) -> usize {
+        self.section
+    }
+
+    /// Bytes of the unixfs file read so far.
+    pub fn unixfs_read(&self) -> usize {
+        self.unixfs_read
+    }
+
+    /// The internal state of the parser, to diagnose stalls.
+    pub fn describe(&self) -> String {
+        format!(
+            "state {:?}, frame len {}, block len {}, block pos {}, stashed {}, unixfs read {}, \
+             unixfs len {}",
+            self.state,
+            self.len,
+            self.blk_len,
+            self.blk_pos,
+            self.buf.len(),
+            self.unixfs_read,
+            self.unixfs_len
+        )
+    }
+
+    /// Where the parser stopped on `error`.
+    pub fn malformed(&self, error: io::Error) -> Malformed {
+        Malformed {
+            error,
+            offset: self.section,
+            cid: self.block_id(),
+        }
+    }
+
+    // the CID of the current block, or the start of the bytes that failed to parse as one
+    fn block_id(&self) -> Option<String> {
+        let state = match self.state {
+            FrameType::Error => self.failed_in,
+            state => state,
+        };
+        if state == FrameType::Cid && !self.buf.is_empty() {
+            let prefix: String = self.buf[..std::cmp::min(self.buf.len(), 16)]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            return Some(format!("0x{}", prefix));
+        }
+        self.cid.map(|cid| cid.to_string())
+    }
+
+    /// The bytes passed to the parser end in the middle of a CAR section.
+    pub fn is_partial(&self) -> bool {
+        self.state != FrameType::Block || self.len != 0 || !self.buf.is_empty()
+    }
+}
+
+/// The decoders of the hot path on their own, for the benchmarks in `benches/`.
+#[cfg(feature = "bench")]
+impl Framed<std::ops::RangeFull> {
+    /// Decode a length split across the buffers `parts`, a section length when `section`
+    /// is set and a protobuf one otherwise.
+    pub fn bench_decode_len(&mut self, parts: &[&[u8]], section: bool) -> Option<usize> {
+        self.state = if section {
+            FrameType::Block
+        } else {
+            FrameType::SkipLen
+        };
+        self.buf.clear();
+        for part in parts {
+            if let Some((len, _)) = self.decode_len(part).ok()? {
+                return Some(len);
+            }
+        }
+        None
+    }
+
+    /// Decode the CID at the start of a block split across the buffers `parts`.
+    pub fn bench_decode_cid(&mut self, parts: &[&[u8]]) -> Option<Cid> {
+        self.state = FrameType::Cid;
+        self.blk_len = MAX_SECTION_LEN;
+        self.blk_pos = 0;
+        self.buf.clear();
+        for part in parts {
+            if let Some((cid, _)) = self.decode_cid(part).ok()? {
+                return Some(cid);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // once it failed the parser doesn't read anything else
+    #[test]
+    fn test_frame_error_state() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0104ffffffff").unwrap();
+
+        let mut framed = Framed::new(..);
+        assert!(framed.next(&data[..]).is_err());
+        assert_eq!(framed.state, FrameType::Error);
+        assert_eq!(framed.block_id().as_deref(), Some("0xffffffff"));
+
+        let e = framed.next(&data[..59]).unwrap_err();
+        assert_eq!(e.to_string(), "parser stopped on an earlier error");
+        assert_eq!(framed.consumed, data.len());
+    }
+
+    fn frame_splits(car: &[u8], split: usize) -> Vec<u8> {
+        let mut framed = Framed::new(..);
+        let mut out = vec![];
+        for chunk in car.chunks(split) {
+            let parts = framed.next(chunk).unwrap();
+            out.extend(framed.take_released());
+            for (start, end) in parts {
+                out.extend_from_slice(&chunk[start..end]);
+            }
+        }
+        assert!(framed.past_end() || !framed.is_partial());
+        out
+    }
+
+    // the CARv1 payload of a CARv2 is forwarded without the pragma, header, padding and
+    // index around it, and a CARv1 header as long as the pragma is forwarded once read
+    #[test]
+    fn test_frame_carv2() {
+        // a header without roots and a raw block
+        let car = hex::decode("0aa16776657273696f6e010c015500046162636461626364").unwrap();
+        for padding in [0, 5] {
+            let data_offset = carv2::PRAGMA.len() + carv2::HEADER_LEN + padding;
+            let mut v2 = carv2::PRAGMA.to_vec();
+            v2.extend_from_slice(&[0; 16]);
+            v2.extend_from_slice(&(data_offset as u64).to_le_bytes());
+            v2.extend_from_slice(&(car.len() as u64).to_le_bytes());
+            v2.extend_from_slice(&((data_offset + car.len()) as u64).to_le_bytes());
+            v2.resize(data_offset, 0);
+            v2.extend_from_slice(&car);
+            v2.extend_from_slice(&[0x80, 0x08, 1, 0, 0, 0]);
+            for split in 1..=v2.len() {
+                assert_eq!(frame_splits(&v2, split), car, "split {}", split);
+                assert_eq!(frame_splits(&car, split), car, "split {}", split);
+            }
+        }
+
+        // the payload can't start inside the CARv2 header
+        let mut v2 = carv2::PRAGMA.to_vec();
+        v2.extend_from_slice(&[0; carv2::HEADER_LEN]);
+        let e = Framed::new(..).next(&v2).unwrap_err();
+        assert_eq!(e.to_string(), "invalid CARv2 header");
+    }
+}
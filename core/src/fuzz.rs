@@ -0,0 +1,131 @@
+//! Entry points of the fuzz targets in `fuzz/`, built with `cargo fuzz` which sets
+//! `--cfg fuzzing`. Inputs that crashed a target are kept in `fuzz/regressions/<target>`
+//! and replayed by the unit tests.
+
+use crate::framed::Framed;
+use crate::range;
+use std::ops::Bound;
+
+// range bounds and control byte ahead of the CAR bytes
+const PREFIX_LEN: usize = 17;
+// set in the control byte for a range to the end of the entity
+const UNBOUNDED: u8 = 0x80;
+
+/// Feed the CAR in `input` to the parser in pseudo random chunks. The input starts with
+/// the first and last byte of the range as little endian `u64`s and a control byte, the
+/// top bit of which ignores the last byte for a range to the end of the entity and the
+/// rest seeds the chunk sizes, 0 to pass the CAR in one buffer.
+pub fn framed(input: &[u8]) {
+    if input.len() < PREFIX_LEN {
+        return;
+    }
+    let (prefix, car) = input.split_at(PREFIX_LEN);
+    let from = u64::from_le_bytes(prefix[0..8].try_into().unwrap());
+    let to = match prefix[16] & UNBOUNDED {
+        0 => Bound::Included(u64::from_le_bytes(prefix[8..16].try_into().unwrap())),
+        _ => Bound::Unbounded,
+    };
+    let mut chunks = Chunks::new(car, prefix[16] & !UNBOUNDED);
+
+    let mut reader = Framed::new((Bound::Included(from), to));
+    let mut bytes_out = 0;
+    let mut failed = false;
+    for chunk in &mut chunks {
+        match reader.next(chunk) {
+            Ok(parts) => {
+                assert!(!failed, "parser resumed after an error");
+                bytes_out += reader.take_released().len();
+                let mut prev = 0;
+                for (start, end) in parts {
+                    assert!(prev <= start && start <= end && end <= chunk.len());
+                    bytes_out += end - start;
+                    prev = end;
+                }
+            }
+            Err(_) => failed = true,
+        }
+    }
+    assert!(bytes_out <= car.len());
+}
+
+/// Parse `input` as the query string of a request.
+pub fn parse_range(input: &[u8]) {
+    let args = String::from_utf8_lossy(input);
+    if let Some(bounds) = range::parse_range(&args) {
+        let from_end = range::parse_range_from_end(&args);
+        let val = range::format_entity_bytes(bounds, from_end);
+        assert_eq!(range::parse_entity_bytes(&val), Some(bounds));
+        assert_eq!(range::parse_from_end(&val), from_end);
+    }
+}
+
+// Splits a slice in chunks of 1 to 512 bytes from a xorshift sequence, or in a single
+// chunk for a 0 seed.
+struct Chunks<'a> {
+    data: &'a [u8],
+    state: u32,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(data: &'a [u8], seed: u8) -> Self {
+        Self {
+            data,
+            state: (seed as u32).wrapping_mul(0x9e37_79b9),
+        }
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let len = if self.state == 0 {
+            self.data.len()
+        } else {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            1 + self.state as usize % 512
+        };
+        let (chunk, rest) = self.data.split_at(len.min(self.data.len()));
+        self.data = rest;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn replay(target: &str, run: fn(&[u8])) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fuzz/regressions")
+            .join(target);
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            println!("replaying {}", path.display());
+            run(&fs::read(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        replay("framed", framed);
+        replay("parse_range", parse_range);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let data = [0u8; 2000];
+        assert_eq!(Chunks::new(&data, 0).count(), 1);
+        let chunks: Vec<_> = Chunks::new(&data, 7).collect();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| (1..=512).contains(&chunk.len())));
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).sum::<usize>(), 2000);
+    }
+}
@@ -0,0 +1,15 @@
+//! The CAR parsing and range selection of the nginx module, without nginx types, I/O or
+//! unsafe code. The `nginx-car-range` crate feeds it the upstream buffers.
+
+#![forbid(unsafe_code)]
+
+pub mod block;
+pub mod carv2;
+pub mod decoder;
+pub mod framed;
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
+pub mod range;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod varint;
@@ -0,0 +1,268 @@
+use std::ops::Bound;
+
+/// The `entity-bytes` range of the query string `args`, the first one if there are
+/// several. Other parameters, like the signed tokens gateways append, may hold anything.
+pub fn parse_range(args: &str) -> Option<(Bound<u64>, Bound<u64>)> {
+    form_urlencoded::parse(args.as_bytes())
+        .find(|(key, _)| key == "entity-bytes")
+        .and_then(|(_, val)| parse_entity_bytes(&val))
+}
+
+/// Parse an `entity-bytes` value, `from:to` where `to` may be `*` for the end of the entity.
+/// Negative bounds count from the end of the entity, they are unbounded here and
+/// [`parse_from_end`] tells where they are.
+pub fn parse_entity_bytes(val: &str) -> Option<(Bound<u64>, Bound<u64>)> {
+    let mut iter = val.trim().splitn(2, ':');
+    Some((parse_bound(iter.next()?)?, parse_bound(iter.next()?)?))
+}
+
+fn parse_bound(s: &str) -> Option<Bound<u64>> {
+    if s == "*" || parse_negative(s).is_some() {
+        return Some(Bound::Unbounded);
+    }
+
+    s.parse().ok().map(Bound::Included)
+}
+
+fn parse_negative(s: &str) -> Option<u64> {
+    s.strip_prefix('-')?.parse().ok().filter(|&n| n > 0)
+}
+
+/// The bounds of an entity range counted back from the end of the entity, `1` being its
+/// last byte, like the negative bounds of `entity-bytes` or a suffix `Range` header. They
+/// are only known once the size of the entity is read from its root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FromEnd {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl FromEnd {
+    /// The bounds of `range` in an entity of `size` bytes, unbounded where they count from
+    /// the end of an entity of unknown size. An entity shorter than the bytes counted keeps
+    /// its first byte.
+    pub fn resolve(
+        &self,
+        range: (Bound<u64>, Bound<u64>),
+        size: Option<u64>,
+    ) -> (Bound<u64>, Bound<u64>) {
+        let resolve = |bound, from_end: Option<u64>| match (from_end, size) {
+            (None, _) => bound,
+            (Some(n), Some(size)) => Bound::Included(size.saturating_sub(n)),
+            (Some(_), None) => Bound::Unbounded,
+        };
+        (resolve(range.0, self.start), resolve(range.1, self.end))
+    }
+}
+
+/// The bounds counted from the end of the entity of the `entity-bytes` range of `args`.
+pub fn parse_range_from_end(args: &str) -> FromEnd {
+    form_urlencoded::parse(args.as_bytes())
+        .find(|(key, _)| key == "entity-bytes")
+        .map_or_else(FromEnd::default, |(_, val)| parse_from_end(&val))
+}
+
+/// [`parse_range_from_end`] of an `entity-bytes` value.
+pub fn parse_from_end(val: &str) -> FromEnd {
+    match val.trim().split_once(':') {
+        Some((from, to)) if parse_entity_bytes(val).is_some() => FromEnd {
+            start: parse_negative(from),
+            end: parse_negative(to),
+        },
+        _ => FromEnd::default(),
+    }
+}
+
+/// A bound of an `entity-bytes` value, `*` when unbounded and negative when it counts from
+/// the end of the entity.
+pub fn format_bound(bound: Bound<u64>, from_end: Option<u64>) -> String {
+    match (from_end, bound) {
+        (Some(n), _) => format!("-{}", n),
+        (None, Bound::Included(b) | Bound::Excluded(b)) => b.to_string(),
+        (None, Bound::Unbounded) => "*".to_string(),
+    }
+}
+
+/// An `entity-bytes` value, in the form [`parse_entity_bytes`] and [`parse_from_end`] read.
+pub fn format_entity_bytes(range: (Bound<u64>, Bound<u64>), from_end: FromEnd) -> String {
+    format!(
+        "{}:{}",
+        format_bound(range.0, from_end.start),
+        format_bound(range.1, from_end.end)
+    )
+}
+
+/// The first and last bytes of an entity of `size` bytes that the resolved `range`
+/// covers, `None` when it covers none of them.
+pub fn satisfied(range: (Bound<u64>, Bound<u64>), size: u64) -> Option<(u64, u64)> {
+    let start = match range.0 {
+        Bound::Included(b) => b,
+        Bound::Excluded(b) => b.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.1 {
+        Bound::Included(b) => b.saturating_add(1),
+        Bound::Excluded(b) => b,
+        Bound::Unbounded => size,
+    }
+    .min(size);
+    (start < end).then(|| (start, end - 1))
+}
+
+/// The `entity-bytes` `Content-Range` of the bytes of an entity of `size` bytes that the
+/// resolved `range` covers, `entity-bytes */<size>` when it covers none of them.
+pub fn format_satisfied(range: (Bound<u64>, Bound<u64>), size: u64) -> String {
+    match satisfied(range, size) {
+        Some((first, last)) => format!("entity-bytes {}-{}/{}", first, last, size),
+        None => format!("entity-bytes */{}", size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_range("entity-bytes=0:100").unwrap(),
+            (Bound::Included(0), Bound::Included(100))
+        );
+
+        assert_eq!(
+            parse_range("entity-bytes=1024:*").unwrap(),
+            (Bound::Included(1024), Bound::Unbounded)
+        );
+    }
+
+    // parameters before the range that need decoding used to end the lookup
+    #[test]
+    fn test_parse_range_extra_args() {
+        let range = Some((Bound::Included(0), Bound::Included(100)));
+        assert_eq!(
+            parse_range("token=a%2Bb%3D%3D&expires=1700000000&entity-bytes=0:100"),
+            range
+        );
+        assert_eq!(
+            parse_range("sig=x+y&tags=a,b:c&tags=d:e&entity-bytes=0%3A100"),
+            range
+        );
+        assert_eq!(
+            parse_range("entity-bytes=0:100&entity-bytes=5:10&st=abc:def"),
+            range
+        );
+        assert_eq!(parse_range("md5=a:b&bytes=0:100"), None);
+    }
+
+    #[test]
+    fn test_parse_range_from_end() {
+        assert_eq!(
+            parse_range("entity-bytes=100:-1"),
+            Some((Bound::Included(100), Bound::Unbounded))
+        );
+        let end = |n| FromEnd {
+            start: None,
+            end: Some(n),
+        };
+        assert_eq!(parse_range_from_end("entity-bytes=100:-1"), end(1));
+        assert_eq!(
+            parse_range_from_end("a=b&entity-bytes=0%3A-1024"),
+            end(1024)
+        );
+        assert_eq!(parse_from_end("*:-10"), end(10));
+        assert_eq!(parse_from_end("0:100"), FromEnd::default());
+        assert_eq!(parse_from_end("0:*"), FromEnd::default());
+
+        // a suffix of the entity
+        assert_eq!(
+            parse_entity_bytes("-1024:*"),
+            Some((Bound::Unbounded, Bound::Unbounded))
+        );
+        assert_eq!(
+            parse_from_end("-1024:-1"),
+            FromEnd {
+                start: Some(1024),
+                end: Some(1),
+            }
+        );
+
+        // `-0` is no byte of the entity
+        assert_eq!(parse_entity_bytes("0:-0"), None);
+        assert_eq!(parse_entity_bytes("-0:*"), None);
+        assert_eq!(parse_from_end("0:-0"), FromEnd::default());
+        assert_eq!(parse_from_end("0:--1"), FromEnd::default());
+    }
+
+    #[test]
+    fn test_resolve_from_end() {
+        let from_end = FromEnd {
+            start: Some(100),
+            end: Some(1),
+        };
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(
+            from_end.resolve(range, Some(1000)),
+            (Bound::Included(900), Bound::Included(999))
+        );
+        assert_eq!(
+            from_end.resolve(range, Some(10)),
+            (Bound::Included(0), Bound::Included(9))
+        );
+        assert_eq!(from_end.resolve(range, None), range);
+        let range = (Bound::Included(10), Bound::Included(20));
+        assert_eq!(FromEnd::default().resolve(range, Some(5)), range);
+    }
+
+    #[test]
+    fn test_format_entity_bytes() {
+        for val in ["0:100", "10:*", "0:-1", "-1024:*", "-10:-1"] {
+            let range = parse_entity_bytes(val).unwrap();
+            assert_eq!(format_entity_bytes(range, parse_from_end(val)), val);
+        }
+    }
+
+    #[test]
+    fn test_satisfied() {
+        let range = (Bound::Included(100), Bound::Included(199));
+        assert_eq!(satisfied(range, 1000), Some((100, 199)));
+        assert_eq!(satisfied(range, 150), Some((100, 149)));
+        assert_eq!(satisfied(range, 100), None);
+        assert_eq!(
+            satisfied((Bound::Included(5), Bound::Included(1)), 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_format_satisfied() {
+        let range = |val| {
+            let range = parse_entity_bytes(val).unwrap();
+            parse_from_end(val).resolve(range, Some(1000))
+        };
+        assert_eq!(
+            format_satisfied(range("0:99"), 1000),
+            "entity-bytes 0-99/1000"
+        );
+        assert_eq!(
+            format_satisfied(range("900:*"), 1000),
+            "entity-bytes 900-999/1000"
+        );
+        assert_eq!(
+            format_satisfied(range("-10:*"), 1000),
+            "entity-bytes 990-999/1000"
+        );
+        // the end is cut to the entity, a range past it covers nothing
+        assert_eq!(
+            format_satisfied(range("500:5000"), 1000),
+            "entity-bytes 500-999/1000"
+        );
+        assert_eq!(
+            format_satisfied(range("1000:*"), 1000),
+            "entity-bytes */1000"
+        );
+        assert_eq!(
+            format_satisfied((Bound::Unbounded, Bound::Unbounded), 0),
+            "entity-bytes */0"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! Unixfs files built as CAR files in memory, in place of binary fixtures, for the tests
+//! of the parser and of the nginx module with the `testing` feature.
+//!
+//! ```ignore
+//! let file = Builder::new().chunk(1000).fanout(4).wrap("file.bin").build(&data);
+//! ```
+
+use crate::framed::{CarHeader, DataType};
+use crate::varint::VarInt;
+use cid::multihash::MultihashGeneric;
+use cid::Cid;
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+
+const DAG_PB: u64 = 0x70;
+const RAW: u64 = 0x55;
+const SHA2_256: u64 = 0x12;
+const MURMUR3_X64_64: u64 = 0x22;
+
+/// A CAR section of a built file.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub cid: Cid,
+    /// The bytes of the section in the CAR, including its length prefix.
+    pub bytes: Range<usize>,
+    /// The bytes of the file in the block, for leaves.
+    pub data: Option<Range<u64>>,
+}
+
+/// A CAR with its blocks in depth first order, the directories wrapping the file first.
+#[derive(Debug)]
+pub struct File {
+    pub car: Vec<u8>,
+    /// The CAR header, including its length prefix.
+    pub header: Range<usize>,
+    pub sections: Vec<Section>,
+}
+
+impl File {
+    /// The root of the CAR, the wrapping directory if any.
+    pub fn root(&self) -> Cid {
+        self.sections[0].cid
+    }
+}
+
+/// Lays out a file like `ipfs add` does with a balanced DAG, defaulting to its 256KiB
+/// raw leaves and 174 links per node.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    chunk: usize,
+    fanout: usize,
+    depth: Option<u32>,
+    raw_leaves: bool,
+    wrap: Option<String>,
+    hamt: Option<usize>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            chunk: 256 << 10,
+            fanout: 174,
+            depth: None,
+            raw_leaves: true,
+            wrap: None,
+            hamt: None,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size of the leaves, the last one may be shorter.
+    pub fn chunk(mut self, chunk: usize) -> Self {
+        self.chunk = chunk;
+        self
+    }
+
+    /// Links per node at most.
+    pub fn fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Levels of nodes above the leaves at most, taking the smallest fanout that fits
+    /// the file instead of the one set.
+    pub fn depth(mut self, depth: u32) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Raw blocks for the leaves, dag-pb unixfs files otherwise.
+    pub fn raw_leaves(mut self, raw: bool) -> Self {
+        self.raw_leaves = raw;
+        self
+    }
+
+    /// Wrap the file in a directory holding it as `name`.
+    pub fn wrap(mut self, name: &str) -> Self {
+        self.wrap = Some(name.to_string());
+        self
+    }
+
+    /// Shard the wrapping directory as a HAMT of `fanout` buckets.
+    pub fn hamt(mut self, fanout: usize) -> Self {
+        assert!(fanout.is_power_of_two() && fanout >= 8);
+        self.hamt = Some(fanout);
+        self
+    }
+
+    pub fn build(&self, data: &[u8]) -> File {
+        assert!(self.chunk > 0);
+        let mut nodes: Vec<Node> = data
+            .chunks(self.chunk)
+            .scan(0, |offset, chunk| {
+                let leaf = leaf(chunk, *offset, self.raw_leaves);
+                *offset += chunk.len() as u64;
+                Some(leaf)
+            })
+            .collect();
+        if nodes.is_empty() {
+            nodes.push(leaf(&[], 0, self.raw_leaves));
+        }
+
+        let fanout = match self.depth {
+            Some(0) => {
+                assert_eq!(nodes.len(), 1, "the file doesn't fit in a single leaf");
+                2
+            }
+            Some(depth) => (2..)
+                .find(|fanout: &usize| fanout.checked_pow(depth).is_none_or(|n| n >= nodes.len()))
+                .unwrap(),
+            None => self.fanout,
+        };
+        assert!(fanout > 1);
+        while nodes.len() > 1 {
+            let mut parents = Vec::new();
+            let mut nodes_iter = nodes.into_iter().peekable();
+            while nodes_iter.peek().is_some() {
+                parents.push(parent(nodes_iter.by_ref().take(fanout).collect()));
+            }
+            nodes = parents;
+        }
+        let mut root = nodes.pop().unwrap();
+
+        if let Some(name) = &self.wrap {
+            root = match self.hamt {
+                Some(fanout) => shard(name, root, fanout),
+                None => directory(name, root),
+            };
+        }
+
+        let mut car = header(&root.cid);
+        let header = 0..car.len();
+        let mut sections = Vec::new();
+        write_node(&mut car, &mut sections, root);
+        File {
+            car,
+            header,
+            sections,
+        }
+    }
+}
+
+// a node of the file being built, with the offset of its first byte
+struct Node {
+    cid: Cid,
+    block: Vec<u8>,
+    offset: u64,
+    len: u64,
+    // the size of the blocks of the DAG under the node, for the links to it
+    tsize: u64,
+    leaf: bool,
+    children: Vec<Node>,
+}
+
+impl Node {
+    // a node over `children`, which isn't a leaf of the file
+    fn new(codec: u64, block: Vec<u8>, children: Vec<Node>) -> Node {
+        Node {
+            cid: cid(codec, &block),
+            tsize: block.len() as u64 + children.iter().map(|child| child.tsize).sum::<u64>(),
+            block,
+            offset: children.first().map_or(0, |child| child.offset),
+            len: children.iter().map(|child| child.len).sum(),
+            leaf: false,
+            children,
+        }
+    }
+}
+
+fn write_node(car: &mut Vec<u8>, sections: &mut Vec<Section>, node: Node) {
+    let start = car.len();
+    let cid = node.cid.to_bytes();
+    car.extend((cid.len() + node.block.len()).encode_var_vec());
+    car.extend(cid);
+    car.extend(&node.block);
+    sections.push(Section {
+        cid: node.cid,
+        bytes: start..car.len(),
+        data: node.leaf.then(|| node.offset..node.offset + node.len),
+    });
+    for child in node.children {
+        write_node(car, sections, child);
+    }
+}
+
+fn leaf(chunk: &[u8], offset: u64, raw: bool) -> Node {
+    let (codec, block) = if raw {
+        (RAW, chunk.to_vec())
+    } else {
+        let mut unixfs = Vec::new();
+        varint_field(&mut unixfs, 1, DataType::File as u64);
+        bytes_field(&mut unixfs, 2, chunk);
+        varint_field(&mut unixfs, 3, chunk.len() as u64);
+        (DAG_PB, dag_pb(&[], &unixfs))
+    };
+    Node {
+        offset,
+        len: chunk.len() as u64,
+        leaf: true,
+        ..Node::new(codec, block, Vec::new())
+    }
+}
+
+fn parent(children: Vec<Node>) -> Node {
+    let links: Vec<_> = children.iter().map(|child| link(child, "")).collect();
+
+    let mut unixfs = Vec::new();
+    varint_field(&mut unixfs, 1, DataType::File as u64);
+    varint_field(&mut unixfs, 3, children.iter().map(|child| child.len).sum());
+    for child in &children {
+        varint_field(&mut unixfs, 4, child.len);
+    }
+
+    Node::new(DAG_PB, dag_pb(&links, &unixfs), children)
+}
+
+fn directory(name: &str, child: Node) -> Node {
+    let mut unixfs = Vec::new();
+    varint_field(&mut unixfs, 1, DataType::Directory as u64);
+
+    Node::new(DAG_PB, dag_pb(&[link(&child, name)], &unixfs), vec![child])
+}
+
+// a shard holding a single entry, in the bucket of the hash of its name like go-unixfs
+fn shard(name: &str, child: Node, fanout: usize) -> Node {
+    let hash = murmur3::murmur3_x64_128(&mut name.as_bytes(), 0).unwrap() as u64;
+    let index = (hash >> (64 - fanout.trailing_zeros())) as usize;
+    let width = format!("{:X}", fanout - 1).len();
+
+    // the bitfield of the buckets in use, big endian
+    let mut buckets = vec![0u8; fanout / 8];
+    buckets[fanout / 8 - 1 - index / 8] |= 1 << (index % 8);
+
+    let mut unixfs = Vec::new();
+    varint_field(&mut unixfs, 1, DataType::HamtShard as u64);
+    bytes_field(&mut unixfs, 2, &buckets);
+    varint_field(&mut unixfs, 5, MURMUR3_X64_64);
+    varint_field(&mut unixfs, 6, fanout as u64);
+
+    let name = format!("{:0width$X}{}", index, name, width = width);
+    Node::new(DAG_PB, dag_pb(&[link(&child, &name)], &unixfs), vec![child])
+}
+
+// the PBLink of a node
+fn link(node: &Node, name: &str) -> Vec<u8> {
+    let mut link = Vec::new();
+    bytes_field(&mut link, 1, &node.cid.to_bytes());
+    bytes_field(&mut link, 2, name.as_bytes());
+    varint_field(&mut link, 3, node.tsize);
+    link
+}
+
+// a dag-pb block, its links ahead of its data like the canonical encoding
+fn dag_pb(links: &[Vec<u8>], unixfs: &[u8]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for link in links {
+        bytes_field(&mut block, 2, link);
+    }
+    bytes_field(&mut block, 1, unixfs);
+    block
+}
+
+fn header(root: &Cid) -> Vec<u8> {
+    let header = serde_ipld_dagcbor::to_vec(&CarHeader {
+        roots: vec![*root],
+        version: 1,
+    })
+    .unwrap();
+    let mut car = header.len().encode_var_vec();
+    car.extend(header);
+    car
+}
+
+pub fn cid(codec: u64, block: &[u8]) -> Cid {
+    let digest = Sha256::digest(block);
+    Cid::new_v1(codec, MultihashGeneric::wrap(SHA2_256, &digest).unwrap())
+}
+
+fn varint_field(buf: &mut Vec<u8>, tag: u64, val: u64) {
+    buf.extend((tag << 3).encode_var_vec());
+    buf.extend(val.encode_var_vec());
+}
+
+fn bytes_field(buf: &mut Vec<u8>, tag: u64, val: &[u8]) {
+    buf.extend((tag << 3 | 2).encode_var_vec());
+    buf.extend(val.len().encode_var_vec());
+    buf.extend(val);
+}
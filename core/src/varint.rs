@@ -27,8 +27,7 @@ pub trait VarInt: Sized + Copy {
     /// Helper: Encode a value and return the encoded form as Vec. The Vec must be at least
     /// `required_space()` bytes long.
     fn encode_var_vec(self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.resize(self.required_space(), 0);
+        let mut v = vec![0; self.required_space()];
         self.encode_var(&mut v);
         v
     }
@@ -77,6 +76,10 @@ impl VarInt for u64 {
         let mut success = false;
         for b in src.iter() {
             let msb_dropped = b & DROP_MSB;
+            // the 10th byte only holds the 64th bit
+            if shift == 9 * 7 && msb_dropped > 1 {
+                return None;
+            }
             result |= (msb_dropped as u64) << shift;
             shift += 7;
 
@@ -87,7 +90,7 @@ impl VarInt for u64 {
         }
 
         if success {
-            Some((result, shift / 7 as usize))
+            Some((result, shift / 7))
         } else {
             None
         }
@@ -110,6 +113,58 @@ impl VarInt for u64 {
     }
 }
 
+/// Maximum length of a varint in the multiformats spec.
+/// https://github.com/multiformats/unsigned-varint
+pub const MAX_UVARINT_LEN: usize = 9;
+
+/// Reasons a varint is rejected instead of waiting for more bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarIntError {
+    /// The value doesn't fit in 64 bits.
+    Overflow,
+    /// Longer than allowed.
+    TooLong,
+    /// Ends with a zero byte, an encoding of the same value could be shorter.
+    NotMinimal,
+}
+
+impl VarIntError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VarIntError::Overflow => "invalid varint: overflows 64 bits",
+            VarIntError::TooLong => "invalid varint: longer than 9 bytes",
+            VarIntError::NotMinimal => "invalid varint: not minimally encoded",
+        }
+    }
+}
+
+/// Decode a protobuf varint, `Ok(None)` if `src` ends before it does.
+///
+/// With the `hardened` feature it must be minimally encoded too, as every encoder does.
+pub fn decode_u64(src: &[u8]) -> Result<Option<(u64, usize)>, VarIntError> {
+    match u64::decode_var(src) {
+        Some((_, read)) if cfg!(feature = "hardened") && read > 1 && src[read - 1] == 0 => {
+            Err(VarIntError::NotMinimal)
+        }
+        Some(decoded) => Ok(Some(decoded)),
+        // 10 bytes hold 64 bits
+        None if src.len() >= 10 => Err(VarIntError::Overflow),
+        None => Ok(None),
+    }
+}
+
+/// Decode a multiformats unsigned varint, which must be minimally encoded in at most
+/// [`MAX_UVARINT_LEN`] bytes, `Ok(None)` if `src` ends before it does.
+pub fn decode_uvarint(src: &[u8]) -> Result<Option<(u64, usize)>, VarIntError> {
+    let src = &src[..std::cmp::min(src.len(), MAX_UVARINT_LEN)];
+    match u64::decode_var(src) {
+        Some((_, read)) if read > 1 && src[read - 1] == 0 => Err(VarIntError::NotMinimal),
+        Some(decoded) => Ok(Some(decoded)),
+        None if src.len() == MAX_UVARINT_LEN => Err(VarIntError::TooLong),
+        None => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,4 +180,37 @@ mod tests {
         // We read 1 byte
         assert_eq!(read, 1);
     }
+
+    #[test]
+    fn test_decode_overflow() {
+        // 2^64 doesn't fit
+        let data = hex::decode("80808080808080808002").unwrap();
+        assert_eq!(u64::decode_var(&data[..]), None);
+        assert_eq!(decode_u64(&data[..]), Err(VarIntError::Overflow));
+
+        let data = hex::decode("ffffffffffffffffff01").unwrap();
+        assert_eq!(decode_u64(&data[..]), Ok(Some((u64::MAX, 10))));
+        assert_eq!(decode_u64(&data[..5]), Ok(None));
+    }
+
+    #[test]
+    fn test_decode_u64_not_minimal() {
+        let decoded = decode_u64(&[0x81, 0x00]);
+        if cfg!(feature = "hardened") {
+            assert_eq!(decoded, Err(VarIntError::NotMinimal));
+        } else {
+            assert_eq!(decoded, Ok(Some((1, 2))));
+        }
+    }
+
+    #[test]
+    fn test_decode_uvarint() {
+        assert_eq!(decode_uvarint(&[0x3a]), Ok(Some((58, 1))));
+        assert_eq!(decode_uvarint(&[0xe8, 0x07]), Ok(Some((1000, 2))));
+        assert_eq!(decode_uvarint(&[0xe8]), Ok(None));
+        assert_eq!(decode_uvarint(&[0x00]), Ok(Some((0, 1))));
+        assert_eq!(decode_uvarint(&[0xba, 0x00]), Err(VarIntError::NotMinimal));
+        assert_eq!(decode_uvarint(&[0xff; 9]), Err(VarIntError::TooLong));
+        assert_eq!(decode_uvarint(&[0xff; 8]), Ok(None));
+    }
 }
@@ -0,0 +1,21 @@
+#!/bin/sh
+# Seed the fuzz corpora from the CAR fixtures, run from the repository root.
+set -e
+
+mkdir -p fuzz/corpus/framed fuzz/corpus/parse_range
+
+# prefix the CAR with the range bounds as little endian u64s and the control byte, see
+# src/fuzz.rs
+seed() {
+    printf '%b' "$2" | cat - "$car" > "fuzz/corpus/framed/$(basename "$car" .car)-$1"
+}
+
+for car in *.car; do
+    seed whole '\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\0200'
+    seed whole-split '\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\0207'
+    seed window-split '\01\04\00\00\00\00\00\00\030\04\00\00\00\00\00\00\052'
+done
+
+printf 'entity-bytes=0:100' > fuzz/corpus/parse_range/bounded
+printf 'entity-bytes=1024:*' > fuzz/corpus/parse_range/unbounded
+printf 'dag-scope=entity&entity-bytes=10:%%2A' > fuzz/corpus/parse_range/encoded
@@ -4,4 +4,8 @@
 #![allow(dead_code)]
 #![allow(clippy::all)]
 
+#[cfg(feature = "nginx")]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+#[cfg(not(feature = "nginx"))]
+include!("standalone.rs");
@@ -1,11 +1,15 @@
 use crate::bindings::*;
 use crate::pool::{Allocator, Buffer, MemoryBuffer};
-use crate::varint::VarInt;
+use crate::scratch::Stash;
+use car_range_core::carv2;
+use car_range_core::framed::{Framed, Malformed, MAX_CID_LEN};
+use car_range_core::range::{format_satisfied, satisfied, FromEnd};
+use car_range_core::varint::{VarInt, MSB};
 use cid::Cid;
 use core2::io::{self, Cursor};
-use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
-use std::ops::{Bound, Range, RangeBounds};
+use std::ops::{Range, RangeBounds};
+use std::time::{Duration, Instant};
 
 mod unixfs_pb {
     include!(concat!(env!("OUT_DIR"), "/unixfs_pb.rs"));
@@ -15,66 +19,142 @@ mod dag_pb {
     include!(concat!(env!("OUT_DIR"), "/merkledag_pb.rs"));
 }
 
-fn lt_bound(bound: Bound<&u64>, val: u64) -> bool {
-    match bound {
-        Bound::Included(&b) => b >= val,
-        Bound::Excluded(&b) => b > val,
-        Bound::Unbounded => false,
-    }
+// Follows the CAR section framing of the bytes forwarded to the client so we can tell
+// how many blocks were actually emitted.
+#[derive(Debug, Default)]
+struct Emitted {
+    // bytes left in the current section
+    remaining: usize,
+    // the section length varint, which may be split across buffers
+    varint: [u8; 10],
+    varint_len: usize,
+    // number of complete sections, including the CAR header
+    sections: usize,
+    // CIDs of the emitted blocks, only collected when debugging
+    cids: Option<Vec<Cid>>,
+    // the start of the current block, until it holds its CID
+    head: Vec<u8>,
 }
 
-fn gt_bound(bound: Bound<&u64>, val: u64) -> bool {
-    match bound {
-        Bound::Included(&b) => b <= val,
-        Bound::Excluded(&b) => b < val,
-        Bound::Unbounded => false,
+impl Emitted {
+    fn feed(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let n = std::cmp::min(self.remaining, bytes.len());
+                self.collect_cid(&bytes[..n]);
+                self.remaining -= n;
+                bytes = &bytes[n..];
+                if self.remaining == 0 {
+                    self.sections += 1;
+                    self.head.clear();
+                }
+                continue;
+            }
+
+            let b = bytes[0];
+            bytes = &bytes[1..];
+            if self.varint_len < self.varint.len() {
+                self.varint[self.varint_len] = b;
+                self.varint_len += 1;
+            }
+            if b & MSB == 0 {
+                self.remaining = usize::decode_var(&self.varint[..self.varint_len])
+                    .map(|(len, _)| len)
+                    .unwrap_or(0);
+                self.varint_len = 0;
+            }
+        }
     }
-}
 
-fn ranges_overlap<T: RangeBounds<u64>>(range1: T, range2: Range<usize>) -> bool {
-    let (start1, end1) = (
-        match range1.start_bound() {
-            Bound::Included(x) => *x,
-            Bound::Excluded(x) => *x + 1,
-            Bound::Unbounded => u64::MIN,
-        },
-        match range1.end_bound() {
-            Bound::Included(x) => *x + 1,
-            Bound::Excluded(x) => *x,
-            Bound::Unbounded => u64::MAX,
-        },
-    );
-    let (start2, end2) = (range2.start as u64, range2.end as u64);
-
-    start1 < end2 && start2 < end1
-}
+    // the CID is at the start of every section after the CAR header
+    fn collect_cid(&mut self, bytes: &[u8]) {
+        let cids = match &mut self.cids {
+            Some(cids) if self.sections > 0 => cids,
+            _ => return,
+        };
+        // already found or not a CID
+        if self.head.len() >= MAX_CID_LEN {
+            return;
+        }
+        let n = std::cmp::min(MAX_CID_LEN - self.head.len(), bytes.len());
+        self.head.extend_from_slice(&bytes[..n]);
+        if let Ok(cid) = Cid::read_bytes(Cursor::new(&self.head[..])) {
+            cids.push(cid);
+            self.head.resize(MAX_CID_LEN, 0);
+        }
+    }
 
-// CAR V1 header, should contain a single root and be CBOR encoded
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
-pub struct CarHeader {
-    pub roots: Vec<Cid>,
-    pub version: u64,
+    fn blocks(&self) -> usize {
+        self.sections.saturating_sub(1)
+    }
 }
 
-// Unixfs data type enum
-#[derive(
-    Debug, Clone, Copy, PartialEq, Eq, num_enum::IntoPrimitive, num_enum::TryFromPrimitive,
-)]
-#[repr(i32)]
-pub enum DataType {
-    Raw = 0,
-    Directory = 1,
-    File = 2,
-    Metadata = 3,
-    Symlink = 4,
-    HamtShard = 5,
+/// The longest filtered CAR wrapped in a CARv2 by default, it is held in memory until it
+/// is complete.
+pub const MAX_CARV2_PAYLOAD: usize = 8 << 20;
+
+/// The most bytes held until the size of the file is read, enough for the CAR header and
+/// the root of a file with thousands of links.
+pub const MAX_PENDING_LEN: usize = 256 << 10;
+
+// When the filter first received and forwarded data, for tracing the filtering stage.
+#[derive(Debug, Default)]
+struct Timings {
+    first_in: Option<Instant>,
+    first_out: Option<Instant>,
+    // the first block after the CAR header was forwarded
+    first_block: Option<Instant>,
+    // time spent filtering
+    busy: Duration,
 }
 
+// aligned so the parser state starts on its own cache line
+#[repr(align(64))]
 pub struct CarBufferContext<'a, R: RangeBounds<u64> + Clone, A: Allocator> {
     pool: A,
-    framed: Framed<R>,
+    framed: Framed<R, Stash>,
     done: usize,
     pos: usize,
+    // shadow buffers passed down the filter chain that may not be sent yet
+    busy: *mut ngx_chain_t,
+    // bytes received from upstream
+    bytes_in: usize,
+    // bytes forwarded down the filter chain after trimming
+    bytes_out: usize,
+    // bytes of the filtered CAR, forwarded or not
+    car_len: usize,
+    // the bytes of the filtered CAR to forward, all of them when unset
+    window: Option<Range<usize>>,
+    // framing of the filtered CAR, out of the window too
+    emitted: Emitted,
+    // the filtered CAR so far, held to be sent in a CARv2 once complete
+    carv2: Option<Vec<u8>>,
+    // the longest filtered CAR held for the CARv2
+    max_carv2_len: usize,
+    // copies of the bytes of the filtered CAR, held until the size of the file is read
+    pending: Option<Vec<u8>>,
+    // flush every forwarded chain so the write filter doesn't postpone the output
+    flush: bool,
+    // the parser error that stopped the filtering
+    error: Option<Malformed>,
+    // the upstream ended in the middle of a CAR section
+    truncated: bool,
+    // the upstream stopped sending before the end of the range
+    stalled: bool,
+    // the upstream response had no CAR blocks
+    empty: bool,
+    // the last buffer must be sent on the next call
+    end_pending: bool,
+    // the range was satisfied before the end of the upstream response
+    early: bool,
+    // shadow buffers or chain links the pool couldn't allocate, any failure ends the
+    // filtering since the response would miss bytes
+    alloc_failures: usize,
+    timings: Timings,
+    // length of the upstream response, if it announced it
+    upstream_len: Option<usize>,
+    // consecutive calls that neither consumed nor forwarded anything
+    idle: usize,
     _marker: PhantomData<&'a ()>,
 }
 
@@ -82,630 +162,747 @@ impl<'a, R: RangeBounds<u64> + Clone, A: Allocator> CarBufferContext<'a, R, A> {
     pub fn new(range: R, pool: A) -> Self {
         Self {
             pool,
-            framed: Framed::new(range),
+            // the stash of the parser is borrowed from the worker scratch region
+            framed: Framed::with_scratch(range, Stash::new()),
             done: 0,
             pos: 0,
+            busy: std::ptr::null_mut(),
+            bytes_in: 0,
+            bytes_out: 0,
+            car_len: 0,
+            window: None,
+            emitted: Emitted::default(),
+            carv2: None,
+            max_carv2_len: MAX_CARV2_PAYLOAD,
+            pending: None,
+            flush: false,
+            error: None,
+            truncated: false,
+            stalled: false,
+            empty: false,
+            end_pending: false,
+            early: false,
+            alloc_failures: 0,
+            timings: Timings::default(),
+            upstream_len: None,
+            idle: 0,
             _marker: PhantomData,
         }
     }
 
-    pub fn buffer(&mut self, input: *mut ngx_chain_t) -> *mut ngx_chain_t {
+    pub fn set_flush(&mut self, flush: bool) {
+        self.flush = flush;
+    }
+
+    pub fn set_upstream_len(&mut self, len: Option<usize>) {
+        self.upstream_len = len;
+    }
+
+    /// Only forward the `window` bytes of the filtered CAR, for the byte ranges of it
+    /// clients resuming a download ask for. The filtering ends with the window.
+    pub fn set_window(&mut self, window: Range<usize>) {
+        self.window = Some(window);
+    }
+
+    /// Fail the response if the upstream CAR header is longer than `len`.
+    pub fn set_max_header_len(&mut self, len: usize) {
+        self.framed.set_max_header_len(len);
+    }
+
+    /// Fail the response if the first block isn't the root of the CAR header.
+    pub fn set_verify_root(&mut self, verify: bool) {
+        self.framed.set_verify_root(verify);
+    }
+
+    /// Only forward the CAR header and the root block, for `dag-scope=block` requests, see
+    /// [`Framed::set_block_scope`]. The filtering ends with the block.
+    pub fn set_block_scope(&mut self, block: bool) {
+        self.framed.set_block_scope(block);
+    }
+
+    /// Count the bounds of `from_end` back from the end of the file, for the negative
+    /// bounds of `entity-bytes` and suffix ranges, see [`Framed::set_from_end`].
+    pub fn set_from_end(&mut self, from_end: FromEnd) {
+        self.framed.set_from_end(from_end);
+    }
+
+    /// Send the filtered CAR in a CARv2 with an index of its blocks, all at once when it
+    /// is complete, see [`carv2::wrap`]. The filtering fails past the length set with
+    /// [`Self::set_max_carv2_len`], [`MAX_CARV2_PAYLOAD`] by default.
+    pub fn set_carv2(&mut self, carv2: bool) {
+        self.carv2 = carv2.then(Vec::new);
+    }
+
+    /// Fail the response if the filtered CAR wrapped in a CARv2 is longer than `len`.
+    pub fn set_max_carv2_len(&mut self, len: usize) {
+        self.max_carv2_len = len;
+    }
+
+    /// Hold the filtered CAR until the size of the file is read from its root, so the
+    /// header of the response can still tell a range past the end of the file, see
+    /// [`Self::unsatisfiable`]. The bytes held are copied and the upstream buffers released,
+    /// past [`MAX_PENDING_LEN`] of them they are forwarded anyway.
+    pub fn set_wait_file_size(&mut self, wait: bool) {
+        self.pending = wait.then(Vec::new);
+    }
+
+    /// Keep the CIDs of the forwarded blocks, see [`Self::emitted_cids`].
+    pub fn set_debug_cids(&mut self, debug: bool) {
+        self.emitted.cids = if debug { Some(Vec::new()) } else { None };
+    }
+
+    /// Filter the upstream chain and return the chain to forward, or the error that stopped
+    /// the filter the first time the upstream CAR fails to parse.
+    pub fn buffer(&mut self, input: *mut ngx_chain_t) -> Result<*mut ngx_chain_t, &Malformed> {
         // start with the first chain link
         let mut cl = input;
         // output buffer chain is null by default
         let mut out: *mut ngx_chain_t = std::ptr::null_mut();
         // once we sent the last buffer this method will always return null
         if self.done == 1 {
-            return out;
+            // the rest of the upstream response is dropped, and counted as discarded
+            while !cl.is_null() {
+                unsafe {
+                    let mut buf = MemoryBuffer::from_ngx_buf((*cl).buf);
+                    self.bytes_in += buf.len();
+                    buf.consume();
+                    cl = (*cl).next;
+                }
+            }
+            if self.end_pending {
+                self.end_pending = false;
+                out = match self.release_pending() {
+                    Some(cl) if !cl.is_null() => unsafe {
+                        (*cl).next = self.last_buf();
+                        if (*cl).next.is_null() {
+                            std::ptr::null_mut()
+                        } else {
+                            cl
+                        }
+                    },
+                    Some(_) => std::ptr::null_mut(),
+                    None => self.last_buf(),
+                };
+                if out.is_null() {
+                    self.alloc_failures += 1;
+                }
+            }
+            return Ok(out);
         }
+        let started = Instant::now();
+        let bytes_in = self.bytes_in;
         // keep track of the last link so we can append to it
         let mut ll = &mut out;
+        let mut tail: *mut ngx_chain_t = std::ptr::null_mut();
+        let mut failed = false;
+        let mut oom = false;
         // iterate over the chain until the next link is null
         while !cl.is_null() {
             let mut buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
             cl = unsafe { (*cl).next };
 
-            println!("==> buf.len(): {}", buf.len());
-
-            // TODO: handle internal errors
-            let parts = self.framed.next(buf.as_bytes()).unwrap();
+            self.bytes_in += buf.len();
+            if self.timings.first_in.is_none() && !buf.is_empty() {
+                self.timings.first_in = Some(started);
+            }
 
-            for (start, end) in parts {
-                println!("==> start: {}, end: {}", start, end);
-                self.pos = end;
-                let sub = buf.len() - end;
-
-                let is_last = match self.framed.range.end_bound() {
-                    Bound::Included(&b) => b == self.framed.unixfs_read as u64,
-                    Bound::Excluded(&b) => b - 1 == self.framed.unixfs_read as u64,
-                    // if the range is unbounded the last buffer should already be
-                    // set as last.
-                    Bound::Unbounded => false,
+            // flush and sync buffers carry no bytes but their flags must reach the write
+            // filter, the last buffer is handled once the chain is parsed. Before the first
+            // forwarded byte there is nothing to flush, and the header must wait for one.
+            if buf.is_empty() && !buf.is_last() && self.bytes_out > 0 && self.done == 0 {
+                let (flush, sync) = unsafe {
+                    let b = buf.as_ngx_buf();
+                    ((*b).flush(), (*b).sync())
                 };
+                if flush == 1 || sync == 1 {
+                    let cl = self.special_buf(flush, sync, false);
+                    if cl.is_null() {
+                        self.alloc_failures += 1;
+                        self.done = 1;
+                        break;
+                    }
+                    *ll = cl;
+                    ll = unsafe { &mut (*cl).next };
+                    tail = cl;
+                }
+            }
 
-                if sub > 0 && !self.framed.is_seek() || is_last {
-                    println!("==> sub: {}, is_last: {}", sub, is_last);
-                    self.done = 1;
-                    buf.set_last_buf(true);
-                    buf.set_last_in_chain(true);
+            let parts = match self.framed.next(buf.as_bytes()) {
+                Ok(parts) => parts,
+                Err(error) => {
+                    self.fail(error);
+                    failed = true;
+                    buf.consume();
+                    break;
+                }
+            };
+
+            // the bytes held for the size of the file go out ahead of the rest
+            let held = self.pending.as_ref().map_or(0, Vec::len);
+            if self.framed.file_size().is_some() || self.framed.past_end() || held > MAX_PENDING_LEN
+            {
+                if let Some(cl) = self.release_pending() {
+                    if cl.is_null() {
+                        self.alloc_failures += 1;
+                        self.done = 1;
+                        buf.consume();
+                        break;
+                    }
+                    *ll = cl;
+                    ll = unsafe { &mut (*cl).next };
+                    tail = cl;
+                }
+            }
+
+            // the start of a section in the range that came in earlier buffers
+            let released = self.framed.take_released();
+            if let Some(payload) = self.carv2.as_mut() {
+                payload.extend_from_slice(&released);
+            } else if let Some((start, end)) = self.clip(released.len()) {
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.extend_from_slice(&released[start..end]);
+                } else {
+                    let cl = self.temp_buf(&released[start..end]);
+                    if cl.is_null() {
+                        self.alloc_failures += 1;
+                        self.done = 1;
+                        buf.consume();
+                        break;
+                    }
+                    self.bytes_out += end - start;
+                    *ll = cl;
+                    ll = unsafe { &mut (*cl).next };
+                    tail = cl;
                 }
+            }
+            self.car_len += released.len();
+            self.emitted.feed(&released);
+
+            // the parser doesn't read past the end of the range
+            let past_end = self.framed.past_end();
+            if past_end {
+                self.done = 1;
+                self.early = !buf.is_last();
+            }
 
-                if sub == buf.len() || start == end {
-                    buf.set_empty();
+            // the upstream buffer may be shared with the cache or other filters, only the
+            // shadows forwarded are trimmed and it is marked consumed once they are sent
+            let mut forwarded = false;
+            // the last shadow of the buffer, which releases it once sent
+            let mut last_shadow: *mut ngx_buf_t = std::ptr::null_mut();
+
+            let count = parts.len();
+            for (i, (start, end)) in parts.into_iter().enumerate() {
+                let sub = match buf.len().checked_sub(end) {
+                    Some(sub) if start < end => sub,
+                    _ => {
+                        self.fail(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "CAR sections overflow the buffer",
+                        ));
+                        failed = true;
+                        break;
+                    }
+                };
+                self.pos = end;
+                let last = past_end && i + 1 == count;
+
+                // the part of the section in the window
+                let part = self.clip(end - start);
+                self.car_len += end - start;
+                self.emitted.feed(&buf.as_bytes()[start..end]);
+                if let Some(payload) = self.carv2.as_mut() {
+                    payload.extend_from_slice(&buf.as_bytes()[start..end]);
+                    continue;
+                }
+                let (start, end, sub) = match part {
+                    Some((from, to)) => (start + from, start + to, sub + (end - start - to)),
+                    None => continue,
+                };
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.extend_from_slice(&buf.as_bytes()[start..end]);
                     continue;
                 }
 
-                let mut cl = self.pool.alloc_chain();
+                let b = self.shadow_buf(buf.as_ngx_buf_mut());
+                let cl = if b.is_null() {
+                    b as *mut ngx_chain_t
+                } else {
+                    self.pool.alloc_chain()
+                };
                 if cl.is_null() {
-                    continue;
+                    self.alloc_failures += 1;
+                    self.done = 1;
+                    oom = true;
+                    break;
                 }
                 unsafe {
-                    (*cl).buf = buf.as_ngx_buf_mut();
+                    (*cl).buf = b;
                     (*cl).next = std::ptr::null_mut();
 
+                    // the shadows inherit the flags of the upstream buffer, only the
+                    // last part may end the response
+                    if last {
+                        (*b).set_last_buf(1);
+                        (*b).set_last_in_chain(1);
+                    } else if i + 1 < count {
+                        (*b).set_last_buf(0);
+                        (*b).set_last_in_chain(0);
+                    }
+
                     if sub > 0 {
-                        ngx_buf_remove_end((*cl).buf, sub);
+                        ngx_buf_remove_end(b, sub);
                     }
 
                     if start > 0 {
-                        ngx_buf_remove_start((*cl).buf, start);
+                        ngx_buf_remove_start(b, start);
                     }
                 }
+                self.bytes_out += end - start;
                 *ll = cl;
                 ll = unsafe { &mut (*cl).next };
+                tail = cl;
+                forwarded = true;
+                last_shadow = b;
+            }
+
+            // update_chains releases the upstream buffer with the first sent shadow that
+            // points to it, so only the last part does, like in ngx_http_ssi_filter_module
+            if !last_shadow.is_null() {
+                unsafe {
+                    (*last_shadow).shadow = buf.as_ngx_buf_mut();
+                    (*last_shadow).set_last_shadow(1);
+                }
+            }
+
+            if !forwarded || failed || oom {
+                buf.consume();
+            }
+            if failed || oom {
+                break;
+            }
 
-                // TODO: for now we don't handle splitting buffers
+            if self
+                .carv2
+                .as_ref()
+                .is_some_and(|p| p.len() > self.max_carv2_len)
+            {
+                self.fail(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "filtered CAR too long for a CARv2",
+                ));
+                failed = true;
                 break;
             }
+
+            // the rest of the CAR is past the window
+            if self.done == 0 && self.window.as_ref().is_some_and(|w| self.car_len >= w.end) {
+                self.done = 1;
+                self.early = !buf.is_last();
+            }
+
+            // the upstream sent its last buffer before the end of the range
+            if self.done == 0 && buf.is_last() {
+                self.done = 1;
+                if self.bytes_in == 0 || !self.framed.is_partial() && self.framed.section() == 0 {
+                    self.empty = true;
+                } else if self.framed.is_partial() {
+                    self.truncated = true;
+                }
+            }
+
+            // end the response even if the range ended in bytes that weren't forwarded, with
+            // the CARv2 of the filtered CAR unless the upstream sent none or cut a section
+            // short
+            if self.done == 1 && (tail.is_null() || unsafe { (*(*tail).buf).last_buf() } == 0) {
+                if let Some(cl) = self.release_pending() {
+                    if cl.is_null() {
+                        self.alloc_failures += 1;
+                        break;
+                    }
+                    *ll = cl;
+                    ll = unsafe { &mut (*cl).next };
+                    tail = cl;
+                }
+                let cl = match self.carv2.take() {
+                    Some(payload) if !payload.is_empty() && !self.truncated => {
+                        match carv2::wrap(&payload) {
+                            Ok(car) => self.carv2_buf(&car),
+                            Err(e) => {
+                                self.fail(e);
+                                failed = true;
+                                break;
+                            }
+                        }
+                    }
+                    _ => self.last_buf(),
+                };
+                if cl.is_null() {
+                    self.alloc_failures += 1;
+                    break;
+                }
+                *ll = cl;
+                ll = unsafe { &mut (*cl).next };
+                tail = cl;
+            }
         }
 
-        out
+        // limit_rate and postpone_output only see what we forward so the flush mode lets
+        // seeks start playing as soon as the first bytes of the range are found.
+        if self.flush && !tail.is_null() {
+            unsafe { (*(*tail).buf).set_flush(1) };
+        }
+
+        let now = Instant::now();
+        if self.timings.first_out.is_none() && !out.is_null() {
+            self.timings.first_out = Some(now);
+        }
+        if self.timings.first_block.is_none() && self.emitted.blocks() > 0 {
+            self.timings.first_block = Some(now);
+        }
+        self.timings.busy += now - started;
+
+        // with buffers still busy we are waiting on the client rather than stalled
+        if self.bytes_in == bytes_in && out.is_null() && self.busy.is_null() {
+            self.idle += 1;
+        } else {
+            self.idle = 0;
+        }
+
+        match self.error {
+            Some(ref e) if failed => Err(e),
+            _ => Ok(out),
+        }
     }
 
-    pub fn done(&self) -> bool {
-        self.done == 1
+    // the bytes of the next `len` bytes of the filtered CAR in the window, `None` when none
+    // of them are
+    fn clip(&self, len: usize) -> Option<(usize, usize)> {
+        let (start, end) = match &self.window {
+            Some(w) => (
+                w.start.saturating_sub(self.car_len).min(len),
+                w.end.saturating_sub(self.car_len).min(len),
+            ),
+            None => (0, len),
+        };
+        if start < end {
+            Some((start, end))
+        } else {
+            None
+        }
     }
 
-    pub fn unixfs_read(&self) -> usize {
-        self.framed.unixfs_read
+    // stop filtering, the rest of the stream can't be trusted
+    fn fail(&mut self, error: io::Error) {
+        self.error = Some(self.framed.malformed(error));
+        self.done = 1;
     }
 
-    pub fn pos(&self) -> usize {
-        self.pos
+    // an empty buffer marking the end of the response
+    fn last_buf(&mut self) -> *mut ngx_chain_t {
+        self.special_buf(0, 0, true)
     }
-}
 
-// a function to remove bytes at the end of a ngx_buf_s mutable pointer
-fn ngx_buf_remove_end(buf: *mut ngx_buf_s, len: usize) {
-    // assert that the buffer is not null
-    assert!(!buf.is_null());
-    unsafe {
-        (*buf).last = (*buf).last.wrapping_sub(len);
-        // if the buffer is in a file, adjust the file_last value
-        if (*buf).in_file() == 1 {
-            (*buf).file_last -= len as i64;
+    // an empty buffer only carrying flags
+    fn special_buf(&mut self, flush: u32, sync: u32, last: bool) -> *mut ngx_chain_t {
+        let b = self.pool.calloc_buf();
+        if b.is_null() {
+            return std::ptr::null_mut();
+        }
+        let cl = self.pool.alloc_chain();
+        if cl.is_null() {
+            return cl;
+        }
+        unsafe {
+            (*b).set_flush(flush);
+            (*b).set_sync(sync);
+            if last {
+                (*b).set_last_buf(1);
+                (*b).set_last_in_chain(1);
+            }
+            (*cl).buf = b;
+            (*cl).next = std::ptr::null_mut();
         }
+        cl
     }
-}
 
-fn ngx_buf_remove_start(buf: *mut ngx_buf_s, len: usize) {
-    // assert that the buffer is not null
-    assert!(!buf.is_null());
-    unsafe {
-        (*buf).pos = (*buf).pos.add(len);
-        if (*buf).in_file() == 1 {
-            (*buf).file_pos += len as i64;
+    // a buffer holding a copy of `data`
+    fn temp_buf(&mut self, data: &[u8]) -> *mut ngx_chain_t {
+        let p = self.pool.alloc_unaligned(data.len()) as *mut u8;
+        if p.is_null() {
+            return std::ptr::null_mut();
         }
+        let cl = self.special_buf(0, 0, false);
+        if cl.is_null() {
+            return cl;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), p, data.len());
+            let b = (*cl).buf;
+            (*b).start = p;
+            (*b).pos = p;
+            (*b).last = p.add(data.len());
+            (*b).end = (*b).last;
+            (*b).set_temporary(1);
+        }
+        cl
     }
-}
-
-#[derive(Debug, PartialEq)]
-enum WireType {
-    Varint = 0,
-    SixtyFourBit = 1,
-    LengthDelimited = 2,
-    StartGroup = 3,
-    EndGroup = 4,
-    ThirtyTwoBit = 5,
-}
 
-impl TryFrom<u64> for WireType {
-    type Error = anyhow::Error;
+    // a buffer with the bytes held until the size of the file was read, `None` when there
+    // are none and a null chain when it couldn't be allocated. Nothing is held afterwards.
+    fn release_pending(&mut self) -> Option<*mut ngx_chain_t> {
+        let pending = self.pending.take().filter(|p| !p.is_empty())?;
+        let cl = self.temp_buf(&pending);
+        if !cl.is_null() {
+            self.bytes_out += pending.len();
+        }
+        Some(cl)
+    }
 
-    #[inline]
-    fn try_from(value: u64) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(WireType::Varint),
-            1 => Ok(WireType::SixtyFourBit),
-            2 => Ok(WireType::LengthDelimited),
-            3 => Ok(WireType::StartGroup),
-            4 => Ok(WireType::EndGroup),
-            5 => Ok(WireType::ThirtyTwoBit),
-            _ => Err(anyhow::format_err!("invalid wire type value: {}", value)),
+    // the last buffer of the response, holding the whole CARv2
+    fn carv2_buf(&mut self, car: &[u8]) -> *mut ngx_chain_t {
+        let cl = self.temp_buf(car);
+        if cl.is_null() {
+            return cl;
+        }
+        unsafe {
+            (*(*cl).buf).set_last_buf(1);
+            (*(*cl).buf).set_last_in_chain(1);
         }
+        self.bytes_out += car.len();
+        cl
     }
-}
 
-#[derive(Debug, PartialEq)]
-enum FrameType {
-    CarHeader,
-    Block,
-    Cid,
-    RawLeaf,
-    MerkleDag,
-    PBLinks,
-    PBData,
-    UnixFs,
-    DataType,
-    FileSize,
-    BlockSizes,
-    UnixFsData,
-}
+    // allocate a buffer pointing into the same memory as the upstream buffer so trimming
+    // it doesn't mutate data still referenced by the upstream or the cache.
+    fn shadow_buf(&mut self, buf: *mut ngx_buf_t) -> *mut ngx_buf_t {
+        let b = self.pool.calloc_buf();
+        if b.is_null() {
+            return b;
+        }
+        unsafe {
+            *b = *buf;
+            // the memory belongs to the upstream buffer so it must not be modified
+            (*b).set_temporary(0);
+            (*b).set_memory((*buf).temporary() | (*buf).memory());
+            (*b).set_recycled((*buf).recycled());
+            // only the last shadow of the buffer points to it, see Self::buffer
+            (*b).shadow = std::ptr::null_mut();
+            (*b).set_last_shadow(0);
+        }
+        b
+    }
 
-struct Framed<R: RangeBounds<u64> + Clone> {
-    // the size of the current frame
-    len: usize,
-    // the size of the CAR block containing the current frame
-    blk_len: usize,
-    // the position of the current frame in the CAR block
-    blk_pos: usize,
-    // the buffer containing enough bytes to decode the varing or CID
-    buf: Vec<u8>,
-    // the range of the CAR file we are reading from.
-    range: R,
-    // the current position in the unixfs file data
-    unixfs_read: usize,
-    // the size of the unixfs frame
-    unixfs_len: usize,
-    // if the current dag node has links in which case it will be included
-    has_links: bool,
-    // the current frame type
-    state: FrameType,
-}
+    // keep track of the buffers sent down the chain and release the upstream buffers
+    // once their shadows are fully sent, the same way ngx_http_ssi_filter_module does.
+    pub fn update_chains(&mut self, out: *mut ngx_chain_t) {
+        unsafe {
+            let mut ll = &mut self.busy;
+            while !(*ll).is_null() {
+                ll = &mut (**ll).next;
+            }
+            *ll = out;
 
-impl<R: RangeBounds<u64> + Clone> Framed<R> {
-    fn new(range: R) -> Self {
-        Self {
-            len: 0,
-            blk_len: 0,
-            blk_pos: 0,
-            unixfs_read: 0,
-            unixfs_len: 0,
-            has_links: false,
-            range,
-            buf: Vec::with_capacity(72),
-            state: FrameType::CarHeader,
-        }
-    }
-
-    // reads all the frames in the buffer returning the number of bytes to remove from the start
-    // and end.
-    fn next(&mut self, buf: &[u8]) -> io::Result<Vec<(usize, usize)>> {
-        let mut ranges = Vec::with_capacity(2);
-        let mut start = 0;
-        let mut pos = 0;
-        let mut maybe = 0;
-        let mut current = buf;
-        while current.len() > 0 {
-            if gt_bound(self.range.end_bound(), self.unixfs_read as u64) {
-                ranges.push((start, pos));
-                return Ok(ranges);
+            while !self.busy.is_null() {
+                let buf = MemoryBuffer::from_ngx_buf((*self.busy).buf);
+                if buf.size() != 0 {
+                    break;
+                }
+                if !buf.shadow().is_null() {
+                    MemoryBuffer::from_ngx_buf(buf.shadow()).consume();
+                }
+                self.busy = (*self.busy).next;
             }
-            if self.state == FrameType::Cid {
-                match self.decode_cid(current) {
-                    Some((cid, read)) => {
-                        println!("cid: {:?}, read {}", cid, read);
-                        self.state = FrameType::Block;
-                        current = &current[read..];
-
-                        if self.include_block() {
-                            pos += read;
-                        } else {
-                            println!("skipping block pos: {}", pos);
-                            maybe += read;
-                        }
+        }
+    }
 
-                        match cid.codec() {
-                            0x55 => {
-                                self.state = FrameType::RawLeaf;
-                                self.len = self.blk_len - self.blk_pos;
-                                self.unixfs_len = self.len;
-                            }
-                            0x70 => {
-                                self.state = FrameType::MerkleDag;
-                            }
-                            _ => {
-                                unimplemented!();
-                            }
-                        };
+    pub fn done(&self) -> bool {
+        self.done == 1
+    }
 
-                        if self.include_block() || self.blk_len < 1000 {
-                            pos += maybe;
-                            maybe = 0;
-                        }
-                        continue;
-                    }
-                    None => {
-                        current = &[];
+    pub fn unixfs_read(&self) -> usize {
+        self.framed.unixfs_read()
+    }
 
-                        // bit of a hack but we assume that a unixfs chunk won't be smaller
-                        // than 1kb so we consider it some kind of intermediate node and include it.
-                        if self.include_block() || self.blk_len < 1000 {
-                            pos = buf.len();
-                        }
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
 
-                        continue;
-                    }
-                };
-            }
-            // beginning of the frame
-            if self.len == 0 {
-                match self.decode_len(current) {
-                    Some((size, read)) => {
-                        println!("decoded size: {}, read: {}", size, read);
-                        current = &current[read..];
-                        self.len = size;
-
-                        if self.include_block() {
-                            pos += read;
-                        } else {
-                            println!("skipping block pos: {}", pos);
-                            maybe += read;
-                        }
+    pub fn bytes_in(&self) -> usize {
+        self.bytes_in
+    }
 
-                        match self.state {
-                            FrameType::Block => {
-                                self.state = FrameType::Cid;
-                                self.blk_len = size;
-                                self.len = 0;
-                                self.has_links = false;
-
-                                // best effort
-                                if self.blk_len < 1000 {
-                                    pos += maybe;
-                                    maybe = 0;
-                                }
-                            }
-                            FrameType::MerkleDag => {
-                                self.blk_pos += read;
-
-                                let key = size as u64;
-                                let wire_type = WireType::try_from(key & 0x7).unwrap();
-                                let tag = key as u32 >> 3;
-
-                                match tag {
-                                    2 => {
-                                        self.state = FrameType::PBLinks;
-                                        self.len = 0;
-                                    }
-                                    1 => {
-                                        self.state = FrameType::PBData;
-                                        self.len = 0;
-                                    }
-                                    _ => unreachable!(),
-                                };
-                            }
-                            FrameType::UnixFs => {
-                                self.blk_pos += read;
-
-                                let key = size as u64;
-                                let wire_type = WireType::try_from(key & 0x7).unwrap();
-                                let tag = key as u32 >> 3;
-
-                                match tag {
-                                    1 => {
-                                        self.state = FrameType::DataType;
-                                        self.len = 0;
-                                    }
-                                    2 => {
-                                        self.state = FrameType::UnixFsData;
-                                        self.len = 0;
-                                    }
-                                    3 => {
-                                        self.state = FrameType::FileSize;
-                                        self.len = 0;
-                                    }
-                                    4 => {
-                                        self.state = FrameType::BlockSizes;
-                                        self.len = 0;
-                                    }
-                                    5 => {
-                                        println!("Data::HashType");
-                                    }
-                                    6 => {
-                                        println!("Data::Fanout");
-                                    }
-                                    _ => unreachable!(),
-                                };
-                            }
-                            FrameType::PBLinks => {
-                                self.blk_pos += read;
-                                self.has_links = true;
-                                println!("blk len: {}, blk pos: {}", self.blk_len, self.blk_pos);
-                            }
-                            FrameType::UnixFsData => {
-                                self.blk_pos += read;
-                                self.unixfs_len = size;
-                                self.len = self.blk_len - self.blk_pos;
-                            }
-                            FrameType::PBData
-                            | FrameType::DataType
-                            | FrameType::FileSize
-                            | FrameType::BlockSizes => {
-                                self.blk_pos += read;
-                                self.len = 0;
-
-                                println!("left {}", current.len());
-
-                                if matches!(self.state, FrameType::DataType) {
-                                    let tp = size as i32;
-                                    let dt: DataType = tp.try_into().unwrap();
-                                    println!("data type: {:?}", dt);
-                                }
-
-                                if self.blk_len - self.blk_pos == 0 {
-                                    self.state = FrameType::Block;
-                                    self.blk_pos = 0;
-                                    println!(
-                                        "end of block, maybe: {}, pos {}, unixfs_len {}",
-                                        maybe, pos, self.unixfs_len
-                                    );
-                                    // include any intermediary blocks so they are ones
-                                    // with no unixfs data
-                                    if self.unixfs_len == 0 {
-                                        pos += maybe;
-                                        maybe = 0;
-                                    }
-                                } else {
-                                    self.state = FrameType::UnixFs;
-                                }
-                            }
-                            _ => {}
-                        };
-                    }
-                    None => {
-                        current = &[];
-                        if self.include_block() {
-                            pos = buf.len();
-                        } else {
-                            println!("skipping block pos: {}", pos);
-                            maybe = buf.len();
-                        }
-                        if matches!(
-                            self.state,
-                            FrameType::MerkleDag
-                                | FrameType::UnixFs
-                                | FrameType::PBData
-                                | FrameType::DataType
-                                | FrameType::FileSize
-                                | FrameType::BlockSizes
-                                | FrameType::PBLinks
-                                | FrameType::UnixFsData
-                        ) {
-                            self.blk_pos += self.buf.len();
-                        }
-                    }
-                };
+    pub fn bytes_out(&self) -> usize {
+        self.bytes_out
+    }
 
-                if self.has_links {
-                    pos += maybe;
-                    maybe = 0;
-                }
+    /// Length of the filtered CAR so far, including the bytes out of the window.
+    pub fn car_len(&self) -> usize {
+        self.car_len
+    }
 
-            // end of the frame
-            } else if current.len() >= self.len {
-                println!("end of frame, len: {}", self.len);
-                if self.include_block() {
-                    pos += self.len;
-                    pos += maybe;
-                    maybe = 0;
-                } else {
-                    println!("skipping block pos: {}", pos);
-                    maybe += self.len;
-                }
-                match self.state {
-                    FrameType::CarHeader => {
-                        self.state = FrameType::Block;
-                    }
-                    FrameType::PBLinks => {
-                        self.state = FrameType::MerkleDag;
-                        self.blk_pos += self.len;
-                    }
-                    FrameType::UnixFsData | FrameType::RawLeaf => {
-                        if maybe > 0 {
-                            println!(
-                                "pushing range start {}, pos {}, maybe {}",
-                                start, pos, maybe
-                            );
-                            if pos > start {
-                                ranges.push((start, pos));
-                                start = start + pos + maybe;
-                            } else {
-                                start += maybe;
-                            }
-                            pos = start;
-                            maybe = 0;
-                        }
+    pub fn window(&self) -> Option<&Range<usize>> {
+        self.window.as_ref()
+    }
 
-                        self.blk_pos = 0;
-                        self.state = FrameType::Block;
-                        self.unixfs_read += self.unixfs_len;
-                        self.unixfs_len = 0;
+    pub fn blocks_out(&self) -> usize {
+        self.emitted.blocks()
+    }
 
-                        println!(
-                            "end of unixfs chunk: pos: {}, maybe: {}, start: {}",
-                            pos, maybe, start
-                        );
-                    }
-                    _ => {}
-                };
-                current = &current[self.len..];
-                self.len = 0;
-            // partial frame
-            } else {
-                println!("partial frame, len: {}, maybe: {}", current.len(), maybe);
-                if self.include_block() {
-                    pos += current.len();
-                    pos += maybe;
-                    maybe = 0;
-                } else {
-                    println!("skipping block pos: {}", pos);
-                    maybe += current.len();
-                }
+    /// CIDs of the forwarded blocks in order, if they are being collected.
+    pub fn emitted_cids(&self) -> Option<&[Cid]> {
+        self.emitted.cids.as_deref()
+    }
 
-                match self.state {
-                    FrameType::PBLinks => {
-                        self.blk_pos += current.len();
+    pub fn error(&self) -> Option<&Malformed> {
+        self.error.as_ref()
+    }
 
-                        // Assume if we have pblink frame we should include this intermediary node
-                        pos += maybe;
-                    }
-                    FrameType::UnixFsData => {
-                        self.blk_pos += current.len();
-                    }
-                    _ => {}
-                };
-                self.len -= current.len();
-                current = &[];
-            }
-        }
-        ranges.push((start, pos));
-        Ok(ranges)
+    pub fn truncated(&self) -> bool {
+        self.truncated
     }
 
-    // since the end bound is inclusive, we add 1 to the unixfs cursor
-    fn include_block(&self) -> bool {
-        println!(
-            "?include block? {:?}, unixfs_read {}, unixfs_len {}",
-            self.state, self.unixfs_read, self.unixfs_len
-        );
-        match self.state {
-            FrameType::CarHeader => true,
-            FrameType::UnixFsData | FrameType::RawLeaf => {
-                if self.unixfs_read == 0 || self.unixfs_len == 0 {
-                    self.range.contains(&(self.unixfs_read as u64 + 1))
-                } else {
-                    ranges_overlap(
-                        self.range.clone(),
-                        self.unixfs_read + 1..self.unixfs_read + self.unixfs_len,
-                    )
-                }
-            }
-            _ => self.range.contains(&(self.unixfs_read as u64 + 1)),
-        }
+    /// The `entity-bytes` `Content-Range` of the part of the file the range covers, see
+    /// [`format_satisfied`], once the size of the file was read from its root.
+    pub fn entity_range(&self) -> Option<String> {
+        let size = self.framed.file_size()?;
+        Some(format_satisfied(self.framed.bounds(), size))
     }
 
-    fn decode_len(&mut self, buf: &[u8]) -> Option<(usize, usize)> {
-        let mut i = 0;
-        loop {
-            self.buf.push(buf[i]);
-            match usize::decode_var(&self.buf[..]) {
-                Some((size, _)) => {
-                    self.buf.clear();
-                    return Some((size, i + 1));
-                }
-                None => {
-                    if buf.len() - (i + 1) > 0 {
-                        i += 1;
-                        continue;
-                    } else {
-                        return None;
-                    }
-                }
-            };
+    /// Whether the range covers none of the bytes of the file, once its size was read
+    /// from its root. The range of an empty file still gets its root.
+    pub fn unsatisfiable(&self) -> bool {
+        self.framed
+            .file_size()
+            .is_some_and(|size| size > 0 && satisfied(self.framed.bounds(), size).is_none())
+    }
+
+    /// Whether the filter failed because the first block isn't the CAR root.
+    pub fn root_mismatch(&self) -> bool {
+        self.framed.root_mismatch()
+    }
+
+    /// Whether the upstream response ended without any block, either empty or with
+    /// just the CAR header.
+    pub fn empty(&self) -> bool {
+        self.empty
+    }
+
+    /// Give up on an upstream that stopped sending, the next call to [`Self::buffer`]
+    /// returns the last buffer of the response.
+    pub fn stall(&mut self) {
+        if self.done == 0 {
+            self.stalled = true;
+            self.end_pending = true;
+            self.done = 1;
         }
     }
 
-    fn decode_cid(&mut self, buf: &[u8]) -> Option<(Cid, usize)> {
-        let mut i = 0;
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
 
-        let filled = self.buf.len();
+    pub fn terminated_early(&self) -> bool {
+        self.early
+    }
 
-        loop {
-            for j in i..std::cmp::min(i + 36, buf.len()) {
-                self.buf.push(buf[j]);
-                i = j;
-            }
-            // start from the next index
-            i += 1;
-            let mut reader = Cursor::new(&self.buf[..]);
-            match Cid::read_bytes(&mut reader) {
-                Ok(cid) => {
-                    let read = reader.position() as usize;
-                    self.buf.clear();
-                    self.blk_pos += read;
-                    return Some((cid, read - filled));
-                }
-                Err(_) => {
-                    if buf.len() > (i + 1) {
-                        continue;
-                    } else {
-                        return None;
-                    }
-                }
-            };
+    /// Upstream bytes left unread when the range was satisfied early, if the upstream
+    /// length is known.
+    pub fn bytes_saved(&self) -> Option<usize> {
+        if !self.early {
+            return None;
         }
+        Some(self.upstream_len?.saturating_sub(self.bytes_in))
     }
 
-    fn is_seek(&self) -> bool {
-        lt_bound(self.range.start_bound(), self.unixfs_read as u64)
+    /// Upstream bytes not forwarded: the ones read out of the range, and the ones left
+    /// unread when the range was satisfied early and the upstream length is known.
+    pub fn bytes_discarded(&self) -> usize {
+        self.bytes_in.saturating_sub(self.bytes_out) + self.bytes_saved().unwrap_or(0)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Allocations that failed, the filter stops at the first one and the request must
+    /// be aborted.
+    pub fn alloc_failures(&self) -> usize {
+        self.alloc_failures
+    }
 
-    fn to_ngx_buf(buf: &[u8]) -> ngx_buf_s {
-        let slice_ptr = buf.as_ptr_range();
+    /// Consecutive calls to [`Self::buffer`] that made no progress, while the range isn't
+    /// satisfied.
+    pub fn idle_calls(&self) -> usize {
+        self.idle
+    }
 
-        ngx_buf_s {
-            pos: slice_ptr.start as *mut u_char,
-            last: slice_ptr.end as *mut u_char,
-            file_pos: 0,
-            file_last: 0,
-            start: slice_ptr.start as *mut u_char,
-            end: slice_ptr.end as *const _ as *mut u_char,
-            tag: std::ptr::null_mut(),
-            file: std::ptr::null_mut(),
-            shadow: std::ptr::null_mut(),
-            _bitfield_align_1: [0u8; 0],
-            _bitfield_1: ngx_buf_s::new_bitfield_1(0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
-            num: 0,
+    /// Forwarded buffers not sent yet.
+    pub fn busy_bufs(&self) -> usize {
+        let mut n = 0;
+        let mut cl = self.busy;
+        while !cl.is_null() {
+            n += 1;
+            cl = unsafe { (*cl).next };
         }
+        n
     }
 
-    struct MockPool;
+    /// The internal state of the parser, to diagnose stalls.
+    pub fn describe(&self) -> String {
+        format!(
+            "{}, {} bytes in, {} bytes out",
+            self.framed.describe(),
+            self.bytes_in,
+            self.bytes_out
+        )
+    }
 
-    impl Allocator for MockPool {
-        fn as_ngx_pool_mut(&mut self) -> *mut ngx_pool_s {
-            std::ptr::null_mut()
-        }
-        fn alloc_chain(&mut self) -> *mut ngx_chain_s {
-            let link = Box::new(ngx_chain_s {
-                buf: std::ptr::null_mut(),
-                next: std::ptr::null_mut(),
-            });
-            Box::into_raw(link)
-        }
-        fn calloc_buf(&mut self) -> *mut ngx_buf_s {
-            let buf = Box::new(ngx_buf_s {
-                pos: std::ptr::null_mut(),
-                last: std::ptr::null_mut(),
-                file_pos: 0,
-                file_last: 0,
-                start: std::ptr::null_mut(),
-                end: std::ptr::null_mut(),
-                tag: std::ptr::null_mut(),
-                file: std::ptr::null_mut(),
-                shadow: std::ptr::null_mut(),
-                _bitfield_align_1: [0u8; 0],
-                _bitfield_1: ngx_buf_s::new_bitfield_1(0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
-                num: 0,
-            });
-            Box::into_raw(buf)
+    /// Time from the first byte received to the first byte forwarded.
+    pub fn first_byte_time(&self) -> Option<Duration> {
+        Some(self.timings.first_out? - self.timings.first_in?)
+    }
+
+    /// Time from the first byte received until the first block of the range is forwarded.
+    pub fn range_start_time(&self) -> Option<Duration> {
+        Some(self.timings.first_block? - self.timings.first_in?)
+    }
+
+    /// Total time spent filtering.
+    pub fn filter_time(&self) -> Duration {
+        self.timings.busy
+    }
+}
+
+// a function to remove bytes at the end of a ngx_buf_s mutable pointer
+fn ngx_buf_remove_end(buf: *mut ngx_buf_s, len: usize) {
+    // assert that the buffer is not null
+    assert!(!buf.is_null());
+    unsafe {
+        (*buf).last = (*buf).last.wrapping_sub(len);
+        // if the buffer is in a file, adjust the file_last value
+        if (*buf).in_file() == 1 {
+            (*buf).file_last -= len as i64;
+        }
+    }
+}
+
+fn ngx_buf_remove_start(buf: *mut ngx_buf_s, len: usize) {
+    // assert that the buffer is not null
+    assert!(!buf.is_null());
+    unsafe {
+        (*buf).pos = (*buf).pos.add(len);
+        if (*buf).in_file() == 1 {
+            (*buf).file_pos += len as i64;
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        chain, file, flush, last, mem, sync, Builder, File as TestFile, TestPool,
+    };
+    use car_range_core::framed::CarHeader;
+    use car_range_core::range::{parse_entity_bytes, parse_from_end};
+    use std::ops::{Bound, Range};
 
     // check the CAR file is a valid car file and contains the given blocks only
     fn check_car(buf: &[u8], blks: Vec<Cid>) {
@@ -737,18 +934,18 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..3552]);
+        let buf1 = mem(&car_data[..3552]);
 
         let l1 = ngx_chain_s {
             buf: &buf1 as *const _ as *mut _,
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(..1024, MockPool);
+        let mut ctx = CarBufferContext::new(..1024, TestPool::new());
 
         let mut buf = vec![];
 
-        let o1 = ctx.buffer(&l1 as *const _ as *mut _);
+        let o1 = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b1 = unsafe { MemoryBuffer::from_ngx_buf((*o1).buf) };
 
         assert!(b1.is_last());
@@ -770,21 +967,23 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..3552]);
+        let buf1 = mem(&car_data[..3552]);
 
         let l1 = ngx_chain_s {
             buf: &buf1 as *const _ as *mut _,
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(..3001, MockPool);
+        let mut ctx = CarBufferContext::new(..3001, TestPool::new());
 
         let mut buf = vec![];
 
-        let o1 = ctx.buffer(&l1 as *const _ as *mut _);
+        let o1 = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b1 = unsafe { MemoryBuffer::from_ngx_buf((*o1).buf) };
 
-        assert!(b1.is_last());
+        // offsets count from 0, byte 3000 starts the leaf after the buffer
+        assert!(!b1.is_last());
+        assert!(!ctx.done());
 
         buf.extend_from_slice(b1.as_bytes());
 
@@ -803,8 +1002,8 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..3552]);
-        let buf2 = to_ngx_buf(&car_data[3552..]);
+        let buf1 = mem(&car_data[..3552]);
+        let buf2 = mem(&car_data[3552..]);
 
         let l2 = ngx_chain_s {
             buf: &buf2 as *const _ as *mut _,
@@ -816,16 +1015,16 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(..3500, MockPool);
+        let mut ctx = CarBufferContext::new(..3500, TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -848,8 +1047,8 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..4096]);
-        let buf2 = to_ngx_buf(&car_data[4096..]);
+        let buf1 = mem(&car_data[..4096]);
+        let buf2 = mem(&car_data[4096..]);
 
         let l2 = ngx_chain_s {
             buf: &buf2 as *const _ as *mut _,
@@ -861,16 +1060,16 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(..3500, MockPool);
+        let mut ctx = CarBufferContext::new(..3500, TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -893,8 +1092,8 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..3552]);
-        let mut buf2 = to_ngx_buf(&car_data[3552..]);
+        let buf1 = mem(&car_data[..3552]);
+        let mut buf2 = mem(&car_data[3552..]);
         buf2.set_last_buf(1);
 
         let l2 = ngx_chain_s {
@@ -907,16 +1106,16 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(4500.., MockPool);
+        let mut ctx = CarBufferContext::new(4500.., TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -951,8 +1150,8 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..3552]);
-        let mut buf2 = to_ngx_buf(&car_data[3552..]);
+        let buf1 = mem(&car_data[..3552]);
+        let mut buf2 = mem(&car_data[3552..]);
         buf2.set_last_buf(1);
 
         let mut expected = vec![];
@@ -969,16 +1168,16 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(5500.., MockPool);
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -1001,9 +1200,9 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..2514]);
-        let buf2 = to_ngx_buf(&car_data[2514..4590]);
-        let mut buf3 = to_ngx_buf(&car_data[4590..]);
+        let buf1 = mem(&car_data[..2514]);
+        let buf2 = mem(&car_data[2514..4590]);
+        let mut buf3 = mem(&car_data[4590..]);
         buf3.set_last_buf(1);
 
         let l3 = ngx_chain_s {
@@ -1021,21 +1220,21 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(5500.., MockPool);
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         assert!(o.is_null());
         let b = MemoryBuffer::from_ngx_buf(l2.buf);
         assert!(b.is_empty());
 
-        let o = ctx.buffer(&l3 as *const _ as *mut _);
+        let o = ctx.buffer(&l3 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -1067,8 +1266,8 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..4096]);
-        let mut buf2 = to_ngx_buf(&car_data[4096..]);
+        let buf1 = mem(&car_data[..4096]);
+        let mut buf2 = mem(&car_data[4096..]);
         buf2.set_last_buf(1);
 
         let l2 = ngx_chain_s {
@@ -1081,16 +1280,16 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(5500.., MockPool);
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         assert!(b.is_last());
@@ -1122,9 +1321,9 @@ mod tests {
 
         let car_data = reader.fill_buf().unwrap();
 
-        let buf1 = to_ngx_buf(&car_data[..2614]);
-        let buf2 = to_ngx_buf(&car_data[2614..3100]);
-        let mut buf3 = to_ngx_buf(&car_data[3100..]);
+        let buf1 = mem(&car_data[..2614]);
+        let buf2 = mem(&car_data[2614..3100]);
+        let mut buf3 = mem(&car_data[3100..]);
         buf3.set_last_buf(1);
 
         let l3 = ngx_chain_s {
@@ -1142,153 +1341,976 @@ mod tests {
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(5500.., MockPool);
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
 
         let mut buf = vec![];
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
         buf.extend_from_slice(b.as_bytes());
 
-        let o = ctx.buffer(&l2 as *const _ as *mut _);
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
         assert!(o.is_null());
         let b = MemoryBuffer::from_ngx_buf(l2.buf);
         assert!(b.is_empty());
 
-        let o = ctx.buffer(&l3 as *const _ as *mut _);
+        let o = ctx.buffer(&l3 as *const _ as *mut _).unwrap();
         let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
 
-        assert!(b.is_last());
+        assert!(b.is_last());
+
+        buf.extend_from_slice(b.as_bytes());
+
+        // header + unxifs_root + raw_block(1000) + raw_block(157)
+        check_car(
+            &buf,
+            vec![
+                "bafybeihnavzumupz6aqh3hi2swo6wyjmgij2y62qbcsadrqa4trwo5zrre",
+                "bafkreigy7zqrooauu5pift4gmzkjgov3mob57e2nawkxxp5hpg2in4yomq",
+                "bafkreick5l3gihtmimedxayy5m4dlex3uxaiztaa2kd6rko4nlj3huuugm",
+            ]
+            .iter()
+            .map(|s| s.clone().try_into().unwrap())
+            .collect(),
+        );
+    }
+
+    // test against some small buffers that are not aligned with the block size.
+    // the buffers were generated by a real nginx instance.
+    #[test]
+    fn test_range_tiny_buffers() {
+        use crate::bindings::*;
+
+        let chunk1 = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let chunk2 = hex::decode("61").unwrap();
+        let chunk3 =
+            hex::decode("01701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b5")
+                .unwrap();
+        let chunk4 = hex::decode("12370a2401701220cbd6719e57578084e7fecd530689626bfbe56aa4b0b6cfb334fc6f0667dcad2d120a4d657869636f2e4a504718a4c6dc010a020801").unwrap();
+        let chunk5 = hex::decode("f501").unwrap();
+        let chunk6 =
+            hex::decode("01701220cbd6719e57578084e7fecd530689626bfbe56aa4b0b6cfb334fc6f0667dcad2d")
+                .unwrap();
+        let chunk7 = hex::decode("122c0a2401551220e82a3d5e3a3ec338f410510043f2b923d8d62bfb158d9ae5133cf160fda8defd120018808040122c0a2401551220607a8cb575d30e374f784922d1cdd2103953331ee0e42592f5e76a55ba4ee5be120018808040122c0a2401551220fb34ed5811abf2bf81e6e0ad788e1c38344446826b5ff399226d3262259bd98b120018808040122c0a2401551220fa38ae984cf83a6076147dbe8aac2cedb24509c35649ca7380a7f233de10d70a120018d3c41c0a17080218d3c4dc0120808040208080402080804020d3c41c").unwrap();
+
+        let exp = [
+            &chunk1[..],
+            &chunk2[..],
+            &chunk3[..],
+            &chunk4[..],
+            &chunk5[..],
+            &chunk6[..],
+            &chunk7[..],
+        ]
+        .concat();
+
+        let buf1 = mem(&chunk1[..]);
+        let buf2 = mem(&chunk2[..]);
+        let buf3 = mem(&chunk3[..]);
+        let buf4 = mem(&chunk4[..]);
+        let buf5 = mem(&chunk5[..]);
+        let buf6 = mem(&chunk6[..]);
+        let buf7 = mem(&chunk7[..]);
+
+        let l7 = ngx_chain_s {
+            buf: &buf7 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        let l6 = ngx_chain_s {
+            buf: &buf6 as *const _ as *mut _,
+            next: &l7 as *const _ as *mut _,
+        };
+        let l5 = ngx_chain_s {
+            buf: &buf5 as *const _ as *mut _,
+            next: &l6 as *const _ as *mut _,
+        };
+        let l4 = ngx_chain_s {
+            buf: &buf4 as *const _ as *mut _,
+            next: &l5 as *const _ as *mut _,
+        };
+        let l3 = ngx_chain_s {
+            buf: &buf3 as *const _ as *mut _,
+            next: &l4 as *const _ as *mut _,
+        };
+        let l2 = ngx_chain_s {
+            buf: &buf2 as *const _ as *mut _,
+            next: &l3 as *const _ as *mut _,
+        };
+        let l1 = ngx_chain_s {
+            buf: &buf1 as *const _ as *mut _,
+            next: &l2 as *const _ as *mut _,
+        };
+
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
+
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
+
+        let mut result: Vec<u8> = vec![];
+
+        let mut cl = o;
+        while !cl.is_null() {
+            let buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
+            cl = unsafe { (*cl).next };
+            println!("** buf out: {:?} \n", buf.as_bytes());
+            result.extend_from_slice(buf.as_bytes());
+        }
+
+        assert_eq!(result.len(), exp.len());
+
+        assert_eq!(result, exp);
+
+        assert_eq!(ctx.bytes_out(), exp.len());
+        assert_eq!(ctx.blocks_out(), 2);
+    }
+
+    // verify that ngx_buf_remove_end can remove 24 bytes at the end of a 1kb buffer
+    #[test]
+    fn test_buf_remove_end() {
+        let mut buf = mem(&vec![0u8; 1024][..]);
+        let mut buf = MemoryBuffer::from_ngx_buf(&mut buf);
+        ngx_buf_remove_end(buf.as_ngx_buf_mut(), 24);
+        assert_eq!(buf.len(), 1000);
+    }
+
+    // test CarBufferContext::buffer against a chain of 1 empty buffer
+    // and an unbounded range
+    #[test]
+    fn test_buf_filter_chain_empty() {
+        let buf = mem(&vec![0u8; 0][..]);
+        // check that the buffer is empty
+        assert_eq!(buf.last, buf.pos);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+
+        assert!(o.is_null());
+        assert_eq!(ctx.idle_calls(), 1);
+
+        // nginx calls the filter with no input to flush its busy buffers
+        ctx.buffer(std::ptr::null_mut()).unwrap();
+        assert_eq!(ctx.idle_calls(), 2);
+        assert!(ctx.describe().starts_with("state CarHeader,"));
+    }
+
+    // the flush mode marks the forwarded chain for immediate sending
+    #[test]
+    fn test_buf_filter_flush() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let buf = mem(&header[..]);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
+        ctx.set_flush(true);
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+
+        assert!(!o.is_null());
+        assert!(unsafe { (*(*o).buf).flush() } == 1);
+        assert_eq!(ctx.bytes_out(), header.len());
+        assert!(ctx.first_byte_time().is_some());
+        // only the CAR header was forwarded
+        assert!(ctx.range_start_time().is_none());
+    }
+
+    // the upstream ends right after the length of the first block
+    #[test]
+    fn test_buf_filter_truncated() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0161").unwrap();
+        let mut buf = mem(&data[..]);
+        buf.set_last_buf(1);
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+
+        assert!(ctx.truncated());
+        assert!(ctx.error().is_none());
+        assert!(ctx.done());
+    }
+
+    // the upstream ends while seeking, in the length of a block that isn't forwarded
+    #[test]
+    fn test_buf_filter_truncated_seek() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let len = hex::decode("e807").unwrap();
+        let b1 = mem(&header[..]);
+        let mut b2 = mem(&len[..]);
+        b2.set_last_buf(1);
+
+        let l1 = ngx_chain_s {
+            buf: &b1 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        let l2 = ngx_chain_s {
+            buf: &mut b2,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
+
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
+        assert!(!o.is_null());
+        assert!(unsafe { (*(*o).buf).last_buf() } == 0);
+
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
+        assert!(ctx.truncated());
+        assert!(!o.is_null());
+        unsafe {
+            assert_eq!((*(*o).buf).last_buf(), 1);
+            assert_eq!((*(*o).buf).pos, (*(*o).buf).last);
+        }
+    }
+
+    // the upstream sends the CAR header and then an empty last buffer
+    #[test]
+    fn test_buf_filter_header_only() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let b1 = mem(&header[..]);
+        let mut b2 = mem(&[]);
+        b2.set_last_buf(1);
+
+        let l1 = ngx_chain_s {
+            buf: &b1 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        let l2 = ngx_chain_s {
+            buf: &mut b2,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(5500.., TestPool::new());
+
+        ctx.buffer(&l1 as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.bytes_out(), header.len());
+
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
+        assert!(!o.is_null());
+        assert_eq!(unsafe { (*(*o).buf).last_buf() }, 1);
+        assert!(ctx.done());
+        assert!(ctx.empty());
+        assert!(!ctx.truncated());
+    }
+
+    // the upstream body is empty
+    #[test]
+    fn test_buf_filter_empty_body() {
+        let mut buf = mem(&[]);
+        buf.set_last_buf(1);
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(!o.is_null());
+        assert_eq!(unsafe { (*(*o).buf).last_buf() }, 1);
+        assert!(ctx.empty());
+        assert!(!ctx.truncated());
+    }
+
+    // the pool can't allocate the shadow buffer or the chain link of the forwarded bytes
+    #[test]
+    fn test_buf_filter_alloc_failure() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+
+        for allocs in 0..2 {
+            let buf = mem(&data[..]);
+            let chain = ngx_chain_s {
+                buf: &buf as *const _ as *mut _,
+                next: std::ptr::null_mut(),
+            };
+
+            let pool = TestPool::failing_after(allocs);
+            let stats = pool.stats();
+            let mut ctx = CarBufferContext::new(.., pool);
+            let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+            assert!(o.is_null());
+            assert_eq!(ctx.alloc_failures(), 1);
+            assert_eq!(ctx.bytes_out(), 0);
+            assert!(ctx.done());
+
+            // nothing is forwarded past the failure
+            let buf = mem(&data[..]);
+            let chain = ngx_chain_s {
+                buf: &buf as *const _ as *mut _,
+                next: std::ptr::null_mut(),
+            };
+            assert!(ctx.buffer(&chain as *const _ as *mut _).unwrap().is_null());
+            assert_eq!(ctx.alloc_failures(), 1);
+            assert_eq!(stats.failures.get(), 1);
+
+            // what was allocated before the failure goes with the pool
+            drop(ctx);
+            assert_eq!(stats.allocs.get(), allocs);
+            assert_eq!(stats.live(), 0);
+        }
+    }
+
+    // the pool can't allocate the buffer ending an empty response
+    #[test]
+    fn test_buf_filter_last_buf_alloc_failure() {
+        let mut buf = mem(&[]);
+        buf.set_last_buf(1);
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::failing_after(0));
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(o.is_null());
+        assert_eq!(ctx.alloc_failures(), 1);
+        assert!(ctx.done());
+    }
+
+    fn flags(b: &ngx_buf_s) -> [u32; 6] {
+        [
+            b.memory(),
+            b.in_file(),
+            b.flush(),
+            b.sync(),
+            b.last_buf(),
+            b.last_in_chain(),
+        ]
+    }
+
+    // a buffer of the upstream response also in a cache file, only its shadow is trimmed
+    #[test]
+    fn test_buf_filter_in_file_shadow() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e012e01551220").unwrap();
+        data.extend_from_slice(&[1u8; 32]);
+        data.extend_from_slice(b"0123456789");
+        let first = data.len();
+        data.extend_from_within(59..first);
+
+        let mut buf = mem(&data[..]);
+        buf.set_memory(1);
+        buf.set_in_file(1);
+        buf.file_pos = 1000;
+        buf.file_last = 1000 + data.len() as i64;
+        let orig = buf;
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(..=4, TestPool::new());
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(ctx.terminated_early());
+        unsafe {
+            let b = (*o).buf;
+            assert_eq!((*b).shadow, &mut buf as *mut _);
+            assert_eq!((*b).last_buf(), 1);
+            assert_eq!((*b).last.offset_from((*b).pos), first as isize);
+            assert_eq!((*b).file_last - (*b).file_pos, first as i64);
+            assert_eq!((*b).temporary(), 0);
+
+            // nothing changed upstream until the shadow is sent
+            assert_eq!((buf.pos, buf.last), (orig.pos, orig.last));
+            assert_eq!(
+                (buf.file_pos, buf.file_last),
+                (orig.file_pos, orig.file_last)
+            );
+            assert_eq!(flags(&buf), flags(&orig));
+
+            (*b).pos = (*b).last;
+            (*b).file_pos = (*b).file_last;
+        }
+        ctx.update_chains(o);
+        assert_eq!(buf.pos, buf.last);
+        assert_eq!(buf.file_pos, buf.file_last);
+        assert_eq!(flags(&buf), flags(&orig));
+        assert_eq!(ctx.busy_bufs(), 0);
+    }
+
+    // every part of a buffer in the range is forwarded, a skipped leaf splitting them, and
+    // only the last one ends the response
+    #[test]
+    fn test_buf_filter_parts() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).build(&data);
+        // the root and its 5 leaves
+        assert_eq!(file.sections.len(), 6);
+        let mut buf = mem(&file.car);
+        buf.set_last_buf(1);
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(1000..=1999, TestPool::new());
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(ctx.done());
+
+        let mut parts = vec![];
+        let mut cl = o;
+        while !cl.is_null() {
+            let b = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
+            parts.push((b.as_bytes(), b.is_last()));
+            cl = unsafe { (*cl).next };
+        }
+        assert_eq!(
+            parts,
+            [
+                (&file.car[..file.sections[0].bytes.end], false),
+                (&file.car[file.sections[2].bytes.clone()], true),
+            ]
+        );
+    }
+
+    // a buffer split in two parts is released once the second one is sent, not the first
+    #[test]
+    fn test_buf_filter_parts_shadow() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let mut buf = mem(&file.car);
+        buf.set_memory(1);
+        let orig = buf;
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        // the first leaf is skipped between the nodes and the leaves of the range
+        let mut ctx = CarBufferContext::new(1500..=2500, TestPool::new());
+
+        let o = ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        unsafe {
+            let first = (*o).buf;
+            let second = (*(*o).next).buf;
+            assert!((*(*o).next).next.is_null());
+            assert!((*first).shadow.is_null());
+            assert_eq!((*second).shadow, &mut buf as *mut _);
+            assert_eq!((*second).last_shadow(), 1);
+
+            // the second part still points into the upstream buffer
+            (*first).pos = (*first).last;
+            ctx.update_chains(o);
+            assert_eq!((buf.pos, buf.last), (orig.pos, orig.last));
+            assert_eq!(ctx.busy_bufs(), 1);
+
+            (*second).pos = (*second).last;
+        }
+        ctx.update_chains(std::ptr::null_mut());
+        assert_eq!(buf.pos, buf.last);
+        assert_eq!(ctx.busy_bufs(), 0);
+    }
+
+    // the range ends with the first buffer, which ends the response, the next one is
+    // consumed without being forwarded or flagged
+    #[test]
+    fn test_buf_filter_range_end_trimmed() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e012e01551220").unwrap();
+        data.extend_from_slice(&[1u8; 32]);
+        data.extend_from_slice(b"0123456789");
+        let block = data[59..].to_vec();
+
+        let b1 = mem(&data[..]);
+        let mut b2 = mem(&block[..]);
+        b2.set_memory(1);
+        let orig = b2;
+
+        let l1 = ngx_chain_s {
+            buf: &b1 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        let l2 = ngx_chain_s {
+            buf: &mut b2,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(..=9, TestPool::new());
+
+        let o = ctx.buffer(&l1 as *const _ as *mut _).unwrap();
+        assert!(ctx.done());
+        assert!(ctx.terminated_early());
+        unsafe {
+            assert_eq!((*(*o).buf).last_buf(), 1);
+            assert!((*o).next.is_null());
+        }
+
+        let o = ctx.buffer(&l2 as *const _ as *mut _).unwrap();
+        assert!(o.is_null());
+        assert_eq!(b2.pos, b2.last);
+        assert_eq!(flags(&b2), flags(&orig));
+        assert_eq!(ctx.bytes_out(), data.len());
+        assert_eq!(ctx.bytes_discarded(), block.len());
+    }
+
+    // flush and sync buffers without bytes are forwarded with their flags
+    #[test]
+    fn test_buf_filter_flag_only() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let chain = chain![mem(&header), flush(), sync(), mem(&[])];
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let o = ctx.buffer(chain.as_ptr()).unwrap();
+        unsafe {
+            assert_eq!((*(*o).buf).last.offset_from((*(*o).buf).pos), 59);
+            let flush = (*(*o).next).buf;
+            assert_eq!((*flush).pos, (*flush).last);
+            assert_eq!(((*flush).flush(), (*flush).sync()), (1, 0));
+            let sync = (*(*(*o).next).next).buf;
+            assert_eq!((*sync).pos, (*sync).last);
+            assert_eq!(((*sync).flush(), (*sync).sync()), (0, 1));
+            // an empty buffer without flags is dropped
+            assert!((*(*(*o).next).next).next.is_null());
+        }
+        assert_eq!(ctx.bytes_out(), 59);
+        assert!(!ctx.done());
+
+        // nothing to flush yet
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        assert!(ctx.buffer(chain.link(1)).unwrap().is_null());
+    }
+
+    // buffers only in a file hold no bytes to parse, they are consumed without being
+    // forwarded and the bytes in memory around them are filtered as usual
+    #[test]
+    fn test_buf_filter_file_only() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let mut chain = chain![mem(&data), file(0..4096), flush(), last()];
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let o = ctx.buffer(chain.as_ptr()).unwrap();
+        unsafe {
+            assert_eq!((*(*o).buf).last.offset_from((*(*o).buf).pos), 59);
+            let flush = (*(*o).next).buf;
+            assert_eq!((*flush).flush(), 1);
+            let last = (*(*(*o).next).next).buf;
+            assert_eq!((*last).last_buf(), 1);
+            assert!((*(*(*o).next).next).next.is_null());
+
+            let b = chain.buf_mut(1);
+            assert_eq!((*b).file_pos, (*b).file_last);
+            assert_eq!((*b).in_file(), 1);
+        }
+        assert!(ctx.done());
+        assert_eq!(ctx.bytes_out(), 59);
+    }
+
+    // the upstream stops sending in the middle of the first block
+    #[test]
+    fn test_buf_filter_stall() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0161").unwrap();
+        let buf = mem(&data[..]);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(!ctx.done());
+
+        ctx.stall();
+        assert!(ctx.stalled());
+        assert!(ctx.done());
+
+        let o = ctx.buffer(std::ptr::null_mut()).unwrap();
+        assert!(!o.is_null());
+        assert_eq!(unsafe { (*(*o).buf).last_buf() }, 1);
+
+        // the last buffer is only sent once
+        assert!(ctx.buffer(std::ptr::null_mut()).unwrap().is_null());
+    }
+
+    // the CID of the block is split across buffers
+    #[test]
+    fn test_emitted_cids() {
+        let cid: Cid = "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"
+            .parse()
+            .unwrap();
+        let mut block = cid.to_bytes();
+        block.extend_from_slice(b"hello");
+        let mut car = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        car.push(block.len() as u8);
+        car.extend_from_slice(&block);
+
+        let mut emitted = Emitted {
+            cids: Some(Vec::new()),
+            ..Default::default()
+        };
+        emitted.feed(&car[..70]);
+        emitted.feed(&car[70..]);
+
+        assert_eq!(emitted.blocks(), 1);
+        assert_eq!(emitted.cids, Some(vec![cid]));
+    }
+
+    // the first block starts with bytes that can't be a CID
+    #[test]
+    fn test_buf_filter_invalid_cid() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0104ffffffff").unwrap();
+        let mut buf = mem(&data[..]);
+        buf.set_last_buf(1);
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 59);
+        assert_eq!(e.cid.as_deref(), Some("0xffffffff"));
+        assert!(ctx.done());
+        assert!(ctx.error().is_some());
+
+        // the error is only returned once
+        assert!(ctx.buffer(std::ptr::null_mut()).unwrap().is_null());
+    }
+
+    // a sha2-512 CID split after the bytes decode_cid tries at once
+    #[test]
+    fn test_buf_filter_split_long_cid() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e014901551340").unwrap();
+        data.extend_from_slice(&[7u8; 64]);
+        data.extend_from_slice(b"hello");
+        let split = 59 + 1 + 37;
+        let b1 = mem(&data[..split]);
+        let b2 = mem(&data[split..]);
+
+        let l1 = ngx_chain_s {
+            buf: &b1 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        let l2 = ngx_chain_s {
+            buf: &b2 as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.buffer(&l1 as *const _ as *mut _).unwrap();
+        ctx.buffer(&l2 as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.unixfs_read(), 5);
+        assert_eq!(ctx.bytes_out(), data.len());
+    }
+
+    // bytes that never parse as a CID, one at a time in a block longer than any CID
+    #[test]
+    fn test_buf_filter_invalid_cid_tiny_buffers() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01c801").unwrap();
+        data.extend_from_slice(&[0xffu8; 200]);
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        let mut calls = 0;
+        for i in 0..data.len() {
+            let buf = mem(&data[i..i + 1]);
+            let chain = ngx_chain_s {
+                buf: &buf as *const _ as *mut _,
+                next: std::ptr::null_mut(),
+            };
+            calls += 1;
+            if ctx.buffer(&chain as *const _ as *mut _).is_err() {
+                break;
+            }
+        }
+        assert!(ctx.done());
+        assert_eq!(ctx.error().unwrap().error.to_string(), "invalid CID");
+        // the header, the block length and the longest CID we try to decode
+        assert_eq!(calls, 59 + 2 + MAX_CID_LEN);
+    }
+
+    // the raw block of an empty file, followed by one with data
+    #[test]
+    fn test_buf_filter_empty_raw_block() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e012401551220").unwrap();
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(&hex::decode("2701551220").unwrap());
+        data.extend_from_slice(&[1u8; 32]);
+        data.extend_from_slice(b"abc");
+        let mut buf = mem(&data[..]);
+        buf.set_last_buf(1);
+
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.unixfs_read(), 3);
+        assert_eq!(ctx.bytes_out(), data.len());
+        assert!(!ctx.truncated());
+        assert_eq!(ctx.blocks_out(), 2);
+    }
+
+    // a unixfs node with a mode and an mtime before its data
+    #[test]
+    fn test_buf_filter_unknown_fields() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e013601701220").unwrap();
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(&hex::decode("0a10080238a4034202080112036162631803").unwrap());
+        let buf = mem(&data[..]);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.unixfs_read(), 3);
+        assert_eq!(ctx.bytes_out(), data.len());
+    }
+
+    // a dag-pb node whose links are longer than the block
+    #[test]
+    fn test_buf_filter_links_past_block() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e013601701220").unwrap();
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(&hex::decode("127f").unwrap());
+        data.extend_from_slice(&[0u8; 16]);
+        let buf = mem(&data[..]);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 59);
+        assert_eq!(
+            e.error.to_string(),
+            "protobuf field longer than its CAR section"
+        );
+    }
+
+    // a raw block whose CID is longer than the block
+    #[test]
+    fn test_buf_filter_cid_past_block() {
+        let mut data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e010501551220").unwrap();
+        data.extend_from_slice(&[0u8; 32]);
+        let buf = mem(&data[..]);
+
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
 
-        buf.extend_from_slice(b.as_bytes());
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
 
-        // header + unxifs_root + raw_block(1000) + raw_block(157)
-        check_car(
-            &buf,
-            vec![
-                "bafybeihnavzumupz6aqh3hi2swo6wyjmgij2y62qbcsadrqa4trwo5zrre",
-                "bafkreigy7zqrooauu5pift4gmzkjgov3mob57e2nawkxxp5hpg2in4yomq",
-                "bafkreick5l3gihtmimedxayy5m4dlex3uxaiztaa2kd6rko4nlj3huuugm",
-            ]
-            .iter()
-            .map(|s| s.clone().try_into().unwrap())
-            .collect(),
-        );
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 59);
+        assert_eq!(e.error.to_string(), "block shorter than its fields");
     }
 
-    // test against some small buffers that are not aligned with the block size.
-    // the buffers were generated by a real nginx instance.
+    // an empty exclusive range ends on the CAR header
     #[test]
-    fn test_range_tiny_buffers() {
-        use crate::bindings::*;
+    fn test_buf_filter_excluded_zero() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let buf = mem(&header[..]);
 
-        let chunk1 = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
-        let chunk2 = hex::decode("61").unwrap();
-        let chunk3 =
-            hex::decode("01701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b5")
-                .unwrap();
-        let chunk4 = hex::decode("12370a2401701220cbd6719e57578084e7fecd530689626bfbe56aa4b0b6cfb334fc6f0667dcad2d120a4d657869636f2e4a504718a4c6dc010a020801").unwrap();
-        let chunk5 = hex::decode("f501").unwrap();
-        let chunk6 =
-            hex::decode("01701220cbd6719e57578084e7fecd530689626bfbe56aa4b0b6cfb334fc6f0667dcad2d")
-                .unwrap();
-        let chunk7 = hex::decode("122c0a2401551220e82a3d5e3a3ec338f410510043f2b923d8d62bfb158d9ae5133cf160fda8defd120018808040122c0a2401551220607a8cb575d30e374f784922d1cdd2103953331ee0e42592f5e76a55ba4ee5be120018808040122c0a2401551220fb34ed5811abf2bf81e6e0ad788e1c38344446826b5ff399226d3262259bd98b120018808040122c0a2401551220fa38ae984cf83a6076147dbe8aac2cedb24509c35649ca7380a7f233de10d70a120018d3c41c0a17080218d3c4dc0120808040208080402080804020d3c41c").unwrap();
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
 
-        let exp = [
-            &chunk1[..],
-            &chunk2[..],
-            &chunk3[..],
-            &chunk4[..],
-            &chunk5[..],
-            &chunk6[..],
-            &chunk7[..],
-        ]
-        .concat();
+        let mut ctx = CarBufferContext::new(..0, TestPool::new());
+
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(ctx.error().is_none());
+    }
 
-        let buf1 = to_ngx_buf(&chunk1[..]);
-        let buf2 = to_ngx_buf(&chunk2[..]);
-        let buf3 = to_ngx_buf(&chunk3[..]);
-        let buf4 = to_ngx_buf(&chunk4[..]);
-        let buf5 = to_ngx_buf(&chunk5[..]);
-        let buf6 = to_ngx_buf(&chunk6[..]);
-        let buf7 = to_ngx_buf(&chunk7[..]);
+    // the header is longer than the configured limit
+    #[test]
+    fn test_buf_filter_max_header() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let buf = mem(&header[..]);
 
-        let l7 = ngx_chain_s {
-            buf: &buf7 as *const _ as *mut _,
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
             next: std::ptr::null_mut(),
         };
-        let l6 = ngx_chain_s {
-            buf: &buf6 as *const _ as *mut _,
-            next: &l7 as *const _ as *mut _,
-        };
-        let l5 = ngx_chain_s {
-            buf: &buf5 as *const _ as *mut _,
-            next: &l6 as *const _ as *mut _,
-        };
-        let l4 = ngx_chain_s {
-            buf: &buf4 as *const _ as *mut _,
-            next: &l5 as *const _ as *mut _,
-        };
-        let l3 = ngx_chain_s {
-            buf: &buf3 as *const _ as *mut _,
-            next: &l4 as *const _ as *mut _,
-        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.set_max_header_len(57);
+
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 0);
+        assert_eq!(e.error.to_string(), "CAR header too large");
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.set_max_header_len(58);
+        assert!(ctx.buffer(&chain as *const _ as *mut _).is_ok());
+    }
+
+    // inputs that used to crash or wedge the parser
+    const REGRESSIONS: &[&str] = &[
+        // an invalid CID
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e010104ffffffff",
+        // a CID longer than its block
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0105015512200000000000000000000000000000000000000000000000000000000000000000",
+        // a block length longer than 9 bytes
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01ffffffffffffffffff",
+        // links longer than their block
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0136017012200000000000000000000000000000000000000000000000000000000000000000127f00000000000000000000000000000000",
+        // a sha2-512 CID
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0149015513400707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070768656c6c6f",
+        // unknown unixfs fields
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01360170122000000000000000000000000000000000000000000000000000000000000000000a10080238a4034202080112036162631803",
+        // a header longer than the limit
+        "ffff03a265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01",
+        // a block length that isn't minimal
+        "3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e018000",
+    ];
+
+    // the upstream response is split in two buffers at `split` and ends after the second
+    fn feed_split<R: RangeBounds<u64> + Clone>(range: R, data: &[u8], split: usize) {
+        let b1 = mem(&data[..split]);
+        let mut b2 = mem(&data[split..]);
+        b2.set_last_buf(1);
+
         let l2 = ngx_chain_s {
-            buf: &buf2 as *const _ as *mut _,
-            next: &l3 as *const _ as *mut _,
+            buf: &mut b2,
+            next: std::ptr::null_mut(),
         };
         let l1 = ngx_chain_s {
-            buf: &buf1 as *const _ as *mut _,
+            buf: &b1 as *const _ as *mut _,
             next: &l2 as *const _ as *mut _,
         };
 
-        let mut ctx = CarBufferContext::new(5500.., MockPool);
+        let mut ctx = CarBufferContext::new(range, TestPool::new());
+        let _ = ctx.buffer(&l1 as *const _ as *mut _);
+        assert!(ctx.done(), "split at {}: {}", split, ctx.describe());
+    }
 
-        let o = ctx.buffer(&l1 as *const _ as *mut _);
+    #[test]
+    fn test_buf_filter_regressions() {
+        for input in REGRESSIONS {
+            let data = hex::decode(input).unwrap();
+            for split in 0..=data.len() {
+                feed_split(.., &data, split);
+                feed_split(1..=2, &data, split);
+                feed_split(u64::MAX.., &data, split);
+            }
+        }
+    }
 
-        let mut result: Vec<u8> = vec![];
+    // the client goes away while a CID is stashed, dropping the context gives the stash
+    // back to the scratch region once
+    #[test]
+    fn test_drop_mid_stream() {
+        crate::scratch::init();
+        let free = crate::scratch::free_slots();
 
-        let mut cl = o;
-        while !cl.is_null() {
-            let buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
-            cl = unsafe { (*cl).next };
-            println!("** buf out: {:?} \n", buf.as_bytes());
-            result.extend_from_slice(buf.as_bytes());
-        }
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e0149015513400707070707").unwrap();
+        let buf = mem(&data[..]);
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
 
-        assert_eq!(result.len(), exp.len());
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.set_debug_cids(true);
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert!(!ctx.done());
+        assert_eq!(crate::scratch::free_slots(), free - 1);
 
-        assert_eq!(result, exp);
+        drop(ctx);
+        assert_eq!(crate::scratch::free_slots(), free);
     }
 
-    // verify that ngx_buf_remove_end can remove 24 bytes at the end of a 1kb buffer
+    // the first block isn't the root announced in the header
     #[test]
-    fn test_buf_remove_end() {
-        let mut buf = to_ngx_buf(&vec![0u8; 1024][..]);
-        let mut buf = MemoryBuffer::from_ngx_buf(&mut buf);
-        ngx_buf_remove_end(buf.as_ngx_buf_mut(), 24);
-        assert_eq!(buf.len(), 1000);
+    fn test_buf_filter_verify_root() {
+        let header = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01").unwrap();
+        let root = hex::decode(
+            "3601701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b5",
+        )
+        .unwrap();
+        let other = hex::decode("3601701220").unwrap();
+        let node = hex::decode("0a10080238a4034202080112036162631803").unwrap();
+
+        let mut data = header.clone();
+        data.extend_from_slice(&root);
+        data.extend_from_slice(&node);
+        let buf = mem(&data[..]);
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.set_verify_root(true);
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.bytes_out(), data.len());
+
+        let mut data = header.clone();
+        data.extend_from_slice(&other);
+        data.extend_from_slice(&[0u8; 32]);
+        data.extend_from_slice(&node);
+        let buf = mem(&data[..]);
+        let chain = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+
+        // only checked when asked to
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.buffer(&chain as *const _ as *mut _).unwrap();
+
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
+        ctx.set_verify_root(true);
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 59);
+        assert_eq!(
+            e.error.to_string(),
+            "first block doesn't match the CAR root"
+        );
+        assert!(ctx.root_mismatch());
     }
 
-    // test CarBufferContext::buffer against a chain of 1 empty buffer
-    // and an unbounded range
+    // the length of the first block doesn't fit in a multiformats varint
     #[test]
-    fn test_buf_filter_chain_empty() {
-        let buf = to_ngx_buf(&vec![0u8; 0][..]);
-        // check that the buffer is empty
-        assert_eq!(buf.last, buf.pos);
+    fn test_buf_filter_long_varint() {
+        let data = hex::decode("3aa265726f6f747381d82a58250001701220afcf9cd411b135aa1db2dd89bef443a93fb7894d2dfcbb657a732671a363b7b56776657273696f6e01ffffffffffffffffff").unwrap();
+        let buf = mem(&data[..]);
 
         let chain = ngx_chain_s {
             buf: &buf as *const _ as *mut _,
             next: std::ptr::null_mut(),
         };
 
-        let mut ctx = CarBufferContext::new(.., MockPool);
+        let mut ctx = CarBufferContext::new(.., TestPool::new());
 
-        let o = ctx.buffer(&chain as *const _ as *mut _);
-
-        assert!(o.is_null());
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.offset, 59);
+        assert_eq!(e.error.to_string(), "invalid varint: longer than 9 bytes");
     }
 
     #[test]
@@ -1307,7 +2329,7 @@ mod tests {
         let mut bufs = vec![];
         let mut offset = 0;
         while offset < car_data.len() {
-            let buf = to_ngx_buf(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
+            let buf = mem(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
             bufs.push(buf);
             offset += 32768;
         }
@@ -1322,7 +2344,7 @@ mod tests {
             chains.push(chain);
         }
 
-        let mut ctx = CarBufferContext::new(..200000, MockPool);
+        let mut ctx = CarBufferContext::new(..200000, TestPool::new());
 
         let mut buf = vec![];
 
@@ -1333,7 +2355,7 @@ mod tests {
             assert!(buf.len() <= 265577);
             let cl: *mut ngx_chain_t = &chains[i] as *const _ as *mut _;
             unsafe { (*cl).buf = &bufs[i] as *const _ as *mut _ };
-            let o = ctx.buffer(cl);
+            let o = ctx.buffer(cl).unwrap();
 
             i += 1;
             // add the buffered data to the output buffer
@@ -1365,7 +2387,7 @@ mod tests {
         let mut bufs = vec![];
         let mut offset = 0;
         while offset < car_data.len() {
-            let buf = to_ngx_buf(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
+            let buf = mem(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
             bufs.push(buf);
             offset += 32768;
         }
@@ -1401,7 +2423,7 @@ mod tests {
         }
 
         // select a range in the second chunk
-        let mut ctx = CarBufferContext::new(263000..333333, MockPool);
+        let mut ctx = CarBufferContext::new(263000..333333, TestPool::new());
 
         let mut buf = vec![];
 
@@ -1412,7 +2434,7 @@ mod tests {
             assert!(buf.len() <= 265577);
             let cl: *mut ngx_chain_t = &chains[i] as *const _ as *mut _;
             unsafe { (*cl).buf = &bufs[i] as *const _ as *mut _ };
-            let o = ctx.buffer(cl);
+            let o = ctx.buffer(cl).unwrap();
 
             i += 1;
             // add the buffered data to the output buffer
@@ -1445,7 +2467,7 @@ mod tests {
         let mut bufs = vec![];
         let mut offset = 0;
         while offset < car_data.len() {
-            let buf = to_ngx_buf(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
+            let buf = mem(&car_data[offset..std::cmp::min(car_data.len(), offset + 32768)]);
             bufs.push(buf);
             offset += 32768;
         }
@@ -1481,7 +2503,7 @@ mod tests {
         }
 
         // select a range in the second chunk
-        let mut ctx = CarBufferContext::new(555555..999999, MockPool);
+        let mut ctx = CarBufferContext::new(555555..999999, TestPool::new());
 
         let mut buf = vec![];
 
@@ -1492,7 +2514,7 @@ mod tests {
             let cl: *mut ngx_chain_t = &chains[i] as *const _ as *mut _;
             unsafe { (*cl).buf = &bufs[i] as *const _ as *mut _ };
 
-            let o = ctx.buffer(cl);
+            let o = ctx.buffer(cl).unwrap();
             i += 1;
 
             println!("-> buffered, null {}", o.is_null());
@@ -1575,13 +2597,23 @@ mod tests {
                 ],
             ),
             TC::new(
-                0..1025,
+                0..1024,
                 1465,
                 vec![
                     "bafybeidutwlr3tfcjuytejeusetv65lltrw6epessuyjzxhjg3kk4wt6ea",
                     "bafybeihrwler3wpt3vws4eutcr6cyqhielsin2hne6ogzkfarfoitt3jwa",
                 ],
             ),
+            // byte 1024 is the first one of the second leaf
+            TC::new(
+                0..1025,
+                2538,
+                vec![
+                    "bafybeidutwlr3tfcjuytejeusetv65lltrw6epessuyjzxhjg3kk4wt6ea",
+                    "bafybeihrwler3wpt3vws4eutcr6cyqhielsin2hne6ogzkfarfoitt3jwa",
+                    "bafybeigy57ubfegrckt4dq33s2g2vcwiahct5fgki6s2likb2yvztft63y",
+                ],
+            ),
             TC::new(
                 1025..1048,
                 1465,
@@ -1608,6 +2640,7 @@ mod tests {
                     println!("new section of size {}", section.len());
                     match reader.next(section) {
                         Ok(parts) => {
+                            buf.extend(reader.take_released());
                             for (start, end) in parts {
                                 println!("=> start {} end {}", start, end);
                                 buf.extend_from_slice(&section[start..end]);
@@ -1624,4 +2657,568 @@ mod tests {
             }
         }
     }
+
+    // the bytes of `file` forwarded for `range`: the header, every node with links up to
+    // the last leaf overlapping the range and those leaves
+    fn window(file: &TestFile, range: &(Bound<u64>, Bound<u64>)) -> Vec<u8> {
+        // an empty leaf covers the offset it is at
+        let overlaps = |data: &Range<u64>| {
+            let last = data.end.max(data.start + 1) - 1;
+            let after_start = match range.start_bound() {
+                Bound::Included(&from) => from <= last,
+                Bound::Excluded(&from) => from < last,
+                Bound::Unbounded => true,
+            };
+            let before_end = match range.end_bound() {
+                Bound::Included(&to) => data.start <= to,
+                Bound::Excluded(&to) => data.start < to,
+                Bound::Unbounded => true,
+            };
+            after_start && before_end
+        };
+        let last = file
+            .sections
+            .iter()
+            .rposition(|section| section.data.as_ref().is_some_and(overlaps));
+        let mut out = file.car[file.header.clone()].to_vec();
+        for section in &file.sections[..last.map_or(0, |last| last + 1)] {
+            if section.data.as_ref().is_none_or(overlaps) {
+                out.extend_from_slice(&file.car[section.bytes.clone()]);
+            }
+        }
+        out
+    }
+
+    fn frame_splits(car: &[u8], range: (Bound<u64>, Bound<u64>), splits: &[usize]) -> Vec<u8> {
+        let mut reader = Framed::new(range);
+        let mut out = vec![];
+        let mut rest = car;
+        let mut splits = splits.iter().cycle();
+        while !rest.is_empty() {
+            let (chunk, next) = rest.split_at((*splits.next().unwrap()).min(rest.len()));
+            let parts = reader.next(chunk).unwrap();
+            out.extend(reader.take_released());
+            for (start, end) in parts {
+                out.extend_from_slice(&chunk[start..end]);
+            }
+            rest = next;
+        }
+        out
+    }
+
+    // offsets count from 0 and an included end is the last byte of the range, a range
+    // ending on the last byte of a leaf doesn't reach into the next one
+    #[test]
+    fn test_frame_range_end() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).build(&data);
+        // the root and its 5 leaves
+        assert_eq!(file.sections.len(), 6);
+        let upto = |leaf: usize| &file.car[..file.sections[leaf + 1].bytes.end];
+
+        let cases = [
+            ((Bound::Unbounded, Bound::Included(999)), 0),
+            ((Bound::Unbounded, Bound::Excluded(1000)), 0),
+            ((Bound::Unbounded, Bound::Included(1000)), 1),
+            ((Bound::Unbounded, Bound::Excluded(1001)), 1),
+            ((Bound::Unbounded, Bound::Included(0)), 0),
+            ((Bound::Unbounded, Bound::Included(4999)), 4),
+        ];
+        for (range, last) in cases {
+            for split in [1, 100, file.car.len()] {
+                assert_eq!(
+                    frame_splits(&file.car, range, &[split]),
+                    upto(last),
+                    "{:?}",
+                    range
+                );
+            }
+        }
+    }
+
+    // a range starting inside a leaf starts with that leaf, the ones before it are skipped
+    #[test]
+    fn test_frame_range_start() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).build(&data);
+        // the header, the root and the leaves from `first` on
+        let from = |first: usize| {
+            let mut out = file.car[..file.sections[0].bytes.end].to_vec();
+            out.extend_from_slice(&file.car[file.sections[first + 1].bytes.start..]);
+            out
+        };
+
+        let cases = [
+            (0, 0),
+            (1, 0),
+            (500, 0),
+            (999, 0),
+            (1000, 1),
+            (1001, 1),
+            (4999, 4),
+        ];
+        for (start, first) in cases {
+            let range = (Bound::Included(start), Bound::Unbounded);
+            for split in [1, 100, file.car.len()] {
+                assert_eq!(
+                    frame_splits(&file.car, range, &[split]),
+                    from(first),
+                    "{}",
+                    start
+                );
+                assert_eq!(
+                    buffer_splits(&file.car, range, &[split], None),
+                    from(first),
+                    "{}",
+                    start
+                );
+            }
+        }
+    }
+
+    // the start of a section cut off by the end of a buffer is held until the section is
+    // known to be in the range, then forwarded ahead of the next buffer, or dropped
+    #[test]
+    fn test_frame_held_prefix() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        // the unixfs data of a dag-pb leaf comes a few bytes into its block
+        let file = Builder::new().chunk(1000).raw_leaves(false).build(&data);
+        assert_eq!(file.sections.len(), 6);
+        let range = (Bound::Included(1000), Bound::Included(1999));
+        let mut want = file.car[..file.sections[0].bytes.end].to_vec();
+        want.extend_from_slice(&file.car[file.sections[2].bytes.clone()]);
+
+        // cut the skipped leaf and the one of the range anywhere in their first bytes
+        for leaf in [1, 2] {
+            let start = file.sections[leaf].bytes.start;
+            for cut in start + 1..start + 60 {
+                let splits = [cut, file.car.len()];
+                assert_eq!(
+                    frame_splits(&file.car, range, &splits),
+                    want,
+                    "cut at {}",
+                    cut
+                );
+                assert_eq!(
+                    buffer_splits(&file.car, range, &splits, None),
+                    want,
+                    "cut at {}",
+                    cut
+                );
+            }
+        }
+    }
+
+    // the data of the HAMT shard wrapping the file isn't counted in the range
+    #[test]
+    fn test_frame_hamt_wrapped() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new()
+            .chunk(1000)
+            .depth(2)
+            .raw_leaves(false)
+            .wrap("file.bin")
+            .hamt(256)
+            .build(&data);
+        // the shard, the root, 2 intermediate nodes and 5 leaves
+        assert_eq!(file.sections.len(), 9);
+
+        let range = (Bound::Included(1000), Bound::Included(1999));
+        let out = frame_splits(&file.car, range, &[700]);
+        assert_eq!(out, window(&file, &range));
+
+        let mut ctx = CarBufferContext::new(range, TestPool::new());
+        ctx.set_verify_root(true);
+        let buf = mem(&file.car);
+        let cl = ngx_chain_s {
+            buf: &buf as *const _ as *mut _,
+            next: std::ptr::null_mut(),
+        };
+        ctx.buffer(&cl as *const _ as *mut _).unwrap();
+        // the shard, the root, the first intermediate node and its second leaf
+        assert_eq!(ctx.blocks_out(), 4);
+    }
+
+    // the block scope forwards the header and the root block only, whatever its codec
+    #[test]
+    fn test_frame_block_scope() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let dag_cbor = hex::decode("3aa265726f6f747381d82a58250001711220151fe9e73c6267a7060c6f6c4cca943c236f4b196723489608edb42a8b8fa80b6776657273696f6e012c01711220151fe9e73c6267a7060c6f6c4cca943c236f4b196723489608edb42a8b8fa80ba165646f646779f5").unwrap();
+        let files = [
+            Builder::new().chunk(1000).fanout(3).build(&data).car,
+            Builder::new().chunk(1000).wrap("file.bin").build(&data).car,
+            dag_cbor,
+        ];
+        for car in files {
+            let (len, read) = usize::decode_var(&car).unwrap();
+            let (section, read_section) = usize::decode_var(&car[read + len..]).unwrap();
+            let want = &car[..read + len + read_section + section];
+            for split in [1, 7, 100, car.len()] {
+                let mut ctx = CarBufferContext::new(.., TestPool::new());
+                ctx.set_block_scope(true);
+                ctx.set_verify_root(true);
+                let mut out = vec![];
+                for chunk in car.chunks(split) {
+                    if ctx.done() {
+                        break;
+                    }
+                    let buf = mem(chunk);
+                    let cl = ngx_chain_s {
+                        buf: &buf as *const _ as *mut _,
+                        next: std::ptr::null_mut(),
+                    };
+                    let mut o = ctx.buffer(&cl as *const _ as *mut _).unwrap();
+                    while !o.is_null() {
+                        let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
+                        out.extend_from_slice(b.as_bytes());
+                        o = unsafe { (*o).next };
+                    }
+                }
+                assert_eq!(out, want);
+                assert!(ctx.done());
+                assert_eq!(ctx.blocks_out(), 1);
+            }
+        }
+    }
+
+    // a range ending before the end of the file is filtered like the range it resolves to
+    // once the size of the file is read from its root
+    #[test]
+    fn test_frame_from_end() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let filter = |file: &TestFile, range: (Bound<u64>, Bound<u64>), from_end| {
+            let mut ctx = CarBufferContext::new(range, TestPool::new());
+            ctx.set_from_end(from_end);
+            let buf = mem(&file.car);
+            let cl = ngx_chain_s {
+                buf: &buf as *const _ as *mut _,
+                next: std::ptr::null_mut(),
+            };
+            ctx.buffer(&cl as *const _ as *mut _).unwrap();
+            (ctx.blocks_out(), ctx.bytes_out(), ctx.done())
+        };
+        let builders = [
+            Builder::new().chunk(1000).fanout(3),
+            Builder::new()
+                .chunk(1000)
+                .raw_leaves(false)
+                .wrap("file.bin"),
+            // a single raw block
+            Builder::new().chunk(10000),
+        ];
+        for builder in builders {
+            let file = builder.build(&data);
+            for (from, n, last) in [(0, 1, 4999), (1500, 2000, 3000), (0, 5000, 0), (0, 9000, 0)] {
+                let from_end = FromEnd {
+                    start: None,
+                    end: Some(n),
+                };
+                assert_eq!(
+                    filter(&file, (Bound::Included(from), Bound::Unbounded), from_end),
+                    filter(
+                        &file,
+                        (Bound::Included(from), Bound::Included(last)),
+                        FromEnd::default()
+                    )
+                );
+            }
+            // suffix ranges
+            for (n, first) in [(1, 4999), (500, 4500), (5000, 0), (9000, 0)] {
+                let from_end = FromEnd {
+                    start: Some(n),
+                    end: None,
+                };
+                assert_eq!(
+                    filter(&file, (Bound::Unbounded, Bound::Unbounded), from_end),
+                    filter(
+                        &file,
+                        (Bound::Included(first), Bound::Unbounded),
+                        FromEnd::default()
+                    )
+                );
+            }
+        }
+    }
+
+    // the CARv1 payload of a CARv2 is filtered like the CARv1, the response ends with it
+    #[test]
+    fn test_frame_carv2() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let data_offset = 51 + 13;
+        let mut car = hex::decode("0aa16776657273696f6e02").unwrap();
+        car.extend_from_slice(&[0; 16]);
+        car.extend_from_slice(&(data_offset as u64).to_le_bytes());
+        car.extend_from_slice(&(file.car.len() as u64).to_le_bytes());
+        car.extend_from_slice(&((data_offset + file.car.len()) as u64).to_le_bytes());
+        car.resize(data_offset, 0);
+        car.extend_from_slice(&file.car);
+        // an index the filter doesn't read
+        car.extend_from_slice(&[0x80, 0x08, 1, 0, 0, 0]);
+
+        let ranges = [
+            (Bound::Included(0), Bound::Unbounded),
+            (Bound::Included(1500), Bound::Included(2500)),
+        ];
+        for range in ranges {
+            for splits in [&[1][..], &[7, 60], &[700], &[car.len()]] {
+                assert_eq!(frame_splits(&car, range, splits), window(&file, &range));
+                assert_eq!(
+                    buffer_splits(&car, range, splits, None),
+                    window(&file, &range)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_entity_range() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let cases = [
+            ("1500:2500", "entity-bytes 1500-2500/5000"),
+            ("-100:*", "entity-bytes 4900-4999/5000"),
+            ("4000:9000", "entity-bytes 4000-4999/5000"),
+            // a range past the end of the file is answered with a 416
+            ("5000:*", "entity-bytes */5000"),
+            ("9000:9999", "entity-bytes */5000"),
+        ];
+        for (val, expected) in cases {
+            let range = parse_entity_bytes(val).unwrap();
+            let mut ctx = CarBufferContext::new(range, TestPool::new());
+            ctx.set_from_end(parse_from_end(val));
+            assert_eq!(ctx.entity_range(), None);
+            assert!(!ctx.unsatisfiable());
+            let mut buf = mem(&file.car);
+            buf.set_last_buf(1);
+            let cl = ngx_chain_s {
+                buf: &mut buf,
+                next: std::ptr::null_mut(),
+            };
+            ctx.buffer(&cl as *const _ as *mut _).unwrap();
+            assert_eq!(ctx.entity_range().as_deref(), Some(expected));
+            assert_eq!(ctx.unsatisfiable(), expected.contains('*'));
+        }
+
+        // an empty file is forwarded whatever the range
+        let file = Builder::new().build(&[]);
+        let mut ctx =
+            CarBufferContext::new((Bound::Included(10), Bound::Unbounded), TestPool::new());
+        let mut buf = mem(&file.car);
+        buf.set_last_buf(1);
+        let cl = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+        ctx.buffer(&cl as *const _ as *mut _).unwrap();
+        assert_eq!(ctx.entity_range().as_deref(), Some("entity-bytes */0"));
+        assert!(!ctx.unsatisfiable());
+    }
+
+    // the header of the response waits for the size of the file, so a range past its end
+    // still gets a 416 when the upstream splits the root before its FileSize
+    #[test]
+    fn test_buf_filter_wait_file_size() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new()
+            .chunk(1000)
+            .fanout(3)
+            .raw_leaves(false)
+            .build(&data);
+        // in the links of the root, its data and FileSize come after them
+        let split = file.sections[0].bytes.start + 40;
+        for (range, unsatisfiable) in [("9000:*", true), ("0:999", false)] {
+            let bounds = parse_entity_bytes(range).unwrap();
+            let mut ctx = CarBufferContext::new(bounds, TestPool::new());
+            ctx.set_wait_file_size(true);
+            let first = chain![mem(&file.car[..split])];
+            let out = ctx.buffer(first.as_ptr()).unwrap();
+            assert!(out.is_null(), "{}", range);
+            assert!(!ctx.unsatisfiable());
+            assert_eq!(ctx.bytes_out(), 0);
+
+            let second = chain![mem(&file.car[split..]), last()];
+            let out = ctx.buffer(second.as_ptr()).unwrap();
+            assert_eq!(ctx.unsatisfiable(), unsatisfiable, "{}", range);
+            if !unsatisfiable {
+                // the held bytes come first, the same CAR as without waiting
+                let mut got = Vec::new();
+                let mut o = out;
+                while !o.is_null() {
+                    got.extend_from_slice(
+                        MemoryBuffer::from_ngx_buf(unsafe { (*o).buf }).as_bytes(),
+                    );
+                    o = unsafe { (*o).next };
+                }
+                let whole = buffer_splits(&file.car, bounds, &[file.car.len()], None);
+                assert_eq!(got, whole);
+            }
+        }
+    }
+
+    #[test]
+    fn test_buf_filter_carv2() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(1500), Bound::Included(2500)),
+        ];
+        for range in ranges {
+            let v2 = carv2::wrap(&window(&file, &range)).unwrap();
+            for splits in [&[1][..], &[7, 60], &[700], &[file.car.len()]] {
+                let mut ctx = CarBufferContext::new(range, TestPool::new());
+                ctx.set_carv2(true);
+                assert_eq!(filter_splits(ctx, &file.car, splits), v2);
+            }
+        }
+
+        // the filtered CAR is held up to the longest CARv2 payload
+        let len = window(&file, &ranges[0]).len();
+        let mut buf = mem(&file.car);
+        buf.set_last_buf(1);
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+        let mut ctx = CarBufferContext::new(ranges[0], TestPool::new());
+        ctx.set_carv2(true);
+        ctx.set_max_carv2_len(len - 1);
+        let e = ctx.buffer(&chain as *const _ as *mut _).unwrap_err();
+        assert_eq!(e.error.to_string(), "filtered CAR too long for a CARv2");
+
+        let mut buf = mem(&file.car);
+        buf.set_last_buf(1);
+        let chain = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+        let mut ctx = CarBufferContext::new(ranges[0], TestPool::new());
+        ctx.set_carv2(true);
+        ctx.set_max_carv2_len(len);
+        assert!(ctx.buffer(&chain as *const _ as *mut _).is_ok());
+
+        // an empty upstream body is no CAR to wrap
+        let mut ctx = CarBufferContext::new(ranges[0], TestPool::new());
+        ctx.set_carv2(true);
+        let mut buf = mem(b"");
+        buf.set_last_buf(1);
+        let cl = ngx_chain_s {
+            buf: &mut buf,
+            next: std::ptr::null_mut(),
+        };
+        let o = ctx.buffer(&cl as *const _ as *mut _).unwrap();
+        let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
+        assert!(b.is_last());
+        assert!(b.as_bytes().is_empty());
+    }
+
+    // like frame_splits through the filter, checking only the last buffer forwarded ends
+    // the response
+    fn buffer_splits(
+        car: &[u8],
+        range: (Bound<u64>, Bound<u64>),
+        splits: &[usize],
+        window: Option<Range<usize>>,
+    ) -> Vec<u8> {
+        let mut ctx = CarBufferContext::new(range, TestPool::new());
+        if let Some(window) = window {
+            ctx.set_window(window);
+        }
+        filter_splits(ctx, car, splits)
+    }
+
+    fn filter_splits(
+        mut ctx: CarBufferContext<(Bound<u64>, Bound<u64>), TestPool>,
+        car: &[u8],
+        splits: &[usize],
+    ) -> Vec<u8> {
+        let mut out = vec![];
+        let mut ended = false;
+        let mut rest = car;
+        let mut splits = splits.iter().cycle();
+        while !rest.is_empty() && !ctx.done() {
+            let (chunk, next) = rest.split_at((*splits.next().unwrap()).min(rest.len()));
+            let mut buf = mem(chunk);
+            buf.set_last_buf(next.is_empty() as u32);
+            let cl = ngx_chain_s {
+                buf: &mut buf,
+                next: std::ptr::null_mut(),
+            };
+            let mut o = ctx.buffer(&cl as *const _ as *mut _).unwrap();
+            while !o.is_null() {
+                let b = unsafe { MemoryBuffer::from_ngx_buf((*o).buf) };
+                assert!(!ended, "buffer forwarded after the last one");
+                ended = b.is_last();
+                out.extend_from_slice(b.as_bytes());
+                o = unsafe { (*o).next };
+            }
+            rest = next;
+        }
+        assert!(ended);
+        out
+    }
+
+    proptest::proptest! {
+        // a built file goes through whole without a range
+        #[test]
+        fn test_frame_built_file(
+            size in 0usize..20000,
+            chunk in 1000usize..3000,
+            fanout in 2usize..6,
+            raw in proptest::bool::ANY,
+        ) {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let file = Builder::new().chunk(chunk).fanout(fanout).raw_leaves(raw).build(&data);
+            let leaves: Vec<_> = file.sections.iter().filter_map(|s| s.data.clone()).collect();
+            proptest::prop_assert_eq!(leaves.first().map(|data| data.start), Some(0));
+            proptest::prop_assert!(leaves.windows(2).all(|w| w[0].end == w[1].start));
+            proptest::prop_assert_eq!(leaves.last().map(|data| data.end), Some(size as u64));
+
+            let out = frame_splits(&file.car, (Bound::Unbounded, Bound::Unbounded), &[file.car.len()]);
+            proptest::prop_assert_eq!(file.root(), file.sections[0].cid);
+            proptest::prop_assert_eq!(&out, &file.car);
+        }
+
+        #[test]
+        fn test_frame_splits(
+            size in 1usize..20000,
+            chunk in 1000usize..3000,
+            fanout in 2usize..6,
+            raw in proptest::bool::ANY,
+            // no directory, a directory or a HAMT shard wrapping the file
+            wrap in 0u8..3,
+            from in 0u64..20000,
+            len in proptest::option::of(0u64..10000),
+            splits in proptest::collection::vec(1usize..3000, 1..20),
+        ) {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut builder = Builder::new().chunk(chunk).fanout(fanout).raw_leaves(raw);
+            if wrap > 0 {
+                builder = builder.wrap("file.bin");
+            }
+            if wrap > 1 {
+                builder = builder.hamt(256);
+            }
+            let file = builder.build(&data);
+            let from = from % size as u64;
+            let range = (Bound::Included(from), len.map_or(Bound::Unbounded, |len| Bound::Included(from + len)));
+            let window = window(&file, &range);
+            proptest::prop_assert_eq!(&frame_splits(&file.car, range, &splits), &window);
+            proptest::prop_assert_eq!(&buffer_splits(&file.car, range, &splits, None), &window);
+            // holding the CAR until the size of the file is read doesn't change it
+            let mut ctx = CarBufferContext::new(range, TestPool::new());
+            ctx.set_wait_file_size(true);
+            proptest::prop_assert_eq!(&filter_splits(ctx, &file.car, &splits), &window);
+
+            // a byte range of the filtered CAR is cut out of the same bytes
+            let start = (from as usize * 7) % (window.len() + 1);
+            let end = start + len.map_or(window.len(), |len| len as usize * 3) + 1;
+            let part = &window[start..end.min(window.len())];
+            proptest::prop_assert_eq!(
+                &buffer_splits(&file.car, range, &splits, Some(start..end)),
+                part
+            );
+        }
+    }
 }
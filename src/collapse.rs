@@ -0,0 +1,376 @@
+//! `car_range_collapse time;` collapses the identical CAR requests arriving together: the
+//! first one of a key fetches from upstream while the others wait, up to `time`, for it to
+//! complete, and then go on to be served by the cache entry it filled. The requests in
+//! flight are kept in a table of the `car_range_collapse` shared memory zone so that the
+//! requests of every worker wait for the same one. A leading request wakes the ones of
+//! its worker when it completes, those of other workers look at it every `POLL`.
+
+use crate::bindings::*;
+use crate::config::LocConf;
+use crate::log::ngx_log_debug_http;
+use crate::module::{catch_panic, ngx_car_range_module};
+use crate::pool::Allocator;
+use crate::request::*;
+use crate::timer;
+use std::cell::RefCell;
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// requests in flight the table holds, and the slots a key can be in
+const SLOTS: usize = 1024;
+const PROBES: usize = 8;
+
+// how often a waiting request looks at the one it waits for, which another worker can't
+// wake it from
+const POLL: ngx_msec_t = 100;
+
+// a request in flight, by the hash of its key, 0 when the slot is free
+#[derive(Debug, Default)]
+#[repr(C)]
+struct Slot {
+    key: AtomicU64,
+    // when the request is assumed gone, in case its worker died before freeing the slot
+    expires: AtomicU64,
+}
+
+#[repr(C)]
+struct Table {
+    slots: [Slot; SLOTS],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Claim {
+    // the request fetches, and frees the slot once complete
+    Leader(usize),
+    // another request of the key is in flight in the slot
+    Follower(usize),
+    // every slot the key can be in is taken, the request goes on by itself
+    Full,
+}
+
+impl Table {
+    // Take a slot for `key` until `expires`, unless a request of the key is in flight.
+    fn claim(&self, key: u64, now: u64, expires: u64) -> Claim {
+        let start = (key % SLOTS as u64) as usize;
+        let mut free = None;
+        for i in 0..PROBES {
+            let j = (start + i) % SLOTS;
+            let slot = &self.slots[j];
+            let held = slot.key.load(Ordering::Acquire);
+            let live = slot.expires.load(Ordering::Acquire) > now;
+            if held == key && live {
+                return Claim::Follower(j);
+            }
+            if free.is_none() && (held == 0 || !live) {
+                free = Some((j, held));
+            }
+        }
+
+        let (j, held) = match free {
+            Some(free) => free,
+            None => return Claim::Full,
+        };
+        let slot = &self.slots[j];
+        match slot
+            .key
+            .compare_exchange(held, key, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                slot.expires.store(expires, Ordering::Release);
+                Claim::Leader(j)
+            }
+            // another worker took it in the meantime, for the same key or another one
+            Err(taken) if taken == key => Claim::Follower(j),
+            Err(_) => Claim::Full,
+        }
+    }
+
+    // Whether the request of `key` in the slot is still in flight.
+    fn in_flight(&self, j: usize, key: u64, now: u64) -> bool {
+        let slot = &self.slots[j];
+        slot.key.load(Ordering::Acquire) == key && slot.expires.load(Ordering::Acquire) > now
+    }
+
+    // Free the slot of a request, unless it expired and was taken by another one since.
+    fn release(&self, j: usize, key: u64) {
+        self.slots[j]
+            .key
+            .compare_exchange(key, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .ok();
+    }
+}
+
+// FNV-1a of the URI and cache key of a request, 0 stands for a free slot
+fn key_hash(uri: &[u8], cache_key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in uri.iter().chain(&[0]).chain(cache_key.as_bytes()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash.max(1)
+}
+
+#[no_mangle]
+static mut ngx_car_range_collapse_zone: *mut ngx_shm_zone_t = ptr::null_mut();
+
+fn table() -> Option<&'static Table> {
+    unsafe {
+        let zone = ngx_car_range_collapse_zone;
+        if zone.is_null() || (*zone).data.is_null() {
+            return None;
+        }
+        Some(&*((*zone).data as *const Table))
+    }
+}
+
+/// Forget the zone of the previous configuration, called from `preconfiguration`.
+pub unsafe fn reset_zone() {
+    ngx_car_range_collapse_zone = ptr::null_mut();
+}
+
+/// Add the `car_range_collapse` shared memory zone holding the requests in flight, called
+/// for every location collapsing requests, which share it.
+pub unsafe fn add_zone(cf: *mut ngx_conf_t, module: *mut ngx_module_t) -> ngx_int_t {
+    let mut name = ngx_string!("car_range_collapse");
+    let size = mem::size_of::<Table>() + 8 * ngx_pagesize;
+    let zone = ngx_shared_memory_add(cf, &mut name, size, module as *mut c_void);
+    if zone.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    (*zone).init = Some(ngx_car_range_init_collapse_zone);
+    ngx_car_range_collapse_zone = zone;
+    NGX_OK as ngx_int_t
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_init_collapse_zone(
+    shm_zone: *mut ngx_shm_zone_t,
+    data: *mut c_void,
+) -> ngx_int_t {
+    // the requests in flight during a reload are still freed by the old workers
+    if !data.is_null() {
+        (*shm_zone).data = data;
+        return NGX_OK as ngx_int_t;
+    }
+
+    let shpool = (*shm_zone).shm.addr as *mut ngx_slab_pool_t;
+    if (*shm_zone).shm.exists != 0 {
+        (*shm_zone).data = (*shpool).data;
+        return NGX_OK as ngx_int_t;
+    }
+
+    let table = ngx_slab_calloc(shpool, mem::size_of::<Table>());
+    if table.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    (*shpool).data = table;
+    (*shm_zone).data = table;
+    NGX_OK as ngx_int_t
+}
+
+// Frees the slot of a leading request when its pool is destroyed, after the log phase,
+// which is when its response is complete and in the cache, and wakes the requests of the
+// worker waiting for it.
+struct Leading {
+    slot: usize,
+    key: u64,
+}
+
+impl Drop for Leading {
+    fn drop(&mut self) {
+        if let Some(table) = table() {
+            table.release(self.slot, self.key);
+        }
+        wake(self.slot, self.key);
+    }
+}
+
+// a request waiting for another one: the slot and key of the other one, and since when
+type Waiter = (*mut ngx_http_request_t, usize, u64, ngx_msec_t);
+
+thread_local! {
+    static WAITING: RefCell<Vec<Waiter>> = const { RefCell::new(Vec::new()) };
+}
+
+// Removes the request from the waiting ones when its pool is destroyed.
+struct Waiting(*mut ngx_http_request_t);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        forget(self.0);
+    }
+}
+
+fn forget(r: *mut ngx_http_request_t) {
+    WAITING.with(|waiting| waiting.borrow_mut().retain(|w| w.0 != r));
+}
+
+// Run the phases of the requests waiting for the one of `key` in the slot again.
+fn wake(slot: usize, key: u64) {
+    let woken: Vec<*mut ngx_http_request_t> = WAITING.with(|waiting| {
+        waiting
+            .borrow()
+            .iter()
+            .filter(|w| w.1 == slot && w.2 == key)
+            .map(|w| w.0)
+            .collect()
+    });
+    for r in woken {
+        unsafe {
+            let wev = (*(*r).connection).write;
+            (*wev).set_delayed(0);
+            timer::del_timer(wev);
+            timer::post_event(wev);
+        }
+    }
+}
+
+fn current_msec() -> ngx_msec_t {
+    unsafe { ptr::read_volatile(ptr::addr_of!(ngx_current_msec)) }
+}
+
+// Wait for the leading request to wake the request, or to look at it again in a while,
+// `left` at most. The client can still go away meanwhile.
+unsafe fn wait(r: *mut ngx_http_request_t, left: ngx_msec_t) -> ngx_int_t {
+    (*r).read_event_handler = Some(ngx_http_test_reading);
+    (*r).write_event_handler = Some(ngx_car_range_collapse_wake);
+    let wev = (*(*r).connection).write;
+    (*wev).set_delayed(1);
+    timer::add_timer(wev, left.min(POLL));
+    NGX_AGAIN as ngx_int_t
+}
+
+// Run the phases from the collapse handler again once the leading request woke the
+// request or the poll timer fired.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_collapse_wake(r: *mut ngx_http_request_t) {
+    let c = (*r).connection;
+    if (*(*c).write).delayed() == 1 {
+        if ngx_handle_write_event((*c).write, 0) != NGX_OK as ngx_int_t {
+            ngx_http_finalize_request(r, NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t);
+        }
+        return;
+    }
+    if ngx_handle_read_event((*c).read, 0) != NGX_OK as ngx_int_t {
+        ngx_http_finalize_request(r, NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t);
+        return;
+    }
+    (*r).read_event_handler = Some(ngx_http_block_reading);
+    (*r).write_event_handler = Some(ngx_http_core_run_phases);
+    ngx_http_core_run_phases(r);
+}
+
+fn collapse(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let req = unsafe { Request::from_ngx_http_request(r) };
+    let conf = unsafe { &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf) };
+    let timeout = conf.collapse();
+    let table = match table() {
+        Some(table) if timeout > 0 => table,
+        _ => return NGX_DECLINED as ngx_int_t,
+    };
+    let now = current_msec();
+
+    let waiter = WAITING.with(|waiting| waiting.borrow().iter().find(|w| w.0 == r).copied());
+    if let Some((_, slot, key, since)) = waiter {
+        let waited = now.wrapping_sub(since);
+        if table.in_flight(slot, key, now) && waited < timeout {
+            return unsafe { wait(r, timeout - waited) };
+        }
+        ngx_log_debug_http!(req, "car_range collapse: waited {}ms", waited);
+        forget(r);
+        return NGX_DECLINED as ngx_int_t;
+    }
+
+    if req.is_subrequest() || !req.accept_car() {
+        return NGX_DECLINED as ngx_int_t;
+    }
+    let uri = unsafe { std::slice::from_raw_parts(req.0.uri.data, req.0.uri.len) };
+    let key = key_hash(uri, &req.cache_key(conf.cache_full(), conf.range_sources()));
+
+    match table.claim(key, now, now + timeout) {
+        Claim::Leader(slot) => {
+            if req.pool().allocate(Leading { slot, key }).is_null() {
+                table.release(slot, key);
+            }
+            NGX_DECLINED as ngx_int_t
+        }
+        Claim::Follower(slot) => {
+            if req.pool().allocate(Waiting(r)).is_null() {
+                return NGX_DECLINED as ngx_int_t;
+            }
+            WAITING.with(|waiting| waiting.borrow_mut().push((r, slot, key, now)));
+            ngx_log_debug_http!(req, "car_range collapse: waiting for slot {}", slot);
+            unsafe { wait(r, timeout) }
+        }
+        Claim::Full => NGX_DECLINED as ngx_int_t,
+    }
+}
+
+/// Precontent phase handler holding back the requests of a CAR already being fetched.
+#[no_mangle]
+pub extern "C" fn ngx_car_range_collapse_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    catch_panic(r, "collapse handler", || collapse(r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_table() -> Box<Table> {
+        Box::new(Table {
+            slots: std::array::from_fn(|_| Slot::default()),
+        })
+    }
+
+    #[test]
+    fn test_claim() {
+        let table = new_table();
+        let key = key_hash(b"/ipfs/bafy", "car;dag-scope=all");
+        let slot = match table.claim(key, 0, 100) {
+            Claim::Leader(slot) => slot,
+            claim => panic!("{:?}", claim),
+        };
+        assert_eq!(table.claim(key, 10, 110), Claim::Follower(slot));
+        assert!(table.in_flight(slot, key, 10));
+
+        // a leader that didn't free its slot in time is replaced
+        assert!(!table.in_flight(slot, key, 100));
+        assert_eq!(table.claim(key, 100, 200), Claim::Leader(slot));
+
+        table.release(slot, key);
+        assert!(!table.in_flight(slot, key, 110));
+        assert_eq!(table.claim(key, 110, 210), Claim::Leader(slot));
+    }
+
+    #[test]
+    fn test_claim_collisions() {
+        let table = new_table();
+        // keys starting at the same slot take the next ones
+        let keys: Vec<u64> = (0..PROBES as u64 + 1)
+            .map(|i| 5 + i * SLOTS as u64)
+            .collect();
+        for (i, &key) in keys[..PROBES].iter().enumerate() {
+            assert_eq!(table.claim(key, 0, 100), Claim::Leader(5 + i));
+        }
+        assert_eq!(table.claim(keys[PROBES], 0, 100), Claim::Full);
+        assert_eq!(table.claim(keys[3], 0, 100), Claim::Follower(8));
+
+        table.release(6, keys[1]);
+        assert_eq!(table.claim(keys[PROBES], 0, 100), Claim::Leader(6));
+        // the slot was taken by another key since
+        table.release(6, keys[1]);
+        assert_eq!(table.claim(keys[PROBES], 0, 100), Claim::Follower(6));
+    }
+
+    #[test]
+    fn test_key_hash() {
+        let all = key_hash(b"/ipfs/bafy", "car;dag-scope=all");
+        assert_ne!(all, key_hash(b"/ipfs/bafy", "car;dag-scope=entity"));
+        assert_ne!(all, key_hash(b"/ipfs/bafz", "car;dag-scope=all"));
+        // the separator keeps the URI and the key apart
+        assert_ne!(key_hash(b"/a", "b"), key_hash(b"/ab", ""));
+        assert_ne!(key_hash(b"", ""), 0);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::bindings::*;
+use crate::car_reader::MAX_CARV2_PAYLOAD;
+use crate::stats::DEFAULT_LABEL;
+use car_range_core::framed::MAX_HEADER_LEN;
+use car_range_core::range::{parse_entity_bytes, parse_from_end, FromEnd};
+use std::ops::Bound;
+
+/// What becomes of the upstream response once the range is satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamAbort {
+    /// Read the rest of it so the connection can be kept alive, unless more than the
+    /// given number of bytes are left.
+    Drain(Option<usize>),
+    /// Stop reading it and close the connection.
+    Close,
+}
+
+impl UpstreamAbort {
+    /// Whether to close the upstream connection with `left` bytes still to read, `None`
+    /// when the upstream length isn't known.
+    pub fn close(&self, left: Option<usize>) -> bool {
+        match *self {
+            UpstreamAbort::Close => true,
+            UpstreamAbort::Drain(None) => false,
+            UpstreamAbort::Drain(Some(max)) => left.is_none_or(|left| left > max),
+        }
+    }
+}
+
+/// Whether the upstream is relied on to apply `entity-bytes` itself, which it tells with
+/// the `X-Entity-Bytes-Applied` header holding the range it applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamApplied {
+    /// The header is ignored and every response filtered.
+    Off,
+    /// Responses with the header are passed through.
+    Trust,
+    /// Every response must have the header.
+    Require,
+}
+
+/// Where the `entity-bytes` range of a request is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSource {
+    /// The `entity-bytes` query parameter.
+    Args,
+    /// A `Range: bytes=` header, of bytes of the entity rather than of the CAR.
+    Header,
+}
+
+/// What becomes of a response given the range the upstream applied.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Applied {
+    Filter,
+    PassThrough,
+    /// The upstream applied another range, or none when it was required to.
+    Mismatch,
+}
+
+impl UpstreamApplied {
+    /// Compare the range the upstream `applied` to the requested one, `from_end` being its
+    /// bounds counted from the end of the entity, see `Request::range_from_end`.
+    pub fn check(
+        &self,
+        applied: Option<&str>,
+        range: (Bound<u64>, Bound<u64>),
+        from_end: FromEnd,
+    ) -> Applied {
+        match (self, applied) {
+            (UpstreamApplied::Off, _) | (UpstreamApplied::Trust, None) => Applied::Filter,
+            (_, Some(value))
+                if parse_entity_bytes(value) == Some(range)
+                    && parse_from_end(value) == from_end =>
+            {
+                Applied::PassThrough
+            }
+            _ => Applied::Mismatch,
+        }
+    }
+}
+
+/// Location configuration for the `car_range_*` directives.
+#[derive(Debug, Default)]
+pub struct LocConf {
+    /// Filter the responses of the location, set by `car_range`.
+    pub enabled: Option<bool>,
+    /// Filter the responses of `/ipfs/<cid>` requests wherever they are handled.
+    pub auto: Option<bool>,
+    /// Flush every forwarded chain instead of letting the write filter postpone output.
+    pub flush: Option<bool>,
+    /// Log the CIDs of the forwarded blocks at the end of the request.
+    pub debug_cids: Option<bool>,
+    /// Status finalizing responses the filter aborts before sending the header.
+    pub error_status: Option<ngx_int_t>,
+    /// Index of the metrics label the counters of the location are kept under.
+    pub metrics_label: Option<usize>,
+    /// Time without upstream bytes after which the response is ended, 0 to wait for
+    /// the upstream timeouts.
+    pub stall_timeout: Option<ngx_msec_t>,
+    /// Longest upstream CAR header accepted.
+    pub max_header_size: Option<usize>,
+    /// Longest filtered CAR held to be wrapped in a CARv2.
+    pub max_carv2_size: Option<usize>,
+    /// Directory the chains of every response are recorded in, empty when disabled.
+    pub trace_dir: Option<String>,
+    /// Record the bytes of the buffers along with their sizes and flags.
+    pub trace_bytes: Option<bool>,
+    /// Cache the whole upstream CAR once for every range of it.
+    pub cache_full: Option<bool>,
+    /// Draining or closing the upstream response the range was satisfied before the end of.
+    pub upstream_abort: Option<UpstreamAbort>,
+    /// Passing through the responses the upstream applied the range to.
+    pub upstream_applied: Option<UpstreamApplied>,
+    /// Where the range is read from, the first source with one wins.
+    pub range_sources: Option<Vec<RangeSource>>,
+    /// Query parameters left out of the query strings built for upstream and cache keys.
+    pub ignore_args: Option<Vec<String>>,
+    /// Serve the `Range` requests of bytes of the filtered CAR.
+    pub byte_ranges: Option<bool>,
+    /// Answer `format=raw` requests with the block cut out of the upstream CAR.
+    pub raw: Option<bool>,
+    /// Hash the filtered CAR and send its CID in a trailer.
+    pub digest: Option<bool>,
+    /// Time identical CAR requests wait for the one fetching, 0 not to collapse them.
+    pub collapse: Option<ngx_msec_t>,
+    /// Path of the CAR `car_range_serve` sends, only set in its location.
+    pub serve: Option<*mut ngx_http_complex_value_t>,
+    /// Content handler of the proxy module `car_range_origin` configured, only set in its
+    /// location.
+    pub origin: ngx_http_handler_pt,
+}
+
+impl LocConf {
+    /// Inherit the values that were not set in this location from the enclosing one.
+    pub fn merge(&mut self, prev: &LocConf) {
+        if self.enabled.is_none() {
+            self.enabled = prev.enabled;
+        }
+        if self.auto.is_none() {
+            self.auto = prev.auto;
+        }
+        if self.flush.is_none() {
+            self.flush = prev.flush;
+        }
+        if self.debug_cids.is_none() {
+            self.debug_cids = prev.debug_cids;
+        }
+        if self.error_status.is_none() {
+            self.error_status = prev.error_status;
+        }
+        if self.metrics_label.is_none() {
+            self.metrics_label = prev.metrics_label;
+        }
+        if self.stall_timeout.is_none() {
+            self.stall_timeout = prev.stall_timeout;
+        }
+        if self.max_header_size.is_none() {
+            self.max_header_size = prev.max_header_size;
+        }
+        if self.max_carv2_size.is_none() {
+            self.max_carv2_size = prev.max_carv2_size;
+        }
+        if self.trace_dir.is_none() {
+            self.trace_dir = prev.trace_dir.clone();
+            self.trace_bytes = prev.trace_bytes;
+        }
+        if self.cache_full.is_none() {
+            self.cache_full = prev.cache_full;
+        }
+        if self.upstream_abort.is_none() {
+            self.upstream_abort = prev.upstream_abort;
+        }
+        if self.upstream_applied.is_none() {
+            self.upstream_applied = prev.upstream_applied;
+        }
+        if self.range_sources.is_none() {
+            self.range_sources = prev.range_sources.clone();
+        }
+        if self.ignore_args.is_none() {
+            self.ignore_args = prev.ignore_args.clone();
+        }
+        if self.byte_ranges.is_none() {
+            self.byte_ranges = prev.byte_ranges;
+        }
+        if self.raw.is_none() {
+            self.raw = prev.raw;
+        }
+        if self.digest.is_none() {
+            self.digest = prev.digest;
+        }
+        if self.collapse.is_none() {
+            self.collapse = prev.collapse;
+        }
+    }
+
+    /// Whether the filter acts on the responses of the location, the CARs
+    /// `car_range_serve` and `car_range_origin` send being filtered without `car_range`.
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false) || self.serve.is_some() || self.origin.is_some()
+    }
+
+    pub fn auto(&self) -> bool {
+        self.auto.unwrap_or(false)
+    }
+
+    pub fn flush(&self) -> bool {
+        self.flush.unwrap_or(false)
+    }
+
+    pub fn debug_cids(&self) -> bool {
+        self.debug_cids.unwrap_or(false)
+    }
+
+    pub fn metrics_label(&self) -> usize {
+        self.metrics_label.unwrap_or(DEFAULT_LABEL)
+    }
+
+    pub fn stall_timeout(&self) -> ngx_msec_t {
+        self.stall_timeout.unwrap_or(0)
+    }
+
+    pub fn max_header_size(&self) -> usize {
+        self.max_header_size.unwrap_or(MAX_HEADER_LEN)
+    }
+
+    pub fn max_carv2_size(&self) -> usize {
+        self.max_carv2_size.unwrap_or(MAX_CARV2_PAYLOAD)
+    }
+
+    pub fn trace_dir(&self) -> Option<&str> {
+        self.trace_dir.as_deref().filter(|dir| !dir.is_empty())
+    }
+
+    pub fn trace_bytes(&self) -> bool {
+        self.trace_bytes.unwrap_or(false)
+    }
+
+    pub fn cache_full(&self) -> bool {
+        self.cache_full.unwrap_or(false)
+    }
+
+    pub fn upstream_abort(&self) -> UpstreamAbort {
+        self.upstream_abort.unwrap_or(UpstreamAbort::Drain(None))
+    }
+
+    pub fn upstream_applied(&self) -> UpstreamApplied {
+        self.upstream_applied.unwrap_or(UpstreamApplied::Off)
+    }
+
+    pub fn range_sources(&self) -> &[RangeSource] {
+        self.range_sources
+            .as_deref()
+            .unwrap_or(&[RangeSource::Args])
+    }
+
+    pub fn ignore_args(&self) -> &[String] {
+        self.ignore_args.as_deref().unwrap_or(&[])
+    }
+
+    pub fn byte_ranges(&self) -> bool {
+        self.byte_ranges.unwrap_or(false)
+    }
+
+    pub fn raw(&self) -> bool {
+        self.raw.unwrap_or(false)
+    }
+
+    pub fn digest(&self) -> bool {
+        self.digest.unwrap_or(false)
+    }
+
+    pub fn collapse(&self) -> ngx_msec_t {
+        self.collapse.unwrap_or(0)
+    }
+
+    pub fn error_status(&self) -> ngx_int_t {
+        self.error_status
+            .unwrap_or(NGX_HTTP_BAD_GATEWAY as ngx_int_t)
+    }
+}
+
+/// Arguments of the directive being parsed, including the directive name.
+pub unsafe fn conf_args<'a>(cf: *mut ngx_conf_t) -> &'a [ngx_str_t] {
+    let args = (*cf).args;
+    std::slice::from_raw_parts((*args).elts as *const ngx_str_t, (*args).nelts)
+}
+
+pub fn parse_flag(value: &ngx_str_t) -> Option<bool> {
+    match value.to_str().ok()? {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// The `args` and `header` range sources in the order given, each one at most once.
+pub fn parse_range_sources(values: &[ngx_str_t]) -> Option<Vec<RangeSource>> {
+    let mut sources = Vec::new();
+    for value in values {
+        let source = match value.to_str().ok()? {
+            "args" => RangeSource::Args,
+            "header" => RangeSource::Header,
+            _ => return None,
+        };
+        if sources.contains(&source) {
+            return None;
+        }
+        sources.push(source);
+    }
+    Some(sources)
+}
+
+/// An error status `error_page` can intercept.
+pub fn parse_status(value: &ngx_str_t) -> Option<ngx_int_t> {
+    let status: ngx_int_t = value.to_str().ok()?.parse().ok()?;
+    (400..=599).contains(&status).then_some(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::ngx_string;
+
+    #[test]
+    fn test_upstream_abort() {
+        assert!(!UpstreamAbort::Drain(None).close(None));
+        assert!(!UpstreamAbort::Drain(None).close(Some(1 << 30)));
+        assert!(!UpstreamAbort::Drain(Some(1024)).close(Some(1024)));
+        assert!(UpstreamAbort::Drain(Some(1024)).close(Some(1025)));
+        // the rest of a chunked response could be of any size
+        assert!(UpstreamAbort::Drain(Some(1024)).close(None));
+        assert!(UpstreamAbort::Close.close(Some(0)));
+    }
+
+    #[test]
+    fn test_parse_range_sources() {
+        assert_eq!(
+            parse_range_sources(&[ngx_string!("header"), ngx_string!("args")]),
+            Some(vec![RangeSource::Header, RangeSource::Args])
+        );
+        assert_eq!(
+            parse_range_sources(&[ngx_string!("args")]),
+            Some(vec![RangeSource::Args])
+        );
+        assert_eq!(
+            parse_range_sources(&[ngx_string!("args"), ngx_string!("args")]),
+            None
+        );
+        assert_eq!(parse_range_sources(&[ngx_string!("query")]), None);
+    }
+
+    #[test]
+    fn test_upstream_applied() {
+        let range = (Bound::Included(0), Bound::Included(1023));
+        for applied in [None, Some("0:1023"), Some("0:*")] {
+            assert_eq!(
+                UpstreamApplied::Off.check(applied, range, FromEnd::default()),
+                Applied::Filter
+            );
+        }
+        assert_eq!(
+            UpstreamApplied::Trust.check(None, range, FromEnd::default()),
+            Applied::Filter
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some(" 0:1023"), range, FromEnd::default()),
+            Applied::PassThrough
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("0:*"), range, FromEnd::default()),
+            Applied::Mismatch
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("bogus"), range, FromEnd::default()),
+            Applied::Mismatch
+        );
+        assert_eq!(
+            UpstreamApplied::Require.check(None, range, FromEnd::default()),
+            Applied::Mismatch
+        );
+        assert_eq!(
+            UpstreamApplied::Require.check(Some("0:1023"), range, FromEnd::default()),
+            Applied::PassThrough
+        );
+
+        // a range ending before the end of the entity isn't the one up to its end
+        let range = (Bound::Included(0), Bound::Unbounded);
+        let last = FromEnd {
+            start: None,
+            end: Some(1),
+        };
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("0:-1"), range, last),
+            Applied::PassThrough
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("0:*"), range, last),
+            Applied::Mismatch
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("0:-1"), range, FromEnd::default()),
+            Applied::Mismatch
+        );
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        let suffix = FromEnd {
+            start: Some(500),
+            end: None,
+        };
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("-500:*"), range, suffix),
+            Applied::PassThrough
+        );
+        assert_eq!(
+            UpstreamApplied::Trust.check(Some("0:*"), range, suffix),
+            Applied::Mismatch
+        );
+    }
+}
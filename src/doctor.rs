@@ -0,0 +1,260 @@
+//! `car-range doctor` checks an install of the module in one command: it writes a minimal
+//! nginx.conf loading the built module, checks it with `nginx -t`, starts nginx on a
+//! loopback port serving a CAR fixture and requests a range of it, which must come back
+//! filtered exactly like `car-range` filters the fixture offline.
+
+use crate::{filter_stream, usage, HeapPool};
+use car_range_core::range;
+use nginx_car_range::car_reader::CarBufferContext;
+use nginx_car_range::scratch;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the range requested from the fixture
+const RANGE: &str = "0:1023";
+
+// how long nginx gets to start listening
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct DoctorArgs {
+    module: PathBuf,
+    nginx: String,
+    fixture: PathBuf,
+}
+
+pub fn parse_args() -> DoctorArgs {
+    let mut module = PathBuf::from("target/release/libnginx_car_range.so");
+    let mut nginx = "nginx".to_string();
+    let mut fixture = PathBuf::from("fixture.car");
+
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage(&format!("missing {}", arg)))
+        };
+        match arg.as_str() {
+            "--module" => module = value().into(),
+            "--nginx" => nginx = value(),
+            "--fixture" => fixture = value().into(),
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    DoctorArgs {
+        module,
+        nginx,
+        fixture,
+    }
+}
+
+// the configuration serving the fixture at /ipfs/fixture, printed for operators to start from
+fn config(module: &Path, fixture: &Path, port: u16) -> String {
+    format!(
+        "load_module {};
+error_log logs/error.log;
+pid logs/nginx.pid;
+
+events {{}}
+
+http {{
+    server {{
+        listen 127.0.0.1:{};
+
+        location = /ipfs/fixture {{
+            alias {};
+            default_type application/vnd.ipld.car;
+            car_range;
+        }}
+    }}
+}}
+",
+        module.display(),
+        port,
+        fixture.display()
+    )
+}
+
+// the nginx process serving the fixture, stopped when dropped
+struct Nginx(Child);
+
+impl Drop for Nginx {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn check(what: &str, res: Result<(), String>) -> Result<(), String> {
+    match &res {
+        Ok(()) => eprintln!("car-range doctor: {}: ok", what),
+        Err(e) => eprintln!("car-range doctor: {}: FAILED\n{}", what, e),
+    }
+    res
+}
+
+// a port free for nginx to listen on, as far as we can tell
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn nginx_test(args: &DoctorArgs, prefix: &Path, conf: &Path) -> Result<(), String> {
+    let out = Command::new(&args.nginx)
+        .arg("-t")
+        .arg("-p")
+        .arg(prefix)
+        .arg("-c")
+        .arg(conf)
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", args.nginx, e))?;
+    if !out.status.success() {
+        return Err(String::from_utf8_lossy(&out.stderr).into_owned());
+    }
+    Ok(())
+}
+
+fn start_nginx(args: &DoctorArgs, prefix: &Path, conf: &Path, port: u16) -> Result<Nginx, String> {
+    let child = Command::new(&args.nginx)
+        .arg("-p")
+        .arg(prefix)
+        .arg("-c")
+        .arg(conf)
+        .arg("-g")
+        .arg("daemon off; master_process off;")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", args.nginx, e))?;
+    let mut nginx = Nginx(child);
+
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if let Ok(Some(status)) = nginx.0.try_wait() {
+            return Err(format!("nginx exited with {}", status));
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            return Err(format!("nginx isn't listening on port {}", port));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(nginx)
+}
+
+// request the range of the fixture, the response is read to the end of the connection
+fn request(port: u16) -> Result<(String, Vec<u8>), String> {
+    let mut stream =
+        TcpStream::connect(("127.0.0.1", port)).map_err(|e| format!("connect: {}", e))?;
+    stream.set_read_timeout(Some(STARTUP_TIMEOUT)).ok();
+    write!(
+        stream,
+        "GET /ipfs/fixture?entity-bytes={} HTTP/1.0\r\n\
+         Host: localhost\r\n\
+         Accept: application/vnd.ipld.car\r\n\r\n",
+        RANGE
+    )
+    .map_err(|e| format!("request: {}", e))?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| format!("response: {}", e))?;
+
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("response without a header")?;
+    let header = String::from_utf8_lossy(&response[..end]).into_owned();
+    Ok((header, response[end + 4..].to_vec()))
+}
+
+// what the module must send for the range of the fixture
+fn expected(fixture: &Path) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("fixture {}: {}", fixture.display(), msg),
+        )
+    };
+    let car = fs::read(fixture)?;
+    if car.starts_with(b"version https://git-lfs") {
+        return Err(invalid("a git-lfs pointer, fetch it with git lfs pull"));
+    }
+
+    let mut out = Vec::new();
+    scratch::init();
+    let mut ctx = CarBufferContext::new(range::parse_entity_bytes(RANGE).unwrap(), HeapPool);
+    filter_stream(&mut ctx, &mut &car[..], 64 << 10, &mut out)?;
+    if ctx.error().is_some() || ctx.truncated() || ctx.empty() {
+        return Err(invalid("not a valid CAR"));
+    }
+    Ok(out)
+}
+
+fn check_response(port: u16, want: &[u8]) -> Result<(), String> {
+    let (header, body) = request(port)?;
+    let status = header.lines().next().unwrap_or("");
+    if !status.contains(" 200 ") {
+        return Err(format!("unexpected status: {}", status));
+    }
+    let filtered = header.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("X-Car-Range") && value.trim() == "filtered"
+        })
+    });
+    if !filtered {
+        return Err(format!("the response wasn't filtered:\n{}", header));
+    }
+    if body != want {
+        return Err(format!(
+            "got {} bytes, the offline filter gives {}",
+            body.len(),
+            want.len()
+        ));
+    }
+    Ok(())
+}
+
+pub fn run(args: DoctorArgs) -> io::Result<i32> {
+    let module = fs::canonicalize(&args.module).map_err(|e| {
+        io::Error::new(e.kind(), format!("module {}: {}", args.module.display(), e))
+    })?;
+    let fixture = fs::canonicalize(&args.fixture).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("fixture {}: {}", args.fixture.display(), e),
+        )
+    })?;
+    let want = expected(&fixture)?;
+
+    let prefix = std::env::temp_dir().join(format!("car-range-doctor-{}", std::process::id()));
+    fs::create_dir_all(prefix.join("logs"))?;
+    let port = free_port()?;
+    let conf = prefix.join("nginx.conf");
+    let snippet = config(&module, &fixture, port);
+    fs::write(&conf, &snippet)?;
+    println!("{}", snippet);
+
+    let res = check("nginx -t", nginx_test(&args, &prefix, &conf)).and_then(|()| {
+        let nginx = start_nginx(&args, &prefix, &conf, port);
+        check(
+            "nginx start",
+            nginx.as_ref().map(|_| ()).map_err(Clone::clone),
+        )?;
+        check(
+            &format!("entity-bytes={} of {}", RANGE, fixture.display()),
+            check_response(port, &want),
+        )
+    });
+    if res.is_err() {
+        if let Ok(log) = fs::read_to_string(prefix.join("logs/error.log")) {
+            eprintln!("car-range doctor: error log:\n{}", log);
+        }
+    }
+    fs::remove_dir_all(&prefix).ok();
+
+    Ok(if res.is_ok() { 0 } else { 1 })
+}
@@ -0,0 +1,128 @@
+use crate::bindings::*;
+use crate::log::request_tag;
+use crate::module::{ngx_car_range_module, FilterContext};
+use crate::pool::Allocator;
+use crate::request::*;
+use std::cell::RefCell;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+type Entry = (*mut ngx_http_request_t, *mut c_void);
+
+thread_local! {
+    // requests being filtered by the worker along with their filter context
+    static REQUESTS: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+}
+
+// Removes the request from the list when its pool is destroyed.
+struct Tracked(*mut ngx_http_request_t);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        REQUESTS.with(|requests| requests.borrow_mut().retain(|&(r, _)| r != self.0));
+    }
+}
+
+/// Keep track of a filtered request and its context until it is freed, replacing the
+/// context it had before an internal redirect. Returns false if the pool is out of memory.
+pub fn track(req: &Request, ctx: *mut c_void) -> bool {
+    let r = &req.0 as *const _ as *mut ngx_http_request_t;
+    let replaced = REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        match requests.iter_mut().find(|(tracked, _)| *tracked == r) {
+            Some(entry) => {
+                entry.1 = ctx;
+                true
+            }
+            None => false,
+        }
+    });
+    if replaced {
+        return true;
+    }
+    if req.pool().allocate(Tracked(r)).is_null() {
+        return false;
+    }
+    REQUESTS.with(|requests| requests.borrow_mut().push((r, ctx)));
+    true
+}
+
+/// The context the filter installed on the request, even if an internal redirect
+/// cleared it from the request since, null if the request isn't filtered.
+pub fn context(req: &Request) -> *mut c_void {
+    let r = &req.0 as *const _ as *mut ngx_http_request_t;
+    REQUESTS.with(|requests| {
+        requests
+            .borrow()
+            .iter()
+            .find(|(tracked, _)| *tracked == r)
+            .map_or(ptr::null_mut(), |&(_, ctx)| ctx)
+    })
+}
+
+unsafe fn describe(r: *mut ngx_http_request_t) -> String {
+    let req = Request::from_ngx_http_request(r);
+    let tp = *ptr::addr_of!(ngx_cached_time);
+    let elapsed =
+        ((*tp).sec - req.0.start_sec) as i64 * 1000 + ((*tp).msec as i64 - req.0.start_msec as i64);
+
+    let mut line = format!(
+        "{} {}?{} {}ms",
+        request_tag(req),
+        req.0.uri.to_str().unwrap_or("-"),
+        req.0.args.to_str().unwrap_or("-"),
+        elapsed
+    );
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    if ctx.is_null() {
+        line.push_str(", finalized by the filter\n");
+        return line;
+    }
+    line.push_str(&format!(
+        ", {}, {} busy buffers, done {}\n",
+        (*ctx).describe(),
+        (*ctx).busy_bufs(),
+        (*ctx).done()
+    ));
+    line
+}
+
+/// `car_range_inflight` directive, installs the handler listing the requests the worker
+/// is filtering in the location.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_car_range_inflight_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    _conf: *mut c_void,
+) -> *mut c_char {
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let clcf =
+        *(*ctx).loc_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_loc_conf_t;
+    (*clcf).handler = Some(ngx_car_range_inflight_handler);
+    ptr::null_mut()
+}
+
+// Dump the state of the filter for each request in flight, one per line.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_inflight_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    if req.method() & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+        return NGX_HTTP_NOT_ALLOWED as ngx_int_t;
+    }
+
+    let rc = req.discard_request_body();
+    if rc != NGX_OK as ngx_int_t {
+        return rc;
+    }
+
+    let requests = REQUESTS.with(|requests| requests.borrow().clone());
+    let body: String = requests.into_iter().map(|(r, _)| describe(r)).collect();
+
+    req.send_response(
+        NGX_HTTP_OK as ngx_uint_t,
+        ngx_string!("text/plain"),
+        body.as_bytes(),
+    )
+}
@@ -1,33 +1,74 @@
-mod bindings;
-mod car_reader;
+// Without the `nginx` feature only the buffer filter around the parser is built, which the
+// module uses
+#![cfg_attr(not(feature = "nginx"), allow(dead_code))]
+
+pub mod bindings;
+pub mod car_reader;
+#[cfg(feature = "nginx")]
+mod collapse;
+#[cfg(feature = "nginx")]
+mod config;
+#[cfg(test)]
+mod conformance;
+#[cfg(feature = "nginx")]
+mod inflight;
+#[cfg(feature = "nginx")]
 mod log;
+#[cfg(feature = "nginx")]
 pub mod module;
-mod pool;
+#[cfg(feature = "nginx")]
+mod origin;
+pub mod pool;
+#[cfg(feature = "nginx")]
+mod raw;
+#[cfg(feature = "nginx")]
 mod request;
-mod varint;
+pub mod scratch;
+#[cfg(feature = "nginx")]
+mod serve;
+#[cfg(test)]
+mod snapshots;
+#[cfg(feature = "nginx")]
+mod stats;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "nginx")]
+mod timer;
+// the module records traces, the car-range binary replays them
+pub mod trace;
+#[cfg(feature = "nginx")]
+mod variables;
 
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 use crate::bindings::*;
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 use crate::module::ngx_car_range_module;
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 use std::os::raw::c_char;
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 use std::ptr;
 
 /// Define modules exported by this library.
 ///
 /// These are normally generated by the Nginx module system, but need to be
-/// defined when building modules outside of it.
+/// defined when building modules outside of it. With the `addon` feature the library
+/// is linked into a module the nginx build generates them for.
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 #[no_mangle]
 pub static mut ngx_modules: [*const ngx_module_t; 2] = [
     unsafe { &ngx_car_range_module as *const ngx_module_t },
     ptr::null(),
 ];
 
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 #[no_mangle]
 pub static mut ngx_module_names: [*const c_char; 2] =
     ["car_range\0".as_ptr() as *const c_char, ptr::null()];
 
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 #[no_mangle]
 pub static mut ngx_module_order: [*const c_char; 32] = [
-    "ngx_http_brotli_filter_nodule\0".as_ptr() as *const c_char,
+    "ngx_http_brotli_filter_module\0".as_ptr() as *const c_char,
     "ngx_http_brotli_static_module\0".as_ptr() as *const c_char,
     "ngx_http_static_module\0".as_ptr() as *const c_char,
     "ngx_http_gzip_static_module\0".as_ptr() as *const c_char,
@@ -61,6 +102,7 @@ pub static mut ngx_module_order: [*const c_char; 32] = [
     ptr::null(),
 ];
 
+#[cfg(all(feature = "nginx", not(feature = "addon")))]
 #[no_mangle]
 pub static mut ngx_module_type: [*const c_char; 2] =
     ["HTTP_AUX_FILTER\0".as_ptr() as *const c_char, ptr::null()];
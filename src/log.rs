@@ -1,14 +1,216 @@
+use crate::bindings::*;
+use crate::request::*;
+
+// index of the $request_id variable, indexing it makes nginx keep a single value per request
+static mut REQUEST_ID_INDEX: ngx_int_t = NGX_ERROR as ngx_int_t;
+
+/// Index `$request_id` so log lines can be correlated, called from `postconfiguration`.
+/// Without it (nginx before 1.11.0) lines carry the connection number and request serial.
+pub unsafe fn index_request_id(cf: *mut ngx_conf_t) {
+    let mut name = ngx_string!("request_id");
+    REQUEST_ID_INDEX = ngx_http_get_variable_index(cf, &mut name);
+}
+
+/// Identifies the request in the log lines of the module, which nginx doesn't do for debug
+/// lines nor for lines written by different workers.
+pub fn request_tag(req: &Request) -> String {
+    let index = unsafe { REQUEST_ID_INDEX };
+    if index != NGX_ERROR as ngx_int_t {
+        if let Some(id) = req.indexed_variable(index as ngx_uint_t) {
+            return format!("request_id: {}", String::from_utf8_lossy(id));
+        }
+    }
+    let c = req.connection();
+    unsafe { format!("request: {}.{}", (*c).number, (*c).requests) }
+}
+
+/// What the filter did for a request, logged once it is complete and exposed to
+/// `log_format` as `$car_range_log_json` for billing and metrics pipelines.
+pub struct Summary<'a> {
+    pub root: Option<&'a str>,
+    /// The requested `entity-bytes`, formatted like in the query string.
+    pub start: String,
+    pub end: String,
+    pub scope: String,
+    /// How the filtering ended, `complete`, `early`, `truncated` and so on.
+    pub outcome: &'static str,
+    pub bytes_in: usize,
+    /// Bytes delivered to the client after filtering.
+    pub bytes_out: usize,
+    pub blocks_out: usize,
+}
+
+// a JSON string, escaping what the path of a request could hold
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Summary<'_> {
+    /// The summary as a JSON object on one line.
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"root\":{},\"entity_bytes\":{},\"dag_scope\":{},\"outcome\":\"{}\",\
+             \"bytes_in\":{},\"bytes_out\":{},\"blocks_out\":{}}}",
+            self.root.map_or("null".to_string(), json_string),
+            json_string(&format!("{}:{}", self.start, self.end)),
+            json_string(&self.scope),
+            self.outcome,
+            self.bytes_in,
+            self.bytes_out,
+            self.blocks_out
+        )
+    }
+}
+
+// the Accept header is matched exactly so the CAR order and duplicates are the defaults of
+// the trustless gateway spec
+impl std::fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "root {}, entity-bytes {}:{}, dag-scope {}, order unk, dups y, {}, \
+             {} bytes in, {} bytes out, {} blocks",
+            self.root.unwrap_or("-"),
+            self.start,
+            self.end,
+            self.scope,
+            self.outcome,
+            self.bytes_in,
+            self.bytes_out,
+            self.blocks_out
+        )
+    }
+}
+
+/// Whether the log has the [`NGX_LOG_DEBUG_HTTP`] bit of its debug mask set, either from
+/// `error_log ... debug` or a matching `debug_connection`.
+///
+/// [`NGX_LOG_DEBUG_HTTP`]: https://nginx.org/en/docs/dev/development_guide.html#logging
+pub fn debug_http_enabled(log: *const ngx_log_t) -> bool {
+    !log.is_null() && unsafe { (*log).log_level } & NGX_LOG_DEBUG_HTTP as ngx_uint_t != 0
+}
+
+/// Like the `ngx_log_debug` C macros the message is only formatted when the connection
+/// log has the [`NGX_LOG_DEBUG_HTTP`] bit of its debug mask set. The [`request_tag`] is
+/// appended to the message.
+///
 /// [`NGX_LOG_DEBUG_HTTP`]: https://nginx.org/en/docs/dev/development_guide.html#logging
 macro_rules! ngx_log_debug_http {
     ( $request:expr, $($arg:tt)* ) => {
         let log = unsafe { (*$request.connection()).log };
-        let level = NGX_LOG_DEBUG as ngx_uint_t;
-        let fmt = std::ffi::CString::new("%s").unwrap();
-        let c_message = std::ffi::CString::new(format!($($arg)*)).unwrap();
-        unsafe {
-            ngx_log_error_core(level, log, 0, fmt.as_ptr(), c_message.as_ptr());
+        if $crate::log::debug_http_enabled(log) {
+            let message = format!(
+                "{}, {}",
+                format_args!($($arg)*),
+                $crate::log::request_tag(&$request)
+            );
+            unsafe {
+                ngx_log_error_core(
+                    NGX_LOG_DEBUG as ngx_uint_t,
+                    log,
+                    0,
+                    "%*s\0".as_ptr() as *const std::os::raw::c_char,
+                    message.len(),
+                    message.as_ptr(),
+                );
+            }
+        }
+    }
+}
+
+/// Writes to the log at `level` (`NGX_LOG_ERR`, `NGX_LOG_WARN`, ...) if the log is enabled
+/// for it, like the `ngx_log_error` C macro.
+///
+/// [`ngx_log_error`]: https://nginx.org/en/docs/dev/development_guide.html#logging
+macro_rules! ngx_log_error {
+    ( $level:expr, $log:expr, $($arg:tt)* ) => {
+        let log: *mut ngx_log_t = $log;
+        let level = $level as ngx_uint_t;
+        if !log.is_null() && unsafe { (*log).log_level } >= level {
+            let message = format!($($arg)*);
+            unsafe {
+                ngx_log_error_core(
+                    level,
+                    log,
+                    0,
+                    "%*s\0".as_ptr() as *const std::os::raw::c_char,
+                    message.len(),
+                    message.as_ptr(),
+                );
+            }
         }
     }
 }
 
+/// Writes to the request connection log at `level`, followed by the [`request_tag`].
+macro_rules! ngx_log_error_http {
+    ( $level:expr, $request:expr, $($arg:tt)* ) => {
+        let log = unsafe { (*$request.connection()).log };
+        $crate::log::ngx_log_error!(
+            $level,
+            log,
+            "{}, {}",
+            format_args!($($arg)*),
+            $crate::log::request_tag(&$request)
+        );
+    }
+}
+
 pub(crate) use ngx_log_debug_http;
+pub(crate) use ngx_log_error;
+pub(crate) use ngx_log_error_http;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(root: Option<&str>) -> Summary<'_> {
+        Summary {
+            root,
+            start: "0".to_string(),
+            end: "*".to_string(),
+            scope: "entity".to_string(),
+            outcome: "early",
+            bytes_in: 4096,
+            bytes_out: 1024,
+            blocks_out: 3,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let cid = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        assert_eq!(
+            summary(Some(cid)).json(),
+            format!(
+                "{{\"root\":\"{}\",\"entity_bytes\":\"0:*\",\"dag_scope\":\"entity\",\
+                 \"outcome\":\"early\",\"bytes_in\":4096,\"bytes_out\":1024,\"blocks_out\":3}}",
+                cid
+            )
+        );
+        assert_eq!(
+            summary(None).to_string(),
+            "root -, entity-bytes 0:*, dag-scope entity, order unk, dups y, early, \
+             4096 bytes in, 1024 bytes out, 3 blocks"
+        );
+    }
+
+    // whatever the client puts in the path stays a single JSON string
+    #[test]
+    fn test_summary_escapes() {
+        let json = summary(Some("a\"b\\c\n")).json();
+        assert!(json.starts_with(r#"{"root":"a\"b\\c\u000a","#), "{}", json);
+        assert_eq!(json.lines().count(), 1);
+    }
+}
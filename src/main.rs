@@ -0,0 +1,334 @@
+//! `car-range` filters a CAR file with the parser of the nginx module, to reproduce the
+//! filtering of a captured upstream response offline:
+//!
+//! ```text
+//! car-range --range 2000:4000 --scope entity in.car > out.car
+//! ```
+//!
+//! or to filter again the chains of a response recorded with `car_range_trace`:
+//!
+//! ```text
+//! car-range replay 12-1.trace [in.car] > out.car
+//! ```
+//!
+//! or to check that a build of the module loads and filters, see [`doctor`]:
+//!
+//! ```text
+//! car-range doctor --module target/release/libnginx_car_range.so
+//! ```
+
+mod doctor;
+
+use car_range_core::range;
+use nginx_car_range::bindings::*;
+use nginx_car_range::car_reader::CarBufferContext;
+use nginx_car_range::pool::Allocator;
+use nginx_car_range::scratch;
+use nginx_car_range::trace::{Replayer, Trace};
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::os::raw::c_void;
+use std::process::exit;
+use std::{mem, ptr};
+
+// the default size of the nginx proxy buffers
+const DEFAULT_CHUNK: usize = 64 << 10;
+
+const USAGE: &str = "usage: car-range --range <from>:<to> [--scope all|entity|block] \
+                     [--chunk <bytes>] [in.car]\n       car-range replay <trace> [in.car]\n       \
+                     car-range doctor [--module <path>] [--nginx <path>] [--fixture <car>]";
+
+// Hands out heap allocated memory, buffers and chain links. They live until the process exits,
+// which is a couple for every chunk read.
+struct HeapPool;
+
+impl HeapPool {
+    fn leak(size: usize, align: usize) -> *mut c_void {
+        let layout = Layout::from_size_align(size.max(1), align).unwrap();
+        unsafe { alloc::alloc(layout) as *mut c_void }
+    }
+}
+
+impl Allocator for HeapPool {
+    fn alloc(&mut self, size: usize) -> *mut c_void {
+        Self::leak(size, mem::align_of::<usize>())
+    }
+
+    fn alloc_unaligned(&mut self, size: usize) -> *mut c_void {
+        Self::leak(size, 1)
+    }
+
+    fn alloc_aligned(&mut self, size: usize, alignment: usize) -> *mut c_void {
+        Self::leak(size, alignment)
+    }
+
+    // nothing is freed, so nothing is dropped either
+    unsafe fn add_cleanup_for_value<T>(&mut self, _value: *mut T) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+type RangeContext<'a> =
+    CarBufferContext<'a, (std::ops::Bound<u64>, std::ops::Bound<u64>), HeapPool>;
+
+struct Args {
+    range: (std::ops::Bound<u64>, std::ops::Bound<u64>),
+    from_end: range::FromEnd,
+    scope: String,
+    chunk: usize,
+    input: Option<String>,
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("car-range: {}\n{}", error, USAGE);
+    exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut range = None;
+    let mut from_end = range::FromEnd::default();
+    let mut scope = "all".to_string();
+    let mut chunk = DEFAULT_CHUNK;
+    let mut input = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--range" => {
+                let val = args.next().unwrap_or_else(|| usage("missing range"));
+                range =
+                    Some(range::parse_entity_bytes(&val).unwrap_or_else(|| usage("invalid range")));
+                from_end = range::parse_from_end(&val);
+            }
+            "--scope" => {
+                scope = args.next().unwrap_or_else(|| usage("missing scope"));
+                if !matches!(scope.as_str(), "all" | "entity" | "block") {
+                    usage("invalid scope");
+                }
+            }
+            "--chunk" => {
+                chunk = args
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .filter(|&chunk| chunk > 0)
+                    .unwrap_or_else(|| usage("invalid chunk size"));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg),
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    Args {
+        range: range.unwrap_or_else(|| usage("missing range")),
+        from_end,
+        scope,
+        chunk,
+        input,
+    }
+}
+
+struct ReplayArgs {
+    trace: String,
+    input: Option<String>,
+}
+
+fn parse_replay_args() -> ReplayArgs {
+    let mut args = std::env::args().skip(2);
+    let trace = match args.next() {
+        Some(arg) if !arg.starts_with("--") => arg,
+        _ => usage("missing trace"),
+    };
+    let input = args.next();
+    if let Some(arg) = args.next() {
+        usage(&format!("unexpected argument {}", arg));
+    }
+    ReplayArgs { trace, input }
+}
+
+// an upstream buffer holding `data`
+fn memory_buf(data: &mut [u8], last: bool) -> ngx_buf_t {
+    let mut b: ngx_buf_t = unsafe { mem::zeroed() };
+    let range = data.as_mut_ptr_range();
+    b.start = range.start;
+    b.pos = range.start;
+    b.last = range.end;
+    b.end = range.end;
+    b.set_memory(1);
+    b.set_last_buf(last as u32);
+    b
+}
+
+// write the forwarded chain and mark its buffers sent, like the write filter
+fn send(out: *mut ngx_chain_t, w: &mut impl Write) -> io::Result<()> {
+    let mut cl = out;
+    while !cl.is_null() {
+        unsafe {
+            let b = (*cl).buf;
+            let len = (*b).last.offset_from((*b).pos) as usize;
+            if len > 0 {
+                w.write_all(std::slice::from_raw_parts((*b).pos, len))?;
+            }
+            (*b).pos = (*b).last;
+            cl = (*cl).next;
+        }
+    }
+    Ok(())
+}
+
+fn open_input(input: Option<&str>) -> io::Result<Box<dyn Read>> {
+    Ok(match input {
+        None | Some("-") => Box::new(io::stdin().lock()),
+        Some(path) => Box::new(File::open(path)?),
+    })
+}
+
+// filter the chain `cl` and write what is forwarded, false once the filter stopped
+fn filter(
+    ctx: &mut RangeContext,
+    cl: *mut ngx_chain_t,
+    output: &mut impl Write,
+) -> io::Result<bool> {
+    let out = match ctx.buffer(cl) {
+        Ok(out) => out,
+        Err(e) => {
+            eprintln!("car-range: malformed CAR: {}", e);
+            return Ok(false);
+        }
+    };
+    if ctx.alloc_failures() > 0 {
+        eprintln!("car-range: failed to allocate buffers");
+        return Ok(false);
+    }
+    send(out, output)?;
+    ctx.update_chains(out);
+    Ok(true)
+}
+
+// print how the filtering ended, and the exit status for it
+fn report(ctx: &RangeContext) -> i32 {
+    let outcome = if ctx.error().is_some() {
+        "malformed"
+    } else if ctx.truncated() {
+        "truncated"
+    } else if ctx.empty() {
+        "empty"
+    } else if ctx.terminated_early() {
+        "early"
+    } else if ctx.done() {
+        "complete"
+    } else {
+        "incomplete"
+    };
+    eprintln!(
+        "car-range: {}, {} bytes in, {} bytes out, {} blocks out, {}",
+        outcome,
+        ctx.bytes_in(),
+        ctx.bytes_out(),
+        ctx.blocks_out(),
+        ctx.describe()
+    );
+
+    match outcome {
+        "malformed" | "truncated" | "empty" => 1,
+        _ => 0,
+    }
+}
+
+// filter `input` read in buffers of `chunk` bytes, like an upstream response
+fn filter_stream(
+    ctx: &mut RangeContext,
+    input: &mut dyn Read,
+    chunk: usize,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let mut chunk = vec![0u8; chunk];
+    while !ctx.done() {
+        let n = input.read(&mut chunk)?;
+        // the end of the file is sent as an empty last buffer, like an upstream would
+        let mut buf = memory_buf(&mut chunk[..n], n == 0);
+        let cl = ngx_chain_t {
+            buf: &mut buf,
+            next: ptr::null_mut(),
+        };
+        if !filter(ctx, &cl as *const _ as *mut _, output)? {
+            break;
+        }
+    }
+    output.flush()
+}
+
+fn run(args: Args) -> io::Result<i32> {
+    let mut input = open_input(args.input.as_deref())?;
+    let mut output = io::BufWriter::new(io::stdout().lock());
+
+    scratch::init();
+    let mut ctx = CarBufferContext::new(args.range, HeapPool);
+    ctx.set_from_end(args.from_end);
+    ctx.set_verify_root(matches!(args.scope.as_str(), "entity" | "block"));
+    ctx.set_block_scope(args.scope == "block");
+    filter_stream(&mut ctx, &mut input, args.chunk, &mut output)?;
+
+    Ok(report(&ctx))
+}
+
+// filter the chains of a trace as they were passed to the module
+fn replay(args: ReplayArgs) -> io::Result<i32> {
+    let trace = Trace::read(BufReader::new(File::open(&args.trace)?))?;
+    let range = range::parse_entity_bytes(&trace.range).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid range {}", trace.range),
+        )
+    })?;
+    // the bytes of the buffers are read from the CAR unless the trace has them
+    let mut input: Box<dyn Read> = if trace.bytes {
+        Box::new(io::empty())
+    } else {
+        open_input(args.input.as_deref())?
+    };
+    let mut output = io::BufWriter::new(io::stdout().lock());
+
+    scratch::init();
+    let mut ctx = CarBufferContext::new(range, HeapPool);
+    ctx.set_from_end(range::parse_from_end(&trace.range));
+    ctx.set_verify_root(matches!(trace.scope.as_str(), "entity" | "block"));
+    ctx.set_block_scope(trace.scope == "block");
+
+    let mut replayer = Replayer::new();
+    for (i, bufs) in trace.chains.iter().enumerate() {
+        let cl = replayer
+            .chain(bufs, &mut input)
+            .map_err(|e| io::Error::new(e.kind(), format!("chain {}: {}", i, e)))?;
+        if !filter(&mut ctx, cl, &mut output)? {
+            eprintln!(
+                "car-range: stopped at chain {} of {}",
+                i,
+                trace.chains.len()
+            );
+            break;
+        }
+    }
+    output.flush()?;
+
+    Ok(report(&ctx))
+}
+
+fn main() {
+    let res = match std::env::args().nth(1).as_deref() {
+        Some("replay") => replay(parse_replay_args()),
+        Some("doctor") => doctor::run(doctor::parse_args()),
+        _ => run(parse_args()),
+    };
+    match res {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("car-range: {}", e);
+            exit(1);
+        }
+    }
+}
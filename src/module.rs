@@ -1,24 +1,88 @@
 use crate::bindings::*;
 use crate::car_reader::CarBufferContext;
-use crate::log::ngx_log_debug_http;
+use crate::collapse::{self, ngx_car_range_collapse_handler};
+use crate::config::{
+    conf_args, parse_flag, parse_range_sources, parse_status, Applied, LocConf, RangeSource,
+    UpstreamAbort, UpstreamApplied,
+};
+use crate::inflight::{self, ngx_car_range_inflight_cfg};
+use crate::log::{self, debug_http_enabled, ngx_log_debug_http, ngx_log_error_http, Summary};
+use crate::origin::ngx_car_range_origin_cfg;
 use crate::pool::{Allocator, Buffer, MemoryBuffer, Pool};
+use crate::raw::{self, sha256_cid, RawBlock};
 use crate::request::*;
-use std::ops::Bound;
+use crate::scratch;
+use crate::serve::ngx_car_range_serve_cfg;
+use crate::stats::{self, ngx_car_range_status_cfg, Stats};
+use crate::timer;
+use crate::trace::Recorder;
+use crate::variables::ngx_car_range_add_variables;
+use car_range_core::range::{format_bound, format_entity_bytes, FromEnd};
+use cid::Cid;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::{Bound, Deref, DerefMut};
 use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 
-/// Static string initializer for [`ngx_str_t`].
-///
-/// The resulting byte string is always nul-terminated (just like a C string).
+// marks responses filtered by the module, so instances in front of it don't filter them again
+const FILTERED_HEADER: &str = "X-Car-Range";
+
+// the range an upstream applied to the CAR itself, with car_range_upstream_applied
+const APPLIED_HEADER: &str = "X-Entity-Bytes-Applied";
+
+// consecutive body filter calls without progress before we report a stall
+const STALL_CALLS: usize = 32;
+
+pub type RangeContext<'a> = CarBufferContext<'a, (Bound<u64>, Bound<u64>), Pool>;
+
+/// Filter context installed on the request by the header filter.
 ///
-/// [`ngx_str_t`]: https://nginx.org/en/docs/dev/development_guide.html#string_overview
-macro_rules! ngx_string {
-    ($s:expr) => {{
-        ngx_str_t {
-            len: $s.len(),
-            data: concat!($s, "\0").as_ptr() as *mut u8,
-        }
-    }};
+/// Dropped by the cleanup of the request pool, once, whether the response completed or
+/// the client went away, which frees the parser buffers and cancels the stall timer.
+pub struct FilterContext<'a> {
+    cbc: RangeContext<'a>,
+    // fires when the upstream stops sending for car_range_stall_timeout
+    stall_timer: ngx_event_t,
+    // records the chains passed to the body filter with car_range_trace
+    trace: Option<Recorder<BufWriter<File>>>,
+    // end of the byte range the Content-Range header promised
+    range_end: Option<usize>,
+    // the block of a raw request, which replaces the upstream CAR with car_range_raw
+    raw: Option<RawBlock>,
+    // hashes the bytes sent with car_range_digest
+    hasher: Option<Sha256>,
+    // CID of the filtered CAR, once all of it was sent
+    digest: Option<Cid>,
+}
+
+impl<'a> FilterContext<'a> {
+    /// CID of the filtered CAR as a raw block, known at the end of the response.
+    pub fn digest(&self) -> Option<&Cid> {
+        self.digest.as_ref()
+    }
+}
+
+impl<'a> Deref for FilterContext<'a> {
+    type Target = RangeContext<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cbc
+    }
+}
+
+impl<'a> DerefMut for FilterContext<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.cbc
+    }
+}
+
+impl<'a> Drop for FilterContext<'a> {
+    fn drop(&mut self) {
+        unsafe { timer::del_timer(&mut self.stall_timer) };
+    }
 }
 
 #[no_mangle]
@@ -28,15 +92,210 @@ pub static mut ngx_http_next_body_filter: ngx_http_output_body_filter_pt = None;
 pub static mut ngx_http_next_header_filter: ngx_http_output_header_filter_pt = None;
 
 #[no_mangle]
-static mut ngx_car_range_commands: [ngx_command_t; 2] = [
+static mut ngx_car_range_commands: [ngx_command_t; 23] = [
     ngx_command_t {
         name: ngx_string!("car_range"), /* directive */
         type_: (NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS) as ngx_uint_t, /* location context and takes no arguments*/
         set: Some(ngx_car_range_cfg), /* configuration setup function */
-        conf: 0,                      /* No offset. Only one context is supported. */
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
         offset: 0, /* No offset when storing the module configuration on struct. */
         post: ptr::null_mut(),
     },
+    ngx_command_t {
+        name: ngx_string!("car_range_auto"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_auto_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_flush"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_flush_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_debug_cids"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_debug_cids_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_error_status"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_error_status_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_metrics_label"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_metrics_label_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_status"),
+        type_: (NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_status_cfg),
+        conf: 0,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_stall_timeout"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_stall_timeout_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_max_header_size"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_max_header_size_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_max_carv2_size"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_max_carv2_size_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_trace"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE12)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_trace_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_cache_full"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_cache_full_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_upstream_abort"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE12)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_upstream_abort_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_upstream_applied"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_upstream_applied_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_range_sources"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE12)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_range_sources_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_ignore_args"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_ignore_args_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_byte_ranges"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_byte_ranges_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_raw"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_raw_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_digest"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_digest_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_collapse"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_car_range_collapse_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_serve"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_car_range_serve_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_origin"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_car_range_origin_cfg),
+        conf: NGX_RS_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("car_range_inflight"),
+        type_: (NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS) as ngx_uint_t,
+        set: Some(ngx_car_range_inflight_cfg),
+        conf: 0,
+        offset: 0,
+        post: ptr::null_mut(),
+    },
     /* command termination */
     ngx_command_t {
         name: ngx_str_t {
@@ -53,7 +312,7 @@ static mut ngx_car_range_commands: [ngx_command_t; 2] = [
 
 #[no_mangle]
 static ngx_car_range_module_ctx: ngx_http_module_t = ngx_http_module_t {
-    preconfiguration: None,
+    preconfiguration: Some(ngx_car_range_preconfiguration),
     postconfiguration: Some(ngx_car_range_filter_init),
 
     create_main_conf: None,
@@ -62,8 +321,8 @@ static ngx_car_range_module_ctx: ngx_http_module_t = ngx_http_module_t {
     create_srv_conf: None,
     merge_srv_conf: None,
 
-    create_loc_conf: None,
-    merge_loc_conf: None,
+    create_loc_conf: Some(ngx_car_range_create_loc_conf),
+    merge_loc_conf: Some(ngx_car_range_merge_loc_conf),
 };
 
 #[no_mangle]
@@ -73,6 +332,8 @@ pub static mut ngx_car_range_module: ngx_module_t = ngx_module_t {
     name: ptr::null_mut(),
     spare0: 0,
     spare1: 0,
+    // nginx refuses to load the module into another version than the one of the headers,
+    // or into one configured with incompatible struct layouts
     version: nginx_version as ngx_uint_t,
     signature: NGX_RS_MODULE_SIGNATURE.as_ptr() as *const c_char,
 
@@ -82,7 +343,7 @@ pub static mut ngx_car_range_module: ngx_module_t = ngx_module_t {
 
     init_master: None,
     init_module: None,
-    init_process: None,
+    init_process: Some(ngx_car_range_init_process),
     init_thread: None,
     exit_thread: None,
     exit_process: None,
@@ -102,13 +363,623 @@ pub static mut ngx_car_range_module: ngx_module_t = ngx_module_t {
 unsafe extern "C" fn ngx_car_range_cfg(
     _cf: *mut ngx_conf_t,
     _cmd: *mut ngx_command_t,
-    _conf: *mut c_void,
+    conf: *mut c_void,
 ) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    conf.enabled = Some(true);
     ptr::null_mut()
 }
 
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_auto_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(auto) => conf.auto = Some(auto),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_flush_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(flush) => conf.flush = Some(flush),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_debug_cids_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(debug) => conf.debug_cids = Some(debug),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_error_status_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_status(&conf_args(cf)[1]) {
+        Some(status) => conf.error_status = Some(status),
+        None => return "must be a status between 400 and 599\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_metrics_label_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let label = match conf_args(cf)[1].to_str() {
+        Ok(label) => label,
+        Err(_) => return "invalid value\0".as_ptr() as *mut c_char,
+    };
+    match stats::add_label(label) {
+        Ok(label) => conf.metrics_label = Some(label),
+        Err(e) => return e.as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_stall_timeout_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let timeout = ngx_parse_time(&conf_args(cf)[1] as *const _ as *mut ngx_str_t, 0);
+    if timeout == NGX_ERROR as ngx_int_t {
+        return "invalid value\0".as_ptr() as *mut c_char;
+    }
+    conf.stall_timeout = Some(timeout as ngx_msec_t);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_max_header_size_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let size = ngx_parse_size(&conf_args(cf)[1] as *const _ as *mut ngx_str_t);
+    if size <= 0 {
+        return "invalid value\0".as_ptr() as *mut c_char;
+    }
+    conf.max_header_size = Some(size as usize);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_max_carv2_size_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let size = ngx_parse_size(&conf_args(cf)[1] as *const _ as *mut ngx_str_t);
+    if size <= 0 {
+        return "invalid value\0".as_ptr() as *mut c_char;
+    }
+    conf.max_carv2_size = Some(size as usize);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_trace_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let args = conf_args(cf);
+    let dir = match args[1].to_str() {
+        Ok("off") => "",
+        Ok(dir) => dir,
+        Err(_) => return "invalid value\0".as_ptr() as *mut c_char,
+    };
+    let bytes = match args.get(2).map(|arg| arg.to_str()) {
+        None => false,
+        Some(Ok("bytes")) if !dir.is_empty() => true,
+        Some(_) => return "invalid value\0".as_ptr() as *mut c_char,
+    };
+    conf.trace_dir = Some(dir.to_string());
+    conf.trace_bytes = Some(bytes);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_cache_full_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(full) => conf.cache_full = Some(full),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_upstream_abort_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let args = conf_args(cf);
+    let abort = match (args[1].to_str(), args.get(2)) {
+        (Ok("close"), None) => UpstreamAbort::Close,
+        (Ok("drain"), None) => UpstreamAbort::Drain(None),
+        (Ok("drain"), Some(max)) => {
+            let max = ngx_parse_size(max as *const _ as *mut ngx_str_t);
+            if max < 0 {
+                return "invalid value\0".as_ptr() as *mut c_char;
+            }
+            UpstreamAbort::Drain(Some(max as usize))
+        }
+        _ => return "must be \"drain [size]\" or \"close\"\0".as_ptr() as *mut c_char,
+    };
+    conf.upstream_abort = Some(abort);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_upstream_applied_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    conf.upstream_applied = match conf_args(cf)[1].to_str() {
+        Ok("off") => Some(UpstreamApplied::Off),
+        Ok("trust") => Some(UpstreamApplied::Trust),
+        Ok("require") => Some(UpstreamApplied::Require),
+        _ => return "must be \"off\", \"trust\" or \"require\"\0".as_ptr() as *mut c_char,
+    };
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_range_sources_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_range_sources(&conf_args(cf)[1..]) {
+        Some(sources) => conf.range_sources = Some(sources),
+        None => {
+            return "must be \"args\" and \"header\", each at most once\0".as_ptr() as *mut c_char
+        }
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_ignore_args_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let mut names = Vec::new();
+    for arg in &conf_args(cf)[1..] {
+        match arg.to_str() {
+            Ok(name) if !name.is_empty() => names.push(name.to_string()),
+            _ => return "invalid value\0".as_ptr() as *mut c_char,
+        }
+    }
+    conf.ignore_args = Some(names);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_byte_ranges_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(on) => conf.byte_ranges = Some(on),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_raw_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(on) => conf.raw = Some(on),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_digest_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    match parse_flag(&conf_args(cf)[1]) {
+        Some(on) => conf.digest = Some(on),
+        None => return "must be \"on\" or \"off\"\0".as_ptr() as *mut c_char,
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_collapse_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    let value = &conf_args(cf)[1];
+    if value.to_str() == Ok("off") {
+        conf.collapse = Some(0);
+        return ptr::null_mut();
+    }
+    let timeout = ngx_parse_time(value as *const _ as *mut ngx_str_t, 0);
+    if timeout == NGX_ERROR as ngx_int_t {
+        return "invalid value\0".as_ptr() as *mut c_char;
+    }
+    conf.collapse = Some(timeout as ngx_msec_t);
+    // the zone is only allocated for the configurations collapsing requests
+    if timeout > 0
+        && collapse::add_zone(cf, ptr::addr_of_mut!(ngx_car_range_module)) != NGX_OK as ngx_int_t
+    {
+        return "failed to add the shared memory zone\0".as_ptr() as *mut c_char;
+    }
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_create_loc_conf(cf: *mut ngx_conf_t) -> *mut c_void {
+    let mut pool = Pool::from_ngx_pool((*cf).pool);
+    pool.allocate(LocConf::default()) as *mut c_void
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_merge_loc_conf(
+    _cf: *mut ngx_conf_t,
+    prev: *mut c_void,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let prev = &*(prev as *const LocConf);
+    let conf = &mut *(conf as *mut LocConf);
+    conf.merge(prev);
+    ptr::null_mut()
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_preconfiguration(cf: *mut ngx_conf_t) -> ngx_int_t {
+    stats::reset_labels();
+    collapse::reset_zone();
+    ngx_car_range_add_variables(cf)
+}
+
+// Counters of the metrics label of the request location.
+fn location_stats(req: &Request) -> Option<&'static Stats> {
+    let conf = unsafe { &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf) };
+    stats::stats(conf.metrics_label())
+}
+
+// Allocate the worker scratch region parsers borrow their stash from.
+#[no_mangle]
+extern "C" fn ngx_car_range_init_process(_cycle: *mut ngx_cycle_t) -> ngx_int_t {
+    scratch::init();
+    NGX_OK as ngx_int_t
+}
+
+// Run a filter or handler, turning a panic into an error for the request rather than
+// aborting the worker along with every other connection it serves.
+pub fn catch_panic<F>(r: *mut ngx_http_request_t, what: &str, f: F) -> ngx_int_t
+where
+    F: FnOnce() -> ngx_int_t,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(rc) => rc,
+        Err(e) => {
+            let msg = match e.downcast_ref::<&str>() {
+                Some(msg) => msg,
+                None => e
+                    .downcast_ref::<String>()
+                    .map_or("unknown", |msg| msg.as_str()),
+            };
+            let req = unsafe { Request::from_ngx_http_request(r) };
+            ngx_log_error_http!(
+                NGX_LOG_ALERT,
+                req,
+                "car_range: panic in the {}: {}",
+                what,
+                msg
+            );
+            // the parser may have been left half way through an update
+            req.set_context(unsafe { &ngx_car_range_module }, ptr::null_mut());
+            NGX_ERROR as ngx_int_t
+        }
+    }
+}
+
 #[no_mangle]
 extern "C" fn ngx_car_range_header_filter(r: *mut ngx_http_request_t) -> ngx_int_t {
+    catch_panic(r, "header filter", || header_filter(r))
+}
+
+#[no_mangle]
+extern "C" fn ngx_car_range_body_filter(
+    r: *mut ngx_http_request_t,
+    body: *mut ngx_chain_t,
+) -> ngx_int_t {
+    catch_panic(r, "body filter", || body_filter(r, body))
+}
+
+// Account for the filtered response once it is complete.
+#[no_mangle]
+extern "C" fn ngx_car_range_log_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    catch_panic(r, "log handler", || log_handler(r))
+}
+
+// End the response when the upstream stopped sending, unless the client is the one not
+// reading the buffers already forwarded.
+#[no_mangle]
+extern "C" fn ngx_car_range_stall_handler(ev: *mut ngx_event_t) {
+    let r = unsafe { (*ev).data as *mut ngx_http_request_t };
+    let c = unsafe { (*r).connection };
+    let rc = catch_panic(r, "stall timer", || unsafe {
+        let req = Request::from_ngx_http_request(r);
+        let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+        if ctx.is_null() || (*ctx).done() {
+            return NGX_OK as ngx_int_t;
+        }
+        if (*ctx).busy_bufs() > 0 {
+            let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+            timer::add_timer(ev, conf.stall_timeout());
+            return NGX_OK as ngx_int_t;
+        }
+
+        (*ctx).stall();
+        // the body filter sends the last buffer
+        ngx_http_output_filter(r, ptr::null_mut())
+    });
+    unsafe {
+        if rc == NGX_ERROR as ngx_int_t {
+            ngx_http_finalize_request(r, NGX_ERROR as ngx_int_t);
+        }
+        ngx_http_run_posted_requests(c);
+    }
+}
+
+/// What the header filter does with a response, see [`decide`].
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Pass the response on untouched, for the reason given.
+    Skip(&'static str),
+    /// Answer a raw request with the block of the CID, with `car_range_raw`.
+    Raw(Cid),
+    /// Pass on a response an upstream instance already filtered.
+    Filtered,
+    /// Replace the response with `car_range_error_status`, the origin applied other
+    /// entity-bytes than the requested ones.
+    Mismatch { applied: String, requested: String },
+    /// Pass on a compressed response, which can't be parsed.
+    Compressed,
+    /// Pass on the 304 of the upstream with the ETag of the filtered CAR.
+    Revalidated,
+    /// Answer with a 304, the client has the filtered CAR.
+    NotModified,
+    /// Filter the CAR.
+    Filter(Filter),
+}
+
+/// How a CAR is filtered, read from the request and the upstream response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub range: (Bound<u64>, Bound<u64>),
+    /// The bounds of `range` counted from the end of the entity.
+    pub from_end: FromEnd,
+    pub scope: DagScope,
+    /// Whether a CARv2 is written from the filtered CAR.
+    pub carv2: bool,
+    /// Whether the response is of an /ipns/ name, which can change.
+    pub mutable: bool,
+    /// Whether the response accepts byte ranges of the filtered CAR.
+    pub byte_ranges: bool,
+    /// The first and last bytes of the filtered CAR the client asked for.
+    pub byte_range: Option<(u64, u64)>,
+    /// Whether only the headers of the filtered CAR are sent, for a HEAD request.
+    pub header_only: bool,
+}
+
+impl Filter {
+    /// The context cutting the range out of an upstream CAR of `upstream_len` bytes.
+    pub fn context<'a, A: Allocator>(
+        &self,
+        conf: &LocConf,
+        upstream_len: Option<usize>,
+        pool: A,
+    ) -> CarBufferContext<'a, (Bound<u64>, Bound<u64>), A> {
+        let block_scope = self.scope == DagScope::Block;
+        let mut cbc = CarBufferContext::new(self.range, pool);
+        cbc.set_from_end(self.from_end);
+        cbc.set_flush(conf.flush());
+        cbc.set_debug_cids(conf.debug_cids());
+        cbc.set_upstream_len(upstream_len);
+        cbc.set_max_header_len(conf.max_header_size());
+        // an entity or block response starts with the block the request resolves to
+        cbc.set_verify_root(self.scope != DagScope::All);
+        cbc.set_block_scope(block_scope);
+        cbc.set_carv2(self.carv2);
+        cbc.set_max_carv2_len(conf.max_carv2_size());
+        // the header goes out with the first bytes, a range past the end of the file can
+        // only be answered with a 416 while none were sent
+        cbc.set_wait_file_size(!block_scope);
+        if let Some((first, last)) = self.byte_range {
+            cbc.set_window(first as usize..last as usize + 1);
+        }
+        cbc
+    }
+}
+
+/// Decide what the header filter does with the response to `req` in a location
+/// configured with `conf`, without changing either.
+pub fn decide(req: &Request, conf: &LocConf) -> Decision {
+    // error pages are never filtered
+    if req.filter_finalize() {
+        return Decision::Skip("error page");
+    }
+
+    // subrequests share the output of the main request, which is the one filtered
+    if req.is_subrequest() {
+        return Decision::Skip("subrequest");
+    }
+
+    // websockets and event streams of the same location pass untouched, an upgrade has
+    // no body the held back header could go out with
+    if req.is_streaming() {
+        return Decision::Skip("upgrade or event stream");
+    }
+
+    if conf.raw() {
+        if let Some(cid) = req.raw_block() {
+            // the header has to go out right away
+            if req.header_only() {
+                return Decision::Skip("raw block without a body");
+            }
+            return Decision::Raw(cid);
+        }
+    }
+
+    // locations with car_range, and with car_range_auto any location of an /ipfs/ CID
+    if !conf.enabled() && !(conf.auto() && req.has_root_cid()) {
+        return Decision::Skip("not enabled");
+    }
+
+    if !req.accept_car() {
+        return Decision::Skip("not a CAR request");
+    }
+
+    let scope = req.dag_scope();
+    let block_scope = scope == DagScope::Block;
+    // a CARv2 is written from the filtered CAR, even a whole one
+    let carv2 = req.accept_carv2();
+    let (mut range, source) = match req.entity_range(conf.range_sources()) {
+        Some(found) => found,
+        // a block request gets the root block whatever the range
+        None if block_scope || carv2 => ((Bound::Unbounded, Bound::Unbounded), RangeSource::Args),
+        // the whole CAR streams through without a context, nothing of it is parsed
+        None if scope == DagScope::All => return Decision::Skip("no range"),
+        // the whole entity, which the upstream sent
+        None => return Decision::Skip("whole entity"),
+    };
+    // negative bounds are resolved once the size of the entity is read
+    let mut from_end = req.range_from_end(source);
+
+    // trimming a response already trimmed by an upstream instance would cut the window twice
+    if req.has_header_out(FILTERED_HEADER) {
+        return Decision::Filtered;
+    }
+
+    // trimming a response the origin already trimmed would cut the window twice too, a
+    // block response is cut to its root block whatever the origin did
+    let applied = req.header_out(APPLIED_HEADER);
+    let outcome = if block_scope {
+        Applied::Filter
+    } else {
+        conf.upstream_applied().check(applied, range, from_end)
+    };
+    match outcome {
+        Applied::Filter => {}
+        // the CAR the origin trimmed still has to be wrapped into a CARv2
+        Applied::PassThrough if carv2 => {
+            range = (Bound::Unbounded, Bound::Unbounded);
+            from_end = FromEnd::default();
+        }
+        Applied::PassThrough => return Decision::Skip("applied upstream"),
+        Applied::Mismatch => {
+            return Decision::Mismatch {
+                applied: applied.unwrap_or("-").to_string(),
+                requested: format_entity_bytes(range, from_end),
+            }
+        }
+    }
+
+    // a compressed body can't be parsed, the gunzip filter runs before this one when
+    // enabled, other encodings have to be turned off upstream
+    if req.compressed() {
+        return Decision::Compressed;
+    }
+
+    if req.status() == NGX_HTTP_NOT_MODIFIED as ngx_uint_t {
+        return Decision::Revalidated;
+    }
+    // the client validates against the ETag of the filtered CAR
+    let etag = req.filtered_etag(&req.cache_key(false, conf.range_sources()));
+    let ok = req.status() == NGX_HTTP_OK as ngx_uint_t;
+    if ok && req.is_not_modified_with(etag.as_deref()) {
+        return Decision::NotModified;
+    }
+
+    // an /ipns/ name resolves to another CAR once it is republished, so neither its
+    // bytes nor the response can be assumed to stay the same
+    let mutable = req.namespace() == Namespace::Ipns;
+    // a byte range of the filtered CAR, for CDNs resuming a download of it. Partial
+    // upstream responses are bytes of the upstream CAR instead, and a Range header the
+    // entity range was read from isn't one of the CAR, nor is a CARv2 of it.
+    let byte_ranges = conf.byte_ranges() && !mutable && !carv2 && source != RangeSource::Header;
+    let byte_range = match req.byte_range_with(etag.as_deref()) {
+        Some(range) if byte_ranges && ok => Some(range),
+        _ => None,
+    };
+
+    Decision::Filter(Filter {
+        range,
+        from_end,
+        scope,
+        carv2,
+        mutable,
+        byte_ranges,
+        byte_range,
+        header_only: req.header_only(),
+    })
+}
+
+fn header_filter(r: *mut ngx_http_request_t) -> ngx_int_t {
     let req = unsafe { &mut Request::from_ngx_http_request(r) };
 
     ngx_log_debug_http!(req, "http car_range header filter {}", env!("GIT_HASH"));
@@ -124,34 +995,278 @@ extern "C" fn ngx_car_range_header_filter(r: *mut ngx_http_request_t) -> ngx_int
         };
     }
 
-    if !req.accept_car() {
-        bail!();
+    let conf = unsafe { &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf) };
+
+    let decision = decide(req, conf);
+    match &decision {
+        Decision::Skip(why) => {
+            ngx_log_debug_http!(req, "car_range header filter: {}: skipping", why);
+            bail!();
+        }
+        Decision::Raw(cid) => return raw_header_filter(req, *cid),
+        Decision::Filtered => {
+            ngx_log_debug_http!(req, "car_range header filter: already filtered: skipping");
+            // with car_range_cache_full every range is served from the entry, which must be
+            // the whole CAR
+            if conf.cache_full() {
+                req.set_uncacheable();
+            }
+            bail!();
+        }
+        Decision::Mismatch { applied, requested } => {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: upstream applied entity-bytes {}, requested {}",
+                applied,
+                requested
+            );
+            return req.filter_finalize_request(&ngx_car_range_module, conf.error_status());
+        }
+        Decision::Compressed => {
+            ngx_log_error_http!(
+                NGX_LOG_WARN,
+                req,
+                "car_range: compressed upstream response: not filtering"
+            );
+            bail!();
+        }
+        Decision::Revalidated | Decision::NotModified | Decision::Filter(_) => {}
     }
 
-    let range = match req.range() {
-        Some(range) => range,
-        None => bail!(),
+    // the validators of the upstream CAR are the ones of the whole CAR, revalidations
+    // and HEAD requests get the ones of the filtered CAR without a body to filter
+    if !req.set_filtered_etag(&req.cache_key(false, conf.range_sources())) {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to set ETag");
+        return NGX_ERROR as ngx_int_t;
+    }
+    let filter = match decision {
+        Decision::Filter(filter) => filter,
+        Decision::NotModified => {
+            ngx_log_debug_http!(req, "car_range header filter: not modified");
+            req.set_not_modified();
+            bail!();
+        }
+        _ => {
+            ngx_log_debug_http!(req, "car_range header filter: no body: skipping");
+            bail!();
+        }
     };
 
-    let ctx = req
-        .pool()
-        .allocate(CarBufferContext::new(range, req.pool())) as *mut c_void;
+    // a HEAD request gets the headers of the filtered CAR without one, the entity range
+    // needs the root of the file, which is in the body
+    if filter.header_only {
+        ngx_log_debug_http!(req, "car_range header filter: no body: headers only");
+        if !set_filtered_headers(req, filter.carv2, filter.byte_ranges, filter.mutable) {
+            return NGX_ERROR as ngx_int_t;
+        }
+        bail!();
+    }
+
+    let cbc = filter.context(conf, req.content_length(), req.pool());
+    let ctx = req.pool().allocate(FilterContext {
+        cbc,
+        stall_timer: unsafe { std::mem::zeroed() },
+        trace: conf.trace_dir().and_then(|dir| {
+            start_trace(req, dir, filter.range, filter.from_end, conf.trace_bytes())
+        }),
+        range_end: None,
+        raw: None,
+        hasher: conf.digest().then(Sha256::new),
+        digest: None,
+    });
+    if ctx.is_null() {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to allocate context");
+        if let Some(stats) = location_stats(req) {
+            Stats::add(&stats.alloc_failures, 1);
+        }
+        // forwarding the whole upstream CAR would ignore the range
+        return NGX_ERROR as ngx_int_t;
+    }
+    // an internal redirect cleared the context of the previous response, which must not
+    // fire its timer on this one
+    let prev = inflight::context(req) as *mut FilterContext;
+    if !prev.is_null() {
+        ngx_log_debug_http!(req, "car_range header filter: redirected: new context");
+        unsafe { timer::del_timer(&mut (*prev).stall_timer) };
+    }
+
     unsafe {
-        req.set_context(&ngx_car_range_module, ctx);
+        req.set_context(&ngx_car_range_module, ctx as *mut c_void);
+        if conf.stall_timeout() > 0 {
+            let ev = ptr::addr_of_mut!((*ctx).stall_timer);
+            (*ev).handler = Some(ngx_car_range_stall_handler);
+            (*ev).data = r as *mut c_void;
+            (*ev).log = (*req.connection()).log;
+            (*ev).set_cancelable(1);
+            timer::add_timer(ev, conf.stall_timeout());
+        }
+    }
+    if !inflight::track(req, ctx as *mut c_void) {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to track request");
+        return NGX_ERROR as ngx_int_t;
+    }
+    if let Some(stats) = location_stats(req) {
+        Stats::add(&stats.requests, 1);
     }
     ngx_log_debug_http!(
         req,
         "car_range header filter set context, range {:?}",
-        range
+        filter.range
+    );
+
+    // X-Stream-Error can only follow the body when the protocol framing allows trailers,
+    // the response to a byte range has a length instead, the connection is closed when
+    // the CAR falls short of it
+    if filter.byte_range.is_none() {
+        req.set_expect_trailers(true);
+    }
+    req.set_filter_need_in_memory();
+    if !set_filtered_headers(req, filter.carv2, filter.byte_ranges, filter.mutable) {
+        return NGX_ERROR as ngx_int_t;
+    }
+
+    // the header is sent by the body filter along with the first forwarded bytes so a
+    // broken upstream CAR can still be replaced with an error through error_page.
+    NGX_OK as ngx_int_t
+}
+
+// The headers of a filtered CAR, the same for GET and HEAD requests. False when one of
+// them can't be set, which is logged.
+fn set_filtered_headers(req: &mut Request, carv2: bool, byte_ranges: bool, mutable: bool) -> bool {
+    // the length is unknown until the range is cut out of the CAR, the one of the
+    // upstream CAR doesn't hold
+    req.set_content_length_missing();
+    // CAR blocks hardly compress, and gzip_types could list CARs for other locations
+    req.disable_gzip();
+    if carv2 {
+        req.set_content_type(ngx_string!("application/vnd.ipld.car; version=2"));
+    }
+    if !req.add_header(ngx_string!("X-Car-Range"), ngx_string!("filtered")) {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add header");
+        return false;
+    }
+    if byte_ranges
+        && !req.has_header_out("Accept-Ranges")
+        && !req.add_header(ngx_string!("Accept-Ranges"), ngx_string!("bytes"))
+    {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add header");
+        return false;
+    }
+    if mutable && !req.strip_immutable() {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to set Cache-Control");
+        return false;
+    }
+    true
+}
+
+// Answer a raw request with the block of the upstream CAR, the body filter sends it once
+// it has all of it.
+fn raw_header_filter(req: &mut Request, cid: Cid) -> ngx_int_t {
+    let r = &mut req.0 as *mut ngx_http_request_t;
+    let car = req.status() == NGX_HTTP_OK as ngx_uint_t
+        && req.content_type() == "application/vnd.ipld.car";
+    // the block is the same whichever CAR it is read from
+    let revalidated = req.status() == NGX_HTTP_NOT_MODIFIED as ngx_uint_t;
+    if (car || revalidated) && !req.set_etag(&format!("\"{}.raw\"", cid)) {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to set ETag");
+        return NGX_ERROR as ngx_int_t;
+    }
+    if car && req.is_not_modified() {
+        ngx_log_debug_http!(req, "car_range header filter: raw: not modified");
+        req.set_not_modified();
+        return unsafe {
+            ngx_http_next_header_filter
+                .map(|cb| cb(r))
+                .unwrap_or(NGX_ERROR as ngx_int_t)
+        };
+    }
+    if !car {
+        ngx_log_debug_http!(
+            req,
+            "car_range header filter: raw: no upstream CAR: skipping"
+        );
+        return unsafe {
+            ngx_http_next_header_filter
+                .map(|cb| cb(r))
+                .unwrap_or(NGX_ERROR as ngx_int_t)
+        };
+    }
+
+    let ctx = req.pool().allocate(FilterContext {
+        cbc: CarBufferContext::new((Bound::Unbounded, Bound::Unbounded), req.pool()),
+        stall_timer: unsafe { std::mem::zeroed() },
+        trace: None,
+        range_end: None,
+        raw: Some(RawBlock::new(cid, req.content_length())),
+        hasher: None,
+        digest: None,
+    });
+    if ctx.is_null() {
+        ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to allocate context");
+        return NGX_ERROR as ngx_int_t;
+    }
+    unsafe { req.set_context(&ngx_car_range_module, ctx as *mut c_void) };
+    ngx_log_debug_http!(
+        req,
+        "car_range header filter set context, raw block {}",
+        cid
     );
 
+    req.set_content_type(ngx_string!("application/vnd.ipld.raw"));
     req.set_content_length_missing();
     req.set_filter_need_in_memory();
+    req.disable_gzip();
+    NGX_OK as ngx_int_t
+}
+
+// Record the chains of the response in a file of `dir` named after the connection and
+// request, a failure to create it only costs the trace.
+fn start_trace(
+    req: &Request,
+    dir: &str,
+    range: (Bound<u64>, Bound<u64>),
+    from_end: FromEnd,
+    bytes: bool,
+) -> Option<Recorder<BufWriter<File>>> {
+    let c = req.connection();
+    let path = unsafe { format!("{}/{}-{}.trace", dir, (*c).number, (*c).requests) };
+    let scope = req.arg("dag-scope").unwrap_or_else(|| "all".to_string());
+    match Recorder::create(&path, range, from_end, &scope, bytes) {
+        Ok(rec) => {
+            ngx_log_debug_http!(req, "car_range: tracing to {}", path);
+            Some(rec)
+        }
+        Err(e) => {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: failed to create trace {}: {}",
+                path,
+                e
+            );
+            None
+        }
+    }
+}
 
-    bail!()
+fn chain_has_last(chain: *mut ngx_chain_t) -> bool {
+    let mut cl = chain;
+    while !cl.is_null() {
+        let buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
+        if buf.is_last() {
+            return true;
+        }
+        cl = unsafe { (*cl).next };
+    }
+    false
 }
 
 fn log_buf_info(r: &mut Request, chain: *mut ngx_chain_t, tag: &str) {
+    if !debug_http_enabled(unsafe { (*r.connection()).log }) {
+        return;
+    }
+
     let mut cl = chain;
     while !cl.is_null() {
         let buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
@@ -172,11 +1287,7 @@ fn log_buf_info(r: &mut Request, chain: *mut ngx_chain_t, tag: &str) {
     }
 }
 
-#[no_mangle]
-extern "C" fn ngx_car_range_body_filter(
-    r: *mut ngx_http_request_t,
-    body: *mut ngx_chain_t,
-) -> ngx_int_t {
+fn body_filter(r: *mut ngx_http_request_t, body: *mut ngx_chain_t) -> ngx_int_t {
     let req = unsafe { &mut Request::from_ngx_http_request(r) };
 
     ngx_log_debug_http!(req, "http car_range body filter {}", env!("GIT_HASH"));
@@ -193,8 +1304,7 @@ extern "C" fn ngx_car_range_body_filter(
     }
 
     let ctx = unsafe {
-        let cbc = req.get_context(&ngx_car_range_module)
-            as *mut CarBufferContext<(Bound<u64>, Bound<u64>), Pool>;
+        let cbc = req.get_context(&ngx_car_range_module) as *mut FilterContext;
         if cbc.is_null() {
             ngx_log_debug_http!(req, "car_range body filter: no ctx: skipping");
             bail!();
@@ -202,18 +1312,276 @@ extern "C" fn ngx_car_range_body_filter(
         cbc
     };
 
+    if let Some(block) = unsafe { (*ctx).raw.as_mut() } {
+        return raw::body_filter(req, block, body);
+    }
+
     unsafe {
-        let out = (*ctx).buffer(body);
+        if let Some(rec) = (*ctx).trace.as_mut() {
+            if let Err(e) = rec.record(body) {
+                ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to trace: {}", e);
+                (*ctx).trace = None;
+            }
+        }
 
-        log_buf_info(
-            req,
-            out,
-            &format!(
-                "output, read {}, pos {}",
-                (*ctx).unixfs_read(),
-                (*ctx).pos()
-            ),
-        );
+        let truncated = (*ctx).truncated();
+        let stalled = (*ctx).stalled();
+        let empty = (*ctx).empty();
+        let early = (*ctx).terminated_early();
+        let bytes_in = (*ctx).bytes_in();
+        let bytes_out = (*ctx).bytes_out();
+        let alloc_failures = (*ctx).alloc_failures();
+
+        let mut failed = false;
+        // trailers the list couldn't allocate
+        let mut trailer_failures = 0;
+        let out = match (*ctx).buffer(body) {
+            Ok(out) => out,
+            Err(e) => {
+                ngx_log_error_http!(
+                    NGX_LOG_ERR,
+                    req,
+                    "car_range: malformed CAR from upstream: {}, root {}",
+                    e,
+                    req.root_cid().unwrap_or("-")
+                );
+                failed = true;
+                ptr::null_mut()
+            }
+        };
+        if !truncated && (*ctx).truncated() {
+            ngx_log_error_http!(
+                NGX_LOG_WARN,
+                req,
+                "car_range: upstream response ended in the middle of a CAR section"
+            );
+            // the response still ends cleanly, tell the client it is incomplete
+            if !req.add_trailer(
+                ngx_string!("X-Stream-Error"),
+                ngx_string!("upstream CAR truncated"),
+            ) {
+                ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add trailer");
+                trailer_failures += 1;
+            }
+        }
+        if !empty && (*ctx).empty() {
+            ngx_log_error_http!(
+                NGX_LOG_WARN,
+                req,
+                "car_range: upstream response has no CAR blocks, {} bytes, root {}",
+                (*ctx).bytes_in(),
+                req.root_cid().unwrap_or("-")
+            );
+        }
+        if !stalled && (*ctx).stalled() {
+            let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+            ngx_log_error_http!(
+                NGX_LOG_WARN,
+                req,
+                "car_range: no upstream bytes for {}ms, ending the response, {}",
+                conf.stall_timeout(),
+                (*ctx).describe()
+            );
+            if !req.add_trailer(
+                ngx_string!("X-Stream-Error"),
+                ngx_string!("upstream CAR stalled"),
+            ) {
+                ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add trailer");
+                trailer_failures += 1;
+            }
+        }
+
+        // wait for the upstream again
+        if (*ctx).done() {
+            timer::del_timer(&mut (*ctx).stall_timer);
+        } else if (*ctx).bytes_in() > bytes_in && (*ctx).stall_timer.timer_set() == 1 {
+            let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+            timer::add_timer(&mut (*ctx).stall_timer, conf.stall_timeout());
+        }
+
+        if (*ctx).idle_calls() == STALL_CALLS {
+            ngx_log_error_http!(
+                NGX_LOG_WARN,
+                req,
+                "car_range: no progress after {} calls, {}",
+                STALL_CALLS,
+                (*ctx).describe()
+            );
+        }
+
+        if !early && (*ctx).terminated_early() {
+            let saved = match (*ctx).bytes_saved() {
+                Some(saved) => saved.to_string(),
+                None => "unknown".to_string(),
+            };
+            ngx_log_error_http!(
+                NGX_LOG_INFO,
+                req,
+                "car_range: range satisfied after {} upstream bytes, {} bytes left upstream",
+                (*ctx).bytes_in(),
+                saved
+            );
+
+            let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+            if conf.upstream_abort().close((*ctx).bytes_saved()) && req.stop_upstream() {
+                ngx_log_debug_http!(req, "car_range body filter: closing upstream");
+            }
+        }
+
+        if let Some(stats) = location_stats(req) {
+            let emitted = (*ctx).bytes_out() - bytes_out;
+            // a CARv2 goes out at once, longer than the upstream bytes of the last call
+            let trimmed = ((*ctx).bytes_in() - bytes_in).saturating_sub(emitted);
+            Stats::add(&stats.bytes_emitted, emitted as u64);
+            Stats::add(&stats.bytes_trimmed, trimmed as u64);
+            if failed && (*ctx).root_mismatch() {
+                Stats::add(&stats.verification_failures, 1);
+            } else if failed {
+                Stats::add(&stats.parse_errors, 1);
+            }
+            if !truncated && (*ctx).truncated() || !stalled && (*ctx).stalled() {
+                Stats::add(&stats.upstream_truncations, 1);
+            }
+            let alloc_failures = (*ctx).alloc_failures() - alloc_failures + trailer_failures;
+            Stats::add(&stats.alloc_failures, alloc_failures as u64);
+            if !early && (*ctx).terminated_early() {
+                Stats::add(&stats.early_terminations, 1);
+                let saved = (*ctx).bytes_saved().unwrap_or(0);
+                Stats::add(&stats.bytes_saved, saved as u64);
+            }
+        }
+
+        // the filter stopped, what it forwarded so far misses bytes of the range
+        if (*ctx).alloc_failures() > alloc_failures || trailer_failures > 0 {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: failed to allocate buffers, {}",
+                (*ctx).describe()
+            );
+            return NGX_ERROR as ngx_int_t;
+        }
+
+        if !req.header_sent() {
+            if failed {
+                let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+                return req.filter_finalize_request(&ngx_car_range_module, conf.error_status());
+            }
+            // a range past the end of the file gets no CAR, the upstream response is dropped
+            if (*ctx).unsatisfiable() {
+                ngx_log_debug_http!(req, "car_range body filter: range past the end of the file");
+                let value = (*ctx).entity_range().unwrap_or_default();
+                if !req.add_header_value(ngx_string!("X-Entity-Range"), &value) {
+                    ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add header");
+                    return NGX_ERROR as ngx_int_t;
+                }
+                return req.filter_finalize_request(
+                    &ngx_car_range_module,
+                    NGX_HTTP_RANGE_NOT_SATISFIABLE as ngx_int_t,
+                );
+            }
+            // nothing to send yet
+            if out.is_null() && !(*ctx).done() && !chain_has_last(body) {
+                req.and_buffered();
+                return NGX_OK as ngx_int_t;
+            }
+            // the part of the file the CAR covers, Content-Range being the one of its bytes,
+            // unknown when the root of the file was too long to hold the header for its size
+            if let Some(value) = (*ctx).entity_range() {
+                if !req.add_header_value(ngx_string!("X-Entity-Range"), &value) {
+                    ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add header");
+                    return NGX_ERROR as ngx_int_t;
+                }
+            }
+            // the whole filtered CAR goes out with the header, like a CAR served from a
+            // file or the cache that the first buffers hold, so its length is known and no
+            // trailer can follow it
+            if (*ctx).done()
+                && (*ctx).window().is_none()
+                && !(*ctx).truncated()
+                && !(*ctx).stalled()
+                && (*ctx).hasher.is_none()
+            {
+                req.set_content_length((*ctx).bytes_out());
+                req.set_expect_trailers(false);
+            }
+            if let Some(window) = (*ctx).window().cloned() {
+                let car_len = (*ctx).car_len();
+                let (status, value) = match content_range(&window, car_len, (*ctx).done()) {
+                    Some(range) => {
+                        let end = if (*ctx).done() {
+                            car_len.min(window.end)
+                        } else {
+                            window.end
+                        };
+                        (*ctx).range_end = Some(end);
+                        req.set_content_length(end - window.start);
+                        (NGX_HTTP_PARTIAL_CONTENT, range)
+                    }
+                    None => (
+                        NGX_HTTP_RANGE_NOT_SATISFIABLE,
+                        format!("bytes */{}", car_len),
+                    ),
+                };
+                if !req.add_header_value(ngx_string!("Content-Range"), &value) {
+                    ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add header");
+                    return NGX_ERROR as ngx_int_t;
+                }
+                if status == NGX_HTTP_RANGE_NOT_SATISFIABLE {
+                    return req.filter_finalize_request(&ngx_car_range_module, status as ngx_int_t);
+                }
+                req.set_status(status as ngx_uint_t);
+            }
+            let rc = ngx_http_next_header_filter
+                .map(|cb| cb(r))
+                .unwrap_or(NGX_ERROR as ngx_int_t);
+            if rc == NGX_ERROR as ngx_int_t || rc > NGX_OK as ngx_int_t || req.header_only() {
+                return rc;
+            }
+        } else if failed {
+            // too late for an error page, closing the connection tells the client the
+            // response is incomplete
+            return NGX_ERROR as ngx_int_t;
+        } else if (*ctx).done() && (*ctx).range_end.is_some_and(|end| (*ctx).car_len() < end) {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: filtered CAR ended at {} bytes, short of the byte range",
+                (*ctx).car_len()
+            );
+            return NGX_ERROR as ngx_int_t;
+        }
+
+        // the trailer has to be in the list before the last buffer goes to the chunked filter
+        if let Some(hasher) = (*ctx).hasher.as_mut() {
+            let mut last = false;
+            let mut cl = out;
+            while !cl.is_null() {
+                let buf = MemoryBuffer::from_ngx_buf((*cl).buf);
+                cl = (*cl).next;
+                hasher.update(buf.as_bytes());
+                last |= buf.is_last();
+            }
+            if last {
+                let cid = sha256_cid((*ctx).hasher.take().unwrap_or_default());
+                if !req.add_trailer_value(ngx_string!("X-Car-Range-Digest"), &cid.to_string()) {
+                    ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: failed to add trailer");
+                }
+                (*ctx).digest = Some(cid);
+            }
+        }
+
+        if debug_http_enabled((*req.connection()).log) {
+            log_buf_info(
+                req,
+                out,
+                &format!(
+                    "output, read {}, pos {}",
+                    (*ctx).unixfs_read(),
+                    (*ctx).pos()
+                ),
+            );
+        }
 
         // indicates that the filter is delaying sending buffers.
         // TODO: not sure if it has any effect but in the brotli filter it is set.
@@ -227,6 +1595,9 @@ extern "C" fn ngx_car_range_body_filter(
             .map(|cb| cb(r, out))
             .unwrap_or(NGX_ERROR as ngx_int_t);
 
+        // release the upstream buffers whose shadows were sent
+        (*ctx).update_chains(out);
+
         // Calling finalize request seems to cause some issues with file descriptors
         // it helps telling nginx to stop calling the filter but it's unclear if it's
         // better than the client simply closing the request when it gets the end trailer.
@@ -238,9 +1609,107 @@ extern "C" fn ngx_car_range_body_filter(
     }
 }
 
+// How the filtering of the response ended.
+fn outcome(ctx: &RangeContext) -> &'static str {
+    if ctx.error().is_some() {
+        "malformed"
+    } else if ctx.truncated() {
+        "truncated"
+    } else if ctx.stalled() {
+        "stalled"
+    } else if ctx.empty() {
+        "empty"
+    } else if ctx.terminated_early() {
+        "early"
+    } else if ctx.done() {
+        "complete"
+    } else {
+        "incomplete"
+    }
+}
+
+/// What the filter did for the request, once it is logged.
+pub fn summary<'a>(req: &'a Request, ctx: &FilterContext) -> Summary<'a> {
+    let conf = unsafe { &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf) };
+    let found = req.entity_range(conf.range_sources());
+    let (start, end) = found.map_or((Bound::Unbounded, Bound::Unbounded), |(range, _)| range);
+    let from_end = found
+        .map(|(_, source)| req.range_from_end(source))
+        .unwrap_or_default();
+    Summary {
+        root: req.root_cid(),
+        start: format_bound(start, from_end.start),
+        end: format_bound(end, from_end.end),
+        scope: req.arg("dag-scope").unwrap_or_else(|| "all".to_string()),
+        outcome: outcome(ctx),
+        bytes_in: ctx.bytes_in(),
+        bytes_out: ctx.bytes_out(),
+        blocks_out: ctx.blocks_out(),
+    }
+}
+
+fn log_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let req = unsafe { &mut Request::from_ngx_http_request(r) };
+
+    let ctx = req.get_context(unsafe { &ngx_car_range_module }) as *mut FilterContext;
+    if ctx.is_null() {
+        return NGX_OK as ngx_int_t;
+    }
+
+    if let Some(raw) = unsafe { (*ctx).raw.as_ref() } {
+        ngx_log_error_http!(NGX_LOG_INFO, req, "car_range: {}", raw);
+        return NGX_OK as ngx_int_t;
+    }
+
+    if let Some(stats) = location_stats(req) {
+        let (bytes_in, bytes_out) = unsafe { ((*ctx).bytes_in(), (*ctx).bytes_out()) };
+        stats.observe_response(bytes_in, bytes_out);
+    }
+
+    let summary = summary(req, unsafe { &*ctx });
+    ngx_log_error_http!(NGX_LOG_INFO, req, "car_range: {}", summary);
+
+    if let Some(cids) = unsafe { (*ctx).emitted_cids() } {
+        let cids: Vec<String> = cids.iter().map(|cid| cid.to_string()).collect();
+        ngx_log_error_http!(
+            NGX_LOG_INFO,
+            req,
+            "car_range: emitted {} blocks: {}",
+            cids.len(),
+            cids.join(" ")
+        );
+    }
+
+    NGX_OK as ngx_int_t
+}
+
 // Prepend to filter chain
 #[no_mangle]
-unsafe extern "C" fn ngx_car_range_filter_init(_: *mut ngx_conf_t) -> ngx_int_t {
+unsafe extern "C" fn ngx_car_range_filter_init(cf: *mut ngx_conf_t) -> ngx_int_t {
+    if stats::add_zone(cf, ptr::addr_of_mut!(ngx_car_range_module)) != NGX_OK as ngx_int_t {
+        return NGX_ERROR as ngx_int_t;
+    }
+
+    log::index_request_id(cf);
+
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let cmcf =
+        *(*ctx).main_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_main_conf_t;
+    let h =
+        ngx_array_push(&mut (*cmcf).phases[ngx_http_phases_NGX_HTTP_LOG_PHASE as usize].handlers)
+            as *mut ngx_http_handler_pt;
+    if h.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    *h = Some(ngx_car_range_log_handler);
+
+    let phase = ngx_http_phases_NGX_HTTP_PRECONTENT_PHASE as usize;
+    let h = ngx_array_push(&mut (*cmcf).phases[phase].handlers) as *mut ngx_http_handler_pt;
+    if h.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    *h = Some(ngx_car_range_collapse_handler);
+
     ngx_http_next_body_filter = ngx_http_top_body_filter;
     ngx_http_top_body_filter = Some(ngx_car_range_body_filter);
 
@@ -0,0 +1,210 @@
+//! `car_range_origin url;` fetches the CARs of the `/ipfs/` requests of a location from the
+//! gateway at `url`, through the proxy module configured as if the location had:
+//!
+//! ```nginx
+//! proxy_pass url$car_range_ipfs_path?$car_range_upstream_args;
+//! proxy_set_header Accept application/vnd.ipld.car;
+//! proxy_set_header Accept-Encoding "";
+//! proxy_set_header Range "";
+//! proxy_set_header If-Range "";
+//! ```
+
+use crate::bindings::*;
+use crate::config::{conf_args, LocConf};
+use crate::module::ngx_car_range_module;
+use crate::pool::{Allocator, Pool};
+use crate::request::*;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+// NGX_CONF_ERROR, returned once the error is logged
+const CONF_ERROR: *mut c_char = usize::MAX as *mut c_char;
+
+// the request headers of a CAR fetch, the range is cut by the filter and not by the origin
+const HEADERS: &[(&str, &str)] = &[
+    ("Accept", "application/vnd.ipld.car"),
+    ("Accept-Encoding", ""),
+    ("Range", ""),
+    ("If-Range", ""),
+];
+
+// the proxy_pass URL of the origin, which names the CID and path after its own /ipfs/
+fn proxy_pass_url(origin: &str) -> String {
+    let sep = if origin.ends_with('/') { "" } else { "/" };
+    format!(
+        "{}{}$car_range_ipfs_path?$car_range_upstream_args",
+        origin, sep
+    )
+}
+
+// copy `s` into the configuration pool
+unsafe fn conf_str(cf: *mut ngx_conf_t, s: &str) -> Option<ngx_str_t> {
+    let data = Pool::from_ngx_pool((*cf).pool).alloc_unaligned(s.len()) as *mut u8;
+    if data.is_null() {
+        return None;
+    }
+    ptr::copy_nonoverlapping(s.as_ptr(), data, s.len());
+    Some(ngx_str_t { len: s.len(), data })
+}
+
+// The proxy module of the cycle being configured, looked up by name rather than linked
+// against so this module still loads into an nginx built without it.
+unsafe fn proxy_module(cf: *mut ngx_conf_t) -> Option<*mut ngx_module_t> {
+    let cycle = (*cf).cycle;
+    (0..(*cycle).modules_n)
+        .map(|i| *(*cycle).modules.add(i))
+        .find(|&m| {
+            !m.is_null()
+                && !(*m).name.is_null()
+                && CStr::from_ptr((*m).name).to_bytes() == b"ngx_http_proxy_module"
+        })
+}
+
+// Run the `args[0]` directive of the proxy module with `args` in the location being
+// configured, like nginx does when it reads it from the configuration.
+unsafe fn proxy_directive(
+    cf: *mut ngx_conf_t,
+    proxy: *mut ngx_module_t,
+    args: &[&str],
+) -> *mut c_char {
+    let mut cmd = (*proxy).commands;
+    while (*cmd).name.len != 0 && (*cmd).name.to_str() != Ok(args[0]) {
+        cmd = cmd.add(1);
+    }
+    let set = match (*cmd).set {
+        Some(set) if (*cmd).name.len != 0 => set,
+        _ => return "has no proxy module directive to run\0".as_ptr() as *mut c_char,
+    };
+
+    let array = ngx_array_create((*cf).pool, args.len(), std::mem::size_of::<ngx_str_t>());
+    if array.is_null() {
+        return "failed to allocate\0".as_ptr() as *mut c_char;
+    }
+    for arg in args {
+        let elt = ngx_array_push(array) as *mut ngx_str_t;
+        match conf_str(cf, arg) {
+            Some(s) if !elt.is_null() => *elt = s,
+            _ => return "failed to allocate\0".as_ptr() as *mut c_char,
+        }
+    }
+
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let conf = *(*ctx).loc_conf.add((*proxy).ctx_index);
+    let args = (*cf).args;
+    (*cf).args = array;
+    let rv = set(cf, cmd, conf);
+    (*cf).args = args;
+    rv
+}
+
+/// `car_range_origin url;` fetches the CARs of the location from the gateway at `url`,
+/// such as `https://origin.example/ipfs/`, with the proxy module.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_car_range_origin_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    if conf.origin.is_some() {
+        return "is duplicate\0".as_ptr() as *mut c_char;
+    }
+    let origin = match conf_args(cf)[1].to_str() {
+        Ok(origin) if origin.starts_with("http://") || origin.starts_with("https://") => {
+            origin.to_string()
+        }
+        _ => return "must be an http:// or https:// URL\0".as_ptr() as *mut c_char,
+    };
+    let proxy = match proxy_module(cf) {
+        Some(proxy) => proxy,
+        None => {
+            ngx_conf_log_error(
+                NGX_LOG_EMERG as ngx_uint_t,
+                cf,
+                0,
+                "\"car_range_origin\" needs nginx built with ngx_http_proxy_module\0".as_ptr()
+                    as *const c_char,
+            );
+            return CONF_ERROR;
+        }
+    };
+
+    let rv = proxy_directive(cf, proxy, &["proxy_pass", &proxy_pass_url(&origin)]);
+    if !rv.is_null() {
+        return rv;
+    }
+    for &(name, value) in HEADERS {
+        let rv = proxy_directive(cf, proxy, &["proxy_set_header", name, value]);
+        if !rv.is_null() {
+            return rv;
+        }
+    }
+
+    // the requests of the location go through our handler, which only lets /ipfs/ ones
+    // reach the origin
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let clcf =
+        *(*ctx).loc_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_loc_conf_t;
+    conf.origin = (*clcf).handler;
+    (*clcf).handler = Some(ngx_car_range_origin_handler);
+    ptr::null_mut()
+}
+
+// The status answering `req` without reaching the origin, which only serves /ipfs/ paths.
+fn rejected(req: &Request) -> Option<ngx_int_t> {
+    match req.ipfs_path() {
+        Some(_) => None,
+        None => Some(NGX_HTTP_NOT_FOUND as ngx_int_t),
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_origin_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    if let Some(status) = rejected(req) {
+        return status;
+    }
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    match conf.origin {
+        Some(handler) => handler(r),
+        None => NGX_DECLINED as ngx_int_t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_pass_url() {
+        assert_eq!(
+            proxy_pass_url("https://origin.example/ipfs/"),
+            "https://origin.example/ipfs/$car_range_ipfs_path?$car_range_upstream_args"
+        );
+        assert_eq!(
+            proxy_pass_url("http://127.0.0.1:8080/ipfs"),
+            "http://127.0.0.1:8080/ipfs/$car_range_ipfs_path?$car_range_upstream_args"
+        );
+    }
+
+    #[test]
+    fn test_rejected() {
+        let not_found = Some(NGX_HTTP_NOT_FOUND as ngx_int_t);
+        for uri in [
+            "/",
+            "/index.html",
+            "/ipfs/",
+            "/ipns/example.com/a",
+            "/bafy/ipfs/a",
+        ] {
+            let mut r = MockRequest::new().uri(uri);
+            assert_eq!(rejected(r.request()), not_found, "{}", uri);
+        }
+
+        let mut r = MockRequest::new()
+            .uri("/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/a.txt");
+        assert_eq!(rejected(r.request()), None);
+    }
+}
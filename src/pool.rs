@@ -3,32 +3,38 @@ use std::marker::PhantomData;
 use std::os::raw::c_void;
 use std::{mem, ptr};
 
+/// Size of a cache line on the platforms we run on.
+pub const CACHE_LINE: usize = 64;
+
 pub struct Pool(*mut ngx_pool_t);
 
 impl Pool {
+    /// # Safety
+    ///
+    /// `pool` must be a live nginx pool, the allocations are freed along with it.
     pub unsafe fn from_ngx_pool(pool: *mut ngx_pool_t) -> Pool {
         assert!(!pool.is_null());
         Pool(pool)
     }
 }
 
+// The calls into nginx are all here, the rest of the allocator and the buffers are plain
+// Rust that runs under Miri with the allocators of the tests.
 impl Allocator for Pool {
-    fn as_ngx_pool_mut(&mut self) -> *mut ngx_pool_t {
-        self.0
+    fn alloc(&mut self, size: usize) -> *mut c_void {
+        unsafe { ngx_palloc(self.0, size) }
     }
-}
 
-pub trait Allocator {
-    fn as_ngx_pool_mut(&mut self) -> *mut ngx_pool_t;
+    fn alloc_unaligned(&mut self, size: usize) -> *mut c_void {
+        unsafe { ngx_pnalloc(self.0, size) }
+    }
 
-    fn alloc(&mut self, size: usize) -> *mut c_void {
-        let pool = self.as_ngx_pool_mut();
-        unsafe { ngx_palloc(pool, size) }
+    fn alloc_aligned(&mut self, size: usize, alignment: usize) -> *mut c_void {
+        unsafe { ngx_pmemalign(self.0, size, alignment) }
     }
 
     unsafe fn add_cleanup_for_value<T>(&mut self, value: *mut T) -> Result<(), ()> {
-        let pool = self.as_ngx_pool_mut();
-        let cln = ngx_pool_cleanup_add(pool, 0);
+        let cln = ngx_pool_cleanup_add(self.0, 0);
         if cln.is_null() {
             return Err(());
         }
@@ -38,9 +44,53 @@ pub trait Allocator {
         Ok(())
     }
 
+    // reuses the links the pool freed
+    fn alloc_chain(&mut self) -> *mut ngx_chain_t {
+        unsafe { ngx_alloc_chain_link(self.0) }
+    }
+}
+
+/// Memory living as long as the request, or whatever stands in for its pool.
+pub trait Allocator {
+    /// Memory aligned to a word, null when it can't be allocated.
+    fn alloc(&mut self, size: usize) -> *mut c_void;
+
+    fn alloc_unaligned(&mut self, size: usize) -> *mut c_void;
+
+    fn alloc_aligned(&mut self, size: usize, alignment: usize) -> *mut c_void;
+
+    /// Drop `value` in place when the memory is freed, an error when the cleanup can't be
+    /// allocated, like the null of the other allocations.
+    ///
+    /// # Safety
+    ///
+    /// `value` must be an initialized value allocated from this allocator.
+    #[allow(clippy::result_unit_err)]
+    unsafe fn add_cleanup_for_value<T>(&mut self, value: *mut T) -> Result<(), ()>;
+
+    // ngx_palloc only aligns allocations to NGX_ALIGNMENT, the size of a word.
+    fn alloc_type<T>(&mut self) -> *mut T {
+        if mem::align_of::<T>() > mem::align_of::<usize>() {
+            self.alloc_aligned(mem::size_of::<T>(), mem::align_of::<T>()) as *mut T
+        } else {
+            self.alloc(mem::size_of::<T>()) as *mut T
+        }
+    }
+
+    fn calloc_type<T>(&mut self) -> *mut T {
+        let p = self.alloc_type::<T>();
+        if !p.is_null() {
+            unsafe { ptr::write_bytes(p, 0, 1) };
+        }
+        p
+    }
+
     fn allocate<T>(&mut self, value: T) -> *mut T {
         unsafe {
-            let p = self.alloc(mem::size_of::<T>()) as *mut T;
+            let p = self.alloc_type::<T>();
+            if p.is_null() {
+                return p;
+            }
             ptr::write(p, value);
             if self.add_cleanup_for_value(p).is_err() {
                 ptr::drop_in_place(p);
@@ -51,14 +101,11 @@ pub trait Allocator {
     }
 
     fn alloc_chain(&mut self) -> *mut ngx_chain_t {
-        let pool = self.as_ngx_pool_mut();
-        unsafe { ngx_alloc_chain_link(pool) }
+        self.alloc_type::<ngx_chain_t>()
     }
 
     fn calloc_buf(&mut self) -> *mut ngx_buf_t {
-        let size = std::mem::size_of::<ngx_buf_t>();
-        let pool = self.as_ngx_pool_mut();
-        unsafe { ngx_pcalloc(pool, size) as *mut ngx_buf_t }
+        self.calloc_type::<ngx_buf_t>()
     }
 }
 
@@ -71,9 +118,15 @@ pub trait Buffer<'a> {
 
     fn as_ngx_buf_mut(&mut self) -> *mut ngx_buf_t;
 
+    /// The bytes between `pos` and `last`, which the buffer must keep alive for `'a`.
     fn as_bytes(&self) -> &'a [u8] {
         let buf = self.as_ngx_buf();
-        unsafe { std::slice::from_raw_parts((*buf).pos, self.len()) }
+        let len = self.len();
+        // the pointers of buffers without bytes may be null, which a slice can't start at
+        if len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts((*buf).pos, len) }
     }
 
     fn len(&self) -> usize {
@@ -81,8 +134,13 @@ pub trait Buffer<'a> {
         unsafe {
             let pos = (*buf).pos;
             let last = (*buf).last;
-            assert!(last >= pos);
-            usize::wrapping_sub(last as _, pos as _)
+            if pos == last {
+                return 0;
+            }
+            // unlike casts to integers, this keeps the pointers' provenance
+            let len = last.offset_from(pos);
+            assert!(len >= 0);
+            len as usize
         }
     }
 
@@ -90,33 +148,34 @@ pub trait Buffer<'a> {
         self.len() == 0
     }
 
-    fn is_last(&self) -> bool {
+    fn in_memory(&self) -> bool {
         let buf = self.as_ngx_buf();
-        unsafe { (*buf).last_buf() == 1 }
+        unsafe { (*buf).temporary() == 1 || (*buf).memory() == 1 || (*buf).mmap() == 1 }
     }
 
-    fn set_last_buf(&mut self, last: bool) {
-        let buf = self.as_ngx_buf_mut();
-        unsafe {
-            (*buf).set_last_buf(if last { 1 } else { 0 });
+    /// Remaining bytes to send, same as the `ngx_buf_size` macro.
+    fn size(&self) -> usize {
+        let buf = self.as_ngx_buf();
+        if self.in_memory() {
+            self.len()
+        } else {
+            unsafe { ((*buf).file_last - (*buf).file_pos) as usize }
         }
     }
 
-    fn set_last_in_chain(&mut self, last: bool) {
-        let buf = self.as_ngx_buf_mut();
-        unsafe {
-            (*buf).set_last_in_chain(if last { 1 } else { 0 });
-        }
+    fn is_last(&self) -> bool {
+        let buf = self.as_ngx_buf();
+        unsafe { (*buf).last_buf() == 1 }
     }
 
-    fn set_empty(&mut self) {
+    /// Mark the buffer as fully consumed without touching its flags.
+    fn consume(&mut self) {
         let buf = self.as_ngx_buf_mut();
         unsafe {
             if (*buf).in_file() == 1 {
                 (*buf).file_pos = (*buf).file_last;
             }
             (*buf).pos = (*buf).last;
-            (*buf).set_sync(1);
         }
     }
 
@@ -124,6 +183,11 @@ pub trait Buffer<'a> {
         let buf = self.as_ngx_buf();
         unsafe { (*buf).in_file() == 1 }
     }
+
+    fn shadow(&self) -> *mut ngx_buf_t {
+        let buf = self.as_ngx_buf();
+        unsafe { (*buf).shadow }
+    }
 }
 
 pub struct MemoryBuffer<'a> {
@@ -150,3 +214,95 @@ impl<'a> Buffer<'a> for MemoryBuffer<'a> {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestPool;
+    use std::alloc::{self, Layout};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct Counted(Rc<Cell<usize>>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    // the pool cleanup registered by allocate drops the value in place, exactly once
+    #[test]
+    fn test_cleanup_drops_once() {
+        let drops = Rc::new(Cell::new(0));
+        let layout = Layout::new::<Counted>();
+        unsafe {
+            let p = alloc::alloc(layout) as *mut Counted;
+            ptr::write(p, Counted(drops.clone()));
+            cleanup_type::<Counted>(p as *mut c_void);
+            assert_eq!(drops.get(), 1);
+            alloc::dealloc(p as *mut u8, layout);
+        }
+        assert_eq!(Rc::strong_count(&drops), 1);
+    }
+
+    // a buffer without bytes has an empty slice of them, even when its pointers are null
+    // like the ones of the zeroed buffers nginx flags with flush or last_buf
+    #[test]
+    fn test_buffer_bytes() {
+        let data = b"0123456789".to_vec();
+        let mut b = crate::testing::mem(&data);
+        let mut buf = MemoryBuffer::from_ngx_buf(&mut b);
+        assert_eq!(buf.as_bytes(), b"0123456789");
+        assert_eq!(buf.size(), 10);
+        buf.consume();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_bytes(), b"");
+
+        let mut b: ngx_buf_t = unsafe { mem::zeroed() };
+        let buf = MemoryBuffer::from_ngx_buf(&mut b);
+        assert_eq!(buf.len(), 0);
+        assert!(buf.as_bytes().is_empty());
+    }
+
+    // buffers come zeroed out of memory the test pool fills with a pattern
+    #[test]
+    fn test_calloc_buf() {
+        let mut pool = TestPool::new();
+        let b = pool.calloc_buf();
+        let cl = pool.alloc_chain();
+        assert!(!b.is_null() && !cl.is_null());
+        let buf = MemoryBuffer::from_ngx_buf(b);
+        assert!(buf.is_empty() && !buf.in_memory() && !buf.is_last());
+        assert!(buf.shadow().is_null());
+        unsafe {
+            (*cl).buf = b;
+            (*cl).next = ptr::null_mut();
+        }
+        assert_eq!(pool.stats().live(), 2);
+    }
+
+    // values allocated live until the pool is destroyed, or are dropped right away when
+    // their cleanup can't be registered
+    #[test]
+    fn test_allocate_dropped_with_pool() {
+        let drops = Rc::new(Cell::new(0));
+        let mut pool = TestPool::new();
+        let stats = pool.stats();
+        assert!(!pool.allocate(Counted(drops.clone())).is_null());
+        assert!(!pool.allocate(Counted(drops.clone())).is_null());
+        assert_eq!(drops.get(), 0);
+        drop(pool);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(stats.cleanups_run.get(), 2);
+        assert_eq!(stats.live(), 0);
+
+        let drops = Rc::new(Cell::new(0));
+        let mut pool = TestPool::failing_after(1);
+        assert!(pool.allocate(Counted(drops.clone())).is_null());
+        assert_eq!(drops.get(), 1);
+        assert!(pool.allocate(Counted(drops.clone())).is_null());
+        assert_eq!(drops.get(), 2);
+        assert_eq!(pool.stats().failures.get(), 2);
+    }
+}
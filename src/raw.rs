@@ -0,0 +1,238 @@
+//! `car_range_raw on;` answers the requests of a raw block, `/ipfs/<cid>?format=raw`,
+//! with the block cut out of the CAR the upstream sends for it, once it hashes to the CID.
+
+use crate::bindings::*;
+use crate::config::LocConf;
+use crate::log::ngx_log_error_http;
+use crate::module::{ngx_car_range_module, ngx_http_next_body_filter, ngx_http_next_header_filter};
+use crate::pool::{Buffer, MemoryBuffer};
+use crate::request::*;
+use car_range_core::block::BlockExtractor;
+use cid::multihash::MultihashGeneric;
+use cid::Cid;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::ptr;
+
+// multihash codes of the hash functions blocks can be checked with
+const IDENTITY: u64 = 0x00;
+const SHA2_256: u64 = 0x12;
+
+// multicodec of raw blocks
+const RAW: u64 = 0x55;
+
+/// Whether `data` is the block `cid` names, `None` when we don't have its hash function.
+pub fn verify(cid: &Cid, data: &[u8]) -> Option<bool> {
+    let hash = cid.hash();
+    match hash.code() {
+        IDENTITY => Some(hash.digest() == data),
+        SHA2_256 => Some(Sha256::digest(data).as_slice() == hash.digest()),
+        _ => None,
+    }
+}
+
+/// CID of the bytes `hasher` read as a raw block, so any bytes, like a filtered CAR, can
+/// be named and checked the way blocks are.
+pub fn sha256_cid(hasher: Sha256) -> Cid {
+    let hash = MultihashGeneric::wrap(SHA2_256, &hasher.finalize())
+        .expect("a sha2-256 digest fits a multihash");
+    Cid::new_v1(RAW, hash)
+}
+
+/// The block of a raw request being read from the upstream CAR.
+pub struct RawBlock {
+    blocks: BlockExtractor,
+    cid: Cid,
+    // length of the upstream response, if it announced it
+    upstream_len: Option<usize>,
+    bytes_in: usize,
+    // the block went out, the rest of the upstream response is dropped
+    sent: bool,
+}
+
+impl RawBlock {
+    pub fn new(cid: Cid, upstream_len: Option<usize>) -> Self {
+        Self {
+            blocks: BlockExtractor::new(cid),
+            cid,
+            upstream_len,
+            bytes_in: 0,
+            sent: false,
+        }
+    }
+}
+
+impl fmt::Display for RawBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "raw block {}, {}, {} bytes in, {} bytes out",
+            self.cid,
+            if self.sent { "sent" } else { "not sent" },
+            self.bytes_in,
+            self.blocks
+                .block()
+                .filter(|_| self.sent)
+                .map_or(0, <[u8]>::len)
+        )
+    }
+}
+
+// replace the response with the error page, the header wasn't sent
+fn fail(req: &mut Request) -> ngx_int_t {
+    unsafe {
+        let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+        req.filter_finalize_request(&ngx_car_range_module, conf.error_status())
+    }
+}
+
+/// Read the upstream chain until the block is complete, then send it if it hashes to its
+/// CID. The upstream buffers are consumed as they are read, the block is a copy.
+pub fn body_filter(req: &mut Request, raw: &mut RawBlock, body: *mut ngx_chain_t) -> ngx_int_t {
+    let r = &mut req.0 as *mut ngx_http_request_t;
+
+    let mut last = false;
+    let mut failed = None;
+    let mut cl = body;
+    while !cl.is_null() {
+        let mut buf = unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) };
+        cl = unsafe { (*cl).next };
+
+        last |= buf.is_last();
+        raw.bytes_in += buf.len();
+        if !raw.sent && failed.is_none() {
+            failed = raw.blocks.push(buf.as_bytes()).err();
+        }
+        buf.consume();
+    }
+    if raw.sent {
+        return NGX_OK as ngx_int_t;
+    }
+
+    if let Some(e) = failed {
+        ngx_log_error_http!(
+            NGX_LOG_ERR,
+            req,
+            "car_range: malformed CAR from upstream: {} at offset {}, block {}",
+            e,
+            raw.blocks.offset(),
+            raw.cid
+        );
+        return fail(req);
+    }
+    let block = match raw.blocks.block() {
+        Some(block) => block,
+        None if last => {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: block {} not in the upstream CAR of {} bytes",
+                raw.cid,
+                raw.bytes_in
+            );
+            return fail(req);
+        }
+        None => {
+            req.and_buffered();
+            return NGX_OK as ngx_int_t;
+        }
+    };
+    match verify(&raw.cid, block) {
+        Some(true) => {}
+        Some(false) => {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: block {} from upstream doesn't match its hash",
+                raw.cid
+            );
+            return fail(req);
+        }
+        None => {
+            ngx_log_error_http!(
+                NGX_LOG_ERR,
+                req,
+                "car_range: block {} uses the unsupported hash function 0x{:x}",
+                raw.cid,
+                raw.cid.hash().code()
+            );
+            return fail(req);
+        }
+    }
+    raw.sent = true;
+    req.not_buffered();
+
+    // the rest of the upstream CAR isn't needed
+    let conf = unsafe { &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf) };
+    let left = raw.upstream_len.map(|len| len.saturating_sub(raw.bytes_in));
+    if !last && conf.upstream_abort().close(left) {
+        req.stop_upstream();
+    }
+
+    req.set_content_length(block.len());
+    let rc = unsafe {
+        ngx_http_next_header_filter
+            .map(|cb| cb(r))
+            .unwrap_or(NGX_ERROR as ngx_int_t)
+    };
+    if rc == NGX_ERROR as ngx_int_t || rc > NGX_OK as ngx_int_t || req.header_only() {
+        return rc;
+    }
+
+    let b = unsafe { ngx_create_temp_buf(req.0.pool, block.len()) };
+    if b.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(block.as_ptr(), (*b).last, block.len());
+        (*b).last = (*b).last.add(block.len());
+        (*b).set_last_buf(if req.is_subrequest() { 0 } else { 1 });
+        (*b).set_last_in_chain(1);
+    }
+    let mut out = ngx_chain_t {
+        buf: b,
+        next: ptr::null_mut(),
+    };
+    unsafe {
+        ngx_http_next_body_filter
+            .map(|cb| cb(r, &mut out))
+            .unwrap_or(NGX_ERROR as ngx_int_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let cid =
+            Cid::try_from("bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq").unwrap();
+        assert_eq!(verify(&cid, b"hello"), Some(true));
+        assert_eq!(verify(&cid, b"hello!"), Some(false));
+
+        // an identity CID holds the block
+        let cid = Cid::try_from(&[0x01, 0x55, 0x00, 0x02, b'h', b'i'][..]).unwrap();
+        assert_eq!(verify(&cid, b"hi"), Some(true));
+        assert_eq!(verify(&cid, b"ho"), Some(false));
+
+        // a blake2b-256 CID
+        let mut bytes = vec![0x01, 0x55, 0xa0, 0xe4, 0x02, 0x20];
+        bytes.extend_from_slice(&[0; 32]);
+        let cid = Cid::try_from(&bytes[..]).unwrap();
+        assert_eq!(verify(&cid, b"hello"), None);
+    }
+
+    #[test]
+    fn test_sha256_cid() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hel");
+        hasher.update(b"lo");
+        let cid = sha256_cid(hasher);
+        assert_eq!(
+            cid.to_string(),
+            "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq"
+        );
+        assert_eq!(verify(&cid, b"hello"), Some(true));
+    }
+}
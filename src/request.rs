@@ -1,7 +1,29 @@
 use crate::bindings::*;
+use crate::config::RangeSource;
 use crate::pool::Pool;
-use std::borrow::Cow;
-use std::ops::Bound;
+use car_range_core::range::{
+    format_entity_bytes, parse_entity_bytes, parse_from_end, parse_range, parse_range_from_end,
+    FromEnd,
+};
+use cid::Cid;
+use std::cmp::Ordering;
+use std::ops::{Bound, Range};
+
+/// Static string initializer for [`ngx_str_t`].
+///
+/// The resulting byte string is always nul-terminated (just like a C string).
+///
+/// [`ngx_str_t`]: https://nginx.org/en/docs/dev/development_guide.html#string_overview
+macro_rules! ngx_string {
+    ($s:expr) => {{
+        ngx_str_t {
+            len: $s.len(),
+            data: concat!($s, "\0").as_ptr() as *mut u8,
+        }
+    }};
+}
+
+pub(crate) use ngx_string;
 
 impl ngx_str_t {
     /// Yields a `&str` slice if the [`NgxStr`] contains valid UTF-8.
@@ -14,23 +36,329 @@ impl ngx_str_t {
     }
 }
 
-fn parse_bound(s: &str) -> Option<Bound<u64>> {
-    if s == "*" {
-        return Some(Bound::Unbounded);
+fn parse_arg(args: &str, name: &str) -> Option<String> {
+    form_urlencoded::parse(args.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, val)| val.into_owned())
+}
+
+/// Namespace of a gateway request path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// `/ipfs/<cid>`, content addressed, so the response never changes.
+    Ipfs,
+    /// `/ipns/<name>`, a mutable name, the response changes when it is republished.
+    Ipns,
+    Other,
+}
+
+/// The `dag-scope` of a CAR request, the blocks of the DAG under the path it asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DagScope {
+    /// Every block, the default.
+    All,
+    /// The blocks of the file or directory the path resolves to.
+    Entity,
+    /// The block the path resolves to.
+    Block,
+}
+
+impl DagScope {
+    fn parse(val: &str) -> Option<Self> {
+        match val {
+            "all" => Some(DagScope::All),
+            "entity" => Some(DagScope::Entity),
+            "block" => Some(DagScope::Block),
+            _ => None,
+        }
+    }
+}
+
+// the dag-scope of the query string, or of the legacy car-scope and depth parameters
+// when it has none
+fn parse_dag_scope(args: &str) -> Option<DagScope> {
+    let mut legacy = None;
+    for (key, val) in form_urlencoded::parse(args.as_bytes()) {
+        if key == "dag-scope" {
+            return DagScope::parse(&val);
+        }
+        legacy = legacy.or_else(|| legacy_scope(&key, &val));
+    }
+    legacy.and_then(DagScope::parse)
+}
+
+// the namespace of the request path and the rest of the path after its prefix
+fn parse_namespace(path: &str) -> (Namespace, &str) {
+    if let Some(rest) = path.strip_prefix("/ipfs/") {
+        (Namespace::Ipfs, rest)
+    } else if let Some(rest) = path.strip_prefix("/ipns/") {
+        (Namespace::Ipns, rest)
+    } else {
+        (Namespace::Other, path)
     }
+}
 
-    s.parse().ok().map(Bound::Included)
+// the CID following /ipfs/ in the request path
+fn parse_root_cid(path: &str) -> Option<&str> {
+    let cid = match parse_namespace(path) {
+        (Namespace::Ipfs, rest) => rest.split('/').next()?,
+        _ => return None,
+    };
+    if cid.is_empty() {
+        return None;
+    }
+    Some(cid)
 }
 
-fn parse_range(args: &str) -> Option<(Bound<u64>, Bound<u64>)> {
-    let mut it = form_urlencoded::parse(args.as_bytes());
-    while let Some((Cow::Borrowed(key), Cow::Borrowed(val))) = it.next() {
-        if key == "entity-bytes" {
-            let mut iter = val.trim().splitn(2, ":");
-            return Some((parse_bound(iter.next()?)?, parse_bound(iter.next()?)?));
+// the path percent-encoded for a request line, the bytes a path segment can hold are kept
+fn escape_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => escaped.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*'
+            | b'+' | b',' | b';' | b'=' | b':' | b'@' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
         }
     }
-    None
+    escaped
+}
+
+// the CID of a request for a single block, /ipfs/<cid> without a path in the DAG
+fn parse_block_path(path: &str) -> Option<Cid> {
+    let cid = match parse_namespace(path) {
+        (Namespace::Ipfs, rest) => rest,
+        _ => return None,
+    };
+    let cid = cid.strip_suffix('/').unwrap_or(cid);
+    if cid.contains('/') {
+        return None;
+    }
+    Cid::try_from(cid).ok()
+}
+
+// the query string without the `names` parameters, as sent by the client otherwise
+fn strip_args(args: &str, names: &[&str]) -> String {
+    args.split('&')
+        .filter(|arg| !arg.is_empty() && !names.contains(&arg.split('=').next().unwrap_or("")))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// the dag-scope of the legacy car-scope and depth parameters
+fn legacy_scope(key: &str, val: &str) -> Option<&'static str> {
+    match (key, val) {
+        ("car-scope", "all") | ("depth", "all") => Some("all"),
+        ("car-scope", "file") | ("depth", "1") => Some("entity"),
+        ("car-scope", "block") | ("depth", "0") => Some("block"),
+        _ => None,
+    }
+}
+
+// the query string with dag-scope and entity-bytes first and normalized, translated from
+// the legacy car-scope, depth and bytes parameters when they are missing, and the other
+// parameters as sent by the client but for the `ignore` ones
+fn canonical_args(args: &str, ignore: &[&str]) -> String {
+    let mut scope = None;
+    let mut legacy_scopes = Vec::new();
+    let mut range = None;
+    let mut legacy_range = None;
+    let mut rest = Vec::new();
+    for arg in args.split('&').filter(|arg| !arg.is_empty()) {
+        let (key, val) = match form_urlencoded::parse(arg.as_bytes()).next() {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match &*key {
+            "dag-scope" => scope = Some(val.into_owned()),
+            "entity-bytes" => range = Some(val.into_owned()),
+            "bytes" => legacy_range = Some(val.into_owned()),
+            "car-scope" | "depth" => legacy_scopes.extend(legacy_scope(&key, &val)),
+            key if ignore.contains(&key) => {}
+            _ => rest.push(arg),
+        }
+    }
+
+    let mut canonical = Vec::new();
+    if let Some(scope) = scope.or_else(|| legacy_scopes.first().map(|s| s.to_string())) {
+        canonical.push(format!(
+            "dag-scope={}",
+            form_urlencoded::byte_serialize(scope.as_bytes()).collect::<String>()
+        ));
+    }
+    if let Some(range) = range.or(legacy_range) {
+        let range = match parse_entity_bytes(&range) {
+            Some(bounds) => format_entity_bytes(bounds, parse_from_end(&range)),
+            None => form_urlencoded::byte_serialize(range.as_bytes()).collect(),
+        };
+        canonical.push(format!("entity-bytes={}", range));
+    }
+    canonical.extend(rest.into_iter().map(str::to_string));
+    canonical.join("&")
+}
+
+// the parts of a CAR request its response depends on, the range left out when the
+// cache entry holds the whole CAR
+fn cache_key(
+    scope: Option<&str>,
+    range: Option<(Bound<u64>, Bound<u64>)>,
+    from_end: FromEnd,
+    full: bool,
+) -> String {
+    let mut key = format!("car;dag-scope={}", scope.unwrap_or("all"));
+    if let (Some(range), false) = (range, full) {
+        key.push_str(&format!(
+            ";entity-bytes={}",
+            format_entity_bytes(range, from_end)
+        ));
+    }
+    key
+}
+
+// the first and last byte of a `Range: bytes=first-last` header. The length of the
+// filtered CAR is only known once it is sent, so open and suffix ranges aren't served, nor
+// are several ranges, which CDNs resuming a fetch don't ask for.
+fn parse_byte_range(value: &str) -> Option<(u64, u64)> {
+    let (unit, range) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (first, last) = range.trim().split_once('-')?;
+    let first: u64 = first.trim().parse().ok()?;
+    let last: u64 = last.trim().parse().ok()?;
+    // the end of the window is past the last byte
+    if first > last || last == u64::MAX {
+        return None;
+    }
+    Some((first, last))
+}
+
+// the entity range of a `Range: bytes=first-last`, `bytes=first-` or `bytes=-suffix`
+// header, as the `entity-bytes` it stands for, a suffix counting its start from the end of
+// the entity like `entity-bytes=-suffix:*`. Multiple ranges have no such equivalent.
+fn parse_range_header(value: &str) -> Option<((Bound<u64>, Bound<u64>), FromEnd)> {
+    let (unit, range) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (first, last) = range.trim().split_once('-')?;
+    if first.trim().is_empty() {
+        let suffix = match last.trim().parse::<u64>().ok()? {
+            0 => return None,
+            suffix => suffix,
+        };
+        let from_end = FromEnd {
+            start: Some(suffix),
+            end: None,
+        };
+        return Some(((Bound::Unbounded, Bound::Unbounded), from_end));
+    }
+    let first: u64 = first.trim().parse().ok()?;
+    let last = match last.trim() {
+        "" => Bound::Unbounded,
+        last => match last.parse::<u64>().ok()? {
+            last if last >= first => Bound::Included(last),
+            _ => return None,
+        },
+    };
+    Some(((Bound::Included(first), last), FromEnd::default()))
+}
+
+/// The `Content-Range` of the `window` of a filtered CAR `len` bytes long so far, `None`
+/// when the CAR ended before the window. The window is sent whole unless the CAR ended
+/// in it, which makes its end and length known.
+pub fn content_range(window: &Range<usize>, len: usize, done: bool) -> Option<String> {
+    match (done, len.cmp(&window.start)) {
+        (true, Ordering::Less | Ordering::Equal) => None,
+        (true, _) => Some(format!(
+            "bytes {}-{}/{}",
+            window.start,
+            len.min(window.end) - 1,
+            len
+        )),
+        (false, _) => Some(format!("bytes {}-{}/*", window.start, window.end - 1)),
+    }
+}
+
+// the Cache-Control value without its immutable directive, None when it has none
+fn strip_immutable(cache_control: &str) -> Option<String> {
+    let directives: Vec<&str> = cache_control
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .collect();
+    let kept: Vec<&str> = directives
+        .iter()
+        .copied()
+        .filter(|d| !d.eq_ignore_ascii_case("immutable"))
+        .collect();
+    if kept.len() == directives.len() {
+        return None;
+    }
+    Some(kept.join(", "))
+}
+
+// the ETag of a filtered CAR: the one of the upstream CAR, weak or strong like it, with the
+// parts of the request the filtered CAR depends on. None when the upstream one is malformed.
+fn filtered_etag(etag: &str, key: &str) -> Option<String> {
+    let etag = etag.trim();
+    let (weak, opaque) = match etag.strip_prefix("W/") {
+        Some(opaque) => ("W/", opaque),
+        None => ("", etag),
+    };
+    let opaque = opaque.strip_prefix('"')?.strip_suffix('"')?;
+    if opaque.contains('"') {
+        return None;
+    }
+    Some(format!("{}\"{}.{}\"", weak, opaque, key))
+}
+
+// whether the ETags of an If-None-Match value list `etag`, compared weakly
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |etag: &str| {
+        let etag = etag.trim();
+        etag.strip_prefix("W/").unwrap_or(etag).to_string()
+    };
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+    let etag = opaque(etag);
+    if_none_match.split(',').any(|t| opaque(t) == etag)
+}
+
+// the media range of an Accept value listing `content_type` with a quality above 0, the
+// first entry of the type deciding. Wildcards are ignored, they would turn every browser
+// request into a CAR one.
+fn accepted<'a>(accept: &'a str, content_type: &str) -> Option<&'a str> {
+    let range = accept.split(',').find(|range| {
+        range
+            .split(';')
+            .next()
+            .is_some_and(|t| t.trim().eq_ignore_ascii_case(content_type))
+    })?;
+    let q = media_param(range, "q").map_or(Some(1.0), |q| q.parse::<f32>().ok());
+    q.is_some_and(|q| q > 0.0 && q <= 1.0).then_some(range)
+}
+
+// whether an Accept value lists `content_type`, see `accepted`
+fn accepts(accept: &str, content_type: &str) -> bool {
+    accepted(accept, content_type).is_some()
+}
+
+// the value of the `name` parameter of a media range
+fn media_param<'a>(range: &'a str, name: &str) -> Option<&'a str> {
+    range
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// whether a body with the `Content-Encoding` is compressed, identity being no encoding
+fn is_compressed(encoding: &str) -> bool {
+    let encoding = encoding.trim();
+    !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity")
 }
 
 // Wrapper for the nginx http request to provide safer access and operations.
@@ -57,9 +385,154 @@ impl Request {
         unsafe { Pool::from_ngx_pool(self.0.pool) }
     }
 
+    /// The `entity-bytes` range of the query string.
     pub fn range(&self) -> Option<(Bound<u64>, Bound<u64>)> {
+        self.lookup(parse_range)
+    }
+
+    /// The entity range, from the first of `sources` the request has one in, along with
+    /// that source. See `parse_range_header` for the `Range` header.
+    pub fn entity_range(
+        &self,
+        sources: &[RangeSource],
+    ) -> Option<((Bound<u64>, Bound<u64>), RangeSource)> {
+        sources.iter().find_map(|&source| {
+            let range = match source {
+                RangeSource::Args => self.range(),
+                RangeSource::Header => {
+                    parse_range_header(self.header_in("Range")?).map(|(range, _)| range)
+                }
+            };
+            range.map(|range| (range, source))
+        })
+    }
+
+    /// The bounds of the range of `source` counted from the end of the entity, for a
+    /// negative `from` or `to` of `entity-bytes` and a suffix `Range` header.
+    pub fn range_from_end(&self, source: RangeSource) -> FromEnd {
+        match source {
+            // from the same query string as the range
+            RangeSource::Args => self
+                .lookup(|args| parse_range(args).map(|_| parse_range_from_end(args)))
+                .unwrap_or_default(),
+            RangeSource::Header => self
+                .header_in("Range")
+                .and_then(parse_range_header)
+                .map(|(_, from_end)| from_end)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The `dag-scope` of the request, `all` when it has none or one this module doesn't
+    /// know, see `parse_dag_scope`.
+    pub fn dag_scope(&self) -> DagScope {
+        self.lookup(parse_dag_scope).unwrap_or(DagScope::All)
+    }
+
+    /// Value of the `name` query parameter.
+    pub fn arg(&self, name: &str) -> Option<String> {
+        self.lookup(|args| parse_arg(args, name))
+    }
+
+    /// The query string as the client sent it, which internal redirects leave alone.
+    fn client_args(&self) -> Option<&str> {
+        let uri = self.0.unparsed_uri.to_str().ok()?;
+        Some(uri.split_once('?').map_or("", |(_, args)| args))
+    }
+
+    // Look a parameter up in the query string, then after an internal redirect in the one
+    // of the client, since redirects like the one of X-Accel-Redirect replace it with
+    // their own.
+    fn lookup<T>(&self, f: impl Fn(&str) -> Option<T>) -> Option<T> {
+        let found = self.0.args.to_str().ok().and_then(&f);
+        if found.is_some() || self.0.internal() == 0 {
+            return found;
+        }
+        self.client_args().and_then(f)
+    }
+
+    /// The query string without `entity-bytes` and the `ignore` parameters, to fetch the
+    /// whole CAR from upstream.
+    pub fn upstream_args(&self, ignore: &[String]) -> Option<String> {
         let args = self.0.args.to_str().ok()?;
-        parse_range(args)
+        let mut names = vec!["entity-bytes"];
+        names.extend(ignore.iter().map(String::as_str));
+        Some(strip_args(args, &names))
+    }
+
+    /// The query string in the canonical form to send upstream, without the `ignore`
+    /// parameters, see `canonical_args`.
+    pub fn canonical_args(&self, ignore: &[String]) -> Option<String> {
+        let args = self.0.args.to_str().ok()?;
+        let ignore: Vec<&str> = ignore.iter().map(String::as_str).collect();
+        Some(canonical_args(args, &ignore))
+    }
+
+    /// Key of the response in `proxy_cache`, empty for requests of something else than
+    /// a CAR. With `full` every range of a CAR shares the key, and its CARv2 too.
+    pub fn cache_key(&self, full: bool, sources: &[RangeSource]) -> String {
+        if !self.accept_car() {
+            return String::new();
+        }
+        let found = self.entity_range(sources);
+        let from_end = found
+            .map(|(_, source)| self.range_from_end(source))
+            .unwrap_or_default();
+        let mut key = cache_key(
+            self.arg("dag-scope").as_deref(),
+            found.map(|(range, _)| range),
+            from_end,
+            full,
+        );
+        if !full && self.accept_carv2() {
+            key.push_str(";version=2");
+        }
+        key
+    }
+
+    /// Root CID of the requested DAG, as found in the request path.
+    pub fn root_cid(&self) -> Option<&str> {
+        let path = self.0.uri.to_str().ok()?;
+        parse_root_cid(path)
+    }
+
+    /// Whether the request path is `/ipfs/` followed by a CID that parses.
+    pub fn has_root_cid(&self) -> bool {
+        self.root_cid()
+            .is_some_and(|cid| Cid::try_from(cid).is_ok())
+    }
+
+    /// Namespace of the request path, `/ipns/` responses change with the name.
+    pub fn namespace(&self) -> Namespace {
+        match self.0.uri.to_str() {
+            Ok(path) => parse_namespace(path).0,
+            Err(_) => Namespace::Other,
+        }
+    }
+
+    /// The path after `/ipfs/`, the CID and the path in its DAG, escaped to be sent
+    /// upstream. `None` for other paths.
+    pub fn ipfs_path(&self) -> Option<String> {
+        match parse_namespace(self.0.uri.to_str().ok()?) {
+            (Namespace::Ipfs, rest) if !rest.is_empty() => Some(escape_path(rest)),
+            _ => None,
+        }
+    }
+
+    /// Value of an indexed variable, `None` if it isn't found.
+    pub fn indexed_variable(&self, index: ngx_uint_t) -> Option<&[u8]> {
+        let r = &self.0 as *const _ as *mut ngx_http_request_t;
+        unsafe {
+            let v = ngx_http_get_indexed_variable(r, index);
+            if v.is_null() || (*v).not_found() == 1 {
+                return None;
+            }
+            Some(std::slice::from_raw_parts((*v).data, (*v).len() as usize))
+        }
+    }
+
+    pub fn get_loc_conf(&self, module: &ngx_module_t) -> *mut std::os::raw::c_void {
+        unsafe { *self.0.loc_conf.add(module.ctx_index) }
     }
 
     pub fn get_context(&self, module: &ngx_module_t) -> *mut std::os::raw::c_void {
@@ -70,95 +543,160 @@ impl Request {
         unsafe { *self.0.ctx.add(module.ctx_index) = ctx }
     }
 
+    /// Whether the client asks for a CAR with `format=car` or by accepting
+    /// `application/vnd.ipld.car`, like gateways do.
     pub fn accept_car(&self) -> bool {
-        // Headers is a ngx list which is a sequence of arrays:
-        // struct ngx_list_t {
-        //     last: *mut ngx_list_part_t,
-        //     part: ngx_list_part_t,
-        //     size: usize,
-        //     nalloc: ngx_uint_t,
-        //     pool: *mut ngx_pool_t,
-        // }
-        // The array parts looks like:
-        // struct ngx_list_part_t {
-        //     elts: *mut ::std::os::raw::c_void,
-        //     nelts: ngx_uint_t,
-        //     next: *mut ngx_list_part_t,
-        // }
-        let headers = self.0.headers_in.headers;
-
-        // we iterate over the array and then go to the next one in the list
-        let mut part = headers.part;
-        let mut i = 0;
-
-        // There should at least be a few headers but just to be safe...
-        if part.elts.is_null() {
-            return false;
+        self.arg("format").as_deref() == Some("car") || self.accept("application/vnd.ipld.car")
+    }
+
+    /// Whether the client asks for a CARv2 with `version=2` in its `Accept` header.
+    pub fn accept_carv2(&self) -> bool {
+        self.accepted("application/vnd.ipld.car")
+            .and_then(|range| media_param(range, "version"))
+            == Some("2")
+    }
+
+    /// CID of the block a request of `/ipfs/<cid>` asks for with `format=raw` or by
+    /// accepting `application/vnd.ipld.raw`.
+    pub fn raw_block(&self) -> Option<Cid> {
+        let raw =
+            self.arg("format").as_deref() == Some("raw") || self.accept("application/vnd.ipld.raw");
+        if !raw {
+            return None;
         }
+        parse_block_path(self.0.uri.to_str().ok()?)
+    }
+
+    /// Whether an `Accept` header accepts `content_type`, see `accepts`. Header names are
+    /// matched ignoring their case, HTTP/2 clients and Node.js send them in lowercase.
+    fn accept(&self, content_type: &str) -> bool {
+        self.accepted(content_type).is_some()
+    }
 
-        // Each HTTP header in the array is shaped as:
-        // struct ngx_table_elt_s {
-        //     hash: ngx_uint_t,
-        //     key: ngx_str_t,
-        //     value: ngx_str_t,
-        //     lowcase_key: *mut u_char,
-        //     next: *mut ngx_table_elt_t,
-        // }
-        // Create a slice over the first array in the list
-        let mut arr: &[ngx_table_elt_t] =
-            unsafe { std::slice::from_raw_parts(part.elts as *const ngx_table_elt_t, part.nelts) };
-
-        loop {
-            // only iterate first array for now
-            if i >= arr.len() {
-                if part.next.is_null() {
-                    break;
+    // the media range of the first `Accept` header accepting `content_type`
+    fn accepted(&self, content_type: &str) -> Option<&str> {
+        // the headers are a list of arrays of ngx_table_elt_t
+        let mut part: *const ngx_list_part_t = &self.0.headers_in.headers.part;
+        while !part.is_null() {
+            let headers = unsafe {
+                if (*part).elts.is_null() {
+                    return None;
+                }
+                std::slice::from_raw_parts((*part).elts as *const ngx_table_elt_t, (*part).nelts)
+            };
+            for h in headers {
+                let accept = h
+                    .key
+                    .to_str()
+                    .is_ok_and(|k| k.eq_ignore_ascii_case("Accept"));
+                if !accept {
+                    continue;
+                }
+                if let Some(range) = h
+                    .value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| accepted(v, content_type))
+                {
+                    return Some(range);
                 }
-                part = unsafe { *part.next };
-                arr = unsafe {
-                    std::slice::from_raw_parts(part.elts as *const ngx_table_elt_t, part.nelts)
-                };
-                i = 0;
             }
+            part = unsafe { (*part).next };
+        }
+        None
+    }
 
-            let table = arr[i];
-
-            // increment the index for the next iteration
-            i += 1;
+    /// Whether this is a subrequest rather than the request of the client.
+    pub fn is_subrequest(&self) -> bool {
+        self.0.main != &self.0 as *const _ as *mut _
+    }
 
-            // the key and values are nginx string objects
-            // struct ngx_str_t {
-            //      len: usize,
-            //      data: *mut u_char,
-            // }
-            // create a byte slice from the nginx string object
-            let bytes = unsafe { std::slice::from_raw_parts(table.key.data, table.key.len) };
-            if bytes.is_empty() {
-                continue;
-            }
+    pub fn header_sent(&self) -> bool {
+        self.0.header_sent() == 1
+    }
 
-            // As per RFC5987, the character set and language encoding in HTTP headers
-            // must be UTF-8 characters so we can skip the expensive validation check.
-            let k = unsafe { std::str::from_utf8_unchecked(bytes) };
+    pub fn header_only(&self) -> bool {
+        self.0.header_only() == 1
+    }
 
-            if !k.contains("Accept") {
-                continue;
-            }
+    /// Whether a filter already replaced the response with an error.
+    pub fn filter_finalize(&self) -> bool {
+        self.0.filter_finalize() == 1
+    }
 
-            let bytes = unsafe { std::slice::from_raw_parts(table.value.data, table.value.len) };
-            if bytes.is_empty() {
-                continue;
-            }
+    /// Replace the response with the `status` error from a filter, going through
+    /// `error_page`. Only possible until the header is sent.
+    pub fn filter_finalize_request(
+        &mut self,
+        module: &ngx_module_t,
+        status: ngx_int_t,
+    ) -> ngx_int_t {
+        unsafe {
+            ngx_http_filter_finalize_request(
+                &mut self.0,
+                module as *const _ as *mut ngx_module_t,
+                status,
+            )
+        }
+    }
 
-            let v = unsafe { std::str::from_utf8_unchecked(bytes) };
+    /// Length of the response body, if known.
+    pub fn content_length(&self) -> Option<usize> {
+        let len = self.0.headers_out.content_length_n;
+        if len < 0 {
+            return None;
+        }
+        Some(len as usize)
+    }
 
-            // Check that the Accept header is in CAR format
-            if v == "application/vnd.ipld.car" {
-                return true;
-            }
+    /// Whether the response body is compressed, like a gzip response of an upstream
+    /// `gunzip` isn't enabled for.
+    pub fn compressed(&self) -> bool {
+        let h = self.0.headers_out.content_encoding;
+        if h.is_null() {
+            return false;
+        }
+        // removed headers have a zero hash
+        if unsafe { (*h).hash } == 0 {
+            return false;
         }
+        match unsafe { (*h).value.to_str() } {
+            Ok(encoding) => is_compressed(encoding),
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the response switches protocols, like a websocket handshake, or is an
+    /// event stream. Neither is a CAR whatever the request accepts, and the header of
+    /// an upgrade can't wait for a body that never comes through the filters.
+    pub fn is_streaming(&self) -> bool {
+        self.status() == NGX_HTTP_SWITCHING_PROTOCOLS as ngx_uint_t
+            || self.has_header_out("Upgrade")
+            || self
+                .content_type()
+                .eq_ignore_ascii_case("text/event-stream")
+    }
+
+    /// Keep the gzip filter from compressing the response, as if the client didn't
+    /// accept gzip.
+    pub fn disable_gzip(&mut self) {
+        self.0.set_gzip_tested(1);
+        self.0.set_gzip_ok(0);
+    }
+
+    pub fn status(&self) -> ngx_uint_t {
+        self.0.headers_out.status
+    }
 
-        false
+    /// Set the status of the response, replacing the status line of the upstream.
+    pub fn set_status(&mut self, status: ngx_uint_t) {
+        self.0.headers_out.status = status;
+        self.0.headers_out.status_line.len = 0;
+    }
+
+    pub fn set_content_length(&mut self, len: usize) {
+        self.set_content_length_missing();
+        self.0.headers_out.content_length_n = len as off_t;
     }
 
     pub fn set_content_length_missing(&mut self) {
@@ -166,12 +704,260 @@ impl Request {
         if !self.0.headers_out.content_length.is_null() {
             unsafe {
                 (*self.0.headers_out.content_length).hash = 0;
-                (*self.0.headers_out.content_length).next = std::ptr::null_mut();
+                unlink_header(self.0.headers_out.content_length);
             }
             self.0.headers_out.content_length = std::ptr::null_mut();
         }
     }
 
+    /// Tell the protocol filters trailers may follow the body, which makes HTTP/1.1
+    /// responses chunked even when their length is known. HTTP/2 and HTTP/3 send them in
+    /// a header frame after the data, HTTP/1.0 can't send them at all.
+    pub fn set_expect_trailers(&mut self, expect: bool) {
+        self.0.set_expect_trailers(expect as u32);
+    }
+
+    /// Add a trailer, sent after the last chunk of a chunked, HTTP/2 or HTTP/3 response.
+    pub fn add_trailer(&mut self, key: ngx_str_t, value: ngx_str_t) -> bool {
+        push_header(&mut self.0.headers_out.trailers, key, value)
+    }
+
+    /// Add a trailer whose value is copied to the request pool.
+    pub fn add_trailer_value(&mut self, key: ngx_str_t, value: &str) -> bool {
+        match self.pool_str(value) {
+            Some(value) => push_header(&mut self.0.headers_out.trailers, key, value),
+            None => false,
+        }
+    }
+
+    /// Add a response header.
+    pub fn add_header(&mut self, key: ngx_str_t, value: ngx_str_t) -> bool {
+        push_header(&mut self.0.headers_out.headers, key, value)
+    }
+
+    /// Add a response header whose value is copied to the request pool.
+    pub fn add_header_value(&mut self, key: ngx_str_t, value: &str) -> bool {
+        match self.pool_str(value) {
+            Some(value) => push_header(&mut self.0.headers_out.headers, key, value),
+            None => false,
+        }
+    }
+
+    /// Replace the value of the `key` response header, the first one if there are
+    /// several, with a copy in the request pool. False when there is no such header.
+    pub fn set_header_value(&mut self, key: &str, value: &str) -> bool {
+        let h = match self.find_header_out(key) {
+            Some(h) => h as *const ngx_table_elt_t as *mut ngx_table_elt_t,
+            None => return false,
+        };
+        match self.pool_str(value) {
+            Some(value) => {
+                unsafe { (*h).value = value };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the `key` response header, the first one if there are several.
+    pub fn remove_header(&mut self, key: &str) -> bool {
+        match self.find_header_out(key) {
+            Some(h) => {
+                // the header filter skips headers with a zero hash
+                unsafe { (*(h as *const ngx_table_elt_t as *mut ngx_table_elt_t)).hash = 0 };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop `immutable` from the `Cache-Control` of the response, which must not be
+    /// cached forever, see `strip_immutable`.
+    pub fn strip_immutable(&mut self) -> bool {
+        match self.header_out("Cache-Control").and_then(strip_immutable) {
+            Some(value) if value.is_empty() => self.remove_header("Cache-Control"),
+            Some(value) => self.set_header_value("Cache-Control", &value),
+            None => true,
+        }
+    }
+
+    /// Give the response of a CAR request the ETag of the filtered CAR, see
+    /// `filtered_etag`, or none when the upstream one is malformed. `Last-Modified` is
+    /// kept, the filtered CAR changes along with the upstream one.
+    pub fn set_filtered_etag(&mut self, key: &str) -> bool {
+        let etag = match self.header_out("ETag") {
+            Some(etag) => filtered_etag(etag, key),
+            None => return true,
+        };
+        match etag {
+            Some(etag) => self.set_etag(&etag),
+            None => {
+                self.remove_header("ETag");
+                self.0.headers_out.etag = std::ptr::null_mut();
+                true
+            }
+        }
+    }
+
+    /// The `ETag` [`Request::set_filtered_etag`] gives the response, none when the
+    /// upstream one is missing or malformed.
+    pub fn filtered_etag(&self, key: &str) -> Option<String> {
+        filtered_etag(self.header_out("ETag")?, key)
+    }
+
+    /// Replace the `ETag` of the response, or add it.
+    pub fn set_etag(&mut self, etag: &str) -> bool {
+        if self.has_header_out("ETag") {
+            return self.set_header_value("ETag", etag);
+        }
+        if !self.add_header_value(ngx_string!("ETag"), etag) {
+            return false;
+        }
+        // for the filters after this one, like the one of byte ranges
+        self.0.headers_out.etag = self
+            .find_header_out("ETag")
+            .map_or(std::ptr::null_mut(), |h| {
+                h as *const ngx_table_elt_t as *mut ngx_table_elt_t
+            });
+        true
+    }
+
+    /// Whether a conditional GET or HEAD is answered with a 304: with `If-None-Match`
+    /// when it lists the `ETag` of the response, otherwise with `If-Modified-Since` when
+    /// it isn't before its `Last-Modified`.
+    pub fn is_not_modified(&self) -> bool {
+        self.is_not_modified_with(self.header_out("ETag"))
+    }
+
+    /// Like [`Request::is_not_modified`] with `etag` as the `ETag` of the response.
+    pub fn is_not_modified_with(&self, etag: Option<&str>) -> bool {
+        if self.0.method & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+            return false;
+        }
+        if let Some(if_none_match) = self.header_in("If-None-Match") {
+            return match etag {
+                Some(etag) => etag_matches(if_none_match, etag),
+                None => if_none_match.trim() == "*",
+            };
+        }
+        let since = match self.header_in("If-Modified-Since") {
+            Some(since) => since,
+            None => return false,
+        };
+        let modified = self.0.headers_out.last_modified_time;
+        if modified == -1 {
+            return false;
+        }
+        let since = unsafe { ngx_parse_http_time(since.as_ptr() as *mut u_char, since.len()) };
+        since != NGX_ERROR as time_t && since >= modified
+    }
+
+    /// Turn the response into a 304 without the headers of its body, like the not
+    /// modified filter does. The header filters after this one make it header only.
+    pub fn set_not_modified(&mut self) {
+        self.set_status(NGX_HTTP_NOT_MODIFIED as ngx_uint_t);
+        self.0.headers_out.content_type.len = 0;
+        self.set_content_length_missing();
+        self.0.set_allow_ranges(0);
+        self.remove_header("Accept-Ranges");
+        self.0.headers_out.accept_ranges = std::ptr::null_mut();
+        self.remove_header("Content-Encoding");
+        self.0.headers_out.content_encoding = std::ptr::null_mut();
+    }
+
+    // a copy of `value` in the request pool
+    fn pool_str(&self, value: &str) -> Option<ngx_str_t> {
+        let data = self.pool().alloc_unaligned(value.len()) as *mut u8;
+        if data.is_null() {
+            return None;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), data, value.len()) };
+        Some(ngx_str_t {
+            len: value.len(),
+            data,
+        })
+    }
+
+    /// Whether the response, as received from upstream, has the `key` header.
+    pub fn has_header_out(&self, key: &str) -> bool {
+        self.find_header_out(key).is_some()
+    }
+
+    /// Value of the `key` response header, the first one if there are several.
+    pub fn header_out(&self, key: &str) -> Option<&str> {
+        self.find_header_out(key)?.value.to_str().ok()
+    }
+
+    fn find_header_out(&self, key: &str) -> Option<&ngx_table_elt_t> {
+        find_header(&self.0.headers_out.headers, key)
+    }
+
+    /// Value of the `key` request header, the first one if there are several.
+    pub fn header_in(&self, key: &str) -> Option<&str> {
+        find_header(&self.0.headers_in.headers, key)?
+            .value
+            .to_str()
+            .ok()
+    }
+
+    /// The bytes of the filtered CAR the `Range` header asks for, see `parse_byte_range`.
+    /// With `If-Range`, only when it is the strong `ETag` of the response.
+    pub fn byte_range(&self) -> Option<(u64, u64)> {
+        self.byte_range_with(self.header_out("ETag"))
+    }
+
+    /// Like [`Request::byte_range`] with `etag` as the `ETag` of the response.
+    pub fn byte_range_with(&self, etag: Option<&str>) -> Option<(u64, u64)> {
+        if let Some(if_range) = self.header_in("If-Range") {
+            let etag = etag?;
+            if etag.starts_with("W/") || if_range.trim() != etag {
+                return None;
+            }
+        }
+        parse_byte_range(self.header_in("Range")?)
+    }
+
+    /// Stop reading the upstream response as if it were complete, which closes the
+    /// connection rather than keeping it alive. False when there is no upstream, or the
+    /// response is cached or stored, which needs all of it.
+    pub fn stop_upstream(&mut self) -> bool {
+        let u = self.0.upstream;
+        if u.is_null() {
+            return false;
+        }
+        unsafe {
+            if (*u).cacheable() == 1 || (*u).store() == 1 || (*u).peer.connection.is_null() {
+                return false;
+            }
+            (*u).set_keepalive(0);
+            if (*u).buffering() == 1 && !(*u).pipe.is_null() {
+                // the pipe stops reading and the upstream is finalized once it returns
+                (*(*u).pipe).set_upstream_done(1);
+            } else {
+                (*u).length = 0;
+            }
+        }
+        true
+    }
+
+    /// Keep the response out of `proxy_cache`. Only effective until the upstream
+    /// response header is sent, which is when nginx decides to cache it.
+    pub fn set_uncacheable(&mut self) {
+        if !self.0.upstream.is_null() {
+            unsafe { (*self.0.upstream).set_cacheable(0) };
+        }
+    }
+
+    /// Media type of the response, without its parameters.
+    pub fn content_type(&self) -> &str {
+        let ct = self.0.headers_out.content_type;
+        if ct.len == 0 {
+            return "";
+        }
+        ct.to_str()
+            .map_or("", |ct| ct.split(';').next().unwrap_or("").trim())
+    }
+
     pub fn set_content_type(&mut self, ct: ngx_str_t) {
         self.0.headers_out.content_type = ct;
     }
@@ -191,22 +977,1176 @@ impl Request {
         let buffered = self.0.buffered();
         self.0.set_buffered(buffered & !64);
     }
+
+    pub fn method(&self) -> ngx_uint_t {
+        self.0.method
+    }
+
+    pub fn discard_request_body(&mut self) -> ngx_int_t {
+        unsafe { ngx_http_discard_request_body(&mut self.0) }
+    }
+
+    /// Send a complete in-memory response with the given status, content type and body.
+    pub fn send_response(&mut self, status: ngx_uint_t, ct: ngx_str_t, body: &[u8]) -> ngx_int_t {
+        self.0.headers_out.status = status;
+        self.0.headers_out.content_length_n = body.len() as off_t;
+        self.set_content_type(ct);
+
+        let rc = unsafe { ngx_http_send_header(&mut self.0) };
+        if rc == NGX_ERROR as ngx_int_t || rc > NGX_OK as ngx_int_t || self.0.header_only() == 1 {
+            return rc;
+        }
+
+        let b = unsafe { ngx_create_temp_buf(self.0.pool, body.len()) };
+        if b.is_null() {
+            return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(body.as_ptr(), (*b).last, body.len());
+            (*b).last = (*b).last.add(body.len());
+            (*b).set_last_buf(if self.0.main == &mut self.0 as *mut _ {
+                1
+            } else {
+                0
+            });
+            (*b).set_last_in_chain(1);
+        }
+
+        let mut out = ngx_chain_t {
+            buf: b,
+            next: std::ptr::null_mut(),
+        };
+        unsafe { ngx_http_output_filter(&mut self.0, &mut out) }
+    }
+}
+
+// the `key` header of the list, matched ignoring its case
+fn find_header<'a>(list: &'a ngx_list_t, key: &str) -> Option<&'a ngx_table_elt_t> {
+    let mut part: *const ngx_list_part_t = &list.part;
+    while !part.is_null() {
+        let headers = unsafe {
+            if (*part).elts.is_null() {
+                return None;
+            }
+            std::slice::from_raw_parts((*part).elts as *const ngx_table_elt_t, (*part).nelts)
+        };
+        for h in headers {
+            // removed headers have a zero hash
+            if h.hash != 0 && h.key.to_str().is_ok_and(|k| k.eq_ignore_ascii_case(key)) {
+                return Some(h);
+            }
+        }
+        part = unsafe { (*part).next };
+    }
+    None
+}
+
+fn push_header(list: &mut ngx_list_t, key: ngx_str_t, value: ngx_str_t) -> bool {
+    let h = unsafe { ngx_list_push(list) } as *mut ngx_table_elt_t;
+    if h.is_null() {
+        return false;
+    }
+    unsafe {
+        (*h).hash = 1;
+        (*h).key = key;
+        (*h).value = value;
+        (*h).lowcase_key = std::ptr::null_mut();
+        unlink_header(h);
+    }
+    true
 }
 
+// Unlink the header from the next ones of its name, nginx 1.23 and later chain them and
+// older ones have no link.
+#[cfg(ngx_header_next)]
+unsafe fn unlink_header(h: *mut ngx_table_elt_t) {
+    (*h).next = std::ptr::null_mut();
+}
+
+#[cfg(not(ngx_header_next))]
+unsafe fn unlink_header(_h: *mut ngx_table_elt_t) {}
+
+/// A request with the headers, path and query string set by a test, along with the
+/// status and headers of the upstream response, for the lookups of [`Request`] and to
+/// filter a CAR end to end with [`MockRequest::filter`] without nginx. The request
+/// headers are split in list parts of a few elements like nginx allocates them, so
+/// lookups have to follow the parts.
+///
+/// ```ignore
+/// let mut r = MockRequest::new()
+///     .header("accept", "application/vnd.ipld.car")
+///     .args("dag-scope=entity&entity-bytes=0:100");
+/// assert!(r.request().accept_car());
+/// let out = r.filter(&conf, &car, 4096);
+/// ```
 #[cfg(test)]
-mod tests {
-    use super::*;
+pub struct MockRequest {
+    headers: Vec<(String, String)>,
+    uri: String,
+    args: String,
+    // the URI the client requested before an internal redirect
+    client_uri: Option<String>,
+    part_len: usize,
+    head: bool,
+    status: ngx_uint_t,
+    headers_out: Vec<(String, String)>,
+    r: Box<ngx_http_request_t>,
+    // the parts of the header list after the one in it, and their elements
+    parts: Vec<ngx_list_part_t>,
+    elts: Vec<Vec<ngx_table_elt_t>>,
+    elts_out: Vec<ngx_table_elt_t>,
+    strings: Vec<Vec<u8>>,
+}
 
-    #[test]
-    fn test_parse_range() {
-        assert_eq!(
-            parse_range("entity-bytes=0:100").unwrap(),
-            (Bound::Included(0), Bound::Included(100))
-        );
+#[cfg(test)]
+impl MockRequest {
+    pub fn new() -> Self {
+        Self {
+            headers: Vec::new(),
+            uri: "/".to_string(),
+            args: String::new(),
+            client_uri: None,
+            // the size of the parts of the request headers list
+            part_len: 20,
+            head: false,
+            status: NGX_HTTP_OK as ngx_uint_t,
+            headers_out: Vec::new(),
+            r: Box::new(unsafe { std::mem::zeroed() }),
+            parts: Vec::new(),
+            elts: Vec::new(),
+            elts_out: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
 
+    /// Add a request header, names keep their case.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn uri(mut self, uri: &str) -> Self {
+        self.uri = uri.to_string();
+        self
+    }
+
+    /// The query string, without the `?`.
+    pub fn args(mut self, args: &str) -> Self {
+        self.args = args.to_string();
+        self
+    }
+
+    /// Make the request internally redirected from `uri`, with its query string.
+    pub fn redirected_from(mut self, uri: &str) -> Self {
+        self.client_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Make the request a HEAD one.
+    pub fn head(mut self) -> Self {
+        self.head = true;
+        self
+    }
+
+    /// The status of the upstream response, 200 by default.
+    pub fn status(mut self, status: u32) -> Self {
+        self.status = status as ngx_uint_t;
+        self
+    }
+
+    /// Add a header of the upstream response, `Content-Type`, `Content-Length` and
+    /// `Content-Encoding` included.
+    pub fn header_out(mut self, name: &str, value: &str) -> Self {
+        self.headers_out.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Split the headers in list parts of `len` elements.
+    pub fn part_len(mut self, len: usize) -> Self {
+        assert!(len > 0);
+        self.part_len = len;
+        self
+    }
+
+    // a string pointing into bytes owned by the mock, never null
+    fn ngx_str(strings: &mut Vec<Vec<u8>>, s: &[u8]) -> ngx_str_t {
+        let mut bytes = s.to_vec();
+        bytes.push(0);
+        let data = bytes.as_mut_ptr();
+        strings.push(bytes);
+        ngx_str_t { len: s.len(), data }
+    }
+
+    // a header pointing into bytes owned by the mock
+    fn table_elt(strings: &mut Vec<Vec<u8>>, name: &str, value: &str) -> ngx_table_elt_t {
+        let mut h: ngx_table_elt_t = unsafe { std::mem::zeroed() };
+        h.hash = 1;
+        h.key = Self::ngx_str(strings, name.as_bytes());
+        h.value = Self::ngx_str(strings, value.as_bytes());
+        h.lowcase_key = Self::ngx_str(strings, name.to_ascii_lowercase().as_bytes()).data;
+        h
+    }
+
+    /// The request, its header lists rebuilt from the headers added.
+    pub fn request(&mut self) -> &mut Request {
+        let strings = &mut self.strings;
+        strings.clear();
+        self.elts = self
+            .headers
+            .chunks(self.part_len)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|(name, value)| Self::table_elt(strings, name, value))
+                    .collect()
+            })
+            .collect();
+        self.elts_out = self
+            .headers_out
+            .iter()
+            .map(|(name, value)| Self::table_elt(strings, name, value))
+            .collect();
+        if self.elts.is_empty() {
+            self.elts.push(Vec::new());
+        }
+
+        let part = |elts: &mut Vec<ngx_table_elt_t>| ngx_list_part_t {
+            elts: elts.as_mut_ptr() as *mut std::os::raw::c_void,
+            nelts: elts.len(),
+            next: std::ptr::null_mut(),
+        };
+        let (first, rest) = self.elts.split_first_mut().unwrap();
+        // the parts don't move once linked, the vector is never grown after this
+        self.parts = rest.iter_mut().map(part).collect();
+        for i in 1..self.parts.len() {
+            let next: *mut ngx_list_part_t = &mut self.parts[i];
+            self.parts[i - 1].next = next;
+        }
+
+        *self.r = unsafe { std::mem::zeroed() };
+        let headers = &mut self.r.headers_in.headers;
+        headers.part = part(first);
+        headers.part.next = self
+            .parts
+            .first_mut()
+            .map_or(std::ptr::null_mut(), |p| p as *mut _);
+        headers.last = match self.parts.last_mut() {
+            Some(last) => last,
+            None => &mut headers.part,
+        };
+        headers.size = std::mem::size_of::<ngx_table_elt_t>();
+        headers.nalloc = self.part_len;
+        self.r.uri = Self::ngx_str(strings, self.uri.as_bytes());
+        self.r.args = Self::ngx_str(strings, self.args.as_bytes());
+        let unparsed_uri = match &self.client_uri {
+            Some(uri) => {
+                self.r.set_internal(1);
+                uri.clone()
+            }
+            None if self.args.is_empty() => self.uri.clone(),
+            None => format!("{}?{}", self.uri, self.args),
+        };
+        self.r.unparsed_uri = Self::ngx_str(strings, unparsed_uri.as_bytes());
+        let r: *mut ngx_http_request_t = &mut *self.r;
+        self.r.main = r;
+        if self.head {
+            self.r.method = NGX_HTTP_HEAD as ngx_uint_t;
+            self.r.set_header_only(1);
+        } else {
+            self.r.method = NGX_HTTP_GET as ngx_uint_t;
+        }
+
+        // the upstream response, in a single part
+        let out = &mut self.r.headers_out;
+        out.status = self.status;
+        out.content_length_n = -1;
+        out.last_modified_time = -1;
+        out.headers.part = ngx_list_part_t {
+            elts: self.elts_out.as_mut_ptr() as *mut std::os::raw::c_void,
+            nelts: self.elts_out.len(),
+            next: std::ptr::null_mut(),
+        };
+        out.headers.last = &mut out.headers.part;
+        out.headers.size = std::mem::size_of::<ngx_table_elt_t>();
+        out.headers.nalloc = self.elts_out.len();
+        for h in self.elts_out.iter_mut() {
+            let name = h.key.to_str().unwrap_or("");
+            if name.eq_ignore_ascii_case("Content-Type") {
+                out.content_type = h.value;
+            } else if name.eq_ignore_ascii_case("Content-Length") {
+                out.content_length_n = h
+                    .value
+                    .to_str()
+                    .ok()
+                    .and_then(|len| len.parse().ok())
+                    .unwrap_or(-1);
+            } else if name.eq_ignore_ascii_case("Content-Encoding") {
+                out.content_encoding = h;
+            }
+        }
+
+        unsafe { Request::from_ngx_http_request(r) }
+    }
+
+    /// Filter `car` as the upstream response to the request, in buffers of `chunk` bytes,
+    /// the way the header filter decides to in a location configured with `conf`, see
+    /// [`decide`](crate::module::decide). Responses the module passes on get the upstream
+    /// CAR back untouched, the ones it answers without a body an empty one.
+    pub fn filter(&mut self, conf: &crate::config::LocConf, car: &[u8], chunk: usize) -> Vec<u8> {
+        use crate::module::{decide, Decision};
+        use crate::pool::{Buffer, MemoryBuffer};
+        use crate::testing::{chain, last, mem, TestPool};
+
+        let req = self.request();
+        let filter = match decide(req, conf) {
+            Decision::Filter(filter) if !filter.header_only => filter,
+            Decision::Skip(_) | Decision::Filtered | Decision::Compressed => return car.to_vec(),
+            Decision::Raw(_) => unimplemented!("raw blocks aren't cut out by the mock"),
+            _ => return Vec::new(),
+        };
+        let mut ctx = filter.context(conf, req.content_length(), TestPool::new());
+
+        let mut out = Vec::new();
+        let mut chunks = car.chunks(chunk).peekable();
+        while let Some(chunk) = chunks.next() {
+            if ctx.done() {
+                break;
+            }
+            // the last buffer of the response comes with the end of the CAR
+            let chain = if chunks.peek().is_none() {
+                chain![mem(chunk), last()]
+            } else {
+                chain![mem(chunk)]
+            };
+            let Ok(sent) = ctx.buffer(chain.as_ptr()) else {
+                break;
+            };
+            // the next filters send the whole output, which releases the upstream buffers
+            let mut o = sent;
+            while !o.is_null() {
+                let mut b = MemoryBuffer::from_ngx_buf(unsafe { (*o).buf });
+                out.extend_from_slice(b.as_bytes());
+                b.consume();
+                o = unsafe { (*o).next };
+            }
+            ctx.update_chains(sent);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+impl Default for MockRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocConf;
+    use crate::testing::Builder;
+
+    #[test]
+    fn test_parse_arg() {
+        assert_eq!(
+            parse_arg("entity-bytes=0:100&dag-scope=entity", "dag-scope").as_deref(),
+            Some("entity")
+        );
+        assert_eq!(parse_arg("entity-bytes=0:100", "dag-scope"), None);
+    }
+
+    #[test]
+    fn test_strip_args() {
+        let range = ["entity-bytes"];
+        assert_eq!(
+            strip_args("entity-bytes=0:100&dag-scope=entity", &range),
+            "dag-scope=entity"
+        );
+        assert_eq!(
+            strip_args("format=car&entity-bytes=0:*&car-dups=n", &range),
+            "format=car&car-dups=n"
+        );
+        assert_eq!(strip_args("entity-bytes=0:100", &range), "");
+        assert_eq!(strip_args("entity-bytes-x=1&", &range), "entity-bytes-x=1");
+        assert_eq!(
+            strip_args(
+                "token=a:b,c&entity-bytes=0:1&expires=1&token=d&format=car",
+                &["entity-bytes", "token", "expires"]
+            ),
+            "format=car"
+        );
+    }
+
+    // X-Accel-Redirect replaces the query string, the range is the one of the client
+    #[test]
+    fn test_redirected_args() {
+        let mut r = MockRequest::new()
+            .uri("/internal/bafy.car")
+            .redirected_from("/ipfs/bafy?entity-bytes=10:20&dag-scope=entity");
+        assert_eq!(
+            r.request().range(),
+            Some((Bound::Included(10), Bound::Included(20)))
+        );
+        assert_eq!(r.request().arg("dag-scope").as_deref(), Some("entity"));
+
+        let mut r = MockRequest::new()
+            .uri("/internal/bafy.car")
+            .args("entity-bytes=0:*")
+            .redirected_from("/ipfs/bafy?entity-bytes=10:20");
+        assert_eq!(
+            r.request().range(),
+            Some((Bound::Included(0), Bound::Unbounded))
+        );
+
+        // the end counts from the end of the entity only in the query string of the range
+        let mut r = MockRequest::new()
+            .uri("/internal/bafy.car")
+            .args("entity-bytes=0:*")
+            .redirected_from("/ipfs/bafy?entity-bytes=0:-10");
         assert_eq!(
-            parse_range("entity-bytes=1024:*").unwrap(),
-            (Bound::Included(1024), Bound::Unbounded)
+            r.request().range_from_end(RangeSource::Args),
+            FromEnd::default()
         );
+        let mut r = MockRequest::new()
+            .uri("/internal/bafy.car")
+            .redirected_from("/ipfs/bafy?entity-bytes=0:-10");
+        assert_eq!(
+            r.request().range_from_end(RangeSource::Args),
+            FromEnd {
+                start: None,
+                end: Some(10)
+            }
+        );
+
+        // only redirected requests fall back to the URI of the client
+        let mut r = MockRequest::new().uri("/ipfs/bafy");
+        let req = r.request();
+        req.0.unparsed_uri = ngx_string!("/ipfs/bafy?entity-bytes=10:20");
+        assert_eq!(req.range(), None);
+        let mut r = MockRequest::new()
+            .uri("/internal/bafy.car")
+            .redirected_from("/ipfs/bafy");
+        assert_eq!(r.request().range(), None);
+    }
+
+    #[test]
+    fn test_canonical_args() {
+        assert_eq!(
+            canonical_args("entity-bytes=0:100&dag-scope=entity", &[]),
+            "dag-scope=entity&entity-bytes=0:100"
+        );
+        assert_eq!(
+            canonical_args("format=car&entity-bytes=%200%3A*&x=1", &[]),
+            "entity-bytes=0:*&format=car&x=1"
+        );
+        // the legacy parameters only count when the canonical ones are missing
+        assert_eq!(
+            canonical_args("bytes=10:20&car-scope=file", &[]),
+            "dag-scope=entity&entity-bytes=10:20"
+        );
+        assert_eq!(
+            canonical_args("depth=0&bytes=1:2&entity-bytes=3:4&dag-scope=all", &[]),
+            "dag-scope=all&entity-bytes=3:4"
+        );
+        assert_eq!(
+            canonical_args("depth=7&car-scope=all", &[]),
+            "dag-scope=all"
+        );
+        assert_eq!(
+            canonical_args("entity-bytes=a:b", &[]),
+            "entity-bytes=a%3Ab"
+        );
+        assert_eq!(
+            canonical_args("entity-bytes=0%3A-1024", &[]),
+            "entity-bytes=0:-1024"
+        );
+        assert_eq!(
+            canonical_args("entity-bytes=-1024:*", &[]),
+            "entity-bytes=-1024:*"
+        );
+        assert_eq!(canonical_args("", &[]), "");
+        assert_eq!(
+            canonical_args("st=x:y&entity-bytes=1:2&e=1&format=car", &["st", "e"]),
+            "entity-bytes=1:2&format=car"
+        );
+    }
+
+    #[test]
+    fn test_parse_dag_scope() {
+        assert_eq!(
+            parse_dag_scope("entity-bytes=0:100&dag-scope=entity"),
+            Some(DagScope::Entity)
+        );
+        assert_eq!(parse_dag_scope("dag-scope=block"), Some(DagScope::Block));
+        assert_eq!(parse_dag_scope("dag-scope=all"), Some(DagScope::All));
+        assert_eq!(parse_dag_scope("dag-scope=files"), None);
+        assert_eq!(parse_dag_scope("entity-bytes=0:100"), None);
+        // the legacy parameters only count when dag-scope is missing
+        assert_eq!(parse_dag_scope("depth=0"), Some(DagScope::Block));
+        assert_eq!(
+            parse_dag_scope("car-scope=file&dag-scope=all"),
+            Some(DagScope::All)
+        );
+
+        let mut r = MockRequest::new().args("format=car");
+        assert_eq!(r.request().dag_scope(), DagScope::All);
+        let mut r = MockRequest::new().args("car-scope=block");
+        assert_eq!(r.request().dag_scope(), DagScope::Block);
+    }
+
+    // ranges of the same CAR never share a key, unless the entry is the whole CAR
+    #[test]
+    fn test_cache_key() {
+        let range = Some((Bound::Included(0), Bound::Unbounded));
+        assert_eq!(
+            cache_key(Some("entity"), range, FromEnd::default(), false),
+            "car;dag-scope=entity;entity-bytes=0:*"
+        );
+        assert_eq!(
+            cache_key(
+                Some("entity"),
+                range,
+                FromEnd {
+                    start: None,
+                    end: Some(10)
+                },
+                false
+            ),
+            "car;dag-scope=entity;entity-bytes=0:-10"
+        );
+        assert_eq!(
+            cache_key(None, None, FromEnd::default(), false),
+            "car;dag-scope=all"
+        );
+        assert_eq!(
+            cache_key(Some("entity"), range, FromEnd::default(), true),
+            "car;dag-scope=entity"
+        );
+
+        let args = [RangeSource::Args];
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .args("entity-bytes=100:200");
+        assert_eq!(
+            r.request().cache_key(false, &args),
+            "car;dag-scope=all;entity-bytes=100:200"
+        );
+        assert_eq!(r.request().cache_key(true, &args), "car;dag-scope=all");
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .args("entity-bytes=100:-1");
+        assert_eq!(
+            r.request().cache_key(false, &args),
+            "car;dag-scope=all;entity-bytes=100:-1"
+        );
+        assert_eq!(
+            r.request().range_from_end(RangeSource::Args),
+            FromEnd {
+                start: None,
+                end: Some(1)
+            }
+        );
+        assert_eq!(
+            r.request().range_from_end(RangeSource::Header),
+            FromEnd::default()
+        );
+        let mut r = MockRequest::new().args("entity-bytes=100:200");
+        assert_eq!(r.request().cache_key(false, &args), "");
+
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .header("Range", "bytes=100-");
+        assert_eq!(r.request().cache_key(false, &args), "car;dag-scope=all");
+        assert_eq!(
+            r.request().cache_key(false, &[RangeSource::Header]),
+            "car;dag-scope=all;entity-bytes=100:*"
+        );
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .header("Range", "bytes=-500");
+        assert_eq!(
+            r.request().cache_key(false, &[RangeSource::Header]),
+            "car;dag-scope=all;entity-bytes=-500:*"
+        );
+
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car; version=2")
+            .args("entity-bytes=100:200");
+        assert_eq!(
+            r.request().cache_key(false, &args),
+            "car;dag-scope=all;entity-bytes=100:200;version=2"
+        );
+        assert_eq!(r.request().cache_key(true, &args), "car;dag-scope=all");
+    }
+
+    #[test]
+    fn test_is_compressed() {
+        assert!(is_compressed("gzip"));
+        assert!(is_compressed("br"));
+        assert!(!is_compressed("identity"));
+        assert!(!is_compressed(" Identity"));
+        assert!(!is_compressed(""));
+    }
+
+    #[test]
+    fn test_is_streaming() {
+        let mut r = MockRequest::new().header("Upgrade", "websocket");
+        let req = r.request();
+        req.set_status(NGX_HTTP_OK as ngx_uint_t);
+        assert!(!req.is_streaming());
+        req.set_status(NGX_HTTP_SWITCHING_PROTOCOLS as ngx_uint_t);
+        assert!(req.is_streaming());
+
+        let mut r = MockRequest::new();
+        let req = r.request();
+        req.set_status(NGX_HTTP_OK as ngx_uint_t);
+        req.set_content_type(ngx_string!("text/event-stream; charset=utf-8"));
+        assert!(req.is_streaming());
+        req.set_content_type(ngx_string!("application/vnd.ipld.car"));
+        assert!(!req.is_streaming());
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range("bytes=0-1023"), Some((0, 1023)));
+        assert_eq!(parse_byte_range(" Bytes = 100 - 100 "), Some((100, 100)));
+        assert_eq!(parse_byte_range("bytes=100-"), None);
+        assert_eq!(parse_byte_range("bytes=-100"), None);
+        assert_eq!(parse_byte_range("bytes=0-99,200-299"), None);
+        assert_eq!(parse_byte_range("bytes=100-99"), None);
+        assert_eq!(parse_byte_range("items=0-99"), None);
+        assert_eq!(parse_byte_range("bytes=0-18446744073709551615"), None);
+    }
+
+    #[test]
+    fn test_parse_range_header() {
+        assert_eq!(
+            parse_range_header("bytes=0-1023"),
+            Some((
+                (Bound::Included(0), Bound::Included(1023)),
+                FromEnd::default()
+            ))
+        );
+        assert_eq!(
+            parse_range_header(" Bytes = 100 - "),
+            Some(((Bound::Included(100), Bound::Unbounded), FromEnd::default()))
+        );
+        assert_eq!(
+            parse_range_header("bytes=-100"),
+            Some((
+                (Bound::Unbounded, Bound::Unbounded),
+                FromEnd {
+                    start: Some(100),
+                    end: None
+                }
+            ))
+        );
+        assert_eq!(parse_range_header("bytes=-0"), None);
+        assert_eq!(parse_range_header("bytes=-100,200-299"), None);
+        assert_eq!(parse_range_header("bytes=0-99,200-299"), None);
+        assert_eq!(parse_range_header("bytes=100-99"), None);
+        assert_eq!(parse_range_header("items=0-99"), None);
+    }
+
+    // the first source with a range wins
+    #[test]
+    fn test_entity_range() {
+        let both = [RangeSource::Header, RangeSource::Args];
+        let mut r = MockRequest::new()
+            .header("Range", "bytes=10-20")
+            .args("entity-bytes=0:*");
+        assert_eq!(
+            r.request().entity_range(&both),
+            Some((
+                (Bound::Included(10), Bound::Included(20)),
+                RangeSource::Header
+            ))
+        );
+        assert_eq!(
+            r.request()
+                .entity_range(&[RangeSource::Args, RangeSource::Header]),
+            Some(((Bound::Included(0), Bound::Unbounded), RangeSource::Args))
+        );
+        let mut r = MockRequest::new()
+            .header("Range", "bytes=-20")
+            .args("entity-bytes=0:*");
+        assert_eq!(
+            r.request().entity_range(&both),
+            Some(((Bound::Included(0), Bound::Unbounded), RangeSource::Args))
+        );
+        let mut r = MockRequest::new().header("Range", "bytes=10-20");
+        assert_eq!(r.request().entity_range(&[RangeSource::Args]), None);
+    }
+
+    #[test]
+    fn test_content_range() {
+        let window = 100..200;
+        assert_eq!(
+            content_range(&window, 120, false).as_deref(),
+            Some("bytes 100-199/*")
+        );
+        assert_eq!(
+            content_range(&window, 500, true).as_deref(),
+            Some("bytes 100-199/500")
+        );
+        // the CAR ended in the window
+        assert_eq!(
+            content_range(&window, 150, true).as_deref(),
+            Some("bytes 100-149/150")
+        );
+        assert_eq!(content_range(&window, 100, true), None);
+        assert_eq!(content_range(&window, 50, true), None);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let mut r = MockRequest::new().header("range", "bytes=0-99");
+        assert_eq!(r.request().byte_range(), Some((0, 99)));
+        assert_eq!(r.request().header_in("Range"), Some("bytes=0-99"));
+        let mut r = MockRequest::new()
+            .header("Range", "bytes=0-99")
+            .header("If-Range", "\"bafy\"");
+        assert_eq!(r.request().byte_range(), None);
+        assert_eq!(MockRequest::new().request().byte_range(), None);
+    }
+
+    #[test]
+    fn test_parse_root_cid() {
+        assert_eq!(
+            parse_root_cid(
+                "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/a.jpg"
+            ),
+            Some("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi")
+        );
+        assert_eq!(parse_root_cid("/ipfs/"), None);
+        assert_eq!(parse_root_cid("/ipns/example.com"), None);
+    }
+
+    #[test]
+    fn test_has_root_cid() {
+        let mut r = MockRequest::new()
+            .uri("/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/a.jpg");
+        assert!(r.request().has_root_cid());
+        let mut r = MockRequest::new().uri("/ipfs/QmafUYju2Ab4ETi5HJG1cqjmnjs2xw9PUuBKzU7Hi3zvXU");
+        assert!(r.request().has_root_cid());
+        // a path that looks like one isn't enough
+        let mut r = MockRequest::new().uri("/ipfs/bafy/a.jpg");
+        assert!(!r.request().has_root_cid());
+        let mut r = MockRequest::new()
+            .uri("/static/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi");
+        assert!(!r.request().has_root_cid());
+    }
+
+    #[test]
+    fn test_parse_namespace() {
+        assert_eq!(
+            parse_namespace("/ipfs/bafy/a.jpg"),
+            (Namespace::Ipfs, "bafy/a.jpg")
+        );
+        assert_eq!(
+            parse_namespace("/ipns/example.com"),
+            (Namespace::Ipns, "example.com")
+        );
+        assert_eq!(parse_namespace("/ipnsfoo"), (Namespace::Other, "/ipnsfoo"));
+        assert_eq!(parse_namespace("/status"), (Namespace::Other, "/status"));
+
+        let mut r = MockRequest::new().uri("/ipns/example.com/a.jpg");
+        assert_eq!(r.request().namespace(), Namespace::Ipns);
+        let mut r = MockRequest::new().uri("/ipfs/bafy");
+        assert_eq!(r.request().namespace(), Namespace::Ipfs);
+    }
+
+    #[test]
+    fn test_escape_path() {
+        assert_eq!(escape_path("bafy/a.jpg"), "bafy/a.jpg");
+        assert_eq!(escape_path("bafy/a b?.jpg"), "bafy/a%20b%3F.jpg");
+        assert_eq!(escape_path("bafy/caf\u{e9}#1"), "bafy/caf%C3%A9%231");
+
+        let mut r = MockRequest::new().uri("/ipfs/bafy/a b.jpg");
+        assert_eq!(r.request().ipfs_path().as_deref(), Some("bafy/a%20b.jpg"));
+        assert_eq!(MockRequest::new().uri("/ipfs/").request().ipfs_path(), None);
+        assert_eq!(
+            MockRequest::new()
+                .uri("/ipns/example.com")
+                .request()
+                .ipfs_path(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_strip_immutable() {
+        assert_eq!(
+            strip_immutable("public, max-age=29030400, immutable").as_deref(),
+            Some("public, max-age=29030400")
+        );
+        assert_eq!(
+            strip_immutable("Immutable,public").as_deref(),
+            Some("public")
+        );
+        assert_eq!(strip_immutable("immutable").as_deref(), Some(""));
+        assert_eq!(strip_immutable("public, max-age=60"), None);
+        assert_eq!(strip_immutable("no-immutable"), None);
+    }
+
+    #[test]
+    fn test_filtered_etag() {
+        let key = "car;dag-scope=all;entity-bytes=0:1023";
+        assert_eq!(
+            filtered_etag("\"bafy.car\"", key).as_deref(),
+            Some("\"bafy.car.car;dag-scope=all;entity-bytes=0:1023\"")
+        );
+        assert_eq!(
+            filtered_etag(" W/\"bafy\"", "car;dag-scope=entity").as_deref(),
+            Some("W/\"bafy.car;dag-scope=entity\"")
+        );
+        assert_eq!(filtered_etag("bafy", key), None);
+        assert_eq!(filtered_etag("\"", key), None);
+        assert_eq!(filtered_etag("\"a\"b\"", key), None);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"a.car\"", "\"a.car\""));
+        assert!(etag_matches("\"b\", W/\"a\"", "\"a\""));
+        assert!(etag_matches("\"a\"", "W/\"a\""));
+        assert!(etag_matches(" * ", "\"a\""));
+        assert!(!etag_matches("\"b\"", "\"a\""));
+        assert!(!etag_matches("\"a.car\"", "\"a.car.car;dag-scope=all\""));
+    }
+
+    #[test]
+    fn test_parse_block_path() {
+        let cid = "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+        let want = Cid::try_from(cid).ok();
+        assert!(want.is_some());
+        assert_eq!(parse_block_path(&format!("/ipfs/{}", cid)), want);
+        assert_eq!(parse_block_path(&format!("/ipfs/{}/", cid)), want);
+        assert_eq!(parse_block_path(&format!("/ipfs/{}/a.jpg", cid)), None);
+        assert_eq!(parse_block_path("/ipfs/bogus"), None);
+        assert_eq!(parse_block_path(&format!("/ipns/{}", cid)), None);
+    }
+
+    #[test]
+    fn test_raw_block() {
+        let uri = "/ipfs/bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+        let mut r = MockRequest::new().uri(uri).args("format=raw");
+        assert!(r.request().raw_block().is_some());
+        let mut r = MockRequest::new()
+            .uri(uri)
+            .header("Accept", "application/vnd.ipld.raw");
+        assert!(r.request().raw_block().is_some());
+        let mut r = MockRequest::new()
+            .uri(uri)
+            .header("Accept", "application/vnd.ipld.car");
+        assert!(r.request().raw_block().is_none());
+    }
+
+    #[test]
+    fn test_accept_car() {
+        let car = "application/vnd.ipld.car";
+        assert!(MockRequest::new()
+            .header("Host", "localhost")
+            .header("Accept", car)
+            .request()
+            .accept_car());
+        // node and HTTP/2 clients send lowercase names
+        assert!(MockRequest::new()
+            .header("accept", car)
+            .request()
+            .accept_car());
+        assert!(MockRequest::new()
+            .header("ACCEPT", car)
+            .request()
+            .accept_car());
+
+        assert!(!MockRequest::new().request().accept_car());
+        assert!(!MockRequest::new()
+            .header("Accept", "application/json")
+            .request()
+            .accept_car());
+        assert!(!MockRequest::new()
+            .header("Accept-Encoding", car)
+            .request()
+            .accept_car());
+
+        // the query string stands for the header
+        assert!(MockRequest::new()
+            .args("format=car&entity-bytes=0:*")
+            .request()
+            .accept_car());
+        assert!(!MockRequest::new().args("format=raw").request().accept_car());
+
+        // the version of the CAR
+        let carv2 = |accept| {
+            MockRequest::new()
+                .header("Accept", accept)
+                .request()
+                .accept_carv2()
+        };
+        assert!(carv2("application/vnd.ipld.car; version=2"));
+        assert!(carv2(
+            "application/json, application/vnd.ipld.car;q=0.5;version=2"
+        ));
+        assert!(!carv2("application/vnd.ipld.car"));
+        assert!(!carv2("application/vnd.ipld.car; version=1"));
+        assert!(!carv2("application/vnd.ipld.car; version=2; q=0"));
+
+        // quality factors
+        assert!(MockRequest::new()
+            .header(
+                "Accept",
+                "application/vnd.ipld.car;q=0.9, application/json;q=0.1"
+            )
+            .request()
+            .accept_car());
+        assert!(!MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car;q=0")
+            .request()
+            .accept_car());
+    }
+
+    #[test]
+    fn test_media_param() {
+        let range = "application/vnd.ipld.car; version=2 ;Order=dfs;q=0.5";
+        assert_eq!(media_param(range, "version"), Some("2"));
+        assert_eq!(media_param(range, "order"), Some("dfs"));
+        assert_eq!(media_param(range, "dups"), None);
+        assert_eq!(media_param("application/vnd.ipld.car", "version"), None);
+        assert_eq!(
+            accepted(
+                "text/html, application/vnd.ipld.car;version=2",
+                "application/vnd.ipld.car"
+            ),
+            Some(" application/vnd.ipld.car;version=2")
+        );
+    }
+
+    #[test]
+    fn test_accepts() {
+        let car = "application/vnd.ipld.car";
+        assert!(accepts(car, car));
+        assert!(accepts(
+            "application/vnd.ipld.car;q=0.9, application/json;q=0.1",
+            car
+        ));
+        assert!(accepts("text/html, Application/Vnd.Ipld.Car ; Q=0.5", car));
+        assert!(accepts(
+            "application/vnd.ipld.car; version=1; order=dfs",
+            car
+        ));
+        assert!(accepts("application/vnd.ipld.car;q=1.000", car));
+
+        assert!(!accepts("application/vnd.ipld.car;q=0", car));
+        assert!(!accepts("application/vnd.ipld.car; q=0.000, */*", car));
+        assert!(!accepts("application/json;q=0.9", car));
+        assert!(!accepts("*/*", car));
+        assert!(!accepts("application/*", car));
+        assert!(!accepts("application/vnd.ipld.car;q=x", car));
+        assert!(!accepts("application/vnd.ipld.car;q=2", car));
+        assert!(!accepts("application/vnd.ipld.carx", car));
+        assert!(!accepts("", car));
+    }
+
+    // the Accept header is found in any part of the header list
+    #[test]
+    fn test_accept_car_parts() {
+        for len in 1..=4 {
+            let mut r = MockRequest::new().part_len(len);
+            for i in 0..7 {
+                r = r.header(&format!("X-Header-{}", i), "value");
+            }
+            let mut r = r.header("accept", "application/vnd.ipld.car");
+            assert!(r.request().accept_car(), "parts of {}", len);
+
+            let mut r = MockRequest::new()
+                .part_len(len)
+                .header("Accept", "text/html")
+                .header("X-Other", "application/vnd.ipld.car");
+            assert!(!r.request().accept_car(), "parts of {}", len);
+        }
+    }
+
+    #[test]
+    fn test_request_args() {
+        let mut r = MockRequest::new()
+            .uri("/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/a.jpg")
+            .args("dag-scope=entity&entity-bytes=100:*");
+        let req = r.request();
+        assert_eq!(req.range(), Some((Bound::Included(100), Bound::Unbounded)));
+        assert_eq!(req.arg("dag-scope").as_deref(), Some("entity"));
+        assert_eq!(
+            req.root_cid(),
+            Some("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi")
+        );
+
+        let mut r = MockRequest::new().args("entity-bytes=bogus");
+        assert_eq!(r.request().range(), None);
+        assert_eq!(r.request().arg("dag-scope"), None);
+    }
+
+    // the harness filters an upstream CAR end to end, with what the request asks for
+    #[test]
+    fn test_mock_filter() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let file = Builder::new().chunk(1000).fanout(3).build(&data);
+        let leaf = |offset: u64| {
+            let section = file
+                .sections
+                .iter()
+                .find(|s| s.data.as_ref().is_some_and(|data| data.start == offset))
+                .unwrap();
+            &file.car[section.bytes.clone()]
+        };
+        let sent = |out: &[u8], section: &[u8]| out.windows(section.len()).any(|w| w == section);
+        let conf = LocConf {
+            enabled: Some(true),
+            ..Default::default()
+        };
+
+        // neither a CAR request nor a range to filter
+        let mut r = MockRequest::new().args("entity-bytes=0:999");
+        assert_eq!(r.filter(&conf, &file.car, 700), file.car);
+        let mut r = MockRequest::new().args("format=car");
+        assert_eq!(r.filter(&conf, &file.car, 700), file.car);
+
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .args("dag-scope=entity&entity-bytes=0:999");
+        let out = r.filter(&conf, &file.car, 700);
+        assert!(out.starts_with(&file.car[file.header.clone()]));
+        assert!(sent(&out, leaf(0)));
+        assert!(!sent(&out, leaf(4000)));
+        // nor is a location without car_range
+        assert_eq!(r.filter(&LocConf::default(), &file.car, 700), file.car);
+
+        // a suffix of the file from the Range header
+        let mut r = MockRequest::new()
+            .header("Accept", "application/vnd.ipld.car")
+            .header("Range", "bytes=-1000")
+            .args("dag-scope=entity");
+        let header = LocConf {
+            enabled: Some(true),
+            range_sources: Some(vec![RangeSource::Header]),
+            ..Default::default()
+        };
+        let out = r.filter(&header, &file.car, 700);
+        assert!(sent(&out, leaf(4000)));
+        assert!(!sent(&out, leaf(0)));
+
+        // a block request gets the root block whatever the range
+        let mut r = MockRequest::new().args("format=car&dag-scope=block");
+        let root = &file.sections[0].bytes;
+        assert_eq!(r.filter(&conf, &file.car, 700), file.car[..root.end]);
+
+        // the upstream response decides too
+        let range = || {
+            MockRequest::new()
+                .header("Accept", "application/vnd.ipld.car")
+                .args("entity-bytes=0:999")
+        };
+        let mut r = range().header_out("Content-Encoding", "gzip");
+        assert_eq!(r.filter(&conf, &file.car, 700), file.car);
+        let mut r = range().header_out("X-Car-Range", "filtered");
+        assert_eq!(r.filter(&conf, &file.car, 700), file.car);
+        let mut r = range().head();
+        assert_eq!(r.filter(&conf, &file.car, 700), b"");
+    }
+
+    // the header filter and the mock take the same decisions
+    #[test]
+    fn test_decide() {
+        use crate::config::UpstreamApplied;
+        use crate::module::{decide, Decision};
+
+        let conf = LocConf {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let range = || {
+            MockRequest::new()
+                .uri("/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi")
+                .header("Accept", "application/vnd.ipld.car")
+                .args("entity-bytes=0:999")
+        };
+        let filter = |decision: Decision| match decision {
+            Decision::Filter(filter) => filter,
+            decision => panic!("{:?}", decision),
+        };
+
+        let f = filter(decide(range().request(), &conf));
+        assert_eq!(f.range, (Bound::Included(0), Bound::Included(999)));
+        assert_eq!(f.scope, DagScope::All);
+        assert!(!f.header_only && !f.carv2 && !f.mutable && !f.byte_ranges);
+        assert!(filter(decide(range().head().request(), &conf)).header_only);
+
+        assert_eq!(
+            decide(range().status(304).request(), &conf),
+            Decision::Revalidated
+        );
+        assert_eq!(
+            decide(range().status(101).request(), &conf),
+            Decision::Skip("upgrade or event stream")
+        );
+        assert_eq!(
+            decide(
+                range().header_out("Content-Encoding", "br").request(),
+                &conf
+            ),
+            Decision::Compressed
+        );
+        // the gunzip filter ran, or the encoding is a no-op
+        let mut r = range().header_out("Content-Encoding", "identity");
+        filter(decide(r.request(), &conf));
+
+        // the ETag of the filtered CAR is the one the client validates against
+        let mut r = range().header_out("ETag", "\"bafy\"");
+        let key = r.request().cache_key(false, conf.range_sources());
+        let etag = r.request().filtered_etag(&key).unwrap();
+        let mut r = r.header("If-None-Match", &etag);
+        assert_eq!(decide(r.request(), &conf), Decision::NotModified);
+        let mut r = range()
+            .header_out("ETag", "\"bafy\"")
+            .header("If-None-Match", "\"bafy\"");
+        filter(decide(r.request(), &conf));
+
+        // the range the origin applied
+        let applied = LocConf {
+            enabled: Some(true),
+            upstream_applied: Some(UpstreamApplied::Require),
+            ..Default::default()
+        };
+        assert_eq!(
+            decide(
+                range()
+                    .header_out("X-Entity-Bytes-Applied", "0:999")
+                    .request(),
+                &applied
+            ),
+            Decision::Skip("applied upstream")
+        );
+        assert_eq!(
+            decide(range().request(), &applied),
+            Decision::Mismatch {
+                applied: "-".to_string(),
+                requested: "0:999".to_string()
+            }
+        );
+
+        // byte ranges of the filtered CAR, only of a whole upstream response
+        let ranges = LocConf {
+            enabled: Some(true),
+            byte_ranges: Some(true),
+            ..Default::default()
+        };
+        let mut r = range().header("Range", "bytes=0-99");
+        let f = filter(decide(r.request(), &ranges));
+        assert!(f.byte_ranges);
+        assert_eq!(f.byte_range, Some((0, 99)));
+        let mut r = range().header("Range", "bytes=0-99").status(206);
+        assert_eq!(filter(decide(r.request(), &ranges)).byte_range, None);
+        assert_eq!(filter(decide(r.request(), &conf)).byte_range, None);
     }
 }
@@ -0,0 +1,201 @@
+use crate::pool::CACHE_LINE;
+use car_range_core::framed::Scratch;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::ops::Deref;
+
+// Each slot is large enough to stash a CID with a 64 byte digest, which is the largest
+// frame prefix the parser needs to buffer across nginx buffers. Slots are a multiple of
+// the cache line size so no two stashes share a line.
+const SLOT_SIZE: usize = 128;
+const SLOTS: usize = 1024;
+
+// Fixed scratch region allocated once per worker. Nginx workers are single threaded
+// so a thread local is enough to keep the free list consistent.
+struct Arena {
+    region: *mut u8,
+    free: Vec<usize>,
+}
+
+impl Arena {
+    fn layout() -> Layout {
+        Layout::from_size_align(SLOT_SIZE * SLOTS, CACHE_LINE).unwrap()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.region, Self::layout()) };
+    }
+}
+
+thread_local! {
+    static ARENA: RefCell<Option<Arena>> = const { RefCell::new(None) };
+}
+
+/// Allocate the worker scratch region, called from `init_process`.
+pub fn init() {
+    ARENA.with(|arena| {
+        let mut arena = arena.borrow_mut();
+        if arena.is_none() {
+            let region = unsafe { alloc::alloc_zeroed(Arena::layout()) };
+            if region.is_null() {
+                // parsers fall back to heap stashes
+                return;
+            }
+            *arena = Some(Arena {
+                region,
+                free: (0..SLOTS).rev().collect(),
+            });
+        }
+    });
+}
+
+fn borrow_slot() -> Option<(usize, *mut u8)> {
+    ARENA.with(|arena| {
+        let mut arena = arena.borrow_mut();
+        let arena = arena.as_mut()?;
+        let i = arena.free.pop()?;
+        let ptr = unsafe { arena.region.add(i * SLOT_SIZE) };
+        Some((i, ptr))
+    })
+}
+
+fn release_slot(i: usize) {
+    ARENA.with(|arena| {
+        if let Some(arena) = arena.borrow_mut().as_mut() {
+            arena.free.push(i);
+        }
+    });
+}
+
+/// Small byte stash borrowed from the worker scratch region.
+///
+/// Falls back to the heap when the region isn't initialized, every slot is taken or
+/// the stashed bytes outgrow a slot.
+pub struct Stash {
+    slot: Option<(usize, *mut u8)>,
+    len: usize,
+    heap: Vec<u8>,
+}
+
+impl Stash {
+    pub fn new() -> Self {
+        Self {
+            slot: borrow_slot(),
+            len: 0,
+            heap: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, b: u8) {
+        match self.slot {
+            Some((_, ptr)) if self.len < SLOT_SIZE => {
+                unsafe { *ptr.add(self.len) = b };
+                self.len += 1;
+            }
+            Some((i, ptr)) => {
+                // spill the slot to the heap and give it back
+                let stashed = unsafe { std::slice::from_raw_parts(ptr, self.len) };
+                self.heap.extend_from_slice(stashed);
+                self.heap.push(b);
+                self.slot = None;
+                self.len = 0;
+                release_slot(i);
+            }
+            None => self.heap.push(b),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.heap.clear();
+    }
+}
+
+impl Default for Stash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Stash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.slot {
+            Some((_, ptr)) => unsafe { std::slice::from_raw_parts(ptr, self.len) },
+            None => &self.heap[..],
+        }
+    }
+}
+
+impl Scratch for Stash {
+    fn push(&mut self, b: u8) {
+        Stash::push(self, b);
+    }
+
+    fn clear(&mut self) {
+        Stash::clear(self);
+    }
+}
+
+impl Drop for Stash {
+    fn drop(&mut self) {
+        if let Some((i, _)) = self.slot.take() {
+            release_slot(i);
+        }
+    }
+}
+
+/// Slots not borrowed by a stash.
+#[cfg(test)]
+pub fn free_slots() -> usize {
+    ARENA.with(|arena| arena.borrow().as_ref().map_or(0, |arena| arena.free.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stash_spills_to_heap() {
+        init();
+
+        let mut stash = Stash::new();
+        assert!(stash.slot.is_some());
+
+        for i in 0..SLOT_SIZE + 8 {
+            stash.push(i as u8);
+        }
+        assert!(stash.slot.is_none());
+        assert_eq!(stash.len(), SLOT_SIZE + 8);
+        assert_eq!(stash[SLOT_SIZE + 7], (SLOT_SIZE + 7) as u8);
+
+        stash.clear();
+        assert!(stash.is_empty());
+    }
+
+    #[test]
+    fn test_stash_slot_aligned() {
+        init();
+
+        let stash = Stash::new();
+        let (_, ptr) = stash.slot.unwrap();
+        assert_eq!(ptr as usize % CACHE_LINE, 0);
+    }
+
+    #[test]
+    fn test_stash_returns_slot() {
+        init();
+
+        let before = free_slots();
+        {
+            let mut stash = Stash::new();
+            stash.push(1);
+            assert_eq!(&stash[..], &[1]);
+            assert_eq!(free_slots(), before - 1);
+        }
+        assert_eq!(free_slots(), before);
+    }
+}
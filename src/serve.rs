@@ -0,0 +1,319 @@
+use crate::bindings::*;
+use crate::config::{conf_args, LocConf};
+use crate::inflight;
+use crate::log::ngx_log_error_http;
+use crate::module::{ngx_car_range_module, FilterContext};
+use crate::pool::{Allocator, Pool};
+use crate::request::*;
+use crate::timer;
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+/// `car_range_serve path;` serves the CAR at `path`, which may contain variables, from
+/// the location. Requests with a range go through the filter like upstream responses.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_car_range_serve_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    let conf = &mut *(conf as *mut LocConf);
+    if conf.serve.is_some() {
+        return "is duplicate\0".as_ptr() as *mut c_char;
+    }
+
+    let cv = Pool::from_ngx_pool((*cf).pool).calloc_type::<ngx_http_complex_value_t>();
+    if cv.is_null() {
+        return "failed to allocate\0".as_ptr() as *mut c_char;
+    }
+    let mut ccv: ngx_http_compile_complex_value_t = std::mem::zeroed();
+    ccv.cf = cf;
+    ccv.value = &conf_args(cf)[1] as *const _ as *mut ngx_str_t;
+    ccv.complex_value = cv;
+    ccv.set_zero(1);
+    ccv.set_conf_prefix(0);
+    ccv.set_root_prefix(0);
+    if ngx_http_compile_complex_value(&mut ccv) != NGX_OK as ngx_int_t {
+        return "invalid value\0".as_ptr() as *mut c_char;
+    }
+    conf.serve = Some(cv);
+
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let clcf =
+        *(*ctx).loc_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_loc_conf_t;
+    (*clcf).handler = Some(ngx_car_range_serve_handler);
+    ptr::null_mut()
+}
+
+// the bytes of the CAR handed to the filter at a time, the rest isn't read once the
+// filter forwarded the range
+const PART: off_t = 64 << 10;
+
+// a CAR handed to the filter a part at a time: the request, the file and the bytes of it
+// sent so far out of its size
+type Serving = (*mut ngx_http_request_t, *mut ngx_file_t, off_t, off_t);
+
+thread_local! {
+    // requests of the worker waiting for the client to send them more of the CAR
+    static SERVING: RefCell<Vec<Serving>> = const { RefCell::new(Vec::new()) };
+}
+
+// Removes the request from the list when its pool is destroyed.
+struct Served(*mut ngx_http_request_t);
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        SERVING.with(|serving| serving.borrow_mut().retain(|s| s.0 != self.0));
+    }
+}
+
+// the status of a request for a CAR that can't be opened
+fn open_error_status(e: &io::Error) -> ngx_uint_t {
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory | io::ErrorKind::InvalidFilename => {
+            NGX_HTTP_NOT_FOUND as ngx_uint_t
+        }
+        io::ErrorKind::PermissionDenied => NGX_HTTP_FORBIDDEN as ngx_uint_t,
+        _ => NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_uint_t,
+    }
+}
+
+unsafe fn core_loc_conf(r: *mut ngx_http_request_t) -> *mut ngx_http_core_loc_conf_t {
+    *(*r).loc_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_loc_conf_t
+}
+
+// Send the CAR of the request from disk, the body filter trims it to the range.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_serve_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    if req.method() & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+        return NGX_HTTP_NOT_ALLOWED as ngx_int_t;
+    }
+
+    let rc = req.discard_request_body();
+    if rc != NGX_OK as ngx_int_t {
+        return rc;
+    }
+
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    let cv = match conf.serve {
+        Some(cv) => cv,
+        None => return NGX_DECLINED as ngx_int_t,
+    };
+    let mut name: ngx_str_t = std::mem::zeroed();
+    if ngx_http_complex_value(r, cv, &mut name) != NGX_OK as ngx_int_t {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    }
+    // the value is compiled to end with a NUL for the open, which isn't part of the name
+    if name.len > 0 && *name.data.add(name.len - 1) == 0 {
+        name.len -= 1;
+    }
+    let path = match name.to_str() {
+        Ok(path) => path,
+        Err(_) => return NGX_HTTP_NOT_FOUND as ngx_int_t,
+    };
+
+    // opened like the static module does, through the open_file_cache of the location
+    let clcf = core_loc_conf(r);
+    let mut of: ngx_open_file_info_t = std::mem::zeroed();
+    of.read_ahead = (*clcf).read_ahead;
+    of.directio = (*clcf).directio;
+    of.valid = (*clcf).open_file_cache_valid;
+    of.min_uses = (*clcf).open_file_cache_min_uses;
+    of.set_errors((*clcf).open_file_cache_errors as _);
+    of.set_events((*clcf).open_file_cache_events as _);
+    if ngx_http_set_disable_symlinks(r, clcf, &mut name, &mut of) != NGX_OK as ngx_int_t {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    }
+    if ngx_open_cached_file((*clcf).open_file_cache, &mut name, &mut of, req.0.pool)
+        != NGX_OK as ngx_int_t
+    {
+        if of.err == 0 {
+            return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+        }
+        let e = io::Error::from_raw_os_error(of.err as i32);
+        let status = open_error_status(&e);
+        if status == NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_uint_t {
+            let failed = CStr::from_ptr(of.failed).to_string_lossy();
+            ngx_log_error_http!(NGX_LOG_ERR, req, "car_range: {} {}: {}", failed, path, e);
+        }
+        return status as ngx_int_t;
+    }
+    if of.is_file() == 0 {
+        return NGX_HTTP_NOT_FOUND as ngx_int_t;
+    }
+    let len = of.size;
+
+    // the cache closes the file along with the request pool
+    let ngx_file = req.pool().calloc_type::<ngx_file_t>();
+    if ngx_file.is_null() {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    }
+    (*ngx_file).fd = of.fd;
+    (*ngx_file).name = name;
+    (*ngx_file).log = (*req.connection()).log;
+    (*ngx_file).set_directio(of.is_directio());
+
+    req.0.headers_out.status = NGX_HTTP_OK as ngx_uint_t;
+    req.0.headers_out.content_length_n = len;
+    req.0.headers_out.last_modified_time = of.mtime;
+    req.set_content_type(ngx_string!("application/vnd.ipld.car"));
+
+    let rc = ngx_http_send_header(r);
+    if rc == NGX_ERROR as ngx_int_t || rc > NGX_OK as ngx_int_t || req.header_only() {
+        return rc;
+    }
+
+    // without a range the whole CAR is sent, the filter only trims the ones it forwards
+    if inflight::context(req).is_null() {
+        return send_part(r, ngx_file, 0, len, len);
+    }
+    if req.pool().allocate(Served(r)).is_null() {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    }
+    SERVING.with(|serving| serving.borrow_mut().push((r, ngx_file, 0, len)));
+    match send_parts(r) {
+        Some(rc) => rc,
+        None => {
+            if wait(r) != NGX_OK as ngx_int_t {
+                return NGX_ERROR as ngx_int_t;
+            }
+            (*(*r).main).set_count((*(*r).main).count() + 1);
+            NGX_DONE as ngx_int_t
+        }
+    }
+}
+
+// Pass the bytes from `start` to `end` of the CAR to the filters, the last buffer of the
+// response if `end` is its size.
+unsafe fn send_part(
+    r: *mut ngx_http_request_t,
+    file: *mut ngx_file_t,
+    start: off_t,
+    end: off_t,
+    len: off_t,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+    let b = req.pool().calloc_buf();
+    if b.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    // buffers in a file can't be empty, the one of an empty CAR only ends the response
+    if end > start {
+        (*b).file = file;
+        (*b).file_pos = start;
+        (*b).file_last = end;
+        (*b).set_in_file(1);
+    }
+    if end == len {
+        (*b).set_last_buf(if req.is_subrequest() { 0 } else { 1 });
+        (*b).set_last_in_chain(1);
+    }
+
+    let mut out = ngx_chain_t {
+        buf: b,
+        next: ptr::null_mut(),
+    };
+    ngx_http_output_filter(r, &mut out)
+}
+
+// Hand the CAR to the filter a part at a time until it forwarded the range, the CAR ended
+// or the client can't take more. The status to finalize the request with, none when the
+// rest is sent once the client can take it.
+unsafe fn send_parts(r: *mut ngx_http_request_t) -> Option<ngx_int_t> {
+    let req = Request::from_ngx_http_request(r);
+    loop {
+        let (file, start, len) = SERVING.with(|serving| {
+            let serving = serving.borrow();
+            let s = serving.iter().find(|s| s.0 == r)?;
+            Some((s.1, s.2, s.3))
+        })?;
+        let end = (start + PART).min(len);
+        let rc = send_part(r, file, start, end, len);
+        SERVING.with(|serving| {
+            if let Some(s) = serving.borrow_mut().iter_mut().find(|s| s.0 == r) {
+                s.2 = end;
+            }
+        });
+
+        // the filter ended the response once it forwarded the range
+        let ctx = inflight::context(req) as *mut FilterContext;
+        if rc == NGX_ERROR as ngx_int_t || end == len || ctx.is_null() || (*ctx).done() {
+            return Some(rc);
+        }
+        if rc == NGX_AGAIN as ngx_int_t {
+            return None;
+        }
+    }
+}
+
+// Send the rest of the CAR once the client can take it, for send_timeout at most.
+unsafe fn wait(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let clcf = core_loc_conf(r);
+    (*r).write_event_handler = Some(ngx_car_range_serve_write);
+    let wev = (*(*r).connection).write;
+    if (*wev).delayed() == 0 {
+        timer::add_timer(wev, (*clcf).send_timeout);
+    }
+    ngx_handle_write_event(wev, (*clcf).send_lowat)
+}
+
+// Send more of the CAR once the client took the parts sent before, like ngx_http_writer.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_serve_write(r: *mut ngx_http_request_t) {
+    let req = Request::from_ngx_http_request(r);
+    let c = (*r).connection;
+    let wev = (*c).write;
+
+    if (*wev).timedout() == 1 {
+        ngx_log_error_http!(NGX_LOG_INFO, req, "car_range: client timed out");
+        (*c).set_timedout(1);
+        ngx_http_finalize_request(r, NGX_HTTP_REQUEST_TIME_OUT as ngx_int_t);
+        return;
+    }
+    if (*wev).delayed() == 1 || (*r).aio() == 1 {
+        if ngx_handle_write_event(wev, (*core_loc_conf(r)).send_lowat) != NGX_OK as ngx_int_t {
+            ngx_http_finalize_request(r, NGX_ERROR as ngx_int_t);
+        }
+        return;
+    }
+
+    match send_parts(r) {
+        Some(rc) => {
+            (*r).write_event_handler = Some(ngx_http_request_empty_handler);
+            ngx_http_finalize_request(r, rc);
+        }
+        None => {
+            if wait(r) != NGX_OK as ngx_int_t {
+                ngx_http_finalize_request(r, NGX_ERROR as ngx_int_t);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_error_status() {
+        let status = |path: &str| open_error_status(&std::fs::File::open(path).unwrap_err());
+        assert_eq!(
+            status("/nonexistent/bafy.car"),
+            NGX_HTTP_NOT_FOUND as ngx_uint_t
+        );
+        assert_eq!(
+            status("/etc/hostname/bafy.car"),
+            NGX_HTTP_NOT_FOUND as ngx_uint_t
+        );
+        let other = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(
+            open_error_status(&other),
+            NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_uint_t
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Snapshots of the filtered output of fixtures for a set of ranges, so changes to the
+//! parser that change what is forwarded show up as failures rather than go unnoticed.
+//!
+//! A snapshot in `snapshots/` holds the length and SHA-256 of the filtered CAR followed by
+//! the CIDs of the blocks forwarded. A missing snapshot fails the test, and
+//! `UPDATE_SNAPSHOTS=1 cargo test snapshots` records them all again once a change of the
+//! output is intended.
+
+use crate::car_reader::CarBufferContext;
+use crate::pool::{Buffer, MemoryBuffer};
+use crate::testing::{chain, last, mem, Builder, TestPool};
+use car_range_core::range;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// the entity-bytes of the cases, run against every fixture
+const RANGES: &[&str] = &[
+    "0:0",
+    "0:1023",
+    "3500:*",
+    "1000:4999",
+    "555555:999999",
+    "0:1048576",
+    "1048576:2097152",
+    "0:*",
+];
+
+// the upstream buffer sizes, the output mustn't depend on how the CAR is split
+const CHUNKS: &[usize] = &[64 << 10, 4093, 7];
+
+// the most buffers a fixture is split in, the large ones aren't split in tiny buffers
+const MAX_BUFFERS: usize = 10_000;
+
+fn snapshots_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots")
+}
+
+// the snapshot of `car` filtered with `entity-bytes=range` in buffers of `chunk` bytes
+fn snapshot(car: &[u8], range: &str, chunk: usize) -> String {
+    let bounds = range::parse_entity_bytes(range).unwrap();
+    let mut ctx = CarBufferContext::new(bounds, TestPool::new());
+    ctx.set_debug_cids(true);
+    let mut out = Vec::new();
+    let mut chunks = car.chunks(chunk).peekable();
+    while let Some(chunk) = chunks.next() {
+        if ctx.done() {
+            break;
+        }
+        let chain = if chunks.peek().is_none() {
+            chain![mem(chunk), last()]
+        } else {
+            chain![mem(chunk)]
+        };
+        let mut o = ctx.buffer(chain.as_ptr()).unwrap();
+        while !o.is_null() {
+            let b = MemoryBuffer::from_ngx_buf(unsafe { (*o).buf });
+            out.extend_from_slice(b.as_bytes());
+            o = unsafe { (*o).next };
+        }
+    }
+
+    let mut snapshot = format!("bytes {} sha256 ", out.len());
+    for b in Sha256::digest(&out) {
+        write!(snapshot, "{:02x}", b).unwrap();
+    }
+    snapshot.push('\n');
+    for cid in ctx.emitted_cids().unwrap() {
+        writeln!(snapshot, "{}", cid).unwrap();
+    }
+    snapshot
+}
+
+// compare the output of every case against its snapshot, or record it with
+// `UPDATE_SNAPSHOTS`
+fn check_fixture(name: &str, car: &[u8]) {
+    let dir = snapshots_dir();
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    for range in RANGES {
+        let path = dir.join(format!("{}@{}.txt", name, range.replace([':', '*'], "_")));
+        let got = snapshot(car, range, CHUNKS[0]);
+        for &chunk in CHUNKS[1..]
+            .iter()
+            .filter(|&&c| car.len() / c <= MAX_BUFFERS)
+        {
+            assert_eq!(
+                snapshot(car, range, chunk),
+                got,
+                "{} entity-bytes={} in {} byte buffers",
+                name,
+                range,
+                chunk
+            );
+        }
+
+        if update {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(&path, got).unwrap();
+            println!("recorded {}", path.display());
+            continue;
+        }
+        let want = fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!(
+                "no snapshot {}, record it with UPDATE_SNAPSHOTS=1",
+                path.display()
+            )
+        });
+        assert_eq!(got, want, "{} entity-bytes={}", name, range);
+    }
+}
+
+#[test]
+fn test_snapshots_built_files() {
+    let data: Vec<u8> = (0..20000).map(|i| (i * 7) as u8).collect();
+    let builders = [
+        ("raw", Builder::new().chunk(1000).fanout(4)),
+        (
+            "dagpb",
+            Builder::new().chunk(1000).fanout(4).raw_leaves(false),
+        ),
+        (
+            "wrapped",
+            Builder::new().chunk(700).depth(2).wrap("file.bin"),
+        ),
+        (
+            "hamt",
+            Builder::new().chunk(700).depth(3).wrap("file.bin").hamt(16),
+        ),
+    ];
+    for (name, builder) in builders {
+        check_fixture(name, &builder.build(&data).car);
+    }
+}
+
+// the fixtures are stored with git-lfs, their snapshots are recorded from a checkout
+// with them fetched
+#[test]
+#[ignore = "the snapshots of the git-lfs fixtures aren't recorded yet"]
+fn test_snapshots_fixtures() {
+    for name in ["fixture", "midfixture", "sm-dagpb"] {
+        let car =
+            fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("{}.car", name))).unwrap();
+        assert!(
+            !car.starts_with(b"version https://git-lfs"),
+            "{}.car wasn't fetched from git-lfs",
+            name
+        );
+        check_fixture(name, &car);
+    }
+}
@@ -0,0 +1,160 @@
+// The subset of the nginx bindings the CAR parser needs, for builds without the `nginx`
+// feature. The layouts follow the nginx headers so the parser code is the same in both
+// builds. There is no nginx pool to allocate from, the allocation functions return null
+// and only the nginx `Pool` calls them.
+
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+pub type u_char = u8;
+pub type off_t = i64;
+pub type ngx_int_t = isize;
+pub type ngx_uint_t = usize;
+pub type ngx_buf_tag_t = *mut c_void;
+
+#[repr(C)]
+pub struct ngx_pool_s {
+    _opaque: [u8; 0],
+}
+pub type ngx_pool_t = ngx_pool_s;
+
+#[repr(C)]
+pub struct ngx_file_s {
+    _opaque: [u8; 0],
+}
+pub type ngx_file_t = ngx_file_s;
+
+/// The flag bits of `ngx_buf_t`, in declaration order.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ngx_buf_flags(u16);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ngx_buf_s {
+    pub pos: *mut u_char,
+    pub last: *mut u_char,
+    pub file_pos: off_t,
+    pub file_last: off_t,
+    pub start: *mut u_char,
+    pub end: *mut u_char,
+    pub tag: ngx_buf_tag_t,
+    pub file: *mut ngx_file_t,
+    pub shadow: *mut ngx_buf_t,
+    pub _bitfield_align_1: [u8; 0],
+    pub _bitfield_1: ngx_buf_flags,
+    pub num: c_int,
+}
+pub type ngx_buf_t = ngx_buf_s;
+
+macro_rules! buf_flag {
+    ($get:ident, $set:ident, $bit:expr) => {
+        pub fn $get(&self) -> u32 {
+            ((self._bitfield_1.0 >> $bit) & 1) as u32
+        }
+
+        pub fn $set(&mut self, val: u32) {
+            if val != 0 {
+                self._bitfield_1.0 |= 1 << $bit;
+            } else {
+                self._bitfield_1.0 &= !(1 << $bit);
+            }
+        }
+    };
+}
+
+impl ngx_buf_s {
+    buf_flag!(temporary, set_temporary, 0);
+    buf_flag!(memory, set_memory, 1);
+    buf_flag!(mmap, set_mmap, 2);
+    buf_flag!(recycled, set_recycled, 3);
+    buf_flag!(in_file, set_in_file, 4);
+    buf_flag!(flush, set_flush, 5);
+    buf_flag!(sync, set_sync, 6);
+    buf_flag!(last_buf, set_last_buf, 7);
+    buf_flag!(last_in_chain, set_last_in_chain, 8);
+    buf_flag!(last_shadow, set_last_shadow, 9);
+    buf_flag!(temp_file, set_temp_file, 10);
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_bitfield_1(
+        temporary: u32,
+        memory: u32,
+        mmap: u32,
+        recycled: u32,
+        in_file: u32,
+        flush: u32,
+        sync: u32,
+        last_buf: u32,
+        last_in_chain: u32,
+        last_shadow: u32,
+        temp_file: u32,
+    ) -> ngx_buf_flags {
+        let bits = [
+            temporary,
+            memory,
+            mmap,
+            recycled,
+            in_file,
+            flush,
+            sync,
+            last_buf,
+            last_in_chain,
+            last_shadow,
+            temp_file,
+        ];
+        let mut flags = 0;
+        for (i, bit) in bits.into_iter().enumerate() {
+            if bit != 0 {
+                flags |= 1 << i;
+            }
+        }
+        ngx_buf_flags(flags)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ngx_chain_s {
+    pub buf: *mut ngx_buf_t,
+    pub next: *mut ngx_chain_t,
+}
+pub type ngx_chain_t = ngx_chain_s;
+
+pub type ngx_pool_cleanup_pt = Option<unsafe extern "C" fn(data: *mut c_void)>;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ngx_pool_cleanup_s {
+    pub handler: ngx_pool_cleanup_pt,
+    pub data: *mut c_void,
+    pub next: *mut ngx_pool_cleanup_t,
+}
+pub type ngx_pool_cleanup_t = ngx_pool_cleanup_s;
+
+pub unsafe fn ngx_palloc(_pool: *mut ngx_pool_t, _size: usize) -> *mut c_void {
+    ptr::null_mut()
+}
+
+pub unsafe fn ngx_pnalloc(_pool: *mut ngx_pool_t, _size: usize) -> *mut c_void {
+    ptr::null_mut()
+}
+
+pub unsafe fn ngx_pmemalign(
+    _pool: *mut ngx_pool_t,
+    _size: usize,
+    _alignment: usize,
+) -> *mut c_void {
+    ptr::null_mut()
+}
+
+pub unsafe fn ngx_alloc_chain_link(_pool: *mut ngx_pool_t) -> *mut ngx_chain_t {
+    ptr::null_mut()
+}
+
+pub unsafe fn ngx_pool_cleanup_add(
+    _pool: *mut ngx_pool_t,
+    _size: usize,
+) -> *mut ngx_pool_cleanup_t {
+    ptr::null_mut()
+}
@@ -0,0 +1,554 @@
+use crate::bindings::*;
+use crate::config::conf_args;
+use crate::request::*;
+use std::mem;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds of the size histogram buckets, from 1KiB to 256MiB.
+const SIZE_BUCKETS: [u64; 8] = [
+    1 << 10,
+    1 << 14,
+    1 << 18,
+    1 << 20,
+    1 << 22,
+    1 << 24,
+    1 << 26,
+    1 << 28,
+];
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Histogram {
+    // one counter per bucket plus the +Inf bucket, not cumulative
+    buckets: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        let i = SIZE_BUCKETS
+            .iter()
+            .position(|&le| value <= le)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(
+        out: &mut String,
+        name: &str,
+        help: &str,
+        labels: &[Labeled],
+        get: fn(&Stats) -> &Histogram,
+    ) {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} histogram\n",
+            name, help, name
+        ));
+        for (label, stats) in labels {
+            let histogram = get(stats);
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = match SIZE_BUCKETS.get(i) {
+                    Some(le) => le.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                out.push_str(&format!(
+                    "{}_bucket{{label=\"{}\",le=\"{}\"}} {}\n",
+                    name, label, le, cumulative
+                ));
+            }
+            out.push_str(&format!(
+                "{}_sum{{label=\"{}\"}} {}\n{}_count{{label=\"{}\"}} {}\n",
+                name,
+                label,
+                histogram.sum.load(Ordering::Relaxed),
+                name,
+                label,
+                histogram.count.load(Ordering::Relaxed)
+            ));
+        }
+    }
+}
+
+/// Counters of a metrics label.
+pub type Labeled<'a> = (&'a str, &'a Stats);
+
+fn render_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    labels: &[Labeled],
+    get: fn(&Stats) -> &AtomicU64,
+) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} counter\n",
+        name, help, name
+    ));
+    for (label, stats) in labels {
+        out.push_str(&format!(
+            "{}{{label=\"{}\"}} {}\n",
+            name,
+            label,
+            get(stats).load(Ordering::Relaxed)
+        ));
+    }
+}
+
+/// Counters shared by all the workers, stored in the `car_range` shared memory zone.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Stats {
+    pub requests: AtomicU64,
+    pub bytes_emitted: AtomicU64,
+    pub bytes_trimmed: AtomicU64,
+    pub early_terminations: AtomicU64,
+    // upstream bytes left when terminating early
+    pub bytes_saved: AtomicU64,
+    // errors by category: upstream CARs that don't parse or don't match the request,
+    // upstream responses cut short, and allocations the filter couldn't make
+    pub parse_errors: AtomicU64,
+    pub verification_failures: AtomicU64,
+    pub upstream_truncations: AtomicU64,
+    pub alloc_failures: AtomicU64,
+    // bytes forwarded and discarded for each request
+    pub response_size: Histogram,
+    pub trimmed_size: Histogram,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record the sizes of a filtered response that read `bytes_in` upstream bytes and sent
+    /// `bytes_out`. A CARv2 sends more than it reads, and then trims nothing.
+    pub fn observe_response(&self, bytes_in: usize, bytes_out: usize) {
+        self.response_size.observe(bytes_out as u64);
+        self.trimmed_size
+            .observe(bytes_in.saturating_sub(bytes_out) as u64);
+    }
+
+    pub fn render(&self) -> String {
+        format!(
+            "Requests filtered: {}\nBytes trimmed: {}\nEarly terminations: {}\nErrors: parse {} verification {} truncated {} alloc {}\n",
+            self.requests.load(Ordering::Relaxed),
+            self.bytes_trimmed.load(Ordering::Relaxed),
+            self.early_terminations.load(Ordering::Relaxed),
+            self.parse_errors.load(Ordering::Relaxed),
+            self.verification_failures.load(Ordering::Relaxed),
+            self.upstream_truncations.load(Ordering::Relaxed),
+            self.alloc_failures.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Plain text with a section for each label after the default one.
+pub fn render_text(labels: &[Labeled]) -> String {
+    let mut out = String::new();
+    for (i, (label, stats)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push_str(&format!("\nLabel {}:\n", label));
+        }
+        out.push_str(&stats.render());
+    }
+    out
+}
+
+/// Prometheus text exposition format, the series are labeled with their metrics label.
+pub fn render_prometheus(labels: &[Labeled]) -> String {
+    let mut out = String::new();
+    render_counter(
+        &mut out,
+        "car_range_requests_total",
+        "Requests filtered.",
+        labels,
+        |s| &s.requests,
+    );
+    render_counter(
+        &mut out,
+        "car_range_bytes_emitted_total",
+        "Bytes forwarded to clients after filtering.",
+        labels,
+        |s| &s.bytes_emitted,
+    );
+    render_counter(
+        &mut out,
+        "car_range_bytes_trimmed_total",
+        "Upstream bytes discarded by the filter.",
+        labels,
+        |s| &s.bytes_trimmed,
+    );
+    render_counter(
+        &mut out,
+        "car_range_early_terminations_total",
+        "Responses ended before the upstream once the range was satisfied.",
+        labels,
+        |s| &s.early_terminations,
+    );
+    render_counter(
+        &mut out,
+        "car_range_bytes_saved_total",
+        "Upstream bytes left after the range was satisfied, when the upstream length is known.",
+        labels,
+        |s| &s.bytes_saved,
+    );
+    out.push_str(
+        "# HELP car_range_errors_total Filtered responses that failed, by category.\n\
+         # TYPE car_range_errors_total counter\n",
+    );
+    for (label, stats) in labels {
+        for (category, counter) in [
+            ("parse", &stats.parse_errors),
+            ("verification", &stats.verification_failures),
+            ("truncated", &stats.upstream_truncations),
+            ("alloc", &stats.alloc_failures),
+        ] {
+            out.push_str(&format!(
+                "car_range_errors_total{{label=\"{}\",category=\"{}\"}} {}\n",
+                label,
+                category,
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+    }
+    Histogram::render(
+        &mut out,
+        "car_range_response_size_bytes",
+        "Size of the filtered responses.",
+        labels,
+        |s| &s.response_size,
+    );
+    Histogram::render(
+        &mut out,
+        "car_range_trimmed_size_bytes",
+        "Upstream bytes discarded by the filter for each response.",
+        labels,
+        |s| &s.trimmed_size,
+    );
+    out
+}
+
+/// Label of the locations without `car_range_metrics_label`.
+pub const DEFAULT_LABEL: usize = 0;
+
+const MAX_LABELS: usize = 32;
+const MAX_LABEL_LEN: usize = 32;
+
+// counters of a label in the shared zone
+#[repr(C)]
+struct Slot {
+    name_len: usize,
+    name: [u8; MAX_LABEL_LEN],
+    stats: Stats,
+}
+
+impl Slot {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+#[repr(C)]
+struct Zone {
+    slots: [Slot; MAX_LABELS],
+}
+
+#[no_mangle]
+static mut ngx_car_range_shm_zone: *mut ngx_shm_zone_t = ptr::null_mut();
+
+// labels found in the configuration, the workers inherit them from the master
+static mut LABELS: Vec<String> = Vec::new();
+// the slot of the zone holding the counters of each label
+static mut LABEL_SLOTS: [usize; MAX_LABELS] = [0; MAX_LABELS];
+
+fn labels() -> &'static mut Vec<String> {
+    unsafe { &mut *ptr::addr_of_mut!(LABELS) }
+}
+
+/// Forget the labels of the previous configuration, called from `preconfiguration`.
+pub fn reset_labels() {
+    *labels() = vec!["default".to_string()];
+}
+
+/// Register a metrics label while parsing the configuration.
+pub fn add_label(name: &str) -> Result<usize, &'static str> {
+    let valid = |b: u8| b.is_ascii_alphanumeric() || b"_-./".contains(&b);
+    if name.is_empty() || name.len() > MAX_LABEL_LEN || !name.bytes().all(valid) {
+        return Err("must be up to 32 letters, digits, \"_\", \"-\", \".\" or \"/\"\0");
+    }
+    let labels = labels();
+    if let Some(i) = labels.iter().position(|l| l == name) {
+        return Ok(i);
+    }
+    if labels.len() == MAX_LABELS {
+        return Err("too many metrics labels\0");
+    }
+    labels.push(name.to_string());
+    Ok(labels.len() - 1)
+}
+
+unsafe fn zone() -> Option<&'static Zone> {
+    let zone = ngx_car_range_shm_zone;
+    if zone.is_null() || (*zone).data.is_null() {
+        return None;
+    }
+    Some(&*((*zone).data as *const Zone))
+}
+
+/// Counters of a label for the current cycle, if the shared zone was initialized.
+pub fn stats(label: usize) -> Option<&'static Stats> {
+    if label >= labels().len() {
+        return None;
+    }
+    unsafe { Some(&zone()?.slots[LABEL_SLOTS[label]].stats) }
+}
+
+/// Counters of every label of the configuration, the default one first.
+fn labeled_stats() -> Option<Vec<Labeled<'static>>> {
+    let zone = unsafe { zone()? };
+    let labeled = labels()
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (label.as_str(), &zone.slots[unsafe { LABEL_SLOTS[i] }].stats))
+        .collect();
+    Some(labeled)
+}
+
+// Map the labels of the configuration to slots, keeping the counters of the labels
+// already in the zone and reusing the slots of the labels that were removed.
+unsafe fn assign_slots(zone: *mut Zone) {
+    let slots = &mut (*zone).slots;
+    let labels = labels();
+    let mut used = [false; MAX_LABELS];
+    let mut found = [false; MAX_LABELS];
+
+    for (i, label) in labels.iter().enumerate() {
+        if let Some(j) = slots
+            .iter()
+            .position(|slot| slot.name() == label.as_bytes())
+        {
+            LABEL_SLOTS[i] = j;
+            used[j] = true;
+            found[i] = true;
+        }
+    }
+
+    for (i, label) in labels.iter().enumerate() {
+        if found[i] {
+            continue;
+        }
+        let j = match used.iter().position(|used| !used) {
+            Some(j) => j,
+            None => break,
+        };
+        ptr::write_bytes(&mut slots[j], 0, 1);
+        slots[j].name[..label.len()].copy_from_slice(label.as_bytes());
+        slots[j].name_len = label.len();
+        LABEL_SLOTS[i] = j;
+        used[j] = true;
+    }
+}
+
+/// Add the `car_range` shared memory zone holding the counters, called from
+/// `postconfiguration`.
+pub unsafe fn add_zone(cf: *mut ngx_conf_t, module: *mut ngx_module_t) -> ngx_int_t {
+    let mut name = ngx_string!("car_range");
+    let zone = ngx_shared_memory_add(cf, &mut name, 8 * ngx_pagesize, module as *mut c_void);
+    if zone.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    (*zone).init = Some(ngx_car_range_init_zone);
+    ngx_car_range_shm_zone = zone;
+    NGX_OK as ngx_int_t
+}
+
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_init_zone(
+    shm_zone: *mut ngx_shm_zone_t,
+    data: *mut c_void,
+) -> ngx_int_t {
+    // keep the counters of the previous cycle on reload
+    if !data.is_null() {
+        (*shm_zone).data = data;
+        assign_slots(data as *mut Zone);
+        return NGX_OK as ngx_int_t;
+    }
+
+    let shpool = (*shm_zone).shm.addr as *mut ngx_slab_pool_t;
+    if (*shm_zone).shm.exists != 0 {
+        (*shm_zone).data = (*shpool).data;
+        assign_slots((*shpool).data as *mut Zone);
+        return NGX_OK as ngx_int_t;
+    }
+
+    let zone = ngx_slab_calloc(shpool, mem::size_of::<Zone>());
+    if zone.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    (*shpool).data = zone;
+    (*shm_zone).data = zone;
+    assign_slots(zone as *mut Zone);
+    NGX_OK as ngx_int_t
+}
+
+/// `car_range_status [text|prometheus]` directive, installs the status content handler in
+/// the location.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_car_range_status_cfg(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    _conf: *mut c_void,
+) -> *mut c_char {
+    let args = conf_args(cf);
+    let handler = match args.get(1).map(|arg| arg.to_str()) {
+        None | Some(Ok("text")) => ngx_car_range_status_handler,
+        Some(Ok("prometheus")) => ngx_car_range_prometheus_handler,
+        _ => return "must be \"text\" or \"prometheus\"\0".as_ptr() as *mut c_char,
+    };
+
+    let ctx = (*cf).ctx as *mut ngx_http_conf_ctx_t;
+    let clcf =
+        *(*ctx).loc_conf.add(ngx_http_core_module.ctx_index) as *mut ngx_http_core_loc_conf_t;
+    (*clcf).handler = Some(handler);
+    ptr::null_mut()
+}
+
+// Report the counters in plain text, like stub_status.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_status_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    send_stats(r, ngx_string!("text/plain"), render_text)
+}
+
+// Report the counters for Prometheus scrapes.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_prometheus_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    send_stats(
+        r,
+        ngx_string!("text/plain; version=0.0.4"),
+        render_prometheus,
+    )
+}
+
+unsafe fn send_stats(
+    r: *mut ngx_http_request_t,
+    ct: ngx_str_t,
+    render: fn(&[Labeled]) -> String,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    if req.method() & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+        return NGX_HTTP_NOT_ALLOWED as ngx_int_t;
+    }
+
+    let rc = req.discard_request_body();
+    if rc != NGX_OK as ngx_int_t {
+        return rc;
+    }
+
+    let body = match labeled_stats() {
+        Some(labeled) => render(&labeled),
+        None => return NGX_HTTP_SERVICE_UNAVAILABLE as ngx_int_t,
+    };
+
+    req.send_response(NGX_HTTP_OK as ngx_uint_t, ct, body.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_stats() {
+        let stats = Stats::default();
+        Stats::add(&stats.requests, 3);
+        Stats::add(&stats.bytes_trimmed, 1024);
+        Stats::add(&stats.early_terminations, 1);
+        Stats::add(&stats.parse_errors, 2);
+
+        assert_eq!(
+            stats.render(),
+            "Requests filtered: 3\nBytes trimmed: 1024\nEarly terminations: 1\nErrors: parse 2 verification 0 truncated 0 alloc 0\n"
+        );
+        assert!(render_prometheus(&[("default", &stats)])
+            .contains("car_range_errors_total{label=\"default\",category=\"parse\"} 2\n"));
+    }
+
+    #[test]
+    fn test_render_prometheus_histogram() {
+        let stats = Stats::default();
+        stats.response_size.observe(512);
+        stats.response_size.observe(1 << 20);
+        stats.response_size.observe(1 << 30);
+
+        let out = render_prometheus(&[("default", &stats)]);
+
+        assert!(out.contains(
+            "# TYPE car_range_requests_total counter\ncar_range_requests_total{label=\"default\"} 0\n"
+        ));
+        assert!(
+            out.contains("car_range_response_size_bytes_bucket{label=\"default\",le=\"1024\"} 1\n")
+        );
+        assert!(out.contains(
+            "car_range_response_size_bytes_bucket{label=\"default\",le=\"1048576\"} 2\n"
+        ));
+        assert!(out.contains(
+            "car_range_response_size_bytes_bucket{label=\"default\",le=\"268435456\"} 2\n"
+        ));
+        assert!(
+            out.contains("car_range_response_size_bytes_bucket{label=\"default\",le=\"+Inf\"} 3\n")
+        );
+        assert!(out.contains(&format!(
+            "car_range_response_size_bytes_sum{{label=\"default\"}} {}\n",
+            512 + (1 << 20) + (1 << 30)
+        )));
+        assert!(out.contains("car_range_response_size_bytes_count{label=\"default\"} 3\n"));
+        assert!(
+            out.contains("car_range_trimmed_size_bytes_bucket{label=\"default\",le=\"+Inf\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn test_observe_response() {
+        let stats = Stats::default();
+        stats.observe_response(4096, 1024);
+        // the pragma, header and index of a CARv2 make it longer than the CAR it wraps
+        stats.observe_response(1000, 1200);
+
+        let out = render_prometheus(&[("default", &stats)]);
+        assert!(out.contains("car_range_trimmed_size_bytes_sum{label=\"default\"} 3072\n"));
+        assert!(out.contains("car_range_trimmed_size_bytes_count{label=\"default\"} 2\n"));
+        assert!(
+            out.contains("car_range_trimmed_size_bytes_bucket{label=\"default\",le=\"+Inf\"} 2\n")
+        );
+        assert!(out.contains("car_range_response_size_bytes_sum{label=\"default\"} 2224\n"));
+    }
+
+    #[test]
+    fn test_render_labels() {
+        let default = Stats::default();
+        let mirror = Stats::default();
+        Stats::add(&mirror.requests, 7);
+
+        let labels = [("default", &default), ("mirror", &mirror)];
+
+        assert!(render_text(&labels).contains("\nLabel mirror:\nRequests filtered: 7\n"));
+        let out = render_prometheus(&labels);
+        assert!(out.contains("car_range_requests_total{label=\"default\"} 0\n"));
+        assert!(out.contains("car_range_requests_total{label=\"mirror\"} 7\n"));
+    }
+
+    #[test]
+    fn test_add_label() {
+        reset_labels();
+        assert_eq!(add_label("mirror"), Ok(1));
+        assert_eq!(add_label("ipfs"), Ok(2));
+        assert_eq!(add_label("mirror"), Ok(1));
+        assert!(add_label("bad\"label").is_err());
+    }
+}
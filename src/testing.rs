@@ -0,0 +1,275 @@
+//! Support shared by the tests of the modules: an allocator standing in for an nginx pool
+//! and upstream buffer chains, along with the unixfs files of the parser tests.
+//!
+//! ```ignore
+//! let file = Builder::new().chunk(1000).fanout(4).wrap("file.bin").build(&data);
+//! let mut ctx = CarBufferContext::new(.., TestPool::new());
+//! let mut chain = chain![mem(&file.car), file(0..4096), flush(), last()];
+//! let out = ctx.buffer(chain.as_ptr());
+//! ```
+
+use crate::bindings::*;
+use crate::pool::Allocator;
+pub use car_range_core::testing::*;
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::ops::Range;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::{mem, ptr};
+
+/// Counts of what a [`TestPool`] did, readable once the pool was moved or dropped.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    pub allocs: Cell<usize>,
+    pub frees: Cell<usize>,
+    pub failures: Cell<usize>,
+    pub cleanups_added: Cell<usize>,
+    pub cleanups_run: Cell<usize>,
+}
+
+impl PoolStats {
+    /// Allocations not freed yet.
+    pub fn live(&self) -> usize {
+        self.allocs.get() - self.frees.get()
+    }
+}
+
+// a value to drop when the pool is destroyed
+type Cleanup = (unsafe fn(*mut c_void), *mut c_void);
+
+/// An allocator owning what it hands out until it is dropped, like an nginx pool is
+/// destroyed, when it runs the cleanups registered and frees everything. Allocations can
+/// be made to fail after a number of them, to test the paths handling the failures.
+pub struct TestPool {
+    blocks: Vec<(*mut u8, Layout)>,
+    cleanups: Vec<Cleanup>,
+    // allocations left before they fail, unlimited when none
+    budget: Option<usize>,
+    stats: Rc<PoolStats>,
+}
+
+impl TestPool {
+    pub fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            cleanups: Vec::new(),
+            budget: None,
+            stats: Rc::default(),
+        }
+    }
+
+    /// A pool failing every allocation after the first `allocs`.
+    pub fn failing_after(allocs: usize) -> Self {
+        let mut pool = Self::new();
+        pool.budget = Some(allocs);
+        pool
+    }
+
+    pub fn stats(&self) -> Rc<PoolStats> {
+        self.stats.clone()
+    }
+
+    fn take_budget(&mut self) -> bool {
+        match &mut self.budget {
+            Some(0) => {
+                self.stats.failures.set(self.stats.failures.get() + 1);
+                false
+            }
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    // the memory isn't zeroed like the one of a pool, it is filled with a pattern so reads
+    // of bytes never written show up
+    fn alloc_layout(&mut self, size: usize, align: usize) -> *mut c_void {
+        if !self.take_budget() {
+            return ptr::null_mut();
+        }
+        let layout = Layout::from_size_align(size.max(1), align).unwrap();
+        let p = unsafe { alloc::alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { ptr::write_bytes(p, 0xa5, layout.size()) };
+        self.blocks.push((p, layout));
+        self.stats.allocs.set(self.stats.allocs.get() + 1);
+        p as *mut c_void
+    }
+}
+
+impl Default for TestPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allocator for TestPool {
+    fn alloc(&mut self, size: usize) -> *mut c_void {
+        self.alloc_layout(size, mem::align_of::<usize>())
+    }
+
+    fn alloc_unaligned(&mut self, size: usize) -> *mut c_void {
+        self.alloc_layout(size, 1)
+    }
+
+    fn alloc_aligned(&mut self, size: usize, alignment: usize) -> *mut c_void {
+        self.alloc_layout(size, alignment)
+    }
+
+    unsafe fn add_cleanup_for_value<T>(&mut self, value: *mut T) -> Result<(), ()> {
+        if !self.take_budget() {
+            return Err(());
+        }
+        self.cleanups.push((drop_value::<T>, value as *mut c_void));
+        self.stats
+            .cleanups_added
+            .set(self.stats.cleanups_added.get() + 1);
+        Ok(())
+    }
+}
+
+impl Drop for TestPool {
+    fn drop(&mut self) {
+        // like ngx_destroy_pool, the cleanups run last registered first before the memory
+        // is freed
+        for (cleanup, data) in self.cleanups.drain(..).rev() {
+            unsafe { cleanup(data) };
+            self.stats
+                .cleanups_run
+                .set(self.stats.cleanups_run.get() + 1);
+        }
+        for (p, layout) in self.blocks.drain(..) {
+            unsafe { alloc::dealloc(p, layout) };
+            self.stats.frees.set(self.stats.frees.get() + 1);
+        }
+    }
+}
+
+unsafe fn drop_value<T>(data: *mut c_void) {
+    ptr::drop_in_place(data as *mut T);
+}
+
+/// An upstream chain of the buffers listed, see [`chain!`]. The chain owns its buffers
+/// and links so the filter can point into them until it is dropped.
+pub struct Chain {
+    bufs: Vec<*mut ngx_buf_t>,
+    links: Vec<*mut ngx_chain_t>,
+}
+
+impl Chain {
+    pub fn new(bufs: Vec<ngx_buf_t>) -> Self {
+        let bufs: Vec<_> = bufs
+            .into_iter()
+            .map(|b| Box::into_raw(Box::new(b)))
+            .collect();
+        let mut links = Vec::with_capacity(bufs.len());
+        let mut next = ptr::null_mut();
+        for &buf in bufs.iter().rev() {
+            next = Box::into_raw(Box::new(ngx_chain_t { buf, next }));
+            links.push(next);
+        }
+        links.reverse();
+        Self { bufs, links }
+    }
+
+    /// The first link of the chain, null when it is empty.
+    pub fn as_ptr(&self) -> *mut ngx_chain_t {
+        self.links.first().copied().unwrap_or(ptr::null_mut())
+    }
+
+    /// The link of the `i`th buffer, to filter the chain from there.
+    pub fn link(&self, i: usize) -> *mut ngx_chain_t {
+        self.links[i]
+    }
+
+    pub fn buf(&self, i: usize) -> &ngx_buf_t {
+        unsafe { &*self.bufs[i] }
+    }
+
+    pub fn buf_mut(&mut self, i: usize) -> *mut ngx_buf_t {
+        self.bufs[i]
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        for &cl in &self.links {
+            drop(unsafe { Box::from_raw(cl) });
+        }
+        for &b in &self.bufs {
+            drop(unsafe { Box::from_raw(b) });
+        }
+    }
+}
+
+/// Builds a [`Chain`] of buffers made by [`mem`], [`file`], [`flush`], [`sync`] and
+/// [`last`], or any other `ngx_buf_t`.
+///
+/// ```ignore
+/// let mut chain = chain![mem(&car[..100]), flush(), mem(&car[100..]), last()];
+/// ```
+macro_rules! chain {
+    ($($buf:expr),* $(,)?) => {
+        $crate::testing::Chain::new(vec![$($buf),*])
+    };
+}
+pub(crate) use chain;
+
+/// A buffer of `data` in memory the filters must not modify, like the buffers of the
+/// proxy and the cache.
+pub fn mem(data: &[u8]) -> ngx_buf_t {
+    let mut b = empty();
+    let range = data.as_ptr_range();
+    b.start = range.start as *mut u_char;
+    b.pos = b.start;
+    b.last = range.end as *mut u_char;
+    b.end = b.last;
+    b.set_memory(1);
+    b
+}
+
+/// A buffer of the bytes `range` of a file without a copy of them in memory, like the
+/// buffers of a cached response sent with sendfile.
+pub fn file(range: Range<i64>) -> ngx_buf_t {
+    let mut b = empty();
+    b.file_pos = range.start;
+    b.file_last = range.end;
+    b.set_in_file(1);
+    b
+}
+
+pub fn flush() -> ngx_buf_t {
+    let mut b = empty();
+    b.set_flush(1);
+    b
+}
+
+pub fn sync() -> ngx_buf_t {
+    let mut b = empty();
+    b.set_sync(1);
+    b
+}
+
+/// The empty buffer ending the response.
+pub fn last() -> ngx_buf_t {
+    let mut b = empty();
+    b.set_last_buf(1);
+    b.set_last_in_chain(1);
+    b
+}
+
+// a buffer without bytes or flags, its pointers dangling rather than null so its bytes
+// read as an empty slice
+fn empty() -> ngx_buf_t {
+    let mut b: ngx_buf_t = unsafe { mem::zeroed() };
+    let p = NonNull::<u_char>::dangling().as_ptr();
+    b.start = p;
+    b.pos = p;
+    b.last = p;
+    b.end = p;
+    b
+}
@@ -0,0 +1,38 @@
+use crate::bindings::*;
+use std::ptr;
+
+// ngx_event_add_timer and ngx_event_del_timer are inline functions, and ngx_post_event a
+// macro, bindgen can't generate bindings for.
+
+/// Fire the event in `timer` milliseconds, replacing any pending timer.
+pub unsafe fn add_timer(ev: *mut ngx_event_t, timer: ngx_msec_t) {
+    if (*ev).timer_set() == 1 {
+        del_timer(ev);
+    }
+    (*ev).timer.key = ptr::read_volatile(ptr::addr_of!(ngx_current_msec)) + timer;
+    ngx_rbtree_insert(ptr::addr_of_mut!(ngx_event_timer_rbtree), &mut (*ev).timer);
+    (*ev).set_timer_set(1);
+}
+
+/// Cancel the event timer if it is pending.
+pub unsafe fn del_timer(ev: *mut ngx_event_t) {
+    if (*ev).timer_set() == 0 {
+        return;
+    }
+    ngx_rbtree_delete(ptr::addr_of_mut!(ngx_event_timer_rbtree), &mut (*ev).timer);
+    (*ev).set_timer_set(0);
+}
+
+/// Run the event handler once the worker is done with the events it is processing.
+pub unsafe fn post_event(ev: *mut ngx_event_t) {
+    if (*ev).posted() == 1 {
+        return;
+    }
+    (*ev).set_posted(1);
+    let q = ptr::addr_of_mut!(ngx_posted_events);
+    let x = ptr::addr_of_mut!((*ev).queue);
+    (*x).prev = (*q).prev;
+    (*(*x).prev).next = x;
+    (*x).next = q;
+    (*q).prev = x;
+}
@@ -0,0 +1,420 @@
+//! Traces of the buffer chains passed to the body filter, recorded with `car_range_trace` and
+//! replayed with `car-range replay`, so a response that went wrong because of how the
+//! upstream split it can be filtered again the same way offline.
+//!
+//! A trace is a text header followed by a record for every call of the filter:
+//!
+//! ```text
+//! car-range-trace 1
+//! range 2000:4000
+//! scope entity
+//! bytes on
+//! chain 2
+//! buf 4096 memory
+//! <4096 bytes>
+//! buf 0 flush,last_buf
+//! ```
+//!
+//! The bytes of memory buffers follow their `buf` line when they are recorded, otherwise
+//! they are read from the upstream CAR when the trace is replayed. Buffers in files are
+//! only recorded with their size, the filter forwards them without reading them.
+
+use crate::bindings::*;
+use crate::pool::{Buffer, MemoryBuffer};
+use car_range_core::range::{format_entity_bytes, FromEnd};
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::mem;
+use std::ops::Bound;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+
+const MAGIC: &str = "car-range-trace 1";
+
+// the names of the buffer flags in a trace, in the order they are written
+const FLAGS: &[&str] = &[
+    "memory",
+    "file",
+    "flush",
+    "sync",
+    "last_buf",
+    "last_in_chain",
+];
+
+/// A buffer of a recorded chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceBuf {
+    /// Bytes left in the buffer, in memory or in its file.
+    pub size: usize,
+    pub memory: bool,
+    pub file: bool,
+    pub flush: bool,
+    pub sync: bool,
+    pub last_buf: bool,
+    pub last_in_chain: bool,
+    /// The bytes of a memory buffer, if they were recorded.
+    pub data: Option<Vec<u8>>,
+}
+
+/// Writes the chains passed to the filter of one response.
+pub struct Recorder<W: Write> {
+    w: W,
+    bytes: bool,
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Record in a new file at `path`.
+    pub fn create(
+        path: impl AsRef<Path>,
+        range: (Bound<u64>, Bound<u64>),
+        from_end: FromEnd,
+        scope: &str,
+        bytes: bool,
+    ) -> io::Result<Self> {
+        Self::new(
+            BufWriter::new(File::create(path)?),
+            range,
+            from_end,
+            scope,
+            bytes,
+        )
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Record the chains of a response filtered for `range`, with the bounds of `from_end`
+    /// counted from the end of the entity, and `scope` in `w`, with the bytes of the
+    /// buffers if `bytes` is set.
+    pub fn new(
+        mut w: W,
+        range: (Bound<u64>, Bound<u64>),
+        from_end: FromEnd,
+        scope: &str,
+        bytes: bool,
+    ) -> io::Result<Self> {
+        writeln!(w, "{}", MAGIC)?;
+        writeln!(w, "range {}", format_entity_bytes(range, from_end))?;
+        writeln!(w, "scope {}", scope)?;
+        writeln!(w, "bytes {}", if bytes { "on" } else { "off" })?;
+        Ok(Self { w, bytes })
+    }
+
+    /// Record the chain passed to a call of the filter, before the filter consumes it.
+    pub fn record(&mut self, chain: *mut ngx_chain_t) -> io::Result<()> {
+        let mut bufs = Vec::new();
+        let mut cl = chain;
+        while !cl.is_null() {
+            bufs.push(unsafe { MemoryBuffer::from_ngx_buf((*cl).buf) });
+            cl = unsafe { (*cl).next };
+        }
+
+        writeln!(self.w, "chain {}", bufs.len())?;
+        for buf in bufs {
+            let b = buf.as_ngx_buf();
+            let flags = unsafe {
+                [
+                    buf.in_memory(),
+                    buf.is_file(),
+                    (*b).flush() == 1,
+                    (*b).sync() == 1,
+                    (*b).last_buf() == 1,
+                    (*b).last_in_chain() == 1,
+                ]
+            };
+            write!(self.w, "buf {} ", buf.size())?;
+            write_flags(&mut self.w, &flags)?;
+            writeln!(self.w)?;
+            if self.bytes && buf.in_memory() {
+                self.w.write_all(buf.as_bytes())?;
+                writeln!(self.w)?;
+            }
+        }
+        // whatever happens to the worker next, the trace has the chain that caused it
+        self.w.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+fn write_flags(w: &mut impl Write, flags: &[bool; 6]) -> io::Result<()> {
+    let names: Vec<&str> = FLAGS
+        .iter()
+        .zip(flags)
+        .filter(|(_, &set)| set)
+        .map(|(name, _)| *name)
+        .collect();
+    if names.is_empty() {
+        write!(w, "-")
+    } else {
+        write!(w, "{}", names.join(","))
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// A recorded trace.
+#[derive(Debug)]
+pub struct Trace {
+    /// The `entity-bytes` value of the request.
+    pub range: String,
+    /// The `dag-scope` of the request.
+    pub scope: String,
+    /// The bytes of the memory buffers were recorded.
+    pub bytes: bool,
+    pub chains: Vec<Vec<TraceBuf>>,
+}
+
+impl Trace {
+    pub fn read(mut r: impl BufRead) -> io::Result<Trace> {
+        let mut line = String::new();
+        let mut next_line = |r: &mut dyn BufRead| -> io::Result<Option<String>> {
+            line.clear();
+            if r.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            Ok(Some(line.trim_end_matches('\n').to_string()))
+        };
+
+        if next_line(&mut r)?.as_deref() != Some(MAGIC) {
+            return Err(invalid("not a car-range trace"));
+        }
+        let mut header = |name: &str, r: &mut dyn BufRead| -> io::Result<String> {
+            next_line(r)?
+                .as_deref()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|val| val.strip_prefix(' '))
+                .map(str::to_string)
+                .ok_or_else(|| invalid(format!("missing {}", name)))
+        };
+        let range = header("range", &mut r)?;
+        let scope = header("scope", &mut r)?;
+        let bytes = match header("bytes", &mut r)?.as_str() {
+            "on" => true,
+            "off" => false,
+            val => return Err(invalid(format!("invalid bytes {}", val))),
+        };
+
+        let mut chains = Vec::new();
+        let mut line = String::new();
+        while r.read_line(&mut line)? > 0 {
+            let n: usize = line
+                .trim_end()
+                .strip_prefix("chain ")
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| invalid(format!("invalid chain {}", line.trim_end())))?;
+            let mut chain = Vec::with_capacity(n);
+            for _ in 0..n {
+                line.clear();
+                r.read_line(&mut line)?;
+                let mut buf = parse_buf(line.trim_end())?;
+                if bytes && buf.memory {
+                    let mut data = vec![0; buf.size + 1];
+                    r.read_exact(&mut data)?;
+                    if data.pop() != Some(b'\n') {
+                        return Err(invalid("buffer bytes not followed by a newline"));
+                    }
+                    buf.data = Some(data);
+                }
+                chain.push(buf);
+            }
+            chains.push(chain);
+            line.clear();
+        }
+
+        Ok(Trace {
+            range,
+            scope,
+            bytes,
+            chains,
+        })
+    }
+}
+
+fn parse_buf(line: &str) -> io::Result<TraceBuf> {
+    let err = || invalid(format!("invalid buf {}", line));
+    let mut fields = line.strip_prefix("buf ").ok_or_else(err)?.split(' ');
+    let size = fields.next().and_then(|n| n.parse().ok()).ok_or_else(err)?;
+    let mut flags = [false; 6];
+    match fields.next().ok_or_else(err)? {
+        "-" => {}
+        names => {
+            for name in names.split(',') {
+                let i = FLAGS.iter().position(|&f| f == name).ok_or_else(err)?;
+                flags[i] = true;
+            }
+        }
+    }
+    let [memory, file, flush, sync, last_buf, last_in_chain] = flags;
+    Ok(TraceBuf {
+        size,
+        memory,
+        file,
+        flush,
+        sync,
+        last_buf,
+        last_in_chain,
+        data: None,
+    })
+}
+
+/// Builds the chains of a trace again. The buffers live as long as the replayer, since the
+/// filter holds on to the ones it forwards until they are sent.
+#[derive(Default)]
+pub struct Replayer {
+    bufs: Vec<*mut ngx_buf_t>,
+    links: Vec<*mut ngx_chain_t>,
+    data: Vec<Vec<u8>>,
+}
+
+impl Replayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chain of the recorded `bufs`, the bytes that weren't recorded read from `car`.
+    pub fn chain(
+        &mut self,
+        bufs: &[TraceBuf],
+        car: &mut impl Read,
+    ) -> io::Result<*mut ngx_chain_t> {
+        let mut chain: *mut ngx_chain_t = ptr::null_mut();
+        for tb in bufs.iter().rev() {
+            let mut b: ngx_buf_t = unsafe { mem::zeroed() };
+            let p = NonNull::<u_char>::dangling().as_ptr();
+            (b.start, b.pos, b.last, b.end) = (p, p, p, p);
+            if tb.memory {
+                let data = match &tb.data {
+                    Some(data) => data.clone(),
+                    None => {
+                        let mut data = vec![0; tb.size];
+                        car.read_exact(&mut data)?;
+                        data
+                    }
+                };
+                let range = data.as_ptr_range();
+                b.start = range.start as *mut u_char;
+                b.pos = b.start;
+                b.last = range.end as *mut u_char;
+                b.end = b.last;
+                b.set_memory(1);
+                self.data.push(data);
+            } else if tb.file {
+                b.file_last = tb.size as off_t;
+            }
+            b.set_in_file(tb.file as u32);
+            b.set_flush(tb.flush as u32);
+            b.set_sync(tb.sync as u32);
+            b.set_last_buf(tb.last_buf as u32);
+            b.set_last_in_chain(tb.last_in_chain as u32);
+
+            let buf = Box::into_raw(Box::new(b));
+            chain = Box::into_raw(Box::new(ngx_chain_t { buf, next: chain }));
+            self.bufs.push(buf);
+            self.links.push(chain);
+        }
+        Ok(chain)
+    }
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        unsafe {
+            for &link in &self.links {
+                drop(Box::from_raw(link));
+            }
+            for &buf in &self.bufs {
+                drop(Box::from_raw(buf));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car_reader::CarBufferContext;
+    use crate::testing::{chain, file, flush, last, mem, Builder, TestPool};
+    use car_range_core::range::parse_entity_bytes;
+
+    // the bytes forwarded for the chain
+    fn output(o: *mut ngx_chain_t) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cl = o;
+        while !cl.is_null() {
+            let b = MemoryBuffer::from_ngx_buf(unsafe { (*cl).buf });
+            if b.in_memory() {
+                out.extend_from_slice(b.as_bytes());
+            }
+            cl = unsafe { (*cl).next };
+        }
+        out
+    }
+
+    fn record_and_replay(bytes: bool) {
+        let data: Vec<u8> = (0..20000).map(|i| (i * 7) as u8).collect();
+        let car = Builder::new().chunk(1000).fanout(4).build(&data).car;
+        let range = parse_entity_bytes("3000:6999").unwrap();
+
+        // odd splits, a file buffer and flags, filtered and recorded as they come
+        let mut chains = vec![
+            chain![mem(&car[..37]), flush()],
+            chain![mem(&car[37..1200]), file(0..512)],
+            chain![mem(&car[1200..5001])],
+            chain![mem(&car[5001..]), last()],
+        ];
+        let mut rec = Recorder::new(Vec::new(), range, FromEnd::default(), "all", bytes).unwrap();
+        let mut ctx = CarBufferContext::new(range, TestPool::new());
+        let mut want = Vec::new();
+        for chain in &mut chains {
+            rec.record(chain.as_ptr()).unwrap();
+            let o = ctx.buffer(chain.as_ptr()).unwrap();
+            want.extend(output(o));
+        }
+
+        let trace = Trace::read(&rec.into_inner()[..]).unwrap();
+        assert_eq!(trace.range, "3000:6999");
+        assert_eq!(trace.scope, "all");
+        assert_eq!(trace.bytes, bytes);
+        assert_eq!(trace.chains.len(), 4);
+        assert_eq!(trace.chains[1][1].size, 512);
+        assert!(trace.chains[1][1].file && !trace.chains[1][1].memory);
+        assert!(trace.chains[3][1].last_buf && trace.chains[3][1].last_in_chain);
+
+        let mut replayer = Replayer::new();
+        let mut upstream = &car[..];
+        let mut ctx = CarBufferContext::new(range, TestPool::new());
+        let mut got = Vec::new();
+        for bufs in &trace.chains {
+            let chain = replayer.chain(bufs, &mut upstream).unwrap();
+            let o = ctx.buffer(chain).unwrap();
+            got.extend(output(o));
+        }
+        assert!(ctx.done());
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_trace_replay_bytes() {
+        record_and_replay(true);
+    }
+
+    #[test]
+    fn test_trace_replay_sizes() {
+        record_and_replay(false);
+    }
+
+    #[test]
+    fn test_trace_invalid() {
+        assert!(Trace::read(&b"car-range-trace 2\n"[..]).is_err());
+        let header = "car-range-trace 1\nrange 0:*\nscope all\nbytes off\n";
+        assert!(Trace::read(header.as_bytes()).unwrap().chains.is_empty());
+        let bad = format!("{}chain 1\nbuf 10 memory,bogus\n", header);
+        assert!(Trace::read(bad.as_bytes()).is_err());
+        let short = format!("{}chain 2\nbuf 10 memory\n", header);
+        assert!(Trace::read(short.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use crate::bindings::*;
+use crate::config::LocConf;
+use crate::module::{ngx_car_range_module, summary, FilterContext};
+use crate::pool::Allocator;
+use crate::request::*;
+use car_range_core::range::format_bound;
+use std::ptr;
+use std::time::Duration;
+
+const START: usize = 0;
+const END: usize = 1;
+
+const BYTES_OUT: usize = 0;
+const BLOCKS_OUT: usize = 1;
+const BYTES_IN: usize = 2;
+const SAVED_BYTES: usize = 3;
+
+const SCOPE: usize = 0;
+const DONE: usize = 1;
+
+const OUTCOME: usize = 0;
+const LOG_JSON: usize = 1;
+
+const FIRST_BYTE_TIME: usize = 0;
+const RANGE_START_TIME: usize = 1;
+const FILTER_TIME: usize = 2;
+
+#[no_mangle]
+static mut ngx_car_range_vars: [ngx_http_variable_t; 21] = [
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_start"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_bound_variable),
+        data: START,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_end"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_bound_variable),
+        data: END,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_cache_key"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_cache_key_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_upstream_args"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_upstream_args_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_canonical_args"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_canonical_args_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_ipfs_path"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_ipfs_path_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_build"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_build_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_active"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_active_variable),
+        data: 0,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_bytes_out"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_counter_variable),
+        data: BYTES_OUT,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_blocks_out"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_counter_variable),
+        data: BLOCKS_OUT,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_bytes_in"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_counter_variable),
+        data: BYTES_IN,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_saved_bytes"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_counter_variable),
+        data: SAVED_BYTES,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_scope"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_state_variable),
+        data: SCOPE,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_done"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_state_variable),
+        data: DONE,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_root"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_root_variable),
+        data: 0,
+        flags: 0,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_digest"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_digest_variable),
+        data: 0,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_outcome"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_summary_variable),
+        data: OUTCOME,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_log_json"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_summary_variable),
+        data: LOG_JSON,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_first_byte_time"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_time_variable),
+        data: FIRST_BYTE_TIME,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_range_start_time"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_time_variable),
+        data: RANGE_START_TIME,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+    ngx_http_variable_t {
+        name: ngx_string!("car_range_filter_time"),
+        set_handler: None,
+        get_handler: Some(ngx_car_range_time_variable),
+        data: FILTER_TIME,
+        flags: NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+        index: 0,
+    },
+];
+
+/// Register the module variables, called from `preconfiguration`.
+#[no_mangle]
+pub unsafe extern "C" fn ngx_car_range_add_variables(cf: *mut ngx_conf_t) -> ngx_int_t {
+    for def in (*ptr::addr_of_mut!(ngx_car_range_vars)).iter_mut() {
+        let var = ngx_http_add_variable(cf, &mut def.name, def.flags);
+        if var.is_null() {
+            return NGX_ERROR as ngx_int_t;
+        }
+        (*var).get_handler = def.get_handler;
+        (*var).data = def.data;
+    }
+    NGX_OK as ngx_int_t
+}
+
+// seconds with a microsecond resolution, filtering a buffer is often well below a millisecond
+fn format_duration(d: Duration) -> String {
+    format!("{}.{:06}", d.as_secs(), d.subsec_micros())
+}
+
+/// Copy `value` into the request pool and point the variable at it.
+pub unsafe fn set_variable(
+    req: &Request,
+    v: *mut ngx_http_variable_value_t,
+    value: &[u8],
+) -> ngx_int_t {
+    let data = req.pool().alloc_unaligned(value.len()) as *mut u8;
+    if data.is_null() {
+        return NGX_ERROR as ngx_int_t;
+    }
+    ptr::copy_nonoverlapping(value.as_ptr(), data, value.len());
+
+    (*v).data = data;
+    (*v).set_len(value.len() as u32);
+    (*v).set_valid(1);
+    (*v).set_no_cacheable(0);
+    (*v).set_not_found(0);
+    NGX_OK as ngx_int_t
+}
+
+pub unsafe fn set_not_found(v: *mut ngx_http_variable_value_t) -> ngx_int_t {
+    (*v).set_not_found(1);
+    NGX_OK as ngx_int_t
+}
+
+// $car_range_start and $car_range_end, the bounds of the requested entity range
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_bound_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    let (range, source) = match req.entity_range(conf.range_sources()) {
+        Some(found) => found,
+        None => return set_not_found(v),
+    };
+
+    let from_end = req.range_from_end(source);
+    let bound = if data == START {
+        format_bound(range.0, from_end.start)
+    } else {
+        format_bound(range.1, from_end.end)
+    };
+    set_variable(req, v, bound.as_bytes())
+}
+
+// $car_range_cache_key, the normalized scope and range of a CAR request to add to
+// proxy_cache_key, without the range with car_range_cache_full
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_cache_key_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    let key = req.cache_key(conf.cache_full(), conf.range_sources());
+    set_variable(req, v, key.as_bytes())
+}
+
+// $car_range_upstream_args, the query string without entity-bytes and car_range_ignore_args
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_upstream_args_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    match req.upstream_args(conf.ignore_args()) {
+        Some(args) => set_variable(req, v, args.as_bytes()),
+        None => set_not_found(v),
+    }
+}
+
+// $car_range_canonical_args, the query string with dag-scope and entity-bytes normalized
+// and the legacy parameters translated to them
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_canonical_args_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let conf = &*(req.get_loc_conf(&ngx_car_range_module) as *const LocConf);
+    match req.canonical_args(conf.ignore_args()) {
+        Some(args) => set_variable(req, v, args.as_bytes()),
+        None => set_not_found(v),
+    }
+}
+
+// $car_range_ipfs_path, the CID and path after /ipfs/, escaped, for car_range_origin
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_ipfs_path_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    match req.ipfs_path() {
+        Some(path) => set_variable(req, v, path.as_bytes()),
+        None => set_not_found(v),
+    }
+}
+
+// $car_range_build, the version and commit the module was built from
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_build_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+    let build = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+    set_variable(req, v, build.as_bytes())
+}
+
+// $car_range_active, "1" once the header filter engaged for the request and "0" otherwise
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_active_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let active = !req.get_context(&ngx_car_range_module).is_null();
+    set_variable(req, v, if active { b"1" } else { b"0" })
+}
+
+// $car_range_bytes_out and $car_range_blocks_out, what the filter forwarded so far,
+// $car_range_bytes_in, what it read from upstream, and $car_range_saved_bytes, the upstream
+// bytes it didn't forward. Read from the log phase they hold the totals for the response.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_counter_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    if ctx.is_null() {
+        return set_not_found(v);
+    }
+
+    let count = match data {
+        BYTES_OUT => (*ctx).bytes_out(),
+        BLOCKS_OUT => (*ctx).blocks_out(),
+        SAVED_BYTES => (*ctx).bytes_discarded(),
+        _ => (*ctx).bytes_in(),
+    };
+    set_variable(req, v, count.to_string().as_bytes())
+}
+
+// $car_range_scope, the dag-scope the response is filtered with, and $car_range_done, "1"
+// once the range is complete and "0" before. Not found when the filter isn't engaged, for
+// Lua phases and maps to read as the response goes.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_state_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    if ctx.is_null() {
+        return set_not_found(v);
+    }
+
+    if data == SCOPE {
+        let scope = req.arg("dag-scope").unwrap_or_else(|| "all".to_string());
+        set_variable(req, v, scope.as_bytes())
+    } else {
+        set_variable(req, v, if (*ctx).done() { b"1" } else { b"0" })
+    }
+}
+
+// $car_range_root, the root CID of the requested path
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_root_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    match req.root_cid() {
+        Some(root) => set_variable(req, v, root.as_bytes()),
+        None => set_not_found(v),
+    }
+}
+
+// $car_range_digest, the CID of the filtered CAR with car_range_digest, not found until
+// the response is complete
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_digest_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    match ctx.as_ref().and_then(|ctx| ctx.digest()) {
+        Some(cid) => set_variable(req, v, cid.to_string().as_bytes()),
+        None => set_not_found(v),
+    }
+}
+
+// $car_range_outcome, how the filtering ended, and $car_range_log_json, the summary of the
+// request logged by the module as a JSON object. Read them from the log phase.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_summary_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    if ctx.is_null() {
+        return set_not_found(v);
+    }
+
+    let summary = summary(req, &*ctx);
+    if data == OUTCOME {
+        set_variable(req, v, summary.outcome.as_bytes())
+    } else {
+        set_variable(req, v, summary.json().as_bytes())
+    }
+}
+
+// $car_range_first_byte_time, $car_range_range_start_time and $car_range_filter_time, the
+// timings of the filtering stage for tracing. Not found until they are known.
+#[no_mangle]
+unsafe extern "C" fn ngx_car_range_time_variable(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    data: usize,
+) -> ngx_int_t {
+    let req = Request::from_ngx_http_request(r);
+
+    let ctx = req.get_context(&ngx_car_range_module) as *mut FilterContext;
+    if ctx.is_null() {
+        return set_not_found(v);
+    }
+
+    let time = match data {
+        FIRST_BYTE_TIME => (*ctx).first_byte_time(),
+        RANGE_START_TIME => (*ctx).range_start_time(),
+        _ => Some((*ctx).filter_time()),
+    };
+    match time {
+        Some(time) => set_variable(req, v, format_duration(time).as_bytes()),
+        None => set_not_found(v),
+    }
+}
@@ -0,0 +1,92 @@
+//! A proxy-wasm filter doing what the nginx module does, for Envoy and the other proxies
+//! running proxy-wasm filters: responses to requests accepting a CAR with an
+//! `entity-bytes` range only keep the blocks needed to verify the range.
+//!
+//! ```text
+//! cargo build --release --target wasm32-wasip1
+//! ```
+
+use car_range_core::decoder::{self, CarRangeDecoder, Part};
+use proxy_wasm::traits::{Context, HttpContext};
+use proxy_wasm::types::{Action, LogLevel};
+use std::ops::Bound;
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Warn);
+    proxy_wasm::set_http_context(|_, _| -> Box<dyn HttpContext> {
+        Box::new(CarRange::default())
+    });
+}}
+
+#[derive(Default)]
+struct CarRange {
+    // set once the request is known to ask for a range of a CAR
+    decoder: Option<CarRangeDecoder<(Bound<u64>, Bound<u64>)>>,
+    // the range was read or the upstream CAR is malformed, the rest of the body is dropped
+    done: bool,
+}
+
+impl Context for CarRange {}
+
+impl HttpContext for CarRange {
+    fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+        if self.get_http_request_header("accept").as_deref() != Some("application/vnd.ipld.car") {
+            return Action::Continue;
+        }
+        let Some(path) = self.get_http_request_header(":path") else {
+            return Action::Continue;
+        };
+        let Some((_, args)) = path.split_once('?') else {
+            return Action::Continue;
+        };
+        if let Some(range) = decoder::parse_range(args) {
+            let mut decoder = CarRangeDecoder::new(range);
+            decoder.set_verify_root(args.split('&').any(|arg| arg == "dag-scope=entity"));
+            self.decoder = Some(decoder);
+        }
+        Action::Continue
+    }
+
+    fn on_http_response_headers(&mut self, _: usize, _: bool) -> Action {
+        if self.decoder.is_some() {
+            // the length of the filtered body isn't known until it is sent
+            self.set_http_response_header("content-length", None);
+            self.set_http_response_header("x-car-range", Some("filtered"));
+        }
+        Action::Continue
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, _: bool) -> Action {
+        let Some(mut decoder) = self.decoder.take() else {
+            return Action::Continue;
+        };
+        let mut out = Vec::new();
+        if !self.done {
+            let body = self
+                .get_http_response_body(0, body_size)
+                .unwrap_or_default();
+            match decoder.push(&body) {
+                Ok(()) => {
+                    while let Some(part) = decoder.pull() {
+                        match part {
+                            Part::Held(bytes) => out.extend_from_slice(&bytes),
+                            Part::Range(range) => out.extend_from_slice(&body[range]),
+                        }
+                    }
+                    self.done = decoder.past_end();
+                }
+                Err(e) => {
+                    proxy_wasm::hostcalls::log(
+                        LogLevel::Error,
+                        &format!("car_range: malformed CAR: {}", e),
+                    )
+                    .ok();
+                    self.done = true;
+                }
+            }
+        }
+        self.set_http_response_body(0, body_size, &out);
+        self.decoder = Some(decoder);
+        Action::Continue
+    }
+}